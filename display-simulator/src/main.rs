@@ -122,7 +122,7 @@ fn main() {
 
     display::draw_time_ticker(&mut display, &now, start_display_hour);
     display::draw_base_calendar(&mut display, start_display_hour);
-    display::draw_sync_time(&mut display, &now);
+    display::draw_sync_time(&mut display, &now, false);
     //display::draw_days(&mut display, &now.weekday(), 3);
 
     let today = now.date();
@@ -55,7 +55,6 @@
               export PS1="(esp-rs)$PS1"
 
               export AP_SSID="Thesis-MM"
-              export AP_PASS="Thesis2026"
 
               # This variable is important - it tells rustup where to find the esp toolchain,
               # without needing to copy it into your local ~/.rustup/ folder.
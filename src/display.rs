@@ -1,11 +1,12 @@
 use core::range::RangeInclusive;
 
 use embedded_graphics::mono_font::{MonoFont, MonoTextStyle};
-use embedded_graphics::prelude::{Dimensions, DrawTarget, OriginDimensions, Point};
+use embedded_graphics::prelude::{Dimensions, DrawTarget, OriginDimensions, Point, Size};
 use embedded_graphics::prelude::{Drawable, Primitive};
 use embedded_graphics::primitives::{Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle};
 use embedded_graphics::text::Text;
 use heapless::format as hformat;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
 use weact_studio_epd::Color as EpdColor;
 
 #[cfg(feature = "defmt")]
@@ -32,6 +33,14 @@ const OVERWRITE_STYLE: PrimitiveStyle<EpdColor> = PrimitiveStyleBuilder::new()
 const BORDERLESS_OVERWRITE_STYLE: PrimitiveStyle<EpdColor> =
     PrimitiveStyle::with_fill(EpdColor::White);
 
+/// Fits the Wi-Fi login with an escaped SSID of full length
+const QR_MAX_VERSION: Version = Version::new(7);
+/// Light modules around the code, required by the scanners
+const QR_QUIET_ZONE: i32 = 4;
+/// The QR codes of the config screen fill its bottom next to each other
+const QR_CELL_SIZE: i32 = DISPLAY_WIDTH as i32 / 2;
+const QR_TOP: i32 = DISPLAY_HEIGHT as i32 - QR_CELL_SIZE - EXTRA_BOTTOM_SPACE;
+
 const fn calculate_row_padding(start_hour: u8, end_hour: u8) -> i32 {
     assert!(
         end_hour > start_hour,
@@ -155,15 +164,17 @@ const fn calculate_text_width(char_count: u16, font: MonoFont) -> u16 {
         + font.character_size.width as u16 / 2
 }
 
-pub(crate) fn draw_sync_time<D>(display: &mut D, time: &jiff::Zoned)
+/// Shows the time of the sync the events are from, marked as offline if it isn't the current one
+pub(crate) fn draw_sync_time<D>(display: &mut D, time: &jiff::Zoned, offline: bool)
 where
     D: DrawTarget<Color = EpdColor> + OriginDimensions,
     D::Error: core::fmt::Debug,
 {
     #[cfg(feature = "defmt")]
     crate::defmt::info!("Calendar sync time: {}", crate::defmt::Debug2Format(&time));
-    let fmt_time: heapless::String<11> =
-        hformat!("Sync: {:02}:{:02}", time.hour(), time.minute()).unwrap();
+    let label = if offline { "Offline since" } else { "Sync:" };
+    let fmt_time: heapless::String<20> =
+        hformat!("{} {:02}:{:02}", label, time.hour(), time.minute()).unwrap();
 
     let text_style = embedded_graphics::text::TextStyleBuilder::new()
         .alignment(embedded_graphics::text::Alignment::Right)
@@ -538,6 +549,129 @@ where
     .unwrap();
 }
 
+/// The config screen of the access point, the text above the QR codes for joining it and
+/// for opening the setup page
+pub(crate) fn draw_portal_config<D>(display: &mut D, text: &str, wifi_login: &str, url: &str)
+where
+    D: DrawTarget<Color = EpdColor> + OriginDimensions,
+    D::Error: core::fmt::Debug,
+{
+    let text_style = embedded_graphics::text::TextStyleBuilder::new()
+        .alignment(embedded_graphics::text::Alignment::Center)
+        .baseline(embedded_graphics::text::Baseline::Middle)
+        .build();
+
+    Text::with_text_style(
+        text,
+        Point::new(DISPLAY_WIDTH as i32 / 2, QR_TOP / 2),
+        CHARACTER_STYLE,
+        text_style,
+    )
+    .draw(display)
+    .unwrap();
+
+    for (i, (data, caption)) in [(wifi_login, "Join Wi-Fi"), (url, "Open setup page")]
+        .into_iter()
+        .enumerate()
+    {
+        let center_x = QR_CELL_SIZE * i as i32 + QR_CELL_SIZE / 2;
+        draw_qr_code(
+            display,
+            data,
+            Point::new(center_x, QR_TOP + QR_CELL_SIZE / 2),
+        );
+        Text::with_text_style(
+            caption,
+            Point::new(center_x, QR_TOP + QR_CELL_SIZE),
+            MINI_CHARACTER_STYLE,
+            text_style,
+        )
+        .draw(display)
+        .unwrap();
+    }
+}
+
+/// Draws `data` as a QR code around `center`, with the largest scale that fits the cell
+fn draw_qr_code<D>(display: &mut D, data: &str, center: Point)
+where
+    D: DrawTarget<Color = EpdColor>,
+    D::Error: core::fmt::Debug,
+{
+    let mut temp = [0u8; QR_MAX_VERSION.buffer_len()];
+    let mut out = [0u8; QR_MAX_VERSION.buffer_len()];
+    let Ok(qr) = QrCode::encode_text(
+        data,
+        &mut temp,
+        &mut out,
+        QrCodeEcc::Low,
+        Version::MIN,
+        QR_MAX_VERSION,
+        None,
+        true,
+    ) else {
+        #[cfg(feature = "defmt")]
+        crate::defmt::warn!("Too long for a QR code: {}", data);
+        return;
+    };
+
+    let modules = qr.size();
+    let scale = (QR_CELL_SIZE / (modules + 2 * QR_QUIET_ZONE)).max(1);
+    let top_left = center - Point::new(modules * scale / 2, modules * scale / 2);
+    let style = PrimitiveStyle::with_fill(EpdColor::Black);
+    for y in 0..modules {
+        for x in 0..modules {
+            if qr.get_module(x, y) {
+                Rectangle::new(
+                    top_left + Point::new(x * scale, y * scale),
+                    Size::new(scale as u32, scale as u32),
+                )
+                .into_styled(style)
+                .draw(display)
+                .unwrap();
+            }
+        }
+    }
+}
+
+/// Draws a full screen error with a hint on how to resolve it and the technical details
+pub(crate) fn draw_error<D>(display: &mut D, title: &str, hint: &str, details: &str)
+where
+    D: DrawTarget<Color = EpdColor> + OriginDimensions,
+    D::Error: core::fmt::Debug,
+{
+    let text_style = embedded_graphics::text::TextStyleBuilder::new()
+        .alignment(embedded_graphics::text::Alignment::Center)
+        .baseline(embedded_graphics::text::Baseline::Middle)
+        .build();
+    let center = display.bounding_box().center();
+    let line_height = EVENT_FONT.character_size.height as i32;
+
+    Text::with_text_style(
+        title,
+        center - Point::new(0, line_height),
+        CHARACTER_STYLE,
+        text_style,
+    )
+    .draw(display)
+    .unwrap();
+    Text::with_text_style(
+        hint,
+        center + Point::new(0, line_height),
+        CHARACTER_STYLE,
+        text_style,
+    )
+    .draw(display)
+    .unwrap();
+    Text::with_text_style(
+        details,
+        center + Point::new(0, 3 * line_height),
+        MINI_CHARACTER_STYLE,
+        text_style,
+    )
+    .draw(display)
+    .unwrap();
+}
+
 #[cfg(not(target_arch = "xtensa"))]
 pub use not_xtensa::*;
 #[cfg(target_arch = "xtensa")]
@@ -557,10 +691,21 @@ pub mod xtensa {
     use super::draw_event;
     use crate::hardware;
 
+    /// Where the drawn events come from, decides the time shown in the corner
+    pub(crate) enum EventSource<'a> {
+        /// Fetched on this wake
+        Network,
+        /// Fetched on an earlier wake and kept in the RTC memory
+        Kept(&'a jiff::Zoned),
+        /// Stored after the last successful sync, the calendars are unreachable
+        Offline(&'a jiff::Zoned),
+    }
+
     pub(crate) async fn write_to_screen<DI, BSY, RST, DELAY>(
         display: &mut Display420BlackWhite,
         driver: &mut WeActStudio420BlackWhiteDriver<DI, BSY, RST, DELAY>,
         events: &mut [vcal_parser::vevent::VEventData],
+        source: EventSource<'_>,
         rtc: &mut Rtc<'_>,
     ) where
         DI: AsyncWriteOnlyDataCommand,
@@ -582,9 +727,11 @@ pub mod xtensa {
 
         events.sort();
 
+        // The events reach until the end of the day
+        let (window_start, window_end) = crate::networking::display_window(&time);
         for event in events
             .iter()
-            .filter(|f| f.dtstart.is_some() && f.dtend.is_some())
+            .filter(|f| f.overlaps(window_start.timestamp(), window_end.timestamp()))
         {
             let start_dt = event.dtstart.unwrap().to_zoned(tz.clone());
             let end_dt = event.dtend.unwrap().to_zoned(tz.clone());
@@ -604,7 +751,58 @@ pub mod xtensa {
         if super::limit_to_today() {
             crate::display::draw_time_ticker(display, &time, start_display_hour);
         }
-        crate::display::draw_sync_time(display, &time);
+        match source {
+            EventSource::Network => crate::display::draw_sync_time(display, &time, false),
+            EventSource::Kept(synced_at) => {
+                crate::display::draw_sync_time(display, synced_at, false)
+            }
+            EventSource::Offline(synced_at) => {
+                crate::display::draw_sync_time(display, synced_at, true)
+            }
+        }
+        driver.full_update(display).await.unwrap();
+
+        crate::wifi::wait_until_wifi_stop().await;
+
+        crate::hardware::go_to_deep_sleep(rtc);
+    }
+
+    /// Shows why the events couldn't be fetched, then sleeps until the next retry
+    pub(crate) async fn write_error_screen<DI, BSY, RST, DELAY>(
+        display: &mut Display420BlackWhite,
+        driver: &mut WeActStudio420BlackWhiteDriver<DI, BSY, RST, DELAY>,
+        error: &crate::networking::NetworkError,
+        rtc: &mut Rtc<'_>,
+    ) where
+        DI: AsyncWriteOnlyDataCommand,
+        BSY: embedded_hal::digital::InputPin + Wait,
+        RST: EhalOutputPin,
+        DELAY: DelayNs,
+    {
+        let details: heapless::String<64> = super::hformat!("{}", error).unwrap_or_default();
+        const RECONFIGURE: &str = "Press the button to reconfigure";
+        if error.is_auth_failure() {
+            super::draw_error(display, "Authentication failed", RECONFIGURE, &details);
+        } else if matches!(
+            error,
+            crate::networking::NetworkError::WrongUrl
+                | crate::networking::NetworkError::PlainHttpDisabled
+        ) {
+            super::draw_error(display, "Invalid calendar URL", RECONFIGURE, &details);
+        } else if error.is_too_many_events() {
+            super::draw_error(display, "Too many events", RECONFIGURE, &details);
+        } else {
+            let retry = hardware::next_wakeup_time(rtc);
+            let hint: heapless::String<32> =
+                super::hformat!("Retry at {:02}:{:02}", retry.hour(), retry.minute())
+                    .unwrap_or_default();
+            let title = if matches!(error, crate::networking::NetworkError::WifiUnavailable) {
+                "Wi-Fi unavailable"
+            } else {
+                "Server unreachable"
+            };
+            super::draw_error(display, title, &hint, &details);
+        }
         driver.full_update(display).await.unwrap();
 
         crate::wifi::wait_until_wifi_stop().await;
@@ -0,0 +1,304 @@
+//! Keeps the resolved addresses of the servers in the RTC memory, so the wakes within the TTL
+//! of the answer connect without a DNS lookup.
+//!
+//! embassy-net doesn't tell the TTL of an answer, so the queries are sent by hand to the DNS
+//! server of the network. The `DnsSocket` is the fallback for anything else. Only the IPv4
+//! addresses are kept, IPv6-only networks look the hosts up on every wake. The lookups the
+//! cache answered on the last wake are counted for the diagnostics.
+use core::net::{IpAddr, Ipv4Addr};
+use core::sync::atomic::Ordering;
+
+use embassy_net::dns::DnsSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embedded_nal_async::{AddrType, Dns};
+use portable_atomic::AtomicU32;
+use smoltcp::wire::{
+    DnsFlags, DnsOpcode, DnsPacket, DnsQueryType, DnsQuestion, DnsRcode, DnsRecord, DnsRecordData,
+    DnsRepr,
+};
+
+use crate::fnv::fnv1a;
+
+/// Number of hosts kept, the CalDAV server, the OAuth2 token endpoint and the ics feeds
+const SLOTS: usize = 4;
+/// Longer TTLs are cut, the clock may be corrected in between
+const MAX_TTL_S: u32 = 24 * 60 * 60;
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(3);
+
+/// FNV-1a of the host name, the names themselves would take too much of the RTC memory
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static HOSTS: [AtomicU32; SLOTS] = [const { AtomicU32::new(0) }; SLOTS];
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static ADDRESSES: [AtomicU32; SLOTS] = [const { AtomicU32::new(0) }; SLOTS];
+/// Unix time in seconds when the answer expires
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static EXPIRES_S: [AtomicU32; SLOTS] = [const { AtomicU32::new(0) }; SLOTS];
+/// Rejects the slots after a power loss, when the memory isn't initialized
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static CHECKSUMS: [AtomicU32; SLOTS] = [const { AtomicU32::new(0) }; SLOTS];
+
+/// Duration of the last lookup which went to the network in milliseconds
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static LOOKUP_MS: AtomicU32 = AtomicU32::new(0);
+/// Lookups answered from the cache on the last wake
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static CACHE_HITS: AtomicU32 = AtomicU32::new(0);
+
+fn host_hash(host: &str) -> u32 {
+    fnv1a(host.bytes().map(|b| b.to_ascii_lowercase()))
+}
+
+fn checksum(host: u32, address: u32, expires: u32) -> u32 {
+    fnv1a(
+        [host, address, expires]
+            .iter()
+            .flat_map(|value| value.to_le_bytes()),
+    )
+}
+
+fn lookup(host: u32, now: u32) -> Option<Ipv4Addr> {
+    (0..SLOTS).find_map(|slot| {
+        let address = ADDRESSES[slot].load(Ordering::Relaxed);
+        let expires = EXPIRES_S[slot].load(Ordering::Relaxed);
+        let valid = HOSTS[slot].load(Ordering::Relaxed) == host
+            && CHECKSUMS[slot].load(Ordering::Relaxed) == checksum(host, address, expires)
+            // An expiry too far ahead was stored before the clock got corrected
+            && (now..=now.saturating_add(MAX_TTL_S)).contains(&expires);
+        valid.then(|| Ipv4Addr::from_bits(address))
+    })
+}
+
+/// Replaces the slot of the host, or the one which expires first
+fn store(host: u32, address: Ipv4Addr, expires: u32) {
+    let slot = (0..SLOTS)
+        .find(|&slot| HOSTS[slot].load(Ordering::Relaxed) == host)
+        .or_else(|| (0..SLOTS).min_by_key(|&slot| EXPIRES_S[slot].load(Ordering::Relaxed)))
+        .unwrap_or_default();
+    let address = address.to_bits();
+    HOSTS[slot].store(host, Ordering::Relaxed);
+    ADDRESSES[slot].store(address, Ordering::Relaxed);
+    EXPIRES_S[slot].store(expires, Ordering::Relaxed);
+    CHECKSUMS[slot].store(checksum(host, address, expires), Ordering::Relaxed);
+}
+
+/// Forgets every address, a connection to one of them failed
+pub(crate) fn clear() {
+    for checksum in &CHECKSUMS {
+        checksum.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Resets the counter, called before the first connection of a wake
+pub(crate) fn start_wake() {
+    CACHE_HITS.store(0, Ordering::Relaxed);
+}
+
+/// The lookups of the last wake which fetched the calendars
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CacheStats {
+    pub hits: u32,
+    /// The lookups the cache answered, estimated with the duration of the last real one
+    pub saved_ms: u32,
+}
+
+pub(crate) fn cache_stats() -> CacheStats {
+    let hits = CACHE_HITS.load(Ordering::Relaxed);
+    CacheStats {
+        hits,
+        saved_ms: hits.saturating_mul(LOOKUP_MS.load(Ordering::Relaxed)),
+    }
+}
+
+/// A [`DnsSocket`] which answers from the RTC memory while the TTL lasts
+pub struct CachingDns<'a> {
+    socket: DnsSocket<'a>,
+    stack: Stack<'a>,
+    /// Unix time in seconds at `started`, the RTC isn't reachable from here
+    started_s: u32,
+    started: embassy_time::Instant,
+}
+
+impl<'a> CachingDns<'a> {
+    pub fn new(stack: Stack<'a>, now: jiff::Timestamp) -> Self {
+        Self {
+            socket: DnsSocket::new(stack),
+            stack,
+            started_s: now.as_second() as u32,
+            started: embassy_time::Instant::now(),
+        }
+    }
+
+    fn now_s(&self) -> u32 {
+        self.started_s + self.started.elapsed().as_secs() as u32
+    }
+
+    /// Sends a query to the DNS server of the network, returns the address and its TTL
+    async fn query(&self, host: &str, type_: DnsQueryType) -> Option<(IpAddr, u32)> {
+        let server = self
+            .stack
+            .config_v4()
+            .and_then(|config| config.dns_servers.first().copied());
+        let server = match server {
+            Some(server) => IpAddress::Ipv4(server),
+            None => IpAddress::Ipv6(*self.stack.config_v6()?.dns_servers.first()?),
+        };
+
+        let mut name = heapless::Vec::<u8, 255>::new();
+        for label in host.trim_end_matches('.').split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return None;
+            }
+            name.push(label.len() as u8).ok()?;
+            name.extend_from_slice(label.as_bytes()).ok()?;
+        }
+        name.push(0).ok()?;
+
+        let transaction_id = embassy_time::Instant::now().as_ticks() as u16;
+        let repr = DnsRepr {
+            transaction_id,
+            opcode: DnsOpcode::Query,
+            flags: DnsFlags::RECURSION_DESIRED,
+            question: DnsQuestion { name: &name, type_ },
+        };
+        let mut request = [0u8; 300];
+        let len = repr.buffer_len();
+        repr.emit(&mut DnsPacket::new_unchecked(&mut request[..len]));
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 2];
+        let mut rx_buffer = [0u8; 512];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_buffer = [0u8; 300];
+        let mut socket = UdpSocket::new(
+            self.stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let endpoint = IpEndpoint::new(server, DNS_PORT);
+        socket.bind(0).ok()?;
+        socket.send_to(&request[..len], endpoint).await.ok()?;
+
+        let mut response = [0u8; 512];
+        embassy_time::with_timeout(QUERY_TIMEOUT, async {
+            loop {
+                let (len, meta) = socket.recv_from(&mut response).await.ok()?;
+                if meta.endpoint == endpoint
+                    && let Some(answer) = parse_answer(&response[..len], transaction_id, type_)
+                {
+                    return Some(answer);
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+}
+
+/// Reads the first record of the asked type, the TTL is the lowest one of the CNAME chain
+fn parse_answer(packet: &[u8], transaction_id: u16, type_: DnsQueryType) -> Option<(IpAddr, u32)> {
+    let packet = DnsPacket::new_checked(packet).ok()?;
+    if packet.transaction_id() != transaction_id
+        || !packet.flags().contains(DnsFlags::RESPONSE)
+        || packet.rcode() != DnsRcode::NoError
+        || packet.question_count() != 1
+    {
+        return None;
+    }
+
+    let (mut rest, _) = DnsQuestion::parse(packet.payload()).ok()?;
+    let mut ttl = MAX_TTL_S;
+    for _ in 0..packet.answer_record_count() {
+        let (next, record) = DnsRecord::parse(rest).ok()?;
+        rest = next;
+        ttl = ttl.min(record.ttl);
+        match record.data {
+            DnsRecordData::A(address) if type_ == DnsQueryType::A => {
+                return Some((IpAddr::V4(address), ttl));
+            }
+            DnsRecordData::Aaaa(address) if type_ == DnsQueryType::Aaaa => {
+                return Some((IpAddr::V6(address), ttl));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The record types worth asking for, the A records first while there is an IPv4 address.
+/// IPv6-only networks get the AAAA records, which DNS64 makes up for the IPv4-only servers.
+pub(crate) fn query_types(stack: Stack<'_>) -> impl Iterator<Item = DnsQueryType> {
+    [
+        (stack.config_v4().is_some(), DnsQueryType::A),
+        (stack.config_v6().is_some(), DnsQueryType::Aaaa),
+    ]
+    .into_iter()
+    .filter_map(|(configured, type_)| configured.then_some(type_))
+}
+
+impl Dns for CachingDns<'_> {
+    type Error = embassy_net::dns::Error;
+
+    async fn get_host_by_name(
+        &self,
+        host: &str,
+        addr_type: AddrType,
+    ) -> Result<IpAddr, Self::Error> {
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return Ok(address);
+        }
+        let ipv4 = self.stack.config_v4().is_some();
+        let addr_type = match addr_type {
+            AddrType::Either if !ipv4 => AddrType::IPv6,
+            addr_type => addr_type,
+        };
+
+        let hash = host_hash(host);
+        let now = self.now_s();
+        if ipv4
+            && !matches!(addr_type, AddrType::IPv6)
+            && let Some(address) = lookup(hash, now)
+        {
+            crate::defmt::info!("{} resolved from the cache", host);
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Ok(IpAddr::V4(address));
+        }
+
+        let started = embassy_time::Instant::now();
+        let types = query_types(self.stack).filter(|type_| match addr_type {
+            AddrType::IPv4 => *type_ == DnsQueryType::A,
+            AddrType::IPv6 => *type_ == DnsQueryType::Aaaa,
+            AddrType::Either => true,
+        });
+        let mut answer = None;
+        for type_ in types {
+            answer = self.query(host, type_).await;
+            if answer.is_some() {
+                break;
+            }
+        }
+        let result = match answer {
+            Some((address, ttl)) => {
+                crate::defmt::info!("{} resolved, TTL {} s", host, ttl);
+                if let IpAddr::V4(address) = address {
+                    store(hash, address, now.saturating_add(ttl.min(MAX_TTL_S)));
+                }
+                Ok(address)
+            }
+            None => self.socket.get_host_by_name(host, addr_type).await,
+        };
+        LOOKUP_MS.store(started.elapsed().as_millis() as u32, Ordering::Relaxed);
+        result
+    }
+
+    async fn get_host_by_address(
+        &self,
+        address: IpAddr,
+        result: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        self.socket.get_host_by_address(address, result).await
+    }
+}
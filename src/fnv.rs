@@ -0,0 +1,8 @@
+//! The FNV-1a hash behind the checksums and keys of the state kept in the RTC memory.
+
+/// 32-bit FNV-1a over `bytes`
+pub(crate) fn fnv1a(bytes: impl Iterator<Item = u8>) -> u32 {
+    bytes.fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
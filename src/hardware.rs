@@ -7,10 +7,56 @@ use esp_hal::{
 use crate::{BOOT_TYPES, BootType};
 
 const SLEEP_DURATION: u64 = 300;
+
+/// Seconds of the next deep sleep if it isn't the regular interval, zero otherwise
+static SLEEP_OVERRIDE_S: portable_atomic::AtomicU32 = portable_atomic::AtomicU32::new(0);
 const TZ: jiff::tz::TimeZone = jiff::tz::TimeZone::fixed(jiff::tz::offset(2));
 
+/// A `u64` for the RTC memory, esp-hal only persists atomics of up to 32 bits
+pub(crate) struct PersistentU64 {
+    low: portable_atomic::AtomicU32,
+    high: portable_atomic::AtomicU32,
+}
+
+// SAFETY: Only contains atomics, which are valid for any bit pattern
+unsafe impl esp_hal::Persistable for PersistentU64 {}
+
+impl PersistentU64 {
+    pub(crate) const fn new(value: u64) -> Self {
+        Self {
+            low: portable_atomic::AtomicU32::new(value as u32),
+            high: portable_atomic::AtomicU32::new((value >> 32) as u32),
+        }
+    }
+
+    pub(crate) fn load(&self) -> u64 {
+        let low = self.low.load(core::sync::atomic::Ordering::Relaxed) as u64;
+        let high = self.high.load(core::sync::atomic::Ordering::Relaxed) as u64;
+        (high << 32) | low
+    }
+
+    pub(crate) fn store(&self, value: u64) {
+        self.low
+            .store(value as u32, core::sync::atomic::Ordering::Relaxed);
+        self.high
+            .store((value >> 32) as u32, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Makes the next deep sleep last `seconds` instead of the regular interval
+pub(crate) fn set_sleep_duration(seconds: u32) {
+    SLEEP_OVERRIDE_S.store(seconds, core::sync::atomic::Ordering::Relaxed);
+}
+
+fn sleep_duration() -> u64 {
+    match SLEEP_OVERRIDE_S.load(core::sync::atomic::Ordering::Relaxed) {
+        0 => SLEEP_DURATION,
+        seconds => seconds as u64,
+    }
+}
+
 pub(crate) fn go_to_deep_sleep(rtc: &mut esp_hal::rtc_cntl::Rtc<'_>) -> ! {
-    let sleep_time = core::time::Duration::from_secs(SLEEP_DURATION);
+    let sleep_time = core::time::Duration::from_secs(sleep_duration());
     let timer_wakeup = TimerWakeupSource::new(sleep_time);
 
     #[cfg(debug_assertions)]
@@ -40,6 +86,18 @@ pub(crate) fn get_time(rtc: &esp_hal::rtc_cntl::Rtc<'_>) -> jiff::Zoned {
     now.to_zoned(TZ)
 }
 
+/// The time of the next timer wakeup if the device went to sleep now
+pub(crate) fn next_wakeup_time(rtc: &esp_hal::rtc_cntl::Rtc<'_>) -> jiff::Zoned {
+    get_time(rtc)
+        .checked_add(jiff::SignedDuration::from_secs(sleep_duration() as i64))
+        .unwrap()
+}
+
+/// A timer wake follows a sleep the firmware chose, unlike the button or a reset
+pub(crate) fn woke_from_timer() -> bool {
+    matches!(wakeup_cause(), SleepSource::Timer)
+}
+
 // Sets the boot type based on wakeup cause
 pub(crate) fn apply_wakeup_boot_type() {
     match wakeup_cause() {
@@ -68,7 +126,8 @@ pub async fn button_task(mut button: Input<'static>) {
             |x| {
                 Some(match BootType::from_u8(x) {
                     BootType::Display => BootType::Config as u8,
-                    BootType::Config => BootType::Display as u8,
+                    // Leaving the fallback retries the network right away
+                    BootType::Config | BootType::Fallback => BootType::Display as u8,
                 })
             },
         )
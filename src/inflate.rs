@@ -0,0 +1,457 @@
+//! Streaming decompression of `gzip` and `deflate` encoded response bodies.
+//!
+//! The calendar responses are mostly repeated XML and iCalendar text, compressing them cuts the
+//! time the radio spends receiving over TLS. The decoder only lives as long as a compressed
+//! response and needs a 32 KiB window for the back references of deflate plus about 11 KiB of
+//! decompressor state, both on the heap.
+#[cfg(target_arch = "xtensa")]
+use alloc::{boxed::Box, vec::Vec};
+#[cfg(not(target_arch = "xtensa"))]
+use std::{boxed::Box, vec::Vec};
+
+use miniz_oxide::inflate::TINFLStatus;
+use miniz_oxide::inflate::core::{DecompressorOxide, decompress, inflate_flags};
+
+/// Largest distance of a deflate back reference, the window has to keep that much output
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Value of the `Accept-Encoding` header for the responses decoded here
+pub(crate) const ACCEPT_ENCODING: &str = "gzip, deflate";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// Parses the `Content-Encoding` header, `None` for encodings that weren't asked for
+    pub(crate) fn from_header(value: &[u8]) -> Option<Self> {
+        let value = core::str::from_utf8(value).ok()?.trim();
+        if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+            Some(Self::Identity)
+        } else if value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip") {
+            Some(Self::Gzip)
+        } else if value.eq_ignore_ascii_case("deflate") {
+            Some(Self::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum InflateError {
+    #[error("Invalid gzip header")]
+    GzipHeader,
+    #[error("Corrupt compressed data")]
+    Corrupt,
+    #[error("Compressed body ended early")]
+    Truncated,
+}
+
+/// Skips the gzip member header (RFC 1952), which may be split across reads
+#[derive(Default)]
+struct GzipHeader {
+    read: usize,
+    flags: u8,
+    /// Bytes left in the current optional field
+    skip: usize,
+    field: GzipField,
+}
+
+#[derive(Default, PartialEq, Eq)]
+enum GzipField {
+    #[default]
+    Fixed,
+    ExtraLen,
+    Extra,
+    Name,
+    Comment,
+    HeaderCrc,
+    Done,
+}
+
+impl GzipHeader {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    /// Returns the number of header bytes in `input`
+    fn skip(&mut self, input: &[u8]) -> Result<usize, InflateError> {
+        let mut consumed = 0;
+        while self.field != GzipField::Done && consumed < input.len() {
+            let byte = input[consumed];
+            consumed += 1;
+            match self.field {
+                GzipField::Fixed => {
+                    match (self.read, byte) {
+                        (0, 0x1f) | (1, 0x8b) | (2, 8) => (),
+                        (0..=2, _) => return Err(InflateError::GzipHeader),
+                        (3, flags) => self.flags = flags,
+                        _ => (),
+                    }
+                    self.read += 1;
+                    if self.read == 10 {
+                        self.next_field(GzipField::Fixed);
+                    }
+                }
+                GzipField::ExtraLen => {
+                    // Little endian, the low byte comes first
+                    self.read = if self.skip == 2 {
+                        byte as usize
+                    } else {
+                        self.read | ((byte as usize) << 8)
+                    };
+                    self.skip -= 1;
+                    if self.skip == 0 {
+                        self.skip = self.read;
+                        self.field = GzipField::Extra;
+                        if self.skip == 0 {
+                            self.next_field(GzipField::Extra);
+                        }
+                    }
+                }
+                GzipField::Extra | GzipField::HeaderCrc => {
+                    self.skip -= 1;
+                    if self.skip == 0 {
+                        let field = core::mem::take(&mut self.field);
+                        self.next_field(field);
+                    }
+                }
+                GzipField::Name | GzipField::Comment => {
+                    if byte == 0 {
+                        let field = core::mem::take(&mut self.field);
+                        self.next_field(field);
+                    }
+                }
+                GzipField::Done => unreachable!(),
+            }
+        }
+        Ok(consumed)
+    }
+
+    /// Moves to the next optional field present in the flags
+    fn next_field(&mut self, after: GzipField) {
+        let order = [
+            (GzipField::ExtraLen, Self::FEXTRA),
+            (GzipField::Name, Self::FNAME),
+            (GzipField::Comment, Self::FCOMMENT),
+            (GzipField::HeaderCrc, Self::FHCRC),
+        ];
+        let start = match after {
+            GzipField::Fixed => 0,
+            GzipField::Extra => 1,
+            GzipField::Name => 2,
+            GzipField::Comment => 3,
+            _ => 4,
+        };
+        self.field = order
+            .into_iter()
+            .skip(start)
+            .find(|(_, flag)| self.flags & flag != 0)
+            .map_or(GzipField::Done, |(field, _)| field);
+        self.skip = 2;
+    }
+}
+
+/// Decompresses a body chunk by chunk, the output stays valid until the next call
+pub(crate) struct Inflater {
+    decompressor: Box<DecompressorOxide>,
+    /// The recent output, deflate refers back into it
+    window: Vec<u8>,
+    /// Where the next output is written in the window
+    pos: usize,
+    gzip: Option<GzipHeader>,
+    /// `None` until the first bytes told if a `deflate` body has a zlib wrapper
+    zlib: Option<bool>,
+    done: bool,
+}
+
+impl Inflater {
+    pub(crate) fn new(encoding: ContentEncoding) -> Self {
+        let (gzip, zlib) = match encoding {
+            ContentEncoding::Gzip => (Some(GzipHeader::default()), Some(false)),
+            // Some servers send raw deflate despite RFC 9110 asking for the zlib format
+            ContentEncoding::Deflate | ContentEncoding::Identity => (None, None),
+        };
+        Self {
+            decompressor: Box::new(DecompressorOxide::new()),
+            window: alloc_window(),
+            pos: 0,
+            gzip,
+            zlib,
+            done: false,
+        }
+    }
+
+    /// The end of the compressed stream was reached, trailing bytes are ignored
+    pub(crate) fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Decompresses from `input`, returns the consumed bytes and where the output is in the
+    /// [`Inflater::window`]
+    pub(crate) fn inflate(
+        &mut self,
+        input: &[u8],
+    ) -> Result<(usize, core::ops::Range<usize>), InflateError> {
+        if self.done {
+            // The gzip trailer or padding after the stream
+            return Ok((input.len(), 0..0));
+        }
+
+        let mut consumed = 0;
+        if let Some(header) = &mut self.gzip {
+            consumed = header.skip(input)?;
+            if header.field != GzipField::Done {
+                return Ok((consumed, 0..0));
+            }
+        }
+        let input = &input[consumed..];
+
+        let zlib = match self.zlib {
+            Some(zlib) => zlib,
+            None if input.is_empty() => return Ok((consumed, 0..0)),
+            None => {
+                // The zlib header is a deflate method byte and a check byte
+                let zlib = input[0] & 0x0f == 8
+                    && input.get(1).is_none_or(|&check| {
+                        u16::from_be_bytes([input[0], check]).is_multiple_of(31)
+                    });
+                self.zlib = Some(zlib);
+                zlib
+            }
+        };
+
+        let mut flags =
+            inflate_flags::TINFL_FLAG_HAS_MORE_INPUT | inflate_flags::TINFL_FLAG_IGNORE_ADLER32;
+        if zlib {
+            flags |= inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER;
+        }
+
+        let start = self.pos;
+        let (status, read, written) = decompress(
+            &mut self.decompressor,
+            input,
+            &mut self.window,
+            start,
+            flags,
+        );
+        match status {
+            TINFLStatus::Done => self.done = true,
+            TINFLStatus::NeedsMoreInput | TINFLStatus::HasMoreOutput => (),
+            _ => return Err(InflateError::Corrupt),
+        }
+        self.pos = (start + written) & (WINDOW_SIZE - 1);
+        Ok((consumed + read, start..start + written))
+    }
+
+    pub(crate) fn window(&self) -> &[u8] {
+        &self.window
+    }
+}
+
+fn alloc_window() -> Vec<u8> {
+    #[cfg(target_arch = "xtensa")]
+    return alloc::vec![0; WINDOW_SIZE];
+    #[cfg(not(target_arch = "xtensa"))]
+    return std::vec![0; WINDOW_SIZE];
+}
+
+/// Hands the decompressed body to the parsers, which read it like the plain one
+#[cfg(target_arch = "xtensa")]
+pub(crate) struct InflateReader<'a, R> {
+    inner: &'a mut R,
+    inflater: Inflater,
+    /// Output of the last [`Inflater::inflate`] call which wasn't consumed yet
+    out: core::ops::Range<usize>,
+}
+
+#[cfg(target_arch = "xtensa")]
+impl<'a, R> InflateReader<'a, R>
+where
+    R: embedded_io_async::BufRead<Error = reqwless::Error>,
+{
+    pub(crate) fn new(inner: &'a mut R, encoding: ContentEncoding) -> Self {
+        Self {
+            inner,
+            inflater: Inflater::new(encoding),
+            out: 0..0,
+        }
+    }
+}
+
+#[cfg(target_arch = "xtensa")]
+impl<R> embedded_io_async::ErrorType for InflateReader<'_, R> {
+    type Error = reqwless::Error;
+}
+
+#[cfg(target_arch = "xtensa")]
+impl<R> embedded_io_async::BufRead for InflateReader<'_, R>
+where
+    R: embedded_io_async::BufRead<Error = reqwless::Error>,
+{
+    async fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        while self.out.is_empty() {
+            let input = self.inner.fill_buf().await?;
+            let end_of_body = input.is_empty();
+            if end_of_body && self.inflater.is_done() {
+                break;
+            }
+            let (read, output) = self.inflater.inflate(input).map_err(|e| {
+                crate::defmt::error!("Failed to decompress: {}", crate::defmt::Display2Format(&e));
+                reqwless::Error::Codec
+            })?;
+            if end_of_body && output.is_empty() {
+                crate::defmt::error!("{}", crate::defmt::Display2Format(&InflateError::Truncated));
+                return Err(reqwless::Error::Codec);
+            }
+            self.out = output;
+            self.inner.consume(read);
+        }
+        Ok(&self.inflater.window()[self.out.clone()])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.out.start += amt;
+    }
+}
+
+#[cfg(target_arch = "xtensa")]
+impl<R> embedded_io_async::Read for InflateReader<'_, R>
+where
+    R: embedded_io_async::BufRead<Error = reqwless::Error>,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let available = embedded_io_async::BufRead::fill_buf(self).await?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        embedded_io_async::BufRead::consume(self, len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::{compress_to_vec, compress_to_vec_zlib};
+
+    use super::*;
+
+    /// A calendar-query REPORT answer of Nextcloud with expanded recurrences
+    const NEXTCLOUD_REPORT: &[u8] = include_bytes!("../web-test/fixtures/nextcloud-report.xml");
+
+    /// Feeds the compressed body in chunks like the TLS records arrive
+    fn inflate_chunked(encoding: ContentEncoding, body: &[u8], chunk: usize) -> Vec<u8> {
+        let mut inflater = Inflater::new(encoding);
+        let mut output = Vec::new();
+        for mut input in body.chunks(chunk) {
+            while !input.is_empty() {
+                let (read, out) = inflater.inflate(input).unwrap();
+                output.extend_from_slice(&inflater.window()[out]);
+                input = &input[read..];
+            }
+        }
+        // Output left behind by a full window
+        loop {
+            let (_, out) = inflater.inflate(&[]).unwrap();
+            if out.is_empty() {
+                break;
+            }
+            output.extend_from_slice(&inflater.window()[out]);
+        }
+        assert!(inflater.is_done());
+        output
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        // FNAME set, the name has to be skipped
+        let mut gzip = vec![0x1f, 0x8b, 8, 0x08, 0, 0, 0, 0, 0, 3];
+        gzip.extend_from_slice(b"report.xml\0");
+        gzip.extend_from_slice(&compress_to_vec(body, 6));
+        // The trailer isn't checked
+        gzip.extend_from_slice(&[0; 8]);
+        gzip
+    }
+
+    #[test]
+    fn content_encoding_header() {
+        assert_eq!(
+            ContentEncoding::from_header(b"gzip"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(b" Deflate"),
+            Some(ContentEncoding::Deflate)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(b"identity"),
+            Some(ContentEncoding::Identity)
+        );
+        assert_eq!(ContentEncoding::from_header(b"br"), None);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let body = gzip(NEXTCLOUD_REPORT);
+        for chunk in [1, 7, 512, 16 * 1024] {
+            assert_eq!(
+                inflate_chunked(ContentEncoding::Gzip, &body, chunk),
+                NEXTCLOUD_REPORT
+            );
+        }
+    }
+
+    #[test]
+    fn deflate_with_and_without_zlib_wrapper() {
+        let zlib = compress_to_vec_zlib(NEXTCLOUD_REPORT, 6);
+        let raw = compress_to_vec(NEXTCLOUD_REPORT, 6);
+        for body in [zlib, raw] {
+            assert_eq!(
+                inflate_chunked(ContentEncoding::Deflate, &body, 1024),
+                NEXTCLOUD_REPORT
+            );
+        }
+    }
+
+    #[test]
+    fn output_larger_than_window() {
+        let body: Vec<u8> = NEXTCLOUD_REPORT
+            .iter()
+            .copied()
+            .cycle()
+            .take(5 * WINDOW_SIZE)
+            .collect();
+        let compressed = gzip(&body);
+        assert_eq!(
+            inflate_chunked(ContentEncoding::Gzip, &compressed, 4096),
+            body
+        );
+    }
+
+    #[test]
+    fn corrupt_data() {
+        let mut inflater = Inflater::new(ContentEncoding::Gzip);
+        assert_eq!(
+            inflater.inflate(b"<?xml").err(),
+            Some(InflateError::GzipHeader)
+        );
+        let mut inflater = Inflater::new(ContentEncoding::Deflate);
+        assert!(inflater.inflate(&[0x78, 0x9c, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn nextcloud_report_savings() {
+        let gzip = gzip(NEXTCLOUD_REPORT);
+        let saved = 100 - gzip.len() * 100 / NEXTCLOUD_REPORT.len();
+        println!(
+            "Nextcloud REPORT: {} bytes plain, {} bytes gzip, {}% saved",
+            NEXTCLOUD_REPORT.len(),
+            gzip.len(),
+            saved
+        );
+        assert!(saved >= 70, "only {saved}% saved");
+    }
+}
@@ -0,0 +1,199 @@
+//! Configures IPv6 on the station interface with SLAAC (RFC 4862).
+//!
+//! smoltcp doesn't process router advertisements, so a router solicitation is sent by hand and
+//! the advertisements are read from a raw socket which gets a copy of them. The address is the
+//! autonomous /64 prefix of the router with the EUI-64 of the MAC. The DNS servers come from the
+//! RDNSS option (RFC 8106), or from a stateless DHCPv6 exchange if the router points to one.
+//! Networks which only hand out addresses with stateful DHCPv6 stay on IPv4.
+use core::net::Ipv6Addr;
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{ConfigV6, IpAddress, IpEndpoint, Ipv6Cidr, Stack, StaticConfigV6};
+use embassy_time::Duration;
+use smoltcp::wire::{IpProtocol, IpVersion};
+
+use crate::ipv6_packets::{
+    DHCPV6_OPTION_DNS_SERVERS, DnsServers, PREFIX_LEN, interface_address, parse_advertisement,
+    parse_dns_servers, router_solicitation,
+};
+
+const ALL_DHCP_AGENTS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+/// RFC 4861 sends up to three solicitations four seconds apart
+const SOLICITATIONS: u8 = 3;
+const SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+const DHCPV6_CLIENT_PORT: u16 = 546;
+const DHCPV6_SERVER_PORT: u16 = 547;
+const DHCPV6_INFORMATION_REQUEST: u8 = 11;
+const DHCPV6_OPTION_CLIENTID: u16 = 1;
+const DHCPV6_OPTION_ORO: u16 = 6;
+const DHCPV6_OPTION_ELAPSED_TIME: u16 = 8;
+/// DUID-LL with the Ethernet hardware type
+const DUID_LL_ETHERNET: [u8; 4] = [0, 3, 0, 1];
+const DHCPV6_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to wait for the other address family once the stack got configured
+const SECOND_FAMILY_WAIT: Duration = Duration::from_secs(3);
+
+/// Follows the router advertisements while the station is connected
+#[embassy_executor::task]
+pub async fn slaac_task(stack: Stack<'static>) {
+    loop {
+        stack.wait_link_up().await;
+        embassy_futures::select::select(follow_routers(stack), stack.wait_link_down()).await;
+        // The next network has another prefix
+        stack.set_config_v6(ConfigV6::None);
+    }
+}
+
+async fn follow_routers(stack: Stack<'_>) {
+    let mac = match stack.hardware_address() {
+        embassy_net::HardwareAddress::Ethernet(mac) => mac.0,
+        #[allow(unreachable_patterns)]
+        _ => return core::future::pending().await,
+    };
+
+    let mut rx_meta = [embassy_net::raw::PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 2048];
+    let mut tx_meta = [embassy_net::raw::PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 64];
+    let raw = embassy_net::raw::RawSocket::new::<esp_radio::wifi::WifiDevice<'static>>(
+        stack,
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut solicitation = [0u8; 48];
+    router_solicitation(&mut solicitation);
+    let mut solicitations = 0;
+    // Asked once per connection, the routers repeat their advertisements every few minutes
+    let mut dhcpv6_dns_servers = None;
+    let mut packet = [0u8; 1500];
+    loop {
+        if solicitations < SOLICITATIONS && stack.config_v6().is_none() {
+            raw.send(&solicitation).await;
+            solicitations += 1;
+        }
+
+        let advertisement = embassy_time::with_timeout(SOLICITATION_INTERVAL, async {
+            loop {
+                if let Ok(len) = raw.recv(&mut packet).await
+                    && let Some(advertisement) = parse_advertisement(&packet[..len])
+                {
+                    return advertisement;
+                }
+            }
+        })
+        .await;
+        let Ok(advertisement) = advertisement else {
+            continue;
+        };
+        let Some(prefix) = advertisement.prefix else {
+            continue;
+        };
+
+        let mut config = StaticConfigV6 {
+            address: Ipv6Cidr::new(interface_address(prefix, mac), PREFIX_LEN),
+            gateway: advertisement.default_router.then_some(advertisement.router),
+            dns_servers: advertisement.dns_servers,
+        };
+        let use_dhcpv6 = config.dns_servers.is_empty() && advertisement.other_config;
+        if use_dhcpv6 && let Some(servers) = &dhcpv6_dns_servers {
+            config.dns_servers.clone_from(servers);
+        }
+        if stack.config_v6().as_ref() == Some(&config) {
+            continue;
+        }
+        crate::defmt::info!("IPv6 address from SLAAC: {}", config.address);
+        stack.set_config_v6(ConfigV6::Static(config.clone()));
+
+        // The exchange needs the address
+        if use_dhcpv6 && dhcpv6_dns_servers.is_none() {
+            let servers = dhcpv6_dns(stack, mac).await.unwrap_or_default();
+            if !servers.is_empty() {
+                config.dns_servers.clone_from(&servers);
+                stack.set_config_v6(ConfigV6::Static(config));
+            }
+            dhcpv6_dns_servers = Some(servers);
+        }
+    }
+}
+
+/// Waits a bit for the IPv4 config after the IPv6 one, SLAAC is usually faster than DHCP and
+/// many servers are only reachable over IPv4
+pub(crate) async fn wait_ipv4(stack: Stack<'_>) {
+    wait_for(|| stack.config_v4().is_some()).await;
+}
+
+/// Waits a bit for the IPv6 config after the IPv4 one, for showing both addresses
+pub(crate) async fn wait_ipv6(stack: Stack<'_>) {
+    wait_for(|| stack.config_v6().is_some()).await;
+}
+
+async fn wait_for(configured: impl Fn() -> bool) {
+    let _ = embassy_time::with_timeout(SECOND_FAMILY_WAIT, async {
+        while !configured() {
+            embassy_time::Timer::after(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+}
+
+/// Asks the DHCPv6 servers for the DNS servers with an Information-Request (RFC 8415)
+async fn dhcpv6_dns(stack: Stack<'_>, mac: [u8; 6]) -> Option<DnsServers> {
+    let id = (embassy_time::Instant::now().as_ticks() as u32).to_be_bytes();
+    let transaction_id = [id[1], id[2], id[3]];
+    let [client_id_0, client_id_1] = DHCPV6_OPTION_CLIENTID.to_be_bytes();
+    let [oro_0, oro_1] = DHCPV6_OPTION_ORO.to_be_bytes();
+    let [dns_0, dns_1] = DHCPV6_OPTION_DNS_SERVERS.to_be_bytes();
+    let [elapsed_0, elapsed_1] = DHCPV6_OPTION_ELAPSED_TIME.to_be_bytes();
+    #[rustfmt::skip]
+    let request = [
+        DHCPV6_INFORMATION_REQUEST, transaction_id[0], transaction_id[1], transaction_id[2],
+        client_id_0, client_id_1, 0, 10,
+        DUID_LL_ETHERNET[0], DUID_LL_ETHERNET[1], DUID_LL_ETHERNET[2], DUID_LL_ETHERNET[3],
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5],
+        oro_0, oro_1, 0, 2, dns_0, dns_1,
+        elapsed_0, elapsed_1, 0, 2, 0, 0,
+    ];
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 512];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 64];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(DHCPV6_CLIENT_PORT).ok()?;
+    socket
+        .send_to(
+            &request,
+            IpEndpoint::new(IpAddress::Ipv6(ALL_DHCP_AGENTS), DHCPV6_SERVER_PORT),
+        )
+        .await
+        .ok()?;
+
+    let mut reply = [0u8; 512];
+    let servers = embassy_time::with_timeout(DHCPV6_TIMEOUT, async {
+        loop {
+            let (len, meta) = socket.recv_from(&mut reply).await.ok()?;
+            if meta.endpoint.port == DHCPV6_SERVER_PORT
+                && let Some(servers) = parse_dns_servers(&reply[..len], &transaction_id)
+            {
+                return Some(servers);
+            }
+        }
+    })
+    .await
+    .ok()
+    .flatten()?;
+    crate::defmt::info!("DNS servers from DHCPv6: {}", servers.len());
+    Some(servers)
+}
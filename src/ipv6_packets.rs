@@ -0,0 +1,295 @@
+//! The neighbor discovery and DHCPv6 packets [`crate::ipv6`] sends and reads.
+use core::net::Ipv6Addr;
+
+use smoltcp::wire::{
+    Icmpv6Message, Icmpv6Packet, IpProtocol, Ipv6Packet, Ipv6Repr, NdiscRouterFlags,
+};
+
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+/// Neighbor discovery packets from further away are forged
+const NDP_HOP_LIMIT: u8 = 255;
+/// SLAAC only works with a 64 bit interface identifier
+pub(crate) const PREFIX_LEN: u8 = 64;
+
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_RDNSS: u8 = 25;
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+const DHCPV6_REPLY: u8 = 7;
+pub(crate) const DHCPV6_OPTION_DNS_SERVERS: u16 = 23;
+
+pub(crate) type DnsServers = heapless::Vec<Ipv6Addr, 3>;
+
+/// The parts of a router advertisement the config is made of
+pub(crate) struct Advertisement {
+    pub router: Ipv6Addr,
+    pub default_router: bool,
+    /// The router points to DHCPv6 for the DNS servers
+    pub other_config: bool,
+    pub prefix: Option<Ipv6Addr>,
+    pub dns_servers: DnsServers,
+}
+
+/// The address of the prefix with the modified EUI-64 of the MAC (RFC 4291)
+pub(crate) fn interface_address(prefix: Ipv6Addr, mac: [u8; 6]) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(&[
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]);
+    Ipv6Addr::from(octets)
+}
+
+/// A router solicitation from the unspecified address, the routers answer to all nodes
+pub(crate) fn router_solicitation(packet: &mut [u8; 48]) {
+    let repr = Ipv6Repr {
+        src_addr: Ipv6Addr::UNSPECIFIED,
+        dst_addr: ALL_ROUTERS,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: 8,
+        hop_limit: NDP_HOP_LIMIT,
+    };
+    let header_len = repr.buffer_len();
+    repr.emit(&mut Ipv6Packet::new_unchecked(&mut packet[..header_len]));
+    let mut icmp = Icmpv6Packet::new_unchecked(&mut packet[header_len..]);
+    icmp.set_msg_type(Icmpv6Message::RouterSolicit);
+    icmp.set_msg_code(0);
+    icmp.fill_checksum(&Ipv6Addr::UNSPECIFIED, &ALL_ROUTERS);
+}
+
+/// Reads a router advertisement, returns None for anything else or a forged one
+pub(crate) fn parse_advertisement(packet: &[u8]) -> Option<Advertisement> {
+    let ip = Ipv6Packet::new_checked(packet).ok()?;
+    let router = ip.src_addr();
+    if ip.next_header() != IpProtocol::Icmpv6
+        || ip.hop_limit() != NDP_HOP_LIMIT
+        || !router.is_unicast_link_local()
+    {
+        return None;
+    }
+    let icmp = Icmpv6Packet::new_checked(ip.payload()).ok()?;
+    if icmp.msg_type() != Icmpv6Message::RouterAdvert
+        || icmp.msg_code() != 0
+        || !icmp.verify_checksum(&router, &ip.dst_addr())
+    {
+        return None;
+    }
+
+    let mut advertisement = Advertisement {
+        router,
+        default_router: icmp.router_lifetime() > smoltcp::time::Duration::ZERO,
+        other_config: icmp.router_flags().contains(NdiscRouterFlags::OTHER),
+        prefix: None,
+        dns_servers: heapless::Vec::new(),
+    };
+    let mut options = icmp.payload();
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            break;
+        }
+        let (option, rest) = options.split_at(len);
+        options = rest;
+
+        match option[0] {
+            OPTION_PREFIX_INFORMATION if len == 32 => {
+                let valid_lifetime = u32::from_be_bytes(option[4..8].try_into().ok()?);
+                let prefix = Ipv6Addr::from(<[u8; 16]>::try_from(&option[16..32]).ok()?);
+                if option[2] == PREFIX_LEN
+                    && option[3] & PREFIX_AUTONOMOUS != 0
+                    && valid_lifetime > 0
+                    && !prefix.is_unicast_link_local()
+                    && advertisement.prefix.is_none()
+                {
+                    advertisement.prefix = Some(prefix);
+                }
+            }
+            OPTION_RDNSS => {
+                let lifetime = u32::from_be_bytes(option[4..8].try_into().ok()?);
+                if lifetime > 0 {
+                    for server in option[8..].chunks_exact(16) {
+                        let server = Ipv6Addr::from(<[u8; 16]>::try_from(server).ok()?);
+                        let _ = advertisement.dns_servers.push(server);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(advertisement)
+}
+
+/// Reads the DNS servers option of a DHCPv6 reply, returns None if it isn't the reply for us
+pub(crate) fn parse_dns_servers(reply: &[u8], transaction_id: &[u8; 3]) -> Option<DnsServers> {
+    if reply.len() < 4 || reply[0] != DHCPV6_REPLY || reply[1..4] != *transaction_id {
+        return None;
+    }
+
+    let mut servers = DnsServers::new();
+    let mut options = &reply[4..];
+    while options.len() >= 4 {
+        let code = u16::from_be_bytes([options[0], options[1]]);
+        let len = u16::from_be_bytes([options[2], options[3]]) as usize;
+        let data = options.get(4..4 + len)?;
+        options = &options[4 + len..];
+        if code == DHCPV6_OPTION_DNS_SERVERS {
+            for server in data.chunks_exact(16) {
+                let server = Ipv6Addr::from(<[u8; 16]>::try_from(server).ok()?);
+                let _ = servers.push(server);
+            }
+        }
+    }
+    Some(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        text.split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect()
+    }
+
+    /// A router advertisement with the other config flag, the source link-layer address,
+    /// the MTU, an autonomous /64 prefix and two RDNSS servers
+    const ADVERTISEMENT: &str = "60 00 00 00 00 68 3a ff fe 80 00 00 00 00 00 00 1a 2b 3c ff fe 4d
+        5e 6f ff 02 00 00 00 00 00 00 00 00 00 00 00 00 00 01 86 00 6e 39 40 40 07 08 00 00 00 00
+        00 00 00 00 01 01 18 2b 3c 4d 5e 6f 05 01 00 00 00 00 05 dc 03 04 40 c0 00 01 51 80 00 00
+        38 40 00 00 00 00 20 01 0d b8 12 34 56 78 00 00 00 00 00 00 00 00 19 05 00 00 00 00 07 08
+        20 01 0d b8 12 34 56 78 00 00 00 00 00 00 00 01 20 01 48 60 48 60 00 00 00 00 00 00 00 00
+        88 88";
+    const PREFIX_FLAGS: usize = 75;
+    const RDNSS_LIFETIME: usize = 108;
+    const ROUTER_LIFETIME: usize = 46;
+
+    /// A DHCPv6 reply with a server and client id, two DNS servers and a search domain
+    const DHCPV6_REPLY_PACKET: &str = "07 4f 1a 9c 00 02 00 0e 00 01 00 01 2c 8e 1b 9a 18 2b 3c 4d
+        5e 6f 00 01 00 0a 00 03 00 01 24 6f 28 aa bb cc 00 17 00 20 20 01 0d b8 12 34 56 78 00 00
+        00 00 00 00 00 01 20 01 0d b8 12 34 56 78 00 00 00 00 00 00 00 02 00 18 00 0b 04 68 6f 6d
+        65 04 61 72 70 61 00";
+    const TRANSACTION_ID: [u8; 3] = [0x4f, 0x1a, 0x9c];
+
+    const ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1a2b, 0x3cff, 0xfe4d, 0x5e6f);
+    const PREFIX: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0x1234, 0x5678, 0, 0, 0, 0);
+
+    /// Fixes the checksum after a field was changed
+    fn resign(packet: &mut [u8]) {
+        let (src, dst) = {
+            let ip = Ipv6Packet::new_checked(&*packet).unwrap();
+            (ip.src_addr(), ip.dst_addr())
+        };
+        Icmpv6Packet::new_unchecked(&mut packet[40..]).fill_checksum(&src, &dst);
+    }
+
+    #[test]
+    fn router_advertisement() {
+        let advertisement = parse_advertisement(&hex(ADVERTISEMENT)).unwrap();
+        assert_eq!(advertisement.router, ROUTER);
+        assert!(advertisement.default_router);
+        assert!(advertisement.other_config);
+        assert_eq!(advertisement.prefix, Some(PREFIX));
+        assert_eq!(
+            advertisement.dns_servers,
+            [
+                Ipv6Addr::new(0x2001, 0xdb8, 0x1234, 0x5678, 0, 0, 0, 1),
+                Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+            ]
+        );
+    }
+
+    #[test]
+    fn advertisement_options() {
+        let mut packet = hex(ADVERTISEMENT);
+        packet[PREFIX_FLAGS] &= !PREFIX_AUTONOMOUS;
+        packet[RDNSS_LIFETIME..RDNSS_LIFETIME + 4].fill(0);
+        packet[ROUTER_LIFETIME..ROUTER_LIFETIME + 2].fill(0);
+        resign(&mut packet);
+        let advertisement = parse_advertisement(&packet).unwrap();
+        assert!(!advertisement.default_router);
+        assert_eq!(advertisement.prefix, None);
+        assert!(advertisement.dns_servers.is_empty());
+    }
+
+    #[test]
+    fn forged_advertisements_are_dropped() {
+        let packet = hex(ADVERTISEMENT);
+
+        let mut forwarded = packet.clone();
+        forwarded[7] = 64;
+        assert!(parse_advertisement(&forwarded).is_none());
+
+        let mut corrupted = packet.clone();
+        corrupted[100] ^= 1;
+        assert!(parse_advertisement(&corrupted).is_none());
+
+        // from a global address, with a valid checksum
+        let mut global = packet.clone();
+        global[8..24].copy_from_slice(&PREFIX.octets());
+        resign(&mut global);
+        assert!(parse_advertisement(&global).is_none());
+
+        assert!(parse_advertisement(&packet[..60]).is_none());
+    }
+
+    #[test]
+    fn solicitation_round_trip() {
+        let mut solicitation = [0u8; 48];
+        router_solicitation(&mut solicitation);
+
+        let ip = Ipv6Packet::new_checked(&solicitation[..]).unwrap();
+        assert_eq!(ip.src_addr(), Ipv6Addr::UNSPECIFIED);
+        assert_eq!(ip.dst_addr(), ALL_ROUTERS);
+        assert_eq!(ip.hop_limit(), NDP_HOP_LIMIT);
+        assert_eq!(ip.payload_len(), 8);
+        let icmp = Icmpv6Packet::new_checked(ip.payload()).unwrap();
+        assert_eq!(icmp.msg_type(), Icmpv6Message::RouterSolicit);
+        assert!(icmp.verify_checksum(&ip.src_addr(), &ip.dst_addr()));
+
+        // Our own solicitation isn't an advertisement
+        assert!(parse_advertisement(&solicitation).is_none());
+    }
+
+    #[test]
+    fn eui64_address() {
+        assert_eq!(
+            interface_address(PREFIX, [0x24, 0x6f, 0x28, 0xaa, 0xbb, 0xcc]),
+            Ipv6Addr::new(
+                0x2001, 0xdb8, 0x1234, 0x5678, 0x266f, 0x28ff, 0xfeaa, 0xbbcc
+            )
+        );
+    }
+
+    #[test]
+    fn dhcpv6_dns_servers() {
+        let reply = hex(DHCPV6_REPLY_PACKET);
+        assert_eq!(
+            parse_dns_servers(&reply, &TRANSACTION_ID).unwrap(),
+            [
+                Ipv6Addr::new(0x2001, 0xdb8, 0x1234, 0x5678, 0, 0, 0, 1),
+                Ipv6Addr::new(0x2001, 0xdb8, 0x1234, 0x5678, 0, 0, 0, 2),
+            ]
+        );
+
+        // Someone else's exchange, or an Advertise
+        assert!(parse_dns_servers(&reply, &[0x4f, 0x1a, 0x9d]).is_none());
+        let mut advertise = reply.clone();
+        advertise[0] = 2;
+        assert!(parse_dns_servers(&advertise, &TRANSACTION_ID).is_none());
+        // An option running past the end
+        assert!(parse_dns_servers(&reply[..reply.len() - 1], &TRANSACTION_ID).is_none());
+        // No DNS servers option
+        assert!(
+            parse_dns_servers(&reply[..36], &TRANSACTION_ID)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
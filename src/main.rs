@@ -14,18 +14,34 @@
 pub use ::defmt;
 
 mod display;
+mod dns_cache;
+mod fnv;
 mod hardware;
+mod inflate;
 mod init;
+mod ipv6;
+mod ipv6_packets;
+mod mdns;
+mod mdns_records;
 mod networking;
+mod ntp;
+mod oauth;
+mod oauth_messages;
+mod offline;
 mod parsing;
+mod portal_dns;
+mod rtc_events;
 mod server;
 mod storage;
+mod tls_hello;
+mod tls_pin;
+mod tls_probe;
 mod wifi;
+mod wifi_cache;
 
 use display_interface_spi::SPIInterface;
 use embassy_executor::Spawner;
 use embassy_futures::join::join;
-use embassy_net::dns::DnsSocket;
 use embassy_net::tcp::client::TcpClient;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::mutex::Mutex;
@@ -49,7 +65,14 @@ use crate::storage::NvsConfig;
 
 extern crate alloc;
 
+/// Failed wakes in a row before the portal is raised next to the retries
 const NETWORK_FAIL_LIMIT: u8 = 3;
+const NETWORK_RETRY_MIN_S: u32 = 60;
+const NETWORK_RETRY_MAX_S: u32 = 60 * 60;
+/// How long the fallback portal stays up before the device sleeps until the next attempt
+const FALLBACK_SESSION: embassy_time::Duration = embassy_time::Duration::from_secs(10 * 60);
+const FALLBACK_RETRY_MIN: embassy_time::Duration = embassy_time::Duration::from_secs(30);
+const FALLBACK_RETRY_MAX: embassy_time::Duration = embassy_time::Duration::from_secs(5 * 60);
 
 #[esp_hal::ram(unstable(rtc_fast, persistent))]
 static DISPLAY_SLEEP_COUNT: AtomicU32 = AtomicU32::new(0);
@@ -60,20 +83,20 @@ pub static BOOT_TYPES: AtomicU8 = AtomicU8::new(BootType::Display as u8);
 #[esp_hal::ram(unstable(rtc_fast, persistent))]
 pub static NETWORK_FAIL_COUNT: AtomicU8 = AtomicU8::new(0);
 
+/// Doublings of the fallback retry delay, a later session goes on from there
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static FALLBACK_RETRY_STEP: AtomicU8 = AtomicU8::new(0);
+
 static TLS: static_cell::StaticCell<mbedtls_rs::Tls<'static>> = static_cell::StaticCell::new();
 static HTTP_CLIENT_MUTEX: static_cell::StaticCell<
-    embassy_sync::mutex::Mutex<
-        NoopRawMutex,
-        reqwless::client::HttpClient<
-            'static,
-            TcpClient<'static, 1, 4096, 4096>,
-            DnsSocket<'static>,
-        >,
-    >,
+    embassy_sync::mutex::Mutex<NoopRawMutex, networking::HttpClients<'static>>,
 > = static_cell::StaticCell::new();
-static DNS_SOCKET: static_cell::StaticCell<DnsSocket<'static>> = static_cell::StaticCell::new();
+static DNS: static_cell::StaticCell<dns_cache::CachingDns<'static>> =
+    static_cell::StaticCell::new();
 static TCP_CLIENT: static_cell::StaticCell<TcpClient<'static, 1, 4096, 4096>> =
     static_cell::StaticCell::new();
+static TCP_CLIENTS: static_cell::StaticCell<networking::TcpClients<'static>> =
+    static_cell::StaticCell::new();
 
 type EpdDriver = WeActStudio420BlackWhiteDriver<
     SPIInterface<
@@ -90,6 +113,9 @@ type EpdDriver = WeActStudio420BlackWhiteDriver<
 pub(crate) enum BootType {
     Display = 0,
     Config = 1,
+    /// The saved networks are unreachable, the portal runs on the own access point while the
+    /// networks are retried
+    Fallback = 2,
 }
 
 impl BootType {
@@ -105,6 +131,7 @@ impl BootType {
         match val {
             0 => BootType::Display,
             1 => BootType::Config,
+            2 => BootType::Fallback,
             _ => panic!("Unknown boot type value: {}", val),
         }
     }
@@ -129,7 +156,7 @@ async fn main(spawner: Spawner) {
         BootType::Display => {
             DISPLAY_SLEEP_COUNT.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
         }
-        BootType::Config => (),
+        BootType::Config | BootType::Fallback => (),
     }
 
     let flash = esp_storage::FlashStorage::new(peripherals.FLASH);
@@ -163,6 +190,8 @@ async fn main(spawner: Spawner) {
     );
 
     let mut sync_calendars = alloc::vec::Vec::with_capacity(2);
+    let mut refresh_minutes = storage::DEFAULT_REFRESH_MINUTES;
+    let mut max_events = storage::DEFAULT_MAX_EVENTS;
 
     if let Some(config) = &mut stored_config {
         if let Some(display_config) = &mut config.display {
@@ -175,6 +204,8 @@ async fn main(spawner: Spawner) {
                 core::sync::atomic::Ordering::Relaxed,
             );
             sync_calendars.extend(core::mem::take(&mut display_config.calendars));
+            refresh_minutes = display_config.refresh_minutes;
+            max_events = display_config.max_events;
         } else {
             crate::display::SHOW_CURRENT_DAY_ONLY
                 .store(false, core::sync::atomic::Ordering::Relaxed);
@@ -184,9 +215,39 @@ async fn main(spawner: Spawner) {
 
     crate::defmt::info!("Boot type: {:?}", boot_type);
 
+    match boot_type {
+        BootType::Display if !ntp::sync_due(&mut rtc) => {
+            if let Some((mut events, fetched_at)) = rtc_events::load(&rtc, refresh_minutes) {
+                crate::defmt::info!("Redrawing {} kept events without Wi-Fi", events.len());
+                let (mut display, mut driver) = init::init_display(
+                    peripherals.GPIO12,
+                    peripherals.GPIO11,
+                    peripherals.SPI2,
+                    peripherals.GPIO18,
+                    peripherals.GPIO4,
+                    peripherals.GPIO15,
+                    peripherals.GPIO10,
+                )
+                .await;
+                display::write_to_screen(
+                    &mut display,
+                    &mut driver,
+                    &mut events,
+                    display::EventSource::Kept(&fetched_at),
+                    &mut rtc,
+                )
+                .await;
+                unreachable!()
+            }
+        }
+        BootType::Display => (),
+        // The calendars or the display settings may change
+        BootType::Config | BootType::Fallback => rtc_events::clear(),
+    }
+
     let wifi = peripherals.WIFI;
 
-    let (net_stack, trng, ncreds, network_status) = if boot_type == BootType::Display {
+    let (net_stack, trng, ncreds, network_status, portal) = if boot_type == BootType::Display {
         let config = match stored_config.clone() {
             Some(config) => config,
             _ => {
@@ -195,38 +256,64 @@ async fn main(spawner: Spawner) {
             }
         };
 
-        if config.wifi.is_none() || config.caldav.is_none() {
-            crate::defmt::warn!("Missing credentials (wifi or caldav), rebooting into config mode");
+        if config.wifi.is_empty() || (config.caldav.is_none() && config.ics_feeds.is_empty()) {
+            crate::defmt::warn!(
+                "Missing credentials (wifi or calendar source), rebooting into config mode"
+            );
             BootType::set(BootType::Config);
             crate::wifi::stop_wifi_and_reset().await;
         }
 
-        let wifi_creds = config.wifi.clone().unwrap();
+        let networks = config.wifi.clone();
         let ncreds = Some(config);
 
-        let (net_stack, trng) =
-            wifi::start_con(spawner, wifi, wifi_creds, peripherals.RNG, peripherals.ADC1);
-        (net_stack, trng, ncreds, NetworkStatus::Network)
+        let (net_stack, trng) = wifi::start_con(
+            spawner,
+            wifi,
+            networks,
+            peripherals.RNG,
+            peripherals.ADC1,
+            hardware::get_time(&rtc).timestamp(),
+        );
+        (net_stack, trng, ncreds, NetworkStatus::Network, None)
     } else {
         let ncreds = stored_config.clone();
 
-        let (net_stack, trng, network_status) = if let Some(config) = stored_config.clone() {
-            if let Some(creds) = config.wifi {
-                let (net_stack, trng) =
-                    wifi::start_con(spawner, wifi, creds, peripherals.RNG, peripherals.ADC1);
-                (net_stack, trng, NetworkStatus::Network)
-            } else {
-                let (net_stack, trng) =
-                    wifi::start_ap(spawner, wifi, peripherals.RNG, peripherals.ADC1);
-                (net_stack, trng, NetworkStatus::AccessPoint)
+        let (net_stack, trng, network_status, portal) = match stored_config.clone() {
+            // The fallback doesn't wait for the saved networks, it raises the own access point
+            Some(config) if !config.wifi.is_empty() && boot_type == BootType::Config => {
+                let (net_stack, trng) = wifi::start_con(
+                    spawner,
+                    wifi,
+                    config.wifi,
+                    peripherals.RNG,
+                    peripherals.ADC1,
+                    hardware::get_time(&rtc).timestamp(),
+                );
+                (net_stack, trng, NetworkStatus::Network, None)
+            }
+            _ => {
+                let stored = storage::read_ap_credentials(flash).await;
+                let generated = stored.is_none();
+                let (net_stack, trng, credentials) =
+                    wifi::start_ap(spawner, wifi, peripherals.RNG, peripherals.ADC1, stored);
+                if generated
+                    && storage::write_ap_credentials(flash, &credentials)
+                        .await
+                        .is_err()
+                {
+                    crate::defmt::warn!("Failed to store the access point credentials");
+                }
+                (
+                    net_stack,
+                    trng,
+                    NetworkStatus::AccessPoint,
+                    Some(credentials),
+                )
             }
-        } else {
-            let (net_stack, trng) =
-                wifi::start_ap(spawner, wifi, peripherals.RNG, peripherals.ADC1);
-            (net_stack, trng, NetworkStatus::AccessPoint)
         };
 
-        (net_stack, trng, ncreds, network_status)
+        (net_stack, trng, ncreds, network_status, portal)
     };
 
     {
@@ -236,45 +323,100 @@ async fn main(spawner: Spawner) {
             embassy_time::Duration::from_secs(30)
         };
 
-        let to = embassy_time::with_timeout(timeout, net_stack.wait_config_up()).await;
+        let (to, renewal) = join(
+            embassy_time::with_timeout(timeout, net_stack.wait_config_up()),
+            embassy_time::with_timeout(timeout, wifi_cache::capture_renewal_time(net_stack)),
+        )
+        .await;
 
         if to.is_ok() {
+            if net_stack.config_v4().is_none() {
+                ipv6::wait_ipv4(net_stack).await;
+            }
             if boot_type == BootType::Display {
                 NETWORK_FAIL_COUNT.store(0, core::sync::atomic::Ordering::Relaxed);
+                FALLBACK_RETRY_STEP.store(0, core::sync::atomic::Ordering::Relaxed);
+            }
+            if let (Ok(Some(renewal)), Some(config)) = (renewal, net_stack.config_v4()) {
+                let renew_at = hardware::get_time(&rtc)
+                    .timestamp()
+                    .saturating_add(jiff::SignedDuration::from_secs(renewal as i64))
+                    .unwrap_or_default();
+                wifi_cache::remember_lease(&wifi::connected_ssid(), &config, renew_at);
             }
         } else {
-            let old_count = NETWORK_FAIL_COUNT.load(core::sync::atomic::Ordering::Relaxed);
-
-            let should_reset = match boot_type {
+            wifi_cache::forget_lease();
+            wifi_cache::forget_access_point();
+            match boot_type {
                 BootType::Display => {
-                    if old_count <= NETWORK_FAIL_LIMIT {
-                        NETWORK_FAIL_COUNT.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+                    let fails = NETWORK_FAIL_COUNT
+                        .load(core::sync::atomic::Ordering::Relaxed)
+                        .saturating_add(1);
+                    NETWORK_FAIL_COUNT.store(fails, core::sync::atomic::Ordering::Relaxed);
+                    hardware::set_sleep_duration(network_retry_delay(fails));
+                    // The portal had its session when the limit was reached, the timed wakes
+                    // after it only retry
+                    if fails == NETWORK_FAIL_LIMIT
+                        || (fails > NETWORK_FAIL_LIMIT && !hardware::woke_from_timer())
+                    {
+                        // The credentials stay, a router outage must not need a reconfiguration
+                        BootType::set(BootType::Fallback);
                         crate::wifi::stop_wifi_and_reset().await
+                    }
+
+                    crate::defmt::warn!("Wi-Fi unavailable, {} failed wakes in a row", fails);
+                    let (mut display, mut driver) = init::init_display(
+                        peripherals.GPIO12,
+                        peripherals.GPIO11,
+                        peripherals.SPI2,
+                        peripherals.GPIO18,
+                        peripherals.GPIO4,
+                        peripherals.GPIO15,
+                        peripherals.GPIO10,
+                    )
+                    .await;
+                    if let Some((mut events, synced_at)) = offline::recall(flash, &rtc).await {
+                        join(
+                            crate::wifi::stop_wifi(),
+                            display::write_to_screen(
+                                &mut display,
+                                &mut driver,
+                                &mut events,
+                                display::EventSource::Offline(&synced_at),
+                                &mut rtc,
+                            ),
+                        )
+                        .await;
                     } else {
-                        true
+                        join(
+                            crate::wifi::stop_wifi(),
+                            display::write_error_screen(
+                                &mut display,
+                                &mut driver,
+                                &networking::NetworkError::WifiUnavailable,
+                                &mut rtc,
+                            ),
+                        )
+                        .await;
                     }
+                    unreachable!()
                 }
-                BootType::Config => network_status == NetworkStatus::Network,
-            };
-
-            if should_reset {
-                let mut config = stored_config.unwrap();
-                config.wifi = None;
-                if let Some(display_config) = &mut config.display {
-                    display_config.calendars = sync_calendars;
+                // The network the portal was served on is gone
+                BootType::Config if network_status == NetworkStatus::Network => {
+                    BootType::set(BootType::Fallback);
+                    crate::wifi::stop_wifi_and_reset().await
                 }
-                join(
-                    storage::write_config(flash, config),
-                    crate::wifi::stop_wifi_and_reset(),
-                )
-                .await;
-                unreachable!()
+                BootType::Config | BootType::Fallback => (),
             }
         }
     }
 
-    let ip_config = net_stack.config_v4().unwrap();
-    crate::defmt::info!("Network connected with IP address: {}", ip_config.address);
+    if let Some(config) = net_stack.config_v4() {
+        crate::defmt::info!("Network connected with IP address: {}", config.address);
+    }
+    if let Some(config) = net_stack.config_v6() {
+        crate::defmt::info!("Network connected with IPv6 address: {}", config.address);
+    }
 
     crate::defmt::info!("Microcontroller initialized");
 
@@ -291,60 +433,157 @@ async fn main(spawner: Spawner) {
 
     match boot_type {
         BootType::Display => {
-            networking::sync_time(prev_boot_count, net_stack, &mut rtc).await;
+            let config = ncreds.as_ref().unwrap();
+            let clock_set = ntp::sync_time(net_stack, &mut rtc, &config.ntp_servers).await;
             run_display_mode(
                 &mut rtc,
+                flash,
                 net_stack,
                 trng,
                 &mut display,
                 &mut driver,
-                ncreds.as_ref().unwrap(),
+                config,
                 &sync_calendars,
+                max_events,
+                clock_set,
             )
             .await;
         }
-        BootType::Config => {
-            let text = if network_status == NetworkStatus::Network {
+        BootType::Config | BootType::Fallback => {
+            let text = if let Some(credentials) = &portal {
+                let title = if boot_type == BootType::Fallback {
+                    "Wi-Fi unavailable, retrying..."
+                } else {
+                    "Access point created!"
+                };
                 alloc::format!(
-                    "Connected to Wi-Fi!\nSSID: {}\nIP: {}\n",
-                    ncreds.as_ref().unwrap().wifi.as_ref().unwrap().ssid,
-                    ip_config.address.address()
+                    "{}\nSSID: {}\nPassword: {}\n{}",
+                    title,
+                    credentials.ssid,
+                    credentials.password,
+                    address_lines(net_stack)
                 )
             } else {
+                let hostname = mdns::hostname(
+                    ncreds
+                        .as_ref()
+                        .and_then(|config| config.hostname.as_deref()),
+                    net_stack,
+                );
+                spawner.must_spawn(mdns::mdns_task(net_stack, hostname.clone()));
+                ipv6::wait_ipv6(net_stack).await;
                 alloc::format!(
-                    "Access point created!\nSSID: {}\nPassword: {}\nIp: {}",
-                    env!("AP_SSID"),
-                    env!("AP_PASS"),
-                    ip_config.address.address()
+                    "Connected to Wi-Fi!\nSSID: {}\nhttp://{}.local\n{}",
+                    wifi::connected_ssid(),
+                    hostname,
+                    address_lines(net_stack)
                 )
             };
 
+            let now = hardware::get_time(&rtc).timestamp();
             join(
-                async { run_config_mode(spawner, net_stack, flash, trng) },
+                run_config_mode(spawner, net_stack, flash, trng, now),
                 async {
-                    display::draw_config(&mut display, text.as_str());
+                    match &portal {
+                        Some(credentials) => display::draw_portal_config(
+                            &mut display,
+                            text.as_str(),
+                            &wifi::ap_login_qr_text(credentials),
+                            server::PORTAL_URL,
+                        ),
+                        None => display::draw_config(&mut display, text.as_str()),
+                    }
                     driver.full_update(&display).await.unwrap();
                 },
             )
             .await;
+            if boot_type == BootType::Fallback {
+                run_fallback(&mut rtc, flash).await;
+            }
+        }
+    }
+}
+
+/// Sleep before the next attempt after `fails` failed wakes in a row, doubling up to an hour
+fn network_retry_delay(fails: u8) -> u32 {
+    (NETWORK_RETRY_MIN_S << fails.saturating_sub(1).min(6)).min(NETWORK_RETRY_MAX_S)
+}
+
+/// Wait between the retries of the fallback after `step` doublings, up to five minutes
+fn fallback_retry_delay(step: u8) -> embassy_time::Duration {
+    (FALLBACK_RETRY_MIN * (1 << step.min(8))).min(FALLBACK_RETRY_MAX)
+}
+
+/// The addresses for the config screen, an IPv6 address takes a line of its own
+fn address_lines(stack: embassy_net::Stack<'_>) -> alloc::string::String {
+    let mut lines = alloc::string::String::new();
+    if let Some(config) = stack.config_v4() {
+        lines += &alloc::format!("IP: {}\n", config.address.address());
+    }
+    if let Some(config) = stack.config_v6() {
+        lines += &alloc::format!("IPv6:\n{}\n", config.address.address());
+    }
+    lines
+}
+
+/// Retries the saved networks next to the portal, the display mode takes over once one is
+/// back. The device sleeps until the next attempt if none comes back within the session.
+async fn run_fallback(
+    rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
+    flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
+) -> ! {
+    let deadline = embassy_time::Instant::now() + FALLBACK_SESSION;
+    let mut step = FALLBACK_RETRY_STEP.load(core::sync::atomic::Ordering::Relaxed);
+    let mut delay = fallback_retry_delay(step);
+    while embassy_time::Instant::now() + delay < deadline {
+        embassy_time::Timer::after(delay).await;
+        // Networks saved from the portal in the meantime are tried as well
+        let networks = storage::read_config(flash)
+            .await
+            .map(|config| config.wifi)
+            .unwrap_or_default();
+        for network in networks {
+            // The user is trying a network from the portal
+            if wifi::trial_state() == server::WifiTrial::Testing {
+                break;
+            }
+            if wifi::trial(network.clone()).await == server::WifiTrial::Connected {
+                crate::defmt::info!("{} is reachable again", network.ssid);
+                BootType::set(BootType::Display);
+                wifi::stop_wifi_and_reset().await
+            }
         }
+        step = step.saturating_add(1);
+        FALLBACK_RETRY_STEP.store(step, core::sync::atomic::Ordering::Relaxed);
+        delay = fallback_retry_delay(step);
     }
+
+    let fails = NETWORK_FAIL_COUNT.load(core::sync::atomic::Ordering::Relaxed);
+    crate::defmt::info!("No network came back, sleeping until the next attempt");
+    BootType::set(BootType::Display);
+    hardware::set_sleep_duration(network_retry_delay(fails));
+    wifi::stop_wifi().await;
+    wifi::wait_until_wifi_stop().await;
+    hardware::go_to_deep_sleep(rtc)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_display_mode(
     rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
+    flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
     net_stack: embassy_net::Stack<'static>,
     trng: &'static mut esp_hal::rng::Trng,
     display: &mut Display420BlackWhite,
     driver: &mut EpdDriver,
     config: &NvsConfig,
     calendars: &[alloc::string::String],
+    max_events: u16,
+    clock_set: bool,
 ) {
-    let caldav = config.caldav.clone().unwrap();
-
     let tls = TLS.init(mbedtls_rs::Tls::new(trng).unwrap());
+    let now = hardware::get_time(rtc).timestamp();
     #[allow(clippy::large_stack_frames, reason = "false positive")]
-    let dns_socket = DNS_SOCKET.init_with(|| DnsSocket::new(net_stack));
+    let dns = DNS.init_with(|| dns_cache::CachingDns::new(net_stack, now));
     #[allow(clippy::large_stack_frames, reason = "false positive")]
     let tcp_client = TCP_CLIENT.init_with(|| {
         TcpClient::new(
@@ -354,26 +593,117 @@ async fn run_display_mode(
                 .init_with(embassy_net::tcp::client::TcpClientState::new),
         )
     });
-    let mut client = networking::init_https_client(tcp_client, dns_socket, tls.reference());
-    let mut events = networking::get_events(&mut client, rtc, &caldav, calendars).await;
-
-    join(
-        crate::wifi::stop_wifi(),
-        display::write_to_screen(display, driver, &mut events, rtc),
+    let trust = storage::read_tls_trust(flash).await;
+    let tcp_clients = TCP_CLIENTS.init(networking::TcpClients::new(tcp_client, trust.as_ref()));
+    let mut clients = networking::HttpClients::new(
+        tcp_clients,
+        dns,
+        tls.reference(),
+        trust.as_ref(),
+        config.tls_min_version,
+    );
+    if !clock_set {
+        // NTP is blocked, the calendar server has to tell the time
+        let url = match (&config.caldav, config.ics_feeds.first()) {
+            (Some(caldav), _) => Some((
+                alloc::string::String::from(caldav.url.as_str()),
+                caldav.allow_http,
+            )),
+            (None, Some(feed)) => Some((networking::ics_https_url(&feed.url), false)),
+            (None, None) => None,
+        };
+        if let Some((url, allow_http)) = url
+            && let Err(e) = match clients.for_url(&url, allow_http) {
+                Ok(client) => ntp::sync_time_from_http(client, rtc, &url).await,
+                Err(e) => Err(e),
+            }
+        {
+            crate::defmt::warn!(
+                "Failed to get the time over HTTP: {}",
+                crate::defmt::Display2Format(&e)
+            );
+        }
+    }
+    let events = networking::get_events(
+        &mut clients,
+        rtc,
+        flash,
+        config.caldav.as_ref(),
+        calendars,
+        &config.ics_feeds,
+        max_events,
     )
     .await;
+
+    match events {
+        Ok(mut events) => {
+            offline::remember(flash, rtc, &events).await;
+            let (start, end) = networking::fetch_window(
+                &hardware::get_time(rtc).with_time_zone(networking::USER_TIMEZONE),
+            );
+            rtc_events::store(rtc, &events, (start.timestamp(), end.timestamp()));
+            join(
+                crate::wifi::stop_wifi(),
+                display::write_to_screen(
+                    display,
+                    driver,
+                    &mut events,
+                    display::EventSource::Network,
+                    rtc,
+                ),
+            )
+            .await;
+        }
+        Err(e) => {
+            crate::defmt::error!("Failed to get events: {}", crate::defmt::Display2Format(&e));
+            if wifi_cache::lease_reused() {
+                // The address may belong to someone else by now, ask the DHCP server next time
+                wifi_cache::forget_lease();
+            }
+            // Wrong credentials, URLs or limits need the user, the old events would hide that
+            let needs_config = e.is_auth_failure()
+                || e.is_too_many_events()
+                || matches!(
+                    e,
+                    networking::NetworkError::WrongUrl
+                        | networking::NetworkError::PlainHttpDisabled
+                );
+            if !needs_config
+                && let Some((mut events, synced_at)) = offline::recall(flash, rtc).await
+            {
+                join(
+                    crate::wifi::stop_wifi(),
+                    display::write_to_screen(
+                        display,
+                        driver,
+                        &mut events,
+                        display::EventSource::Offline(&synced_at),
+                        rtc,
+                    ),
+                )
+                .await;
+            } else {
+                join(
+                    crate::wifi::stop_wifi(),
+                    display::write_error_screen(display, driver, &e, rtc),
+                )
+                .await;
+            }
+        }
+    }
 }
 
-fn run_config_mode(
+async fn run_config_mode(
     spawner: Spawner,
     net_stack: embassy_net::Stack<'static>,
     flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
     trng: &'static mut esp_hal::rng::Trng,
+    now: jiff::Timestamp,
 ) {
     #[allow(clippy::large_stack_frames, reason = "false positive")]
     let tls = TLS.init_with(|| mbedtls_rs::Tls::new(trng).unwrap());
     #[allow(clippy::large_stack_frames, reason = "false positive")]
-    let dns_socket = DNS_SOCKET.init_with(|| DnsSocket::new(net_stack));
+    let dns = DNS.init_with(|| dns_cache::CachingDns::new(net_stack, now));
     #[allow(clippy::large_stack_frames, reason = "false positive")]
     let tcp_client = TCP_CLIENT.init_with(|| {
         TcpClient::new(
@@ -383,10 +713,22 @@ fn run_config_mode(
                 .init_with(embassy_net::tcp::client::TcpClientState::new),
         )
     });
+    let trust = storage::read_tls_trust(flash).await;
+    let tcp_clients = TCP_CLIENTS.init(networking::TcpClients::new(tcp_client, trust.as_ref()));
+    let tls_min_version = storage::read_config(flash)
+        .await
+        .map(|config| config.tls_min_version)
+        .unwrap_or_default();
     #[allow(clippy::large_stack_frames, reason = "false positive")]
     let http_client_mutex = HTTP_CLIENT_MUTEX.init_with(|| {
-        let client = networking::init_https_client(tcp_client, dns_socket, tls.reference());
-        embassy_sync::mutex::Mutex::new(client)
+        let clients = networking::HttpClients::new(
+            tcp_clients,
+            dns,
+            tls.reference(),
+            trust.as_ref(),
+            tls_min_version,
+        );
+        embassy_sync::mutex::Mutex::new(clients)
     });
 
     let app = picoserve::make_static!(
@@ -394,6 +736,7 @@ fn run_config_mode(
         server::AppProps {
             flash_storage: flash,
             http_client_mutex,
+            net_stack,
         }
         .build_app()
     );
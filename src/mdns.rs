@@ -0,0 +1,135 @@
+//! Answers mDNS (RFC 6762) for `<hostname>.local` and announces the portal over DNS-SD
+//! (RFC 6763) as an `_http._tcp` service, so it can be opened without reading the address off
+//! the screen.
+//!
+//! Only the records of this device are answered. There is no probing for conflicts, the
+//! default name contains the end of the MAC and a configured one is the user's choice.
+use core::fmt::Write;
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+
+use crate::mdns_records::{Addresses, Records, parse_query, write_response};
+use crate::storage::MAX_HOSTNAME_LEN;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_IPV4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+/// Announced twice, a second apart
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(1);
+
+pub type Hostname = heapless::String<MAX_HOSTNAME_LEN>;
+
+/// The configured name, or `calendar-` with the last three bytes of the MAC
+pub fn hostname(configured: Option<&str>, stack: Stack<'_>) -> Hostname {
+    if let Some(name) = configured.and_then(|name| Hostname::try_from(name).ok()) {
+        return name;
+    }
+    let mut name = Hostname::new();
+    if let embassy_net::HardwareAddress::Ethernet(mac) = stack.hardware_address() {
+        let _ = write!(
+            name,
+            "calendar-{:02x}{:02x}{:02x}",
+            mac.0[3], mac.0[4], mac.0[5]
+        );
+    }
+    name
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>, hostname: Hostname) {
+    if stack.join_multicast_group(MDNS_IPV4).is_err() {
+        crate::defmt::warn!("mDNS: failed to join the IPv4 group");
+    }
+    if stack.join_multicast_group(MDNS_IPV6).is_err() {
+        crate::defmt::warn!("mDNS: failed to join the IPv6 group");
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 2048];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 2048];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(MDNS_PORT)
+        .expect("mDNS: failed to bind to port 5353");
+    crate::defmt::info!("mDNS: answering for {}.local", hostname.as_str());
+
+    let ipv4_group = IpEndpoint::new(IpAddress::Ipv4(MDNS_IPV4), MDNS_PORT);
+    let mut response = [0u8; 512];
+    for _ in 0..ANNOUNCEMENTS {
+        if let Some(len) = write_response(
+            &mut response,
+            0,
+            &hostname,
+            addresses(stack),
+            Records::ALL,
+            Records::NONE,
+        ) {
+            let _ = socket.send_to(&response[..len], ipv4_group).await;
+        }
+        embassy_time::Timer::after(ANNOUNCE_INTERVAL).await;
+    }
+
+    let mut query = [0u8; 512];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        let Some((answers, unicast)) = parse_query(&query[..len], &hostname) else {
+            continue;
+        };
+
+        // The records the client would ask for next
+        let additional = if answers.contains(Records::SERVICE_PTR) {
+            Records::SRV.union(Records::TXT).union(Records::ADDRESSES)
+        } else if answers.contains(Records::SRV) {
+            Records::ADDRESSES
+        } else {
+            Records::NONE
+        }
+        .without(answers);
+
+        // Queries from other ports are from plain DNS resolvers, they expect their ID back
+        let legacy = meta.endpoint.port != MDNS_PORT;
+        let id = if legacy {
+            u16::from_be_bytes([query[0], query[1]])
+        } else {
+            0
+        };
+        let destination = if legacy || unicast {
+            meta.endpoint
+        } else if let IpAddress::Ipv6(_) = meta.endpoint.addr {
+            IpEndpoint::new(IpAddress::Ipv6(MDNS_IPV6), MDNS_PORT)
+        } else {
+            ipv4_group
+        };
+        if let Some(len) = write_response(
+            &mut response,
+            id,
+            &hostname,
+            addresses(stack),
+            answers,
+            additional,
+        ) && socket.send_to(&response[..len], destination).await.is_err()
+        {
+            crate::defmt::warn!("mDNS: failed to answer {}", meta.endpoint);
+        }
+    }
+}
+
+/// The current addresses of the station, SLAAC may add the IPv6 one after the announcements
+fn addresses(stack: Stack<'_>) -> Addresses {
+    Addresses {
+        v4: stack.config_v4().map(|config| config.address.address()),
+        v6: stack.config_v6().map(|config| config.address.address()),
+    }
+}
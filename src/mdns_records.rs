@@ -0,0 +1,521 @@
+//! The mDNS and DNS-SD messages of [`crate::mdns`]: which of the device's records a query
+//! asks for, and the response carrying them.
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+use smoltcp::wire::{DnsFlags, DnsPacket};
+
+/// RFC 6762 recommends 120 s for the records which contain a host name
+const TTL_S: u32 = 120;
+const HTTP_PORT: u16 = 80;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// In the class of a question, asks for a unicast response
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// In the class of a record, it replaces the cached ones of the name
+const CACHE_FLUSH: u16 = 0x8000;
+
+const SERVICE: [&str; 3] = ["_http", "_tcp", "local"];
+const SERVICES: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+/// The records of the device, as bits of a set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Records(u8);
+
+impl Records {
+    pub(crate) const NONE: Self = Self(0);
+    pub(crate) const ADDRESSES: Self = Self(0b1);
+    pub(crate) const SERVICE_PTR: Self = Self(0b10);
+    pub(crate) const SRV: Self = Self(0b100);
+    pub(crate) const TXT: Self = Self(0b1000);
+    pub(crate) const SERVICES_PTR: Self = Self(0b1_0000);
+    pub(crate) const ALL: Self = Self(0b1_1111);
+
+    pub(crate) fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub(crate) fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub(crate) fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// The addresses answered for `<hostname>.local`
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Addresses {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+/// The records the questions of a query ask for, and whether one of them wants a unicast
+/// response. Returns None for responses and queries without a question for this device.
+pub(crate) fn parse_query(query: &[u8], hostname: &str) -> Option<(Records, bool)> {
+    let packet = DnsPacket::new_checked(query).ok()?;
+    if packet.flags().contains(DnsFlags::RESPONSE) {
+        return None;
+    }
+
+    let instance = [hostname, SERVICE[0], SERVICE[1], SERVICE[2]];
+    let mut records = Records::NONE;
+    let mut unicast = false;
+    let mut rest = packet.payload();
+    for _ in 0..packet.question_count() {
+        let after_name = skip_name(rest)?;
+        let name = &rest[..rest.len() - after_name.len()];
+        let fields = after_name.get(..4)?;
+        let type_ = u16::from_be_bytes([fields[0], fields[1]]);
+        let class = u16::from_be_bytes([fields[2], fields[3]]);
+        rest = &after_name[4..];
+        if class & !UNICAST_RESPONSE != CLASS_IN {
+            continue;
+        }
+
+        let matches = |labels: &[&str]| {
+            let mut name = packet.parse_name(name);
+            labels.iter().all(|label| {
+                name.next()
+                    .and_then(Result::ok)
+                    .is_some_and(|other| other.eq_ignore_ascii_case(label.as_bytes()))
+            }) && name.next().is_none()
+        };
+        let asked = |types: &[u16]| type_ == TYPE_ANY || types.contains(&type_);
+        let found = if matches(&[hostname, "local"]) && asked(&[TYPE_A, TYPE_AAAA]) {
+            Records::ADDRESSES
+        } else if matches(&SERVICE) && asked(&[TYPE_PTR]) {
+            Records::SERVICE_PTR
+        } else if matches(&instance) {
+            match type_ {
+                TYPE_ANY => Records::SRV.union(Records::TXT),
+                TYPE_SRV => Records::SRV,
+                TYPE_TXT => Records::TXT,
+                _ => Records::NONE,
+            }
+        } else if matches(&SERVICES) && asked(&[TYPE_PTR]) {
+            Records::SERVICES_PTR
+        } else {
+            Records::NONE
+        };
+        if found != Records::NONE {
+            records = records.union(found);
+            unicast |= class & UNICAST_RESPONSE != 0;
+        }
+    }
+    (records != Records::NONE).then_some((records, unicast))
+}
+
+/// The bytes after a name, which ends with an empty label or a pointer
+fn skip_name(mut bytes: &[u8]) -> Option<&[u8]> {
+    loop {
+        let len = *bytes.first()?;
+        match len {
+            0 => return bytes.get(1..),
+            len if len & 0xc0 == 0xc0 => return bytes.get(2..),
+            len => bytes = bytes.get(1 + len as usize..)?,
+        }
+    }
+}
+
+/// Writes a response with the records, returns its length. Names aren't compressed, the
+/// records of a single device fit anyway.
+pub(crate) fn write_response(
+    buffer: &mut [u8],
+    id: u16,
+    hostname: &str,
+    addresses: Addresses,
+    answers: Records,
+    additional: Records,
+) -> Option<usize> {
+    let mut out = Writer { buffer, len: 12 };
+    let answer_count = write_records(&mut out, hostname, addresses, answers)?;
+    if answer_count == 0 {
+        return None;
+    }
+    let additional_count = write_records(&mut out, hostname, addresses, additional)?;
+
+    let len = out.len;
+    let header = out.buffer.get_mut(..12)?;
+    header[..2].copy_from_slice(&id.to_be_bytes());
+    let flags = DnsFlags::RESPONSE | DnsFlags::AUTHORITATIVE;
+    header[2..4].copy_from_slice(&flags.bits().to_be_bytes());
+    header[4..6].copy_from_slice(&0u16.to_be_bytes());
+    header[6..8].copy_from_slice(&answer_count.to_be_bytes());
+    header[8..10].copy_from_slice(&0u16.to_be_bytes());
+    header[10..12].copy_from_slice(&additional_count.to_be_bytes());
+    Some(len)
+}
+
+/// Writes the records of the set, returns how many there were
+fn write_records(
+    out: &mut Writer<'_>,
+    hostname: &str,
+    addresses: Addresses,
+    records: Records,
+) -> Option<u16> {
+    let host = [hostname, "local"];
+    let instance = [hostname, SERVICE[0], SERVICE[1], SERVICE[2]];
+    let mut count = 0;
+
+    if records.contains(Records::ADDRESSES) {
+        if let Some(address) = addresses.v4 {
+            out.record(&host, TYPE_A, CACHE_FLUSH, |out| {
+                out.bytes(&address.octets())
+            })?;
+            count += 1;
+        }
+        if let Some(address) = addresses.v6 {
+            out.record(&host, TYPE_AAAA, CACHE_FLUSH, |out| {
+                out.bytes(&address.octets())
+            })?;
+            count += 1;
+        }
+    }
+    if records.contains(Records::SERVICE_PTR) {
+        out.record(&SERVICE, TYPE_PTR, 0, |out| out.name(&instance))?;
+        count += 1;
+    }
+    if records.contains(Records::SRV) {
+        out.record(&instance, TYPE_SRV, CACHE_FLUSH, |out| {
+            // Priority and weight
+            out.bytes(&[0, 0, 0, 0])?;
+            out.bytes(&HTTP_PORT.to_be_bytes())?;
+            out.name(&host)
+        })?;
+        count += 1;
+    }
+    if records.contains(Records::TXT) {
+        out.record(&instance, TYPE_TXT, CACHE_FLUSH, |out| {
+            out.bytes(&[6])?;
+            out.bytes(b"path=/")
+        })?;
+        count += 1;
+    }
+    if records.contains(Records::SERVICES_PTR) {
+        out.record(&SERVICES, TYPE_PTR, 0, |out| out.name(&SERVICE))?;
+        count += 1;
+    }
+    Some(count)
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn name(&mut self, labels: &[&str]) -> Option<()> {
+        for label in labels {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Writes a record, `data` writes its content after the length
+    fn record(
+        &mut self,
+        name: &[&str],
+        type_: u16,
+        class_flags: u16,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.bytes(&type_.to_be_bytes())?;
+        self.bytes(&(CLASS_IN | class_flags).to_be_bytes())?;
+        self.bytes(&TTL_S.to_be_bytes())?;
+        let length_at = self.len;
+        self.bytes(&[0, 0])?;
+        data(self)?;
+        let length = (self.len - length_at - 2) as u16;
+        self.buffer[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTNAME: &str = "calendar-a1b2c3";
+
+    fn addresses() -> Addresses {
+        Addresses {
+            v4: Some(Ipv4Addr::new(192, 168, 1, 42)),
+            v6: Some(Ipv6Addr::new(
+                0x2001, 0xdb8, 0x1234, 0x5678, 0x1a2b, 0x3cff, 0xfe4d, 0x5e6f,
+            )),
+        }
+    }
+
+    /// `dns-sd -G v4v6 calendar-a1b2c3.local`: A and AAAA with the unicast bit, the second
+    /// name a pointer to the first
+    fn address_query() -> Vec<u8> {
+        [
+            &[0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 15][..],
+            b"calendar-a1b2c3",
+            &[5],
+            b"local",
+            &[0, 0x00, 0x01, 0x80, 0x01],
+            &[0xc0, 0x0c, 0x00, 0x1c, 0x80, 0x01],
+        ]
+        .concat()
+    }
+
+    /// `dig -p 5353 @224.0.0.251 _http._tcp.local PTR`, a legacy query with an EDNS cookie
+    fn browse_query() -> Vec<u8> {
+        [
+            &[0x5c, 0x1e, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1, 5][..],
+            b"_http",
+            &[4],
+            b"_tcp",
+            &[5],
+            b"local",
+            &[0, 0x00, 0x0c, 0x00, 0x01],
+            &[
+                0, 0x00, 0x29, 0x04, 0xd0, 0, 0, 0, 0, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x08,
+            ],
+            &[0x3f, 0x21, 0x9a, 0x07, 0xe4, 0x55, 0x10, 0xcb],
+        ]
+        .concat()
+    }
+
+    /// Resolving the service found by browsing: PTR of the type and ANY of the instance,
+    /// whose name ends in a pointer to the type
+    fn resolve_query() -> Vec<u8> {
+        [
+            &[0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 5][..],
+            b"_http",
+            &[4],
+            b"_tcp",
+            &[5],
+            b"local",
+            &[0, 0x00, 0x0c, 0x00, 0x01, 15],
+            b"calendar-a1b2c3",
+            &[0xc0, 0x0c, 0x00, 0xff, 0x00, 0x01],
+        ]
+        .concat()
+    }
+
+    fn single_query(labels: &[&str], type_: u16) -> Vec<u8> {
+        let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in labels {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&type_.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn queries_for_the_device() {
+        assert_eq!(
+            parse_query(&address_query(), HOSTNAME),
+            Some((Records::ADDRESSES, true))
+        );
+        assert_eq!(
+            parse_query(&browse_query(), HOSTNAME),
+            Some((Records::SERVICE_PTR, false))
+        );
+        assert_eq!(
+            parse_query(&resolve_query(), HOSTNAME),
+            Some((
+                Records::SERVICE_PTR.union(Records::SRV).union(Records::TXT),
+                false
+            ))
+        );
+        assert_eq!(
+            parse_query(&single_query(&SERVICES, TYPE_PTR), HOSTNAME),
+            Some((Records::SERVICES_PTR, false))
+        );
+        // Names are case insensitive
+        assert_eq!(
+            parse_query(
+                &single_query(&["Calendar-A1B2C3", "LOCAL"], TYPE_AAAA),
+                HOSTNAME
+            ),
+            Some((Records::ADDRESSES, false))
+        );
+    }
+
+    #[test]
+    fn queries_for_others_are_ignored() {
+        assert_eq!(
+            parse_query(&single_query(&["printer", "local"], TYPE_A), HOSTNAME),
+            None
+        );
+        assert_eq!(
+            parse_query(&single_query(&[HOSTNAME, "local", "x"], TYPE_A), HOSTNAME),
+            None
+        );
+        assert_eq!(
+            parse_query(&single_query(&[HOSTNAME, "local"], TYPE_TXT), HOSTNAME),
+            None
+        );
+        assert_eq!(
+            parse_query(
+                &single_query(&["_ipp", "_tcp", "local"], TYPE_PTR),
+                HOSTNAME
+            ),
+            None
+        );
+        // A response from another device
+        let mut response = address_query();
+        response[2] = 0x84;
+        assert_eq!(parse_query(&response, HOSTNAME), None);
+        // Cut off in the second question
+        let query = address_query();
+        assert_eq!(parse_query(&query[..query.len() - 3], HOSTNAME), None);
+    }
+
+    #[test]
+    fn address_response() {
+        let mut buffer = [0u8; 512];
+        let len = write_response(
+            &mut buffer,
+            0,
+            HOSTNAME,
+            addresses(),
+            Records::ADDRESSES,
+            Records::NONE,
+        )
+        .unwrap();
+        let host = [&[15][..], b"calendar-a1b2c3", &[5], b"local", &[0]].concat();
+        let expected = [
+            &[0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0][..],
+            &host,
+            &[0x00, 0x01, 0x80, 0x01, 0, 0, 0, 120, 0, 4, 192, 168, 1, 42],
+            &host,
+            &[0x00, 0x1c, 0x80, 0x01, 0, 0, 0, 120, 0, 16],
+            &addresses().v6.unwrap().octets(),
+        ]
+        .concat();
+        assert_eq!(&buffer[..len], expected);
+
+        // Nothing to answer without an address
+        assert_eq!(
+            write_response(
+                &mut buffer,
+                0,
+                HOSTNAME,
+                Addresses::default(),
+                Records::ADDRESSES,
+                Records::NONE,
+            ),
+            None
+        );
+        assert_eq!(
+            write_response(
+                &mut buffer[..40],
+                0,
+                HOSTNAME,
+                addresses(),
+                Records::ADDRESSES,
+                Records::NONE,
+            ),
+            None
+        );
+    }
+
+    /// The name, type and data of every record, with the names joined by dots
+    fn records(response: &[u8]) -> Vec<(String, u16, Vec<u8>)> {
+        let packet = DnsPacket::new_checked(response).unwrap();
+        let count = packet.answer_record_count() + packet.additional_record_count();
+        let name = |bytes| {
+            packet
+                .parse_name(bytes)
+                .map(|label| String::from_utf8(label.unwrap().to_vec()).unwrap())
+                .collect::<Vec<_>>()
+                .join(".")
+        };
+        let mut rest = packet.payload();
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let after_name = skip_name(rest).unwrap();
+            let owner = name(&rest[..rest.len() - after_name.len()]);
+            let type_ = u16::from_be_bytes([after_name[0], after_name[1]]);
+            let len = u16::from_be_bytes([after_name[8], after_name[9]]) as usize;
+            records.push((owner, type_, after_name[10..10 + len].to_vec()));
+            rest = &after_name[10 + len..];
+        }
+        assert!(rest.is_empty());
+        records
+    }
+
+    #[test]
+    fn browse_round_trip() {
+        let query = browse_query();
+        let (answers, unicast) = parse_query(&query, HOSTNAME).unwrap();
+        assert!(!unicast);
+
+        let mut buffer = [0u8; 512];
+        let additional = Records::SRV
+            .union(Records::TXT)
+            .union(Records::ADDRESSES)
+            .without(answers);
+        let addresses = Addresses {
+            v6: None,
+            ..addresses()
+        };
+        let len = write_response(
+            &mut buffer,
+            0x5c1e,
+            HOSTNAME,
+            addresses,
+            answers,
+            additional,
+        )
+        .unwrap();
+        let response = &buffer[..len];
+
+        let packet = DnsPacket::new_checked(response).unwrap();
+        assert_eq!(packet.transaction_id(), 0x5c1e);
+        assert_eq!(packet.question_count(), 0);
+        assert_eq!(packet.answer_record_count(), 1);
+        assert_eq!(packet.additional_record_count(), 3);
+
+        let instance = [&[15][..], b"calendar-a1b2c3", &[5], b"_http", &[4], b"_tcp"].concat();
+        let instance = [&instance[..], &[5], b"local", &[0]].concat();
+        let target = [&[15][..], b"calendar-a1b2c3", &[5], b"local", &[0]].concat();
+        let srv = [&[0, 0, 0, 0, 0, 80][..], &target].concat();
+        let found = records(response);
+        let expected = [
+            ("_http._tcp.local", TYPE_PTR, instance),
+            ("calendar-a1b2c3.local", TYPE_A, vec![192, 168, 1, 42]),
+            ("calendar-a1b2c3._http._tcp.local", TYPE_SRV, srv),
+            (
+                "calendar-a1b2c3._http._tcp.local",
+                TYPE_TXT,
+                b"\x06path=/".to_vec(),
+            ),
+        ];
+        assert_eq!(found.len(), expected.len());
+        for ((name, type_, data), (expected_name, expected_type, expected_data)) in
+            found.iter().zip(&expected)
+        {
+            assert_eq!(name, expected_name);
+            assert_eq!(type_, expected_type);
+            assert_eq!(data, expected_data);
+        }
+
+        // The device doesn't answer its own response
+        assert_eq!(parse_query(response, HOSTNAME), None);
+    }
+}
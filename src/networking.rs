@@ -1,22 +1,27 @@
 use alloc::string::String;
 use alloc::string::ToString;
 use core::fmt::Write;
-use core::net::{SocketAddr, SocketAddrV4};
 
-use embassy_net::Stack;
-use embassy_net::dns::DnsSocket;
-use embassy_net::tcp::client::TcpClient;
-use embassy_net::udp::PacketMetadata;
+use embassy_net::tcp::client::{TcpClient, TcpConnection};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::once_lock::OnceLock;
 use esp_backtrace as _;
+use esp_storage::FlashStorage;
 use jiff::tz;
 use jiff::tz::TimeZone;
 use reqwless::client::{HttpClient, TlsConfig};
 use reqwless::request::RequestBuilder;
-use smoltcp::wire::DnsQueryType;
 use static_cell::StaticCell;
 pub use vcal_parser::calendars::CalendarData;
 
-use crate::storage::CaldavCreds;
+use crate::dns_cache::CachingDns;
+use crate::inflate::{ContentEncoding, InflateReader};
+use crate::parsing::EventBudget;
+use crate::storage::{
+    CachedCalendar, CachedResource, CaldavCreds, IcsFeed, SyncCache, TlsTrust, TlsVersion,
+};
+use crate::tls_pin::{LeafPin, PinCheck};
 
 const UTC_OFFSET_HOURS: i8 = 2;
 pub const USER_TIMEZONE: TimeZone = TimeZone::fixed(tz::offset(UTC_OFFSET_HOURS));
@@ -29,25 +34,93 @@ pub const CERT_STORE: &core::ffi::CStr = {
         Err(_) => panic!("cert contains interior null bytes or is missing terminator"),
     }
 };
-/// This is a boolean value to whether the initial NTP sync occurred
-#[esp_hal::ram(unstable(rtc_fast, persistent))]
-pub static INITIAL_NTP_SYNC: portable_atomic::AtomicU8 = portable_atomic::AtomicU8::new(0);
 
 pub(crate) static CLIENT_STATE: StaticCell<
     embassy_net::tcp::client::TcpClientState<1, 4096, 4096>,
 > = StaticCell::new();
-static RX_META: StaticCell<[PacketMetadata; 16]> = StaticCell::new();
-static RX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
-static TX_META: StaticCell<[PacketMetadata; 16]> = StaticCell::new();
-static TX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
 
 pub(crate) static REQ_BUFFER: StaticCell<[u8; 8192]> = StaticCell::new();
 
+/// Heap the events of all calendars may take up together, the rest of the 128 KiB is needed
+/// for the TLS buffers and the sync cache
+pub(crate) const EVENT_MEMORY_BUDGET: usize = 24 * 1024;
+
+/// Shortest time range a truncated calendar-query is split into
+const MIN_SLICE: jiff::SignedDuration = jiff::SignedDuration::from_hours(1);
+
+/// The built-in roots merged with the uploaded certificates, built once per boot since the
+/// certificates only change with a restart
+static TRUST_STORE: OnceLock<alloc::ffi::CString> = OnceLock::new();
+
+/// The step of the synchronization during which a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestStage {
+    Principal,
+    CalendarHomeSet,
+    CalendarList,
+    Credentials,
+    CalendarData,
+    IcsFeed,
+    OAuth2,
+    HttpDate,
+    TlsHandshake,
+}
+
+impl core::fmt::Display for RequestStage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            RequestStage::Principal => "principal lookup",
+            RequestStage::CalendarHomeSet => "calendar home set lookup",
+            RequestStage::CalendarList => "calendar listing",
+            RequestStage::Credentials => "credential check",
+            RequestStage::CalendarData => "calendar data",
+            RequestStage::IcsFeed => "ics feed",
+            RequestStage::OAuth2 => "OAuth2 token",
+            RequestStage::HttpDate => "HTTP date",
+            RequestStage::TlsHandshake => "TLS handshake",
+        })
+    }
+}
+
 #[derive(thiserror::Error, picoserve::response::ErrorWithStatusCode, Debug)]
 #[status_code(INTERNAL_SERVER_ERROR)]
 pub enum NetworkError {
     #[error("Failed to send request: {0}")]
     RequestError(#[from] reqwless::Error),
+    #[status_code(BAD_GATEWAY)]
+    #[error("The {stage} request failed: {error}")]
+    StageFailed {
+        stage: RequestStage,
+        error: reqwless::Error,
+    },
+    #[status_code(BAD_GATEWAY)]
+    #[error("The {stage} request returned HTTP {status}")]
+    HttpStatus { stage: RequestStage, status: u16 },
+    #[status_code(GATEWAY_TIMEOUT)]
+    #[error("The {0} request timed out")]
+    Timeout(RequestStage),
+    #[status_code(BAD_GATEWAY)]
+    #[error("DNS lookup failed")]
+    DnsFailed,
+    #[status_code(BAD_GATEWAY)]
+    #[error("NTP request failed")]
+    NtpFailed,
+    #[status_code(BAD_GATEWAY)]
+    #[error("Failed to connect for the TLS handshake")]
+    TlsProbeFailed,
+    #[status_code(BAD_GATEWAY)]
+    #[error("The server rejected the TLS handshake with alert {0}")]
+    TlsAlert(u8),
+    #[status_code(BAD_GATEWAY)]
+    #[error("The server doesn't support the minimum TLS version")]
+    TlsVersionUnsupported,
+    #[status_code(BAD_GATEWAY)]
+    #[error("The {0} response has an unsupported Content-Encoding")]
+    UnsupportedEncoding(RequestStage),
+    #[error("More than {0} events in a calendar")]
+    TooManyEvents(u16),
+    #[error("The events don't fit into the memory")]
+    EventBudgetExceeded,
     #[error("Failed to read to String")]
     ReadError(#[from] core::str::Utf8Error),
     #[status_code(BAD_REQUEST)]
@@ -56,114 +129,298 @@ pub enum NetworkError {
     #[status_code(BAD_REQUEST)]
     #[error("Failed to parse URL")]
     WrongUrl,
+    #[status_code(BAD_REQUEST)]
+    #[error("Plain HTTP is not enabled for this account")]
+    PlainHttpDisabled,
     #[status_code(UNAUTHORIZED)]
     #[error("Invalid credentials")]
     InvalidCredentials,
+    #[status_code(UNAUTHORIZED)]
+    #[error("OAuth2 authorization failed")]
+    AuthorizationFailed,
+    #[status_code(SERVICE_UNAVAILABLE)]
+    #[error("None of the saved Wi-Fi networks is reachable")]
+    WifiUnavailable,
 }
 
-#[derive(Copy, Clone, Default)]
-struct NtpTimestamp {
-    duration: jiff::SignedDuration,
-}
+impl NetworkError {
+    /// Retrying won't help with these, the user has to fix the configuration
+    pub fn is_auth_failure(&self) -> bool {
+        match self {
+            NetworkError::InvalidCredentials | NetworkError::AuthorizationFailed => true,
+            NetworkError::HttpStatus { status, .. } => matches!(status, 401 | 403),
+            _ => false,
+        }
+    }
 
-impl sntpc::NtpTimestampGenerator for NtpTimestamp {
-    fn init(&mut self) {
-        let ticks = embassy_time::Instant::now().as_ticks();
-        let micros = ticks * 1_000_000 / embassy_time::TICK_HZ;
-        self.duration = jiff::SignedDuration::from_micros(micros as i64);
+    /// Retrying won't help with these either, the calendar has to shrink or the limit grow
+    pub fn is_too_many_events(&self) -> bool {
+        matches!(
+            self,
+            NetworkError::TooManyEvents(_) | NetworkError::EventBudgetExceeded
+        )
     }
 
-    fn timestamp_sec(&self) -> u64 {
-        self.duration.as_secs() as u64
+    /// The error for a calendar whose events didn't fit into `budget`
+    fn over_budget(budget: &EventBudget, max_events: u16) -> Self {
+        if budget.events == 0 {
+            NetworkError::TooManyEvents(max_events)
+        } else {
+            NetworkError::EventBudgetExceeded
+        }
     }
+}
 
-    fn timestamp_subsec_micros(&self) -> u32 {
-        self.duration.subsec_micros() as u32
+/// Attaches the failed [`RequestStage`] to the errors coming from reqwless
+pub(crate) trait WithStage<T> {
+    fn stage(self, stage: RequestStage) -> Result<T, NetworkError>;
+}
+
+impl<T> WithStage<T> for Result<T, reqwless::Error> {
+    fn stage(self, stage: RequestStage) -> Result<T, NetworkError> {
+        self.map_err(|error| NetworkError::StageFailed { stage, error })
     }
 }
 
-pub async fn sync_time(
-    prev_boot_count: u32,
-    stack: Stack<'_>,
-    rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
-) {
-    let need_initial_sync = INITIAL_NTP_SYNC.load(core::sync::atomic::Ordering::Relaxed) == 0;
-    // The RTC clock drifts, so every 5th boot we resync it with the NTP time.
-    if prev_boot_count.is_multiple_of(5) || need_initial_sync {
-        crate::defmt::info!("Syncing RTC with NTP (boot {})", prev_boot_count + 1);
-        if let Ok(time) =
-            embassy_time::with_timeout(embassy_time::Duration::from_secs(5), get_time(stack)).await
-        {
-            rtc.set_current_time_us(
-                (time.as_second() as u64 * 1_000_000) + (time.subsec_microsecond() as u64),
+pub(crate) fn check_status(
+    stage: RequestStage,
+    status: reqwless::response::StatusCode,
+) -> Result<(), NetworkError> {
+    if status.is_successful() {
+        Ok(())
+    } else {
+        Err(NetworkError::HttpStatus {
+            stage,
+            status: status.0,
+        })
+    }
+}
+
+/// Appends the uploaded certificates to the built-in roots, or replaces them in exclusive
+/// mode
+fn trust_store(trust: Option<&TlsTrust>) -> &'static core::ffi::CStr {
+    let Some(trust) = trust.filter(|t| !t.ca_pem.is_empty()) else {
+        return CERT_STORE;
+    };
+    if let Some(pem) = TRUST_STORE.try_get() {
+        return pem;
+    }
+
+    let mut pem = alloc::vec::Vec::new();
+    if !trust.exclusive {
+        pem.extend_from_slice(CERT_STORE.to_bytes());
+        pem.push(b'\n');
+    }
+    pem.extend_from_slice(trust.ca_pem.as_bytes());
+
+    match alloc::ffi::CString::new(pem) {
+        Ok(pem) => {
+            crate::defmt::info!(
+                "Using custom certificates{}",
+                if trust.exclusive { " only" } else { "" }
             );
-            if need_initial_sync {
-                INITIAL_NTP_SYNC.store(1, core::sync::atomic::Ordering::Relaxed);
+            TRUST_STORE.get_or_init(|| pem)
+        }
+        Err(_) => {
+            crate::defmt::error!("Custom certificates contain a null byte, ignoring them");
+            CERT_STORE
+        }
+    }
+}
+
+/// Passes the connections of the TCP client through, checking the server's leaf certificate
+/// during the TLS handshake if there is a `pin`
+pub struct PinnedTcp<'a> {
+    client: &'a TcpClient<'a, 1, 4096, 4096>,
+    pin: Option<[u8; 32]>,
+}
+
+impl embedded_nal_async::TcpConnect for PinnedTcp<'_> {
+    type Error = embassy_net::tcp::Error;
+    type Connection<'m>
+        = PinnedConnection<'m>
+    where
+        Self: 'm;
+
+    async fn connect<'m>(
+        &'m self,
+        remote: core::net::SocketAddr,
+    ) -> Result<Self::Connection<'m>, Self::Error> {
+        let connection = embedded_nal_async::TcpConnect::connect(self.client, remote).await?;
+        Ok(PinnedConnection {
+            connection,
+            check: self
+                .pin
+                .map(|pin| alloc::boxed::Box::new(LeafPin::new(pin))),
+        })
+    }
+}
+
+/// A connection of [`PinnedTcp`], which stops reading once the leaf certificate failed the
+/// check
+pub struct PinnedConnection<'m> {
+    connection: TcpConnection<'m, 1, 4096, 4096>,
+    /// Dropped once the leaf certificate matched
+    check: Option<alloc::boxed::Box<LeafPin>>,
+}
+
+impl embedded_io_async::ErrorType for PinnedConnection<'_> {
+    type Error = embassy_net::tcp::Error;
+}
+
+impl embedded_io_async::Read for PinnedConnection<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = embedded_io_async::Read::read(&mut self.connection, buf).await?;
+        let Some(check) = self.check.as_mut() else {
+            return Ok(len);
+        };
+        // The TLS session never sees the bytes of a server that failed the check
+        match check.feed(&buf[..len]) {
+            PinCheck::Pending => Ok(len),
+            PinCheck::Matched => {
+                crate::defmt::info!("The server certificate matches the pinned fingerprint");
+                self.check = None;
+                Ok(len)
+            }
+            PinCheck::Mismatched => {
+                crate::defmt::error!("The server certificate doesn't match the pinned fingerprint");
+                Err(embassy_net::tcp::Error::ConnectionReset)
+            }
+            PinCheck::Unverifiable => {
+                crate::defmt::error!(
+                    "The server certificate is encrypted, only TLS 1.2 servers can be pinned"
+                );
+                Err(embassy_net::tcp::Error::ConnectionReset)
             }
-        } else {
-            crate::defmt::warn!("NTP sync timed out, skipping for this boot");
         }
     }
 }
 
-pub async fn get_time(stack: Stack<'_>) -> jiff::Timestamp {
-    use embassy_net::udp::UdpSocket;
-    use sntpc::{NtpContext, get_time};
+impl embedded_io_async::Write for PinnedConnection<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        embedded_io_async::Write::write(&mut self.connection, buf).await
+    }
 
-    let rx_meta = RX_META.init([PacketMetadata::EMPTY; 16]);
-    #[allow(clippy::large_stack_frames, reason = "false positive")]
-    let rx_buffer = RX_BUFFER.init_with(|| [0; 4096]);
-    let tx_meta = TX_META.init([PacketMetadata::EMPTY; 16]);
-    #[allow(clippy::large_stack_frames, reason = "false positive")]
-    let tx_buffer = TX_BUFFER.init_with(|| [0; 4096]);
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        embedded_io_async::Write::flush(&mut self.connection).await
+    }
+}
 
-    let mut socket = UdpSocket::new(stack, rx_meta, rx_buffer, tx_meta, tx_buffer);
-    socket.bind(123).unwrap();
-    let socket = sntpc_net_embassy::UdpSocketWrapper::new(socket);
+/// The TCP client without a pin, and for the CalDAV server with the pinned leaf certificate
+/// if there is one
+pub struct TcpClients<'a> {
+    unpinned: PinnedTcp<'a>,
+    caldav: PinnedTcp<'a>,
+}
 
-    let context = NtpContext::new(NtpTimestamp::default());
+impl<'a> TcpClients<'a> {
+    pub fn new(client: &'a TcpClient<'a, 1, 4096, 4096>, trust: Option<&TlsTrust>) -> Self {
+        Self {
+            unpinned: PinnedTcp { client, pin: None },
+            caldav: PinnedTcp {
+                client,
+                pin: trust.and_then(|trust| trust.leaf_sha256),
+            },
+        }
+    }
+}
 
-    let ip = match stack
-        .dns_query("pool.ntp.org", DnsQueryType::A)
-        .await
-        .unwrap()
-        .first()
-        .unwrap()
-    {
-        embassy_net::IpAddress::Ipv4(ipv4_addr) => *ipv4_addr,
+fn init_https_client<'a>(
+    tcp_client: &'a PinnedTcp<'a>,
+    dns: &'a CachingDns<'a>,
+    tls_reference: reqwless::TlsReference<'a>,
+    trust: Option<&TlsTrust>,
+    min_version: TlsVersion,
+) -> HttpClient<'a, PinnedTcp<'a>, CachingDns<'a>> {
+    let certs = reqwless::Certificate::new(reqwless::X509::PEM(trust_store(trust)))
+        .or_else(|_| {
+            crate::defmt::error!("Invalid custom certificates, using the built-in roots");
+            reqwless::Certificate::new(reqwless::X509::PEM(CERT_STORE))
+        })
+        .unwrap();
+    // mbedtls offers every version from the minimum up to 1.3 and uses the highest one
+    // the server supports
+    let min_version = match min_version {
+        TlsVersion::Tls1_2 => reqwless::TlsVersion::Tls1_2,
+        TlsVersion::Tls1_3 => reqwless::TlsVersion::Tls1_3,
     };
+    // No TLS session resumption: reqwless only takes the versions, the certificates and
+    // the reference here, it has no way to restore a saved session
+    let tls_config = TlsConfig::new(min_version, certs, tls_reference);
 
-    //todo error handling
-    let result = get_time(SocketAddr::V4(SocketAddrV4::new(ip, 123)), &socket, context)
-        .await
-        .unwrap();
-    let time = jiff::Timestamp::from_second(result.seconds as i64).unwrap();
-    crate::defmt::info!("Current time: {:?}", crate::defmt::Debug2Format(&time));
-    time
+    HttpClient::new_with_tls(tcp_client, dns, tls_config)
 }
 
-pub fn init_https_client<'a>(
-    tcp_client: &'a TcpClient<'a, 1, 4096, 4096>,
-    dns_socket: &'a DnsSocket<'a>,
-    tls_reference: reqwless::TlsReference<'a>,
-) -> HttpClient<'a, TcpClient<'a, 1, 4096, 4096>, DnsSocket<'a>> {
-    let certs = reqwless::Certificate::new(reqwless::X509::PEM(CERT_STORE)).unwrap();
-    let tls_config = TlsConfig::new(reqwless::TlsVersion::Tls1_3, certs, tls_reference);
+fn is_http(url: &str) -> bool {
+    url.get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://"))
+}
 
-    HttpClient::new_with_tls(tcp_client, dns_socket, tls_config)
+/// The TLS client, the one for the CalDAV server which checks the pinned certificate, and a
+/// plain one for the CalDAV accounts that opted into `http://`
+pub struct HttpClients<'a> {
+    pub tls: HttpClient<'a, PinnedTcp<'a>, CachingDns<'a>>,
+    caldav_tls: HttpClient<'a, PinnedTcp<'a>, CachingDns<'a>>,
+    plain: HttpClient<'a, PinnedTcp<'a>, CachingDns<'a>>,
 }
 
-pub async fn calendar_data_req(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
-    date: &jiff::Zoned,
-    req_buffer: &mut [u8; 8192],
-    creds: &CaldavCreds,
-    calendar_ids: &[String],
-) -> alloc::vec::Vec<vcal_parser::vevent::VEventData> {
-    crate::defmt::info!(
-        "Making calendar request for date: {}",
-        crate::defmt::Debug2Format(&date)
-    );
+impl<'a> HttpClients<'a> {
+    pub fn new(
+        tcp_clients: &'a TcpClients<'a>,
+        dns: &'a CachingDns<'a>,
+        tls_reference: reqwless::TlsReference<'a>,
+        trust: Option<&TlsTrust>,
+        min_version: TlsVersion,
+    ) -> Self {
+        Self {
+            tls: init_https_client(
+                &tcp_clients.unpinned,
+                dns,
+                tls_reference,
+                trust,
+                min_version,
+            ),
+            caldav_tls: init_https_client(
+                &tcp_clients.caldav,
+                dns,
+                tls_reference,
+                trust,
+                min_version,
+            ),
+            plain: HttpClient::new(&tcp_clients.unpinned, dns),
+        }
+    }
+
+    /// Picks the client for the URL, `http://` is refused unless the account allows it
+    pub(crate) fn for_url(
+        &mut self,
+        url: &str,
+        allow_http: bool,
+    ) -> Result<&mut HttpClient<'a, PinnedTcp<'a>, CachingDns<'a>>, NetworkError> {
+        match (is_http(url), allow_http) {
+            (false, _) => Ok(&mut self.tls),
+            (true, true) => {
+                crate::defmt::warn!("Connecting to {} without TLS", url);
+                Ok(&mut self.plain)
+            }
+            (true, false) => Err(NetworkError::PlainHttpDisabled),
+        }
+    }
+
+    pub(crate) fn caldav(
+        &mut self,
+        creds: &CaldavCreds,
+    ) -> Result<&mut HttpClient<'a, PinnedTcp<'a>, CachingDns<'a>>, NetworkError> {
+        if is_http(&creds.url) {
+            self.for_url(&creds.url, creds.allow_http)
+        } else {
+            Ok(&mut self.caldav_tls)
+        }
+    }
+}
+
+/// Returns the time range which is visible on the screen
+pub(crate) fn display_window(date: &jiff::Zoned) -> (jiff::Zoned, jiff::Zoned) {
     let mut start_display_hour = date.hour();
     if !crate::display::limit_to_today() {
         start_display_hour =
@@ -180,85 +437,136 @@ pub async fn calendar_data_req(
     let end_zoned = start_zoned
         .checked_add(jiff::Span::new().hours(crate::display::get_display_hours() as i32))
         .unwrap();
+    (start_zoned, end_zoned)
+}
+
+/// Returns the time range the events are fetched for, from the displayed window to the end of
+/// the day, so later wakes of the same day can redraw without the network
+pub(crate) fn fetch_window(date: &jiff::Zoned) -> (jiff::Zoned, jiff::Zoned) {
+    let (start, end) = display_window(date);
+    let end_of_day = date
+        .start_of_day()
+        .and_then(|day| day.checked_add(jiff::Span::new().days(1)))
+        .unwrap();
+    let end = if end_of_day > end { end_of_day } else { end };
+    (start, end)
+}
 
-    let start_utc = start_zoned.with_time_zone(TimeZone::UTC).datetime();
-    let end_utc = end_zoned.with_time_zone(TimeZone::UTC).datetime();
+/// The local day the cached calendar data is expanded for, the display window always
+/// falls inside it
+struct SyncWindow {
+    start: jiff::Timestamp,
+    end: jiff::Timestamp,
+    start_fmt: heapless::String<16>,
+    end_fmt: heapless::String<16>,
+}
 
-    let mut start_fmt = heapless::String::<16>::new();
-    let _ = write!(
-        start_fmt,
-        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
-        start_utc.year(),
-        start_utc.month(),
-        start_utc.day(),
-        start_utc.hour(),
-        start_utc.minute(),
-        start_utc.second()
-    );
+impl SyncWindow {
+    fn new(date: &jiff::Zoned) -> Result<Self, NetworkError> {
+        let start = date
+            .start_of_day()
+            .map_err(|_| NetworkError::ParsingError)?;
+        let end = start
+            .checked_add(jiff::Span::new().days(1))
+            .map_err(|_| NetworkError::ParsingError)?;
+        Ok(Self {
+            start: start.timestamp(),
+            end: end.timestamp(),
+            start_fmt: caldav_time(&start),
+            end_fmt: caldav_time(&end),
+        })
+    }
+}
 
-    let mut end_fmt = heapless::String::<16>::new();
+/// Formats a time for the `time-range` and `expand` attributes
+fn caldav_time(zoned: &jiff::Zoned) -> heapless::String<16> {
+    let utc = zoned.with_time_zone(TimeZone::UTC).datetime();
+    let mut formatted = heapless::String::<16>::new();
     let _ = write!(
-        end_fmt,
+        formatted,
         "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
-        end_utc.year(),
-        end_utc.month(),
-        end_utc.day(),
-        end_utc.hour(),
-        end_utc.minute(),
-        end_utc.second()
+        utc.year(),
+        utc.month(),
+        utc.day(),
+        utc.hour(),
+        utc.minute(),
+        utc.second()
     );
+    formatted
+}
 
-    let body: heapless::String<554> = heapless::format!(
-        r#"<?xml version="1.0" encoding="utf-8" ?>
-<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
-    <d:prop>
-        <d:getetag/>
-        <c:calendar-data>
-            <c:expand start="{}" end="{}"/>
-        </c:calendar-data>
-    </d:prop>
-    <c:filter>
-        <c:comp-filter name="VCALENDAR">
-            <c:comp-filter name="VEVENT">
-                <c:time-range start="{}" end="{}"/>
-            </c:comp-filter>
-        </c:comp-filter>
-    </c:filter>
-</c:calendar-query>"#,
-        start_fmt,
-        end_fmt,
-        start_fmt,
-        end_fmt
+/// The number of events and the heap they take up
+fn events_cost<'e>(
+    events: impl Iterator<Item = &'e vcal_parser::vevent::VEventData>,
+) -> (usize, usize) {
+    events.fold((0, 0), |(count, bytes), event| {
+        (count + 1, bytes + EventBudget::event_size(event))
+    })
+}
+
+/// What is left of the limits of a calendar besides `events`
+fn remaining_budget<'e>(
+    events: impl Iterator<Item = &'e vcal_parser::vevent::VEventData>,
+    max_events: u16,
+    bytes: usize,
+) -> EventBudget {
+    let (count, size) = events_cost(events);
+    EventBudget::new(
+        max_events.saturating_sub(u16::try_from(count).unwrap_or(u16::MAX)),
+        bytes.saturating_sub(size),
     )
-    .unwrap();
+}
+
+/// Returns the events of the displayed window, only asking the server for what changed
+/// since the data in `cache`.
+///
+/// Every calendar may have up to `max_events` events of the day, and all of them together
+/// have to fit into `bytes_left`, which is reduced by what they take up.
+#[allow(clippy::too_many_arguments)]
+pub async fn calendar_data_req(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    date: &jiff::Zoned,
+    req_buffer: &mut [u8; 8192],
+    creds: &CaldavCreds,
+    authorization: &str,
+    calendar_ids: &[String],
+    cache: &mut SyncCache,
+    max_events: u16,
+    bytes_left: &mut usize,
+) -> Result<alloc::vec::Vec<vcal_parser::vevent::VEventData>, NetworkError> {
+    crate::defmt::info!(
+        "Making calendar request for date: {}",
+        crate::defmt::Debug2Format(&date)
+    );
+    let window = SyncWindow::new(date)?;
+    if cache.day_start != window.start.as_second() {
+        // The recurrences were expanded for another day
+        cache.day_start = window.start.as_second();
+        cache.calendars.clear();
+    }
 
     let url = creds.url.as_str();
     let username = creds.username.as_str();
-    let password = creds.password.as_str();
 
-    let url = fluent_uri::Uri::parse(url);
-    let url = match url {
-        Ok(u) => u,
-        Err(e) => {
-            crate::defmt::error!("Failed to parse URL: {}", crate::defmt::Debug2Format(&e));
-            crate::BootType::set(crate::BootType::Config);
-            crate::wifi::stop_wifi_and_reset().await;
-        }
-    };
+    let url = fluent_uri::Uri::parse(url).map_err(|e| {
+        crate::defmt::error!("Failed to parse URL: {}", crate::defmt::Debug2Format(&e));
+        NetworkError::WrongUrl
+    })?;
 
     let origin: heapless::String<{ crate::server::MAX_ORIGIN_LEN }> = heapless::format!(
         "{}://{}",
         url.scheme().as_str(),
-        url.authority().unwrap().as_str()
+        url.authority().ok_or(NetworkError::WrongUrl)?.as_str()
     )
-    .unwrap();
+    .map_err(|_| NetworkError::WrongUrl)?;
 
-    let mut all_cals = alloc::vec::Vec::new();
+    let mut all_events = alloc::vec::Vec::new();
+    let mut paths = alloc::vec::Vec::new();
     for cal_id in calendar_ids {
         let path: heapless::String<{ crate::server::MAX_PATH_LEN }> =
-            heapless::format!("{}calendars/{}{}", url.path().as_str(), username, cal_id).unwrap();
+            heapless::format!("{}calendars/{}{}", url.path().as_str(), username, cal_id)
+                .map_err(|_| NetworkError::WrongUrl)?;
 
-        crate::defmt::info!("url path: {}", url.path().as_str());
         crate::defmt::info!(
             "username: {}, calendar id: {}",
             username,
@@ -266,98 +574,606 @@ pub async fn calendar_data_req(
         );
         crate::defmt::debug!("request path: {}", path);
 
-        let vec = calendar_data_processor(
+        let index = match cache.calendars.iter().position(|c| c.path == path.as_str()) {
+            Some(index) => index,
+            None => {
+                cache.calendars.push(CachedCalendar {
+                    path: String::from(path.as_str()),
+                    ..Default::default()
+                });
+                cache.calendars.len() - 1
+            }
+        };
+        let calendar = &mut cache.calendars[index];
+        sync_calendar(
             client,
             &origin,
-            &path,
-            username,
-            password,
+            authorization,
+            calendar,
+            &window,
+            max_events,
+            *bytes_left,
+            req_buffer,
+        )
+        .await?;
+
+        // The cached events may predate a lower limit
+        let (count, size) = events_cost(calendar.resources.iter().flat_map(|r| &r.events));
+        if count > max_events as usize {
+            return Err(NetworkError::TooManyEvents(max_events));
+        }
+        *bytes_left = bytes_left
+            .checked_sub(size)
+            .ok_or(NetworkError::EventBudgetExceeded)?;
+
+        all_events.extend(
+            calendar
+                .resources
+                .iter()
+                .flat_map(|resource| resource.events.iter().cloned()),
+        );
+        paths.push(path);
+    }
+    cache
+        .calendars
+        .retain(|c| paths.iter().any(|path| path.as_str() == c.path));
+
+    let (start, end) = fetch_window(date);
+    all_events.retain(|event| event.overlaps(start.timestamp(), end.timestamp()));
+    Ok(all_events)
+}
+
+/// Brings one cached calendar up to date, with a single sync-collection REPORT or a CTag
+/// check when nothing changed
+#[allow(clippy::too_many_arguments)]
+async fn sync_calendar(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    origin: &str,
+    authorization: &str,
+    calendar: &mut CachedCalendar,
+    window: &SyncWindow,
+    max_events: u16,
+    bytes: usize,
+    req_buffer: &mut [u8; 8192],
+) -> Result<(), NetworkError> {
+    let mut state = None;
+    if let Some(token) = &calendar.sync_token {
+        let body = alloc::format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+<d:sync-collection xmlns:d="DAV:">
+    <d:sync-token>{}</d:sync-token>
+    <d:sync-level>1</d:sync-level>
+    <d:prop>
+        <d:getetag/>
+    </d:prop>
+</d:sync-collection>"#,
+            token
+        );
+        let changes = dav_request(
+            client,
+            reqwless::request::Method::REPORT,
+            origin,
+            &calendar.path,
+            authorization,
+            "0",
             body.as_bytes(),
+            &mut EventBudget::NONE,
             req_buffer,
         )
         .await;
-        all_cals.extend(vec);
+        match changes {
+            // The rest of the changes would need further requests, the sliced full fetch
+            // is bounded instead
+            Ok(changes) if changes.truncated => {
+                crate::defmt::warn!("sync-collection was truncated, fetching the whole calendar")
+            }
+            Ok(changes) => {
+                return apply_changes(
+                    client,
+                    origin,
+                    authorization,
+                    calendar,
+                    changes,
+                    window,
+                    max_events,
+                    bytes,
+                    req_buffer,
+                )
+                .await;
+            }
+            Err(e @ NetworkError::HttpStatus { status: 401, .. }) => return Err(e),
+            // An expired token is reported with 403 or 409
+            Err(e) => crate::defmt::warn!(
+                "sync-collection failed, fetching the whole calendar: {}",
+                crate::defmt::Display2Format(&e)
+            ),
+        }
+    } else if calendar.ctag.is_some() {
+        let current =
+            collection_state(client, origin, &calendar.path, authorization, req_buffer).await?;
+        if current.ctag.is_some() && current.ctag == calendar.ctag {
+            crate::defmt::info!(
+                "CTag of {} unchanged, using the cached events",
+                calendar.path.as_str()
+            );
+            return Ok(());
+        }
+        state = Some(current);
     }
 
-    all_cals
+    // The token has to be older than the data, a change in between is fetched again
+    let state = match state {
+        Some(state) => state,
+        None => collection_state(client, origin, &calendar.path, authorization, req_buffer).await?,
+    };
+
+    calendar.resources = fetch_resources(
+        client,
+        origin,
+        authorization,
+        &calendar.path,
+        window,
+        max_events,
+        bytes,
+        req_buffer,
+    )
+    .await?;
+    calendar.sync_token = state.sync_token;
+    calendar.ctag = state.ctag;
+    crate::defmt::info!(
+        "Fetched {} resources of {}, sync token: {}",
+        calendar.resources.len(),
+        calendar.path.as_str(),
+        calendar.sync_token.is_some()
+    );
+    Ok(())
+}
+
+/// Fetches the resources of the day with calendar-queries limited to what is left of the
+/// budget. A time range the server truncates is split in half and fetched again, down to
+/// [`MIN_SLICE`].
+#[allow(clippy::too_many_arguments)]
+async fn fetch_resources(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    origin: &str,
+    authorization: &str,
+    path: &str,
+    window: &SyncWindow,
+    max_events: u16,
+    bytes: usize,
+    req_buffer: &mut [u8; 8192],
+) -> Result<alloc::vec::Vec<CachedResource>, NetworkError> {
+    let mut resources: alloc::vec::Vec<CachedResource> = alloc::vec::Vec::new();
+    let mut slices = alloc::vec![(window.start, window.end)];
+    while let Some((start, end)) = slices.pop() {
+        let mut budget =
+            remaining_budget(resources.iter().flat_map(|r| &r.events), max_events, bytes);
+        // The recurrences are still expanded for the whole day, only the filter is narrowed
+        let body = alloc::format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+    <d:prop>
+        <d:getetag/>
+        <c:calendar-data>
+            <c:expand start="{day_start}" end="{day_end}"/>
+        </c:calendar-data>
+    </d:prop>
+    <c:filter>
+        <c:comp-filter name="VCALENDAR">
+            <c:comp-filter name="VEVENT">
+                <c:time-range start="{start}" end="{end}"/>
+            </c:comp-filter>
+        </c:comp-filter>
+    </c:filter>
+    <c:limit>
+        <c:nresults>{limit}</c:nresults>
+    </c:limit>
+</c:calendar-query>"#,
+            day_start = window.start_fmt,
+            day_end = window.end_fmt,
+            start = caldav_time(&start.to_zoned(TimeZone::UTC)),
+            end = caldav_time(&end.to_zoned(TimeZone::UTC)),
+            limit = budget.events.max(1),
+        );
+        let multistatus = dav_request(
+            client,
+            reqwless::request::Method::REPORT,
+            origin,
+            path,
+            authorization,
+            "1",
+            body.as_bytes(),
+            &mut budget,
+            req_buffer,
+        )
+        .await?;
+        if budget.exhausted {
+            return Err(NetworkError::over_budget(&budget, max_events));
+        }
+
+        if multistatus.truncated {
+            let half = end.duration_since(start) / 2;
+            if half < MIN_SLICE {
+                return Err(NetworkError::TooManyEvents(max_events));
+            }
+            let middle = start
+                .checked_add(half)
+                .map_err(|_| NetworkError::ParsingError)?;
+            crate::defmt::info!(
+                "The server truncated the events of {} minutes, splitting them",
+                end.duration_since(start).as_mins()
+            );
+            slices.push((middle, end));
+            slices.push((start, middle));
+            continue;
+        }
+
+        for response in multistatus.responses {
+            // An event spanning several slices matches in each of them
+            if !response.removed && !resources.iter().any(|r| r.href == response.href) {
+                resources.push(cached_resource(response, window));
+            }
+        }
+    }
+    Ok(resources)
+}
+
+/// Downloads the resources reported by sync-collection whose ETag differs from the cache
+#[allow(clippy::too_many_arguments)]
+async fn apply_changes(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    origin: &str,
+    authorization: &str,
+    calendar: &mut CachedCalendar,
+    changes: crate::parsing::Multistatus,
+    window: &SyncWindow,
+    max_events: u16,
+    bytes: usize,
+    req_buffer: &mut [u8; 8192],
+) -> Result<(), NetworkError> {
+    let mut changed = alloc::vec::Vec::new();
+    for response in changes.responses {
+        if response.removed {
+            calendar.resources.retain(|r| r.href != response.href);
+        } else if !calendar
+            .resources
+            .iter()
+            .any(|r| r.href == response.href && Some(&r.etag) == response.etag.as_ref())
+        {
+            changed.push(response.href);
+        }
+    }
+    crate::defmt::info!(
+        "{} changed resources in {}",
+        changed.len(),
+        calendar.path.as_str()
+    );
+
+    if !changed.is_empty() {
+        let mut body = alloc::format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+<c:calendar-multiget xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+    <d:prop>
+        <d:getetag/>
+        <c:calendar-data>
+            <c:expand start="{}" end="{}"/>
+        </c:calendar-data>
+    </d:prop>
+"#,
+            window.start_fmt,
+            window.end_fmt,
+        );
+        for href in &changed {
+            let _ = writeln!(body, "    <d:href>{}</d:href>", href);
+        }
+        body.push_str("</c:calendar-multiget>");
+
+        // The changed resources get what the unchanged ones leave of the budget
+        let mut budget = remaining_budget(
+            calendar
+                .resources
+                .iter()
+                .filter(|r| !changed.contains(&r.href))
+                .flat_map(|r| &r.events),
+            max_events,
+            bytes,
+        );
+        let multistatus = dav_request(
+            client,
+            reqwless::request::Method::REPORT,
+            origin,
+            &calendar.path,
+            authorization,
+            "1",
+            body.as_bytes(),
+            &mut budget,
+            req_buffer,
+        )
+        .await?;
+        if budget.exhausted {
+            return Err(NetworkError::over_budget(&budget, max_events));
+        }
+        for response in multistatus.responses {
+            calendar.resources.retain(|r| r.href != response.href);
+            if !response.removed {
+                calendar.resources.push(cached_resource(response, window));
+            }
+        }
+    }
+
+    if let Some(token) = changes.sync_token {
+        calendar.sync_token = Some(token);
+    }
+    Ok(())
+}
+
+/// Resources outside of the day stay cached without events so their ETag is known
+fn cached_resource(response: crate::parsing::DavResponse, window: &SyncWindow) -> CachedResource {
+    let mut events = response.events;
+    events.retain(|event| event.overlaps(window.start, window.end));
+    CachedResource {
+        href: response.href,
+        etag: response.etag.unwrap_or_default(),
+        events,
+    }
+}
+
+/// Reads the sync token and the CTag of a calendar collection
+async fn collection_state(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    origin: &str,
+    path: &str,
+    authorization: &str,
+    req_buffer: &mut [u8; 8192],
+) -> Result<crate::parsing::Multistatus, NetworkError> {
+    let body = r#"<?xml version="1.0" encoding="utf-8" ?>
+<d:propfind xmlns:d="DAV:" xmlns:cs="http://calendarserver.org/ns/">
+    <d:prop>
+        <d:sync-token/>
+        <cs:getctag/>
+    </d:prop>
+</d:propfind>"#;
+    dav_request(
+        client,
+        reqwless::request::Method::PROPFIND,
+        origin,
+        path,
+        authorization,
+        "0",
+        body.as_bytes(),
+        &mut EventBudget::NONE,
+        req_buffer,
+    )
+    .await
+}
+
+/// Reads the `Content-Encoding` of a response to a request with
+/// [`crate::inflate::ACCEPT_ENCODING`]
+fn content_encoding<'h>(
+    stage: RequestStage,
+    mut headers: impl Iterator<Item = (&'h str, &'h [u8])>,
+) -> Result<ContentEncoding, NetworkError> {
+    match headers.find(|(name, _)| name.eq_ignore_ascii_case("content-encoding")) {
+        Some((_, value)) => {
+            ContentEncoding::from_header(value).ok_or(NetworkError::UnsupportedEncoding(stage))
+        }
+        None => Ok(ContentEncoding::Identity),
+    }
 }
 
-async fn calendar_data_processor(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+#[allow(clippy::too_many_arguments)]
+async fn dav_request(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    method: reqwless::request::Method,
     origin: &str,
     path: &str,
-    username: &str,
-    password: &str,
+    authorization: &str,
+    depth: &str,
     body: &[u8],
+    budget: &mut EventBudget,
     req_buffer: &mut [u8; 8192],
-) -> alloc::vec::Vec<vcal_parser::vevent::VEventData> {
+) -> Result<crate::parsing::Multistatus, NetworkError> {
+    const STAGE: RequestStage = RequestStage::CalendarData;
+    req_buffer.fill(0);
     let mut request = client
-        .request(reqwless::request::Method::REPORT, origin)
+        .request(method, origin)
         .await
-        .unwrap()
-        .basic_auth(username, password)
+        .stage(STAGE)?
         .path(path)
-        .headers(&[("Content-Type", "text/xml; charset=utf-8"), ("Depth", "1")])
+        .headers(&[
+            ("Authorization", authorization),
+            ("Content-Type", "text/xml; charset=utf-8"),
+            ("Depth", depth),
+            ("Accept-Encoding", crate::inflate::ACCEPT_ENCODING),
+        ])
         .body(body);
 
-    let response = request.send(req_buffer).await.unwrap();
+    let response = request.send(req_buffer).await.stage(STAGE)?;
     crate::defmt::debug!("Response status: {:?}", response.status);
+    check_status(STAGE, response.status)?;
+    let encoding = content_encoding(STAGE, response.headers())?;
 
     let mut reader = response.body().reader();
-    let cal = crate::parsing::parse_body_cal(&mut reader).await.unwrap();
-    crate::defmt::info!(
-        "Parsed calendar data: {:?}",
-        crate::defmt::Debug2Format(&cal)
-    );
-    cal
+    match encoding {
+        ContentEncoding::Identity => crate::parsing::parse_multistatus(&mut reader, budget).await,
+        encoding => {
+            let mut reader = InflateReader::new(&mut reader, encoding);
+            crate::parsing::parse_multistatus(&mut reader, budget).await
+        }
+    }
+    .stage(STAGE)
 }
 
+pub(crate) fn ics_https_url(url: &str) -> String {
+    // webcal:// is just a hint for the browsers to open a calendar app
+    match url.strip_prefix("webcal://") {
+        Some(rest) => alloc::format!("https://{}", rest),
+        None => url.to_string(),
+    }
+}
+
+/// Downloads a `.ics` subscription and keeps the events of the [`fetch_window`], with the
+/// same limits as [`calendar_data_req`]
+pub(crate) async fn ics_data_req(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    date: &jiff::Zoned,
+    req_buffer: &mut [u8; 8192],
+    feed: &IcsFeed,
+    max_events: u16,
+    bytes_left: &mut usize,
+) -> Result<alloc::vec::Vec<vcal_parser::vevent::VEventData>, NetworkError> {
+    let url = ics_https_url(&feed.url);
+    crate::defmt::info!("Fetching ics feed: {}", url.as_str());
+
+    let (start, end) = fetch_window(date);
+
+    const STAGE: RequestStage = RequestStage::IcsFeed;
+    let mut request = client
+        .request(reqwless::request::Method::GET, &url)
+        .await
+        .stage(STAGE)?
+        .headers(&[
+            ("Accept", "text/calendar"),
+            ("Accept-Encoding", crate::inflate::ACCEPT_ENCODING),
+        ]);
+
+    let response = request.send(req_buffer).await.stage(STAGE)?;
+    crate::defmt::debug!("Response status: {:?}", response.status);
+    check_status(STAGE, response.status)?;
+    let encoding = content_encoding(STAGE, response.headers())?;
+
+    let (start, end) = (start.timestamp(), end.timestamp());
+    let mut budget = EventBudget::new(max_events, *bytes_left);
+    let mut reader = response.body().reader();
+    let events = match encoding {
+        ContentEncoding::Identity => {
+            crate::parsing::parse_body_ics(&mut reader, start, end, &mut budget).await
+        }
+        encoding => {
+            let mut reader = InflateReader::new(&mut reader, encoding);
+            crate::parsing::parse_body_ics(&mut reader, start, end, &mut budget).await
+        }
+    }
+    .stage(STAGE)?;
+    if budget.exhausted {
+        return Err(NetworkError::over_budget(&budget, max_events));
+    }
+    *bytes_left = budget.bytes;
+    Ok(events)
+}
+
+/// Collects the events of the displayed window from CalDAV and the ics feeds.
+///
+/// CalDAV failures are returned so the reason can be shown on the screen, a broken ics
+/// feed only gets logged. Too many events are an error for both, every calendar may have
+/// `max_events` and all of them share the [`EVENT_MEMORY_BUDGET`].
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn get_events(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+    clients: &mut HttpClients<'_>,
     rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
-    credentials: &CaldavCreds,
+    flash: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    credentials: Option<&CaldavCreds>,
     calendar_ids: &[String],
-) -> alloc::vec::Vec<vcal_parser::vevent::VEventData> {
+    ics_feeds: &[IcsFeed],
+    max_events: u16,
+) -> Result<alloc::vec::Vec<vcal_parser::vevent::VEventData>, NetworkError> {
     #[allow(clippy::large_stack_frames, reason = "false positive")]
     let req_buffer = REQ_BUFFER.init_with(|| [0u8; 8192]);
 
-    let time_from_rtc =
-        jiff::Timestamp::from_second(rtc.current_time_us() as i64 / 1_000_000).unwrap();
-    let tzed = time_from_rtc.to_zoned(USER_TIMEZONE);
+    let tzed = crate::hardware::get_time(rtc).with_time_zone(USER_TIMEZONE);
+    crate::dns_cache::start_wake();
 
     let mut resp = alloc::vec![];
-    let mut success = false;
-    for tries in 1..=3 {
+    let mut bytes_left = EVENT_MEMORY_BUDGET;
+
+    if let Some(credentials) = credentials {
+        let authorization =
+            crate::oauth::authorization(&mut clients.tls, credentials, req_buffer, flash).await?;
+        let client = clients.caldav(credentials)?;
+        let mut cache = crate::storage::read_sync_cache(flash)
+            .await
+            .unwrap_or_default();
+        let cached = cache.clone();
+
+        let mut last_error = NetworkError::Timeout(RequestStage::CalendarData);
+        let mut success = false;
+        for tries in 1..=3 {
+            req_buffer.fill(0);
+            let mut attempt_bytes = EVENT_MEMORY_BUDGET;
+            let req = crate::networking::calendar_data_req(
+                client,
+                &tzed,
+                req_buffer,
+                credentials,
+                &authorization,
+                calendar_ids,
+                &mut cache,
+                max_events,
+                &mut attempt_bytes,
+            );
+            match embassy_time::with_timeout(embassy_time::Duration::from_secs(30), req).await {
+                Ok(Ok(res)) => {
+                    resp = res;
+                    bytes_left = attempt_bytes;
+                    success = true;
+                    break;
+                }
+                Ok(Err(e)) if e.is_auth_failure() || e.is_too_many_events() => return Err(e),
+                Ok(Err(e)) => last_error = e,
+                Err(_) => last_error = NetworkError::Timeout(RequestStage::CalendarData),
+            }
+            // The server may have moved, resolve it again
+            crate::dns_cache::clear();
+            crate::defmt::warn!(
+                "Failed to get calendar data on attempt {}: {}",
+                tries,
+                crate::defmt::Display2Format(&last_error)
+            );
+        }
+
+        if !success {
+            crate::defmt::error!("Failed after 3 attempts");
+            return Err(last_error);
+        }
+        if cache != cached
+            && let Err(e) = crate::storage::write_sync_cache(flash, &cache).await
+        {
+            crate::defmt::warn!(
+                "Failed to cache the calendar data: {}",
+                crate::defmt::Display2Format(&e)
+            );
+        }
+    }
+
+    for feed in ics_feeds.iter().filter(|f| f.enabled) {
         req_buffer.fill(0);
-        let req = crate::networking::calendar_data_req(
-            client,
+        let req = ics_data_req(
+            &mut clients.tls,
             &tzed,
             req_buffer,
-            credentials,
-            calendar_ids,
-        );
-        if let Ok(res) =
-            embassy_time::with_timeout(embassy_time::Duration::from_secs(30), req).await
-        {
-            resp = res;
-            success = true;
-            break;
-        };
-        crate::defmt::warn!(
-            "Failed to get calendar data on attempt {}, retrying...",
-            tries
+            feed,
+            max_events,
+            &mut bytes_left,
         );
+        match embassy_time::with_timeout(embassy_time::Duration::from_secs(30), req).await {
+            Ok(Ok(events)) => resp.extend(events),
+            Ok(Err(e)) if e.is_too_many_events() => {
+                crate::defmt::error!("Too many events in ics feed {}", feed.name);
+                return Err(e);
+            }
+            Ok(Err(e)) => crate::defmt::error!(
+                "Failed to fetch ics feed {}: {}",
+                feed.name,
+                crate::defmt::Display2Format(&e)
+            ),
+            Err(_) => crate::defmt::error!("Fetching ics feed {} timed out", feed.name),
+        }
     }
-
-    if !success {
-        crate::defmt::error!("Failed after 3 attempts, entering deep sleep");
-        crate::hardware::go_to_deep_sleep(rtc);
-    }
-    resp
+    Ok(resp)
 }
 
 pub async fn fetch_domain_endpoint(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
     origin: &str,
     response_buf: &mut [u8; 8192],
 ) -> Option<heapless::String<{ crate::server::MAX_URL_LEN }>> {
@@ -395,118 +1211,86 @@ pub async fn fetch_domain_endpoint(
 }
 
 pub(crate) async fn fetch_principal_url(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
     origin: &str,
-    credentials: &CaldavCreds,
+    url: &str,
+    authorization: &str,
     response_buf: &mut [u8; 8192],
-) -> Option<String> {
+) -> Result<String, NetworkError> {
     const BODY: &str = r#"<d:propfind xmlns:d="DAV:">
       <d:prop>
         <d:current-user-principal />
       </d:prop>
     </d:propfind>"#;
-    let username = credentials.username.as_str();
-    let password = credentials.password.as_str();
-    let url: &str = &credentials.url;
+    const STAGE: RequestStage = RequestStage::Principal;
 
     let mut request = client
         .request(reqwless::request::Method::PROPFIND, origin)
         .await
-        .unwrap()
-        .basic_auth(username, password)
+        .stage(STAGE)?
         .path(url)
-        .headers(&[("Content-Type", "text/xml; charset=utf-8"), ("Depth", "1")])
+        .headers(&[
+            ("Authorization", authorization),
+            ("Content-Type", "text/xml; charset=utf-8"),
+            ("Depth", "1"),
+        ])
         .body(BODY.as_bytes());
 
-    let response = request.send(response_buf).await.unwrap();
+    let response = request.send(response_buf).await.stage(STAGE)?;
 
     crate::defmt::info!("Response status: {:?}", response.status);
-    let res = response.body().read_to_end().await.unwrap();
-
-    let Ok(res) = str::from_utf8(res) else {
-        crate::defmt::error!("Failed to parse response body as UTF-8");
-        return None;
-    };
-
-    parse_principal_url(res)
-}
-
-const DAV_NS: &str = "DAV:";
-const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
-
-pub fn parse_principal_url(xml: &str) -> Option<String> {
-    let doc = roxmltree::Document::parse(xml).ok().unwrap();
-
-    let res = doc
-        .descendants()
-        .find(|n| n.has_tag_name((DAV_NS, "current-user-principal")))?
-        .children()
-        .find(|n| n.has_tag_name((DAV_NS, "href")))?
-        .text();
-    res.map(|f| f.to_string())
+    check_status(STAGE, response.status)?;
+    let mut reader = response.body().reader();
+    crate::parsing::parse_property_href(&mut reader, "current-user-principal")
+        .await
+        .stage(STAGE)?
+        .ok_or(NetworkError::ParsingError)
 }
 
 pub(crate) async fn fetch_calendar_home_set(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
     origin: &str,
     path: &str,
-    credentials: &CaldavCreds,
+    authorization: &str,
     response_buf: &mut [u8; 8192],
-) -> Option<String> {
+) -> Result<String, NetworkError> {
     const BODY: &str = r#"<d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
         <d:prop>
           <c:calendar-home-set />
         </d:prop>
       </d:propfind>"#;
-    let username = credentials.username.as_str();
-    let password = credentials.password.as_str();
-
+    const STAGE: RequestStage = RequestStage::CalendarHomeSet;
     let mut request = client
         .request(reqwless::request::Method::PROPFIND, origin)
         .await
-        .unwrap()
-        .basic_auth(username, password)
+        .stage(STAGE)?
         .path(path)
-        .headers(&[("Content-Type", "text/xml; charset=utf-8"), ("Depth", "1")])
+        .headers(&[
+            ("Authorization", authorization),
+            ("Content-Type", "text/xml; charset=utf-8"),
+            ("Depth", "1"),
+        ])
         .body(BODY.as_bytes());
 
-    let response = request.send(response_buf).await.unwrap();
+    let response = request.send(response_buf).await.stage(STAGE)?;
 
     crate::defmt::info!("Response status: {:?}", response.status);
-    let res = response.body().read_to_end().await.unwrap();
-
-    let res = match str::from_utf8(res) {
-        Ok(v) => v,
-        Err(_) => {
-            crate::defmt::error!("Failed to parse response body as UTF-8");
-            return None;
-        }
-    };
-    let res = get_calendar_home_set(res);
+    check_status(STAGE, response.status)?;
+    let mut reader = response.body().reader();
+    let res = crate::parsing::parse_property_href(&mut reader, "calendar-home-set")
+        .await
+        .stage(STAGE)?;
     crate::defmt::info!("Calendar home set: {}", crate::defmt::Debug2Format(&res));
-    res
-}
-
-fn get_calendar_home_set(xml: &str) -> Option<String> {
-    let doc = roxmltree::Document::parse(xml).ok()?;
-
-    let res = doc
-        .descendants()
-        .find(|n| n.has_tag_name((CALDAV_NS, "calendar-home-set")))?
-        .children()
-        .find(|n| n.has_tag_name((DAV_NS, "href")))?
-        .text()
-        .map(str::to_string);
-    res.map(|f| f.to_string())
+    res.ok_or(NetworkError::ParsingError)
 }
 
 pub(crate) async fn fetch_calendars(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
     origin: &str,
     path: &str,
-    credentials: &CaldavCreds,
+    authorization: &str,
     response_buf: &mut [u8; 8192],
-) -> alloc::vec::Vec<CalendarData> {
+) -> Result<alloc::vec::Vec<CalendarData>, NetworkError> {
     const BODY: &str = r#"<d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
         <d:prop>
           <d:displayname />
@@ -514,52 +1298,51 @@ pub(crate) async fn fetch_calendars(
           <c:supported-calendar-component-set />
         </d:prop>
       </d:propfind>"#;
-    let username = credentials.username.as_str();
-    let password = credentials.password.as_str();
-
+    const STAGE: RequestStage = RequestStage::CalendarList;
     let mut request = client
         .request(reqwless::request::Method::PROPFIND, origin)
         .await
-        .unwrap()
-        .basic_auth(username, password)
+        .stage(STAGE)?
         .path(path)
-        .headers(&[("Content-Type", "text/xml; charset=utf-8"), ("Depth", "1")])
+        .headers(&[
+            ("Authorization", authorization),
+            ("Content-Type", "text/xml; charset=utf-8"),
+            ("Depth", "1"),
+        ])
         .body(BODY.as_bytes());
 
-    let response = request.send(response_buf).await.unwrap();
+    let response = request.send(response_buf).await.stage(STAGE)?;
 
     crate::defmt::info!("Response status: {:?}", response.status);
+    check_status(STAGE, response.status)?;
     let mut reader = response.body().reader();
-    let calendars = crate::parsing::parse_body(&mut reader).await.unwrap();
+    let calendars = crate::parsing::parse_body(&mut reader).await.stage(STAGE)?;
     crate::defmt::info!("Calendars: {:?}", crate::defmt::Debug2Format(&calendars));
 
-    calendars
+    Ok(calendars)
 }
 
 pub(crate) async fn check_credentials(
-    client: &mut HttpClient<'_, TcpClient<'_, 1, 4096, 4096>, DnsSocket<'_>>,
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
     origin: &str,
     path: &str,
-    credentials: &CaldavCreds,
+    authorization: &str,
     response_buf: &mut [u8; 8192],
 ) -> Result<(), NetworkError> {
-    let username = credentials.username.as_str();
-    let password = credentials.password.as_str();
-
+    const STAGE: RequestStage = RequestStage::Credentials;
     let mut request = client
         .request(reqwless::request::Method::PROPFIND, origin)
-        .await?
-        .basic_auth(username, password)
+        .await
+        .stage(STAGE)?
         .path(path)
-        .headers(&[("Content-Type", "text/xml; charset=utf-8"), ("Depth", "0")]);
+        .headers(&[("Authorization", authorization), ("Depth", "0")]);
 
-    let response = request.send(response_buf).await?;
+    let response = request.send(response_buf).await.stage(STAGE)?;
 
     crate::defmt::info!("Response status: {:?}", response.status);
 
-    if !response.status.is_successful() {
-        return Err(NetworkError::InvalidCredentials);
+    match response.status.0 {
+        401 | 403 => Err(NetworkError::InvalidCredentials),
+        _ => check_status(STAGE, response.status),
     }
-
-    Ok(())
 }
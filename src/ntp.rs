@@ -0,0 +1,458 @@
+//! Keeps the RTC in sync with the network time.
+//!
+//! UDP port 123 is often blocked on guest networks, so the NTP servers are tried in order: the
+//! configured ones, the ones announced by the DHCP server (option 42) and finally the public
+//! pool. When none of them answer, the `Date` header of an HTTPS response is used instead.
+//!
+//! The RTC drift is measured between the syncs and corrected on every boot, the next sync is
+//! scheduled for when the remaining error would exceed [`MAX_CLOCK_ERROR_US`].
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use reqwless::client::HttpClient;
+use smoltcp::wire::{
+    DHCP_CLIENT_PORT, DHCP_SERVER_PORT, DhcpMessageType, DhcpOpCode, DhcpPacket, DhcpRepr,
+    IpProtocol, IpVersion, Ipv4Packet, UdpPacket,
+};
+use static_cell::StaticCell;
+
+use crate::dns_cache::CachingDns;
+use crate::hardware::PersistentU64;
+use crate::networking::{NetworkError, PinnedTcp, RequestStage, WithStage};
+use crate::storage::MAX_NTP_SERVERS;
+
+/// This is a boolean value to whether the initial NTP sync occurred
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+pub static INITIAL_NTP_SYNC: portable_atomic::AtomicU8 = portable_atomic::AtomicU8::new(0);
+/// Wall clock time of the last NTP sync in microseconds, zero if unknown
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static LAST_SYNC_US: PersistentU64 = PersistentU64::new(0);
+/// Wall clock time up to which the drift correction has been applied
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static CORRECTED_UNTIL_US: PersistentU64 = PersistentU64::new(0);
+/// Measured drift of the RTC in parts per billion, positive when the RTC runs slow
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static DRIFT_PPB: portable_atomic::AtomicI32 = portable_atomic::AtomicI32::new(0);
+/// Wall clock time when the next NTP sync is due
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static NEXT_SYNC_US: PersistentU64 = PersistentU64::new(0);
+
+const DEFAULT_NTP_SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+const DHCP_OPTION_NTP_SERVERS: u8 = 42;
+/// Time to wait for a single server
+const QUERY_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(2);
+/// Time budget for trying all of the servers
+const SYNC_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(15);
+
+const MINUTE_US: u64 = 60 * 1_000_000;
+/// The clock error which is tolerated before the next NTP sync
+const MAX_CLOCK_ERROR_US: u64 = 2_000_000;
+/// Used while the drift is unknown
+const MIN_SYNC_INTERVAL_US: u64 = 25 * MINUTE_US;
+const MAX_SYNC_INTERVAL_US: u64 = 24 * 60 * MINUTE_US;
+/// Shorter intervals are dominated by the network jitter
+const MIN_DRIFT_MEASUREMENT_US: u64 = 10 * MINUTE_US;
+/// Larger offsets mean that the clock was set by something else, not that it drifted
+const MAX_DRIFT_OFFSET_US: i64 = 10 * MINUTE_US as i64;
+/// The internal RC oscillator is specified to be within 5%
+const MAX_DRIFT_PPB: i64 = 50_000_000;
+
+static RX_META: StaticCell<[PacketMetadata; 16]> = StaticCell::new();
+static RX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
+static TX_META: StaticCell<[PacketMetadata; 16]> = StaticCell::new();
+static TX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
+
+/// Wall clock time for sntpc, the RTC time at the start extended with the precise embassy timer
+#[derive(Copy, Clone)]
+struct NtpTimestamp {
+    rtc_start_us: u64,
+    start: embassy_time::Instant,
+    now_us: u64,
+}
+
+impl NtpTimestamp {
+    fn new(rtc: &esp_hal::rtc_cntl::Rtc<'_>) -> Self {
+        let rtc_start_us = rtc.current_time_us();
+        Self {
+            rtc_start_us,
+            start: embassy_time::Instant::now(),
+            now_us: rtc_start_us,
+        }
+    }
+}
+
+impl sntpc::NtpTimestampGenerator for NtpTimestamp {
+    fn init(&mut self) {
+        self.now_us = self.rtc_start_us + self.start.elapsed().as_micros();
+    }
+
+    fn timestamp_sec(&self) -> u64 {
+        self.now_us / 1_000_000
+    }
+
+    fn timestamp_subsec_micros(&self) -> u32 {
+        (self.now_us % 1_000_000) as u32
+    }
+}
+
+/// Applies the measured drift for the time passed since the last correction
+fn correct_drift(rtc: &mut esp_hal::rtc_cntl::Rtc<'_>) {
+    let drift_ppb = DRIFT_PPB.load(core::sync::atomic::Ordering::Relaxed) as i64;
+    let corrected_until = CORRECTED_UNTIL_US.load();
+    let now = rtc.current_time_us();
+    if drift_ppb == 0 || corrected_until == 0 || now <= corrected_until {
+        return;
+    }
+
+    let correction = (now - corrected_until) as i64 * drift_ppb / 1_000_000_000;
+    let corrected = now.saturating_add_signed(correction);
+    rtc.set_current_time_us(corrected);
+    CORRECTED_UNTIL_US.store(corrected);
+    crate::defmt::debug!("Corrected RTC drift by {} us", correction);
+}
+
+/// Sets the RTC from an NTP offset and updates the drift estimate
+fn apply_offset(rtc: &mut esp_hal::rtc_cntl::Rtc<'_>, offset_us: i64) {
+    let corrected = rtc.current_time_us().saturating_add_signed(offset_us);
+    let last_sync = LAST_SYNC_US.load();
+
+    // The offset is what's left after the correction with the current estimate
+    let residual_ppb = if last_sync != 0
+        && corrected > last_sync + MIN_DRIFT_MEASUREMENT_US
+        && offset_us.abs() < MAX_DRIFT_OFFSET_US
+    {
+        let elapsed = (corrected - last_sync) as i64;
+        let residual = offset_us * 1_000_000_000 / elapsed;
+        let drift = (DRIFT_PPB.load(core::sync::atomic::Ordering::Relaxed) as i64 + residual)
+            .clamp(-MAX_DRIFT_PPB, MAX_DRIFT_PPB);
+        DRIFT_PPB.store(drift as i32, core::sync::atomic::Ordering::Relaxed);
+        crate::defmt::info!("RTC drift: {} ppb (residual {} ppb)", drift, residual);
+        Some(residual)
+    } else {
+        None
+    };
+
+    rtc.set_current_time_us(corrected);
+    INITIAL_NTP_SYNC.store(1, core::sync::atomic::Ordering::Relaxed);
+    LAST_SYNC_US.store(corrected);
+    CORRECTED_UNTIL_US.store(corrected);
+
+    let interval = next_sync_interval(residual_ppb);
+    NEXT_SYNC_US.store(corrected + interval);
+    crate::defmt::info!(
+        "Applied NTP offset of {} us, next sync in {} minutes",
+        offset_us,
+        interval / MINUTE_US
+    );
+}
+
+/// The time until the error of the corrected clock reaches [`MAX_CLOCK_ERROR_US`]
+fn next_sync_interval(residual_ppb: Option<i64>) -> u64 {
+    match residual_ppb {
+        Some(0) => MAX_SYNC_INTERVAL_US,
+        Some(residual) => (MAX_CLOCK_ERROR_US * 1_000_000_000 / residual.unsigned_abs())
+            .clamp(MIN_SYNC_INTERVAL_US, MAX_SYNC_INTERVAL_US),
+        None => MIN_SYNC_INTERVAL_US,
+    }
+}
+
+/// Applies the drift correction and returns whether the clock needs an NTP sync on this
+/// wake, which is always the case before the first sync
+pub(crate) fn sync_due(rtc: &mut esp_hal::rtc_cntl::Rtc<'_>) -> bool {
+    if INITIAL_NTP_SYNC.load(core::sync::atomic::Ordering::Relaxed) == 0 {
+        return true;
+    }
+    correct_drift(rtc);
+    rtc.current_time_us() >= NEXT_SYNC_US.load()
+}
+
+/// Corrects the RTC drift and resyncs with NTP when it is due.
+///
+/// Returns false when the RTC has never been set, [`sync_time_from_http`] should be tried then.
+pub async fn sync_time(
+    stack: Stack<'_>,
+    rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
+    servers: &[heapless::String<64>],
+) -> bool {
+    let need_initial_sync = INITIAL_NTP_SYNC.load(core::sync::atomic::Ordering::Relaxed) == 0;
+    if !need_initial_sync {
+        correct_drift(rtc);
+        if rtc.current_time_us() < NEXT_SYNC_US.load() {
+            return true;
+        }
+    }
+
+    crate::defmt::info!("Syncing RTC with NTP");
+    match embassy_time::with_timeout(SYNC_TIMEOUT, get_offset(stack, rtc, servers)).await {
+        Ok(Ok(offset_us)) => {
+            apply_offset(rtc, offset_us);
+            true
+        }
+        Ok(Err(e)) => {
+            crate::defmt::warn!(
+                "NTP sync failed, skipping for this boot: {}",
+                crate::defmt::Display2Format(&e)
+            );
+            !need_initial_sync
+        }
+        Err(_) => {
+            crate::defmt::warn!("NTP sync timed out, skipping for this boot");
+            !need_initial_sync
+        }
+    }
+}
+
+/// Returns how much the RTC has to be moved forward, in microseconds
+pub async fn get_offset(
+    stack: Stack<'_>,
+    rtc: &esp_hal::rtc_cntl::Rtc<'_>,
+    servers: &[heapless::String<64>],
+) -> Result<i64, NetworkError> {
+    let rx_meta = RX_META.init([PacketMetadata::EMPTY; 16]);
+    #[allow(clippy::large_stack_frames, reason = "false positive")]
+    let rx_buffer = RX_BUFFER.init_with(|| [0; 4096]);
+    let tx_meta = TX_META.init([PacketMetadata::EMPTY; 16]);
+    #[allow(clippy::large_stack_frames, reason = "false positive")]
+    let tx_buffer = TX_BUFFER.init_with(|| [0; 4096]);
+
+    let mut socket = UdpSocket::new(stack, rx_meta, rx_buffer, tx_meta, tx_buffer);
+    socket.bind(NTP_PORT).map_err(|_| NetworkError::NtpFailed)?;
+    let socket = sntpc_net_embassy::UdpSocketWrapper::new(socket);
+    let clock = NtpTimestamp::new(rtc);
+
+    for server in servers {
+        match query_host(stack, &socket, clock, server).await {
+            Ok(time) => return Ok(time),
+            Err(e) => crate::defmt::warn!(
+                "NTP server {} failed: {}",
+                server.as_str(),
+                crate::defmt::Display2Format(&e)
+            ),
+        }
+    }
+
+    for ip in dhcp_ntp_servers(stack).await {
+        match query(&socket, clock, IpAddr::V4(ip)).await {
+            Ok(time) => return Ok(time),
+            Err(e) => crate::defmt::warn!(
+                "NTP server {} from DHCP failed: {}",
+                ip,
+                crate::defmt::Display2Format(&e)
+            ),
+        }
+    }
+
+    query_host(stack, &socket, clock, DEFAULT_NTP_SERVER).await
+}
+
+/// Tries every resolved address of the host until one of them answers
+async fn query_host(
+    stack: Stack<'_>,
+    socket: &sntpc_net_embassy::UdpSocketWrapper<'_>,
+    clock: NtpTimestamp,
+    host: &str,
+) -> Result<i64, NetworkError> {
+    let mut result = Err(NetworkError::DnsFailed);
+    for type_ in crate::dns_cache::query_types(stack) {
+        let Ok(addresses) = stack.dns_query(host, type_).await else {
+            continue;
+        };
+        for address in addresses {
+            result = query(socket, clock, address.into()).await;
+            if result.is_ok() {
+                return result;
+            }
+        }
+    }
+    result
+}
+
+async fn query(
+    socket: &sntpc_net_embassy::UdpSocketWrapper<'_>,
+    clock: NtpTimestamp,
+    ip: IpAddr,
+) -> Result<i64, NetworkError> {
+    let context = sntpc::NtpContext::new(clock);
+    let request = sntpc::get_time(SocketAddr::new(ip, NTP_PORT), socket, context);
+    let result = embassy_time::with_timeout(QUERY_TIMEOUT, request)
+        .await
+        .map_err(|_| NetworkError::NtpFailed)?
+        .map_err(|_| NetworkError::NtpFailed)?;
+    crate::defmt::info!(
+        "NTP offset from {}: {} us, round trip: {} us",
+        ip,
+        result.offset,
+        result.roundtrip
+    );
+    Ok(result.offset)
+}
+
+/// Asks the DHCP server for its NTP servers (option 42) with a DHCPINFORM.
+///
+/// embassy-net doesn't expose the options of the lease and its DHCP socket consumes every
+/// packet sent to the client port, so the reply is read from a raw socket which gets a copy.
+async fn dhcp_ntp_servers(stack: Stack<'_>) -> heapless::Vec<Ipv4Addr, MAX_NTP_SERVERS> {
+    let Some(config) = stack.config_v4() else {
+        return heapless::Vec::new();
+    };
+    let mac = match stack.hardware_address() {
+        embassy_net::HardwareAddress::Ethernet(mac) => mac,
+        #[allow(unreachable_patterns)]
+        _ => return heapless::Vec::new(),
+    };
+
+    let transaction_id = embassy_time::Instant::now().as_ticks() as u32;
+    let repr = DhcpRepr {
+        message_type: DhcpMessageType::Inform,
+        transaction_id,
+        secs: 0,
+        client_hardware_address: mac,
+        client_ip: config.address.address(),
+        your_ip: Ipv4Addr::UNSPECIFIED,
+        server_ip: Ipv4Addr::UNSPECIFIED,
+        router: None,
+        subnet_mask: None,
+        relay_agent_ip: Ipv4Addr::UNSPECIFIED,
+        broadcast: false,
+        requested_ip: None,
+        client_identifier: Some(mac),
+        server_identifier: None,
+        parameter_request_list: Some(&[DHCP_OPTION_NTP_SERVERS]),
+        dns_servers: None,
+        max_size: None,
+        lease_duration: None,
+        renew_duration: None,
+        rebind_duration: None,
+        additional_options: &[],
+    };
+    let mut inform = [0u8; 300];
+    let len = repr.buffer_len();
+    if repr
+        .emit(&mut DhcpPacket::new_unchecked(&mut inform[..len]))
+        .is_err()
+    {
+        return heapless::Vec::new();
+    }
+
+    let mut raw_rx_meta = [embassy_net::raw::PacketMetadata::EMPTY; 4];
+    let mut raw_rx_buffer = [0u8; 2048];
+    let mut raw_tx_meta = [embassy_net::raw::PacketMetadata::EMPTY; 1];
+    let mut raw_tx_buffer = [0u8; 1];
+    let raw = embassy_net::raw::RawSocket::new::<esp_radio::wifi::WifiDevice<'static>>(
+        stack,
+        IpVersion::Ipv4,
+        IpProtocol::Udp,
+        &mut raw_rx_meta,
+        &mut raw_rx_buffer,
+        &mut raw_tx_meta,
+        &mut raw_tx_buffer,
+    );
+
+    let mut udp_rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut udp_rx_buffer = [0u8; 1];
+    let mut udp_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut udp_tx_buffer = [0u8; 300];
+    let mut udp = UdpSocket::new(
+        stack,
+        &mut udp_rx_meta,
+        &mut udp_rx_buffer,
+        &mut udp_tx_meta,
+        &mut udp_tx_buffer,
+    );
+    if udp.bind(DHCP_CLIENT_PORT).is_err()
+        || udp
+            .send_to(
+                &inform[..len],
+                IpEndpoint::new(IpAddress::Ipv4(Ipv4Addr::BROADCAST), DHCP_SERVER_PORT),
+            )
+            .await
+            .is_err()
+    {
+        crate::defmt::warn!("Failed to send DHCPINFORM");
+        return heapless::Vec::new();
+    }
+
+    let mut packet = [0u8; 1500];
+    let reply = embassy_time::with_timeout(QUERY_TIMEOUT, async {
+        loop {
+            if let Ok(len) = raw.recv(&mut packet).await
+                && let Some(servers) = parse_ntp_option(&packet[..len], transaction_id)
+            {
+                return servers;
+            }
+        }
+    })
+    .await;
+
+    let servers = reply.unwrap_or_default();
+    crate::defmt::info!("NTP servers from DHCP: {}", servers.len());
+    servers
+}
+
+/// Reads option 42 from a DHCP reply, returns None if the packet isn't the reply for us
+fn parse_ntp_option(
+    packet: &[u8],
+    transaction_id: u32,
+) -> Option<heapless::Vec<Ipv4Addr, MAX_NTP_SERVERS>> {
+    let ip = Ipv4Packet::new_checked(packet).ok()?;
+    let udp = UdpPacket::new_checked(ip.payload()).ok()?;
+    if udp.src_port() != DHCP_SERVER_PORT || udp.dst_port() != DHCP_CLIENT_PORT {
+        return None;
+    }
+    let dhcp = DhcpPacket::new_checked(udp.payload()).ok()?;
+    if dhcp.opcode() != DhcpOpCode::Reply || dhcp.transaction_id() != transaction_id {
+        return None;
+    }
+
+    Some(
+        dhcp.options()
+            .filter(|option| option.kind == DHCP_OPTION_NTP_SERVERS)
+            .flat_map(|option| option.data.chunks_exact(4))
+            .map(|ip| Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+            .take(MAX_NTP_SERVERS)
+            .collect(),
+    )
+}
+
+/// Sets the RTC from the `Date` header of an HTTPS response, for networks where NTP is blocked.
+///
+/// This only has second precision, the next successful NTP sync corrects it.
+pub(crate) async fn sync_time_from_http(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    rtc: &mut esp_hal::rtc_cntl::Rtc<'_>,
+    url: &str,
+) -> Result<(), NetworkError> {
+    const STAGE: RequestStage = RequestStage::HttpDate;
+    // Only the headers are needed
+    let mut response_buf = alloc::vec![0u8; 2048];
+
+    let mut request = client
+        .request(reqwless::request::Method::HEAD, url)
+        .await
+        .stage(STAGE)?;
+    let response = request.send(&mut response_buf).await.stage(STAGE)?;
+
+    let date = response
+        .headers()
+        .find(|(name, _)| name.eq_ignore_ascii_case("date"))
+        .and_then(|(_, value)| core::str::from_utf8(value).ok())
+        .ok_or(NetworkError::ParsingError)?;
+    let time = jiff::fmt::rfc2822::DateTimeParser::new()
+        .parse_timestamp(date)
+        .map_err(|_| NetworkError::ParsingError)?;
+
+    crate::defmt::info!(
+        "Current time from the HTTP Date header: {:?}",
+        crate::defmt::Debug2Format(&time)
+    );
+    let time_us = time.as_microsecond() as u64;
+    rtc.set_current_time_us(time_us);
+    INITIAL_NTP_SYNC.store(1, core::sync::atomic::Ordering::Relaxed);
+    // Too coarse for measuring the drift, NTP is tried again on the next boot
+    LAST_SYNC_US.store(0);
+    CORRECTED_UNTIL_US.store(time_us);
+    NEXT_SYNC_US.store(0);
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! OAuth2 support for CalDAV servers which don't accept basic authentication.
+//!
+//! The refresh token is obtained once with the device authorization grant (RFC 8628),
+//! the user finishes the sign-in on another device. Access tokens are short-lived, so they
+//! are only kept in RAM and refreshed when needed.
+use alloc::string::String;
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::mutex::Mutex;
+use esp_storage::FlashStorage;
+use reqwless::client::HttpClient;
+use reqwless::request::RequestBuilder;
+
+use crate::dns_cache::CachingDns;
+use crate::fnv::fnv1a;
+use crate::networking::{NetworkError, PinnedTcp, RequestStage, WithStage};
+pub(crate) use crate::oauth_messages::TokenResult;
+use crate::oauth_messages::{
+    DEVICE_CODE_GRANT, basic_authorization, parse_token_body, push_form_field,
+};
+use crate::storage::{CaldavAuth, CaldavCreds, OAuth2Config};
+
+/// Refresh the access token a bit before it actually expires
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
+struct AccessToken {
+    /// [`client_key`] of the config the token was issued for
+    client: u32,
+    header: String,
+    expires_at: embassy_time::Instant,
+}
+
+static ACCESS_TOKEN: embassy_sync::blocking_mutex::Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Option<AccessToken>>,
+> = embassy_sync::blocking_mutex::Mutex::new(RefCell::new(None));
+
+/// A device authorization that waits for the user to sign in
+pub(crate) struct PendingAuthorization {
+    pub creds: CaldavCreds,
+    pub device_code: String,
+    pub interval: embassy_time::Duration,
+    pub expires_at: embassy_time::Instant,
+}
+
+pub(crate) static PENDING_AUTHORIZATION: Mutex<NoopRawMutex, Option<PendingAuthorization>> =
+    Mutex::new(None);
+
+#[derive(serde::Deserialize)]
+struct DeviceAuthorizationBody<'a> {
+    device_code: &'a str,
+    user_code: &'a str,
+    verification_uri: Option<&'a str>,
+    // Google still uses the name from the draft
+    verification_url: Option<&'a str>,
+    verification_uri_complete: Option<&'a str>,
+    expires_in: u32,
+    interval: Option<u32>,
+}
+
+pub(crate) struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: heapless::String<16>,
+    pub verification_uri: heapless::String<128>,
+    pub verification_uri_complete: Option<heapless::String<192>>,
+    pub expires_in: u32,
+    pub interval: u32,
+}
+
+/// Tells the token of another server or client apart after the account was changed
+fn client_key(config: &OAuth2Config) -> u32 {
+    fnv1a(
+        config
+            .token_endpoint
+            .bytes()
+            .chain([0])
+            .chain(config.client_id.bytes()),
+    )
+}
+
+fn client_form(config: &OAuth2Config) -> String {
+    let mut form = String::new();
+    push_form_field(&mut form, "client_id", &config.client_id);
+    if !config.client_secret.is_empty() {
+        push_form_field(&mut form, "client_secret", &config.client_secret);
+    }
+    form
+}
+
+async fn post_form<'buf>(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    endpoint: &str,
+    form: &str,
+    response_buf: &'buf mut [u8; 8192],
+) -> Result<(reqwless::response::StatusCode, &'buf [u8]), NetworkError> {
+    let mut request = client
+        .request(reqwless::request::Method::POST, endpoint)
+        .await
+        .stage(RequestStage::OAuth2)?
+        .headers(&[
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Accept", "application/json"),
+        ])
+        .body(form.as_bytes());
+
+    let response = request
+        .send(response_buf)
+        .await
+        .stage(RequestStage::OAuth2)?;
+    let status = response.status;
+    crate::defmt::info!("OAuth2 response status: {:?}", status);
+    let body = response
+        .body()
+        .read_to_end()
+        .await
+        .stage(RequestStage::OAuth2)?;
+    Ok((status, body))
+}
+
+/// Starts the device authorization grant, the returned code has to be entered by the user
+pub(crate) async fn request_device_code(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    config: &OAuth2Config,
+    response_buf: &mut [u8; 8192],
+) -> Result<DeviceAuthorization, NetworkError> {
+    let mut form = client_form(config);
+    if !config.scope.is_empty() {
+        push_form_field(&mut form, "scope", &config.scope);
+    }
+
+    let (status, body) = post_form(
+        client,
+        &config.device_authorization_endpoint,
+        &form,
+        response_buf,
+    )
+    .await?;
+    if !status.is_successful() {
+        return Err(NetworkError::AuthorizationFailed);
+    }
+
+    let (auth, _) = serde_json_core::from_slice::<DeviceAuthorizationBody>(body)
+        .map_err(|_| NetworkError::ParsingError)?;
+    let verification_uri = auth
+        .verification_uri
+        .or(auth.verification_url)
+        .ok_or(NetworkError::ParsingError)?;
+
+    Ok(DeviceAuthorization {
+        device_code: String::from(auth.device_code),
+        user_code: heapless::String::try_from(auth.user_code)
+            .map_err(|_| NetworkError::ParsingError)?,
+        verification_uri: heapless::String::try_from(verification_uri)
+            .map_err(|_| NetworkError::ParsingError)?,
+        verification_uri_complete: auth
+            .verification_uri_complete
+            .and_then(|u| heapless::String::try_from(u).ok()),
+        expires_in: auth.expires_in,
+        // RFC 8628 says 5 seconds if the server doesn't tell
+        interval: auth.interval.unwrap_or(5),
+    })
+}
+
+/// Checks once whether the user finished the sign-in
+pub(crate) async fn poll_device_token(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    config: &OAuth2Config,
+    device_code: &str,
+    response_buf: &mut [u8; 8192],
+) -> Result<TokenResult, NetworkError> {
+    let mut form = client_form(config);
+    push_form_field(&mut form, "grant_type", DEVICE_CODE_GRANT);
+    push_form_field(&mut form, "device_code", device_code);
+
+    let (_, body) = post_form(client, &config.token_endpoint, &form, response_buf).await?;
+    token_result(body)
+}
+
+/// Exchanges the stored refresh token for a new access token
+pub(crate) async fn refresh_access_token(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    config: &OAuth2Config,
+    response_buf: &mut [u8; 8192],
+) -> Result<TokenResult, NetworkError> {
+    if config.refresh_token.is_empty() {
+        crate::defmt::error!("No refresh token stored, the sign-in has to be done first");
+        return Err(NetworkError::InvalidCredentials);
+    }
+    let mut form = client_form(config);
+    push_form_field(&mut form, "grant_type", "refresh_token");
+    push_form_field(&mut form, "refresh_token", &config.refresh_token);
+
+    let (_, body) = post_form(client, &config.token_endpoint, &form, response_buf).await?;
+    token_result(body)
+}
+
+fn token_result(body: &[u8]) -> Result<TokenResult, NetworkError> {
+    match parse_token_body(body) {
+        Some(TokenResult::Rejected) => {
+            crate::defmt::error!(
+                "OAuth2 token error: {:?}",
+                core::str::from_utf8(body).unwrap_or_default()
+            );
+            Err(NetworkError::AuthorizationFailed)
+        }
+        Some(result) => Ok(result),
+        None => Err(NetworkError::ParsingError),
+    }
+}
+
+pub(crate) fn cache_access_token(
+    config: &OAuth2Config,
+    access_token: &str,
+    expires_in: u32,
+) -> String {
+    let header = alloc::format!("Bearer {}", access_token);
+    let lifetime = (expires_in as u64).saturating_sub(EXPIRY_MARGIN_SECS);
+    ACCESS_TOKEN.lock(|token| {
+        token.replace(Some(AccessToken {
+            client: client_key(config),
+            header: header.clone(),
+            expires_at: embassy_time::Instant::now() + embassy_time::Duration::from_secs(lifetime),
+        }))
+    });
+    header
+}
+
+/// Returns the value of the `Authorization` header for the CalDAV requests.
+///
+/// With OAuth2 the access token is refreshed when needed, if the server rotates the
+/// refresh token the new one is written to flash.
+pub(crate) async fn authorization(
+    client: &mut HttpClient<'_, PinnedTcp<'_>, CachingDns<'_>>,
+    creds: &CaldavCreds,
+    response_buf: &mut [u8; 8192],
+    flash: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+) -> Result<String, NetworkError> {
+    let config = match &creds.auth {
+        CaldavAuth::Basic => return Ok(basic_authorization(&creds.username, &creds.password)),
+        CaldavAuth::OAuth2(config) => config,
+    };
+
+    let client = client_key(config);
+    let cached = ACCESS_TOKEN.lock(|token| {
+        token
+            .borrow()
+            .as_ref()
+            .filter(|t| t.client == client && t.expires_at > embassy_time::Instant::now())
+            .map(|t| t.header.clone())
+    });
+    if let Some(header) = cached {
+        return Ok(header);
+    }
+
+    crate::defmt::info!("Refreshing OAuth2 access token");
+    match refresh_access_token(client, config, response_buf).await? {
+        TokenResult::Granted {
+            access_token,
+            refresh_token,
+            expires_in,
+        } => {
+            if let Some(refresh_token) = refresh_token
+                && refresh_token != config.refresh_token
+            {
+                crate::defmt::info!("Refresh token was rotated, saving the new one");
+                let mut new_config = config.clone();
+                new_config.refresh_token = refresh_token;
+                save_oauth_config(flash, new_config).await;
+            }
+            Ok(cache_access_token(config, &access_token, expires_in))
+        }
+        _ => Err(NetworkError::InvalidCredentials),
+    }
+}
+
+async fn save_oauth_config(
+    flash: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    config: OAuth2Config,
+) {
+    let Some(mut nvs) = crate::storage::read_config(flash).await else {
+        return;
+    };
+    if let Some(caldav) = &mut nvs.caldav {
+        caldav.auth = CaldavAuth::OAuth2(config);
+        if crate::storage::write_config(flash, nvs).await.is_err() {
+            crate::defmt::warn!("Failed to store the rotated refresh token");
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Request and response bodies of the OAuth2 endpoints, apart from [`crate::oauth`] so they
+//! can be tested on the host.
+#[cfg(target_arch = "xtensa")]
+use alloc::string::String;
+use core::fmt::Write;
+#[cfg(not(target_arch = "xtensa"))]
+use std::string::String;
+
+use base64::Engine;
+
+pub(crate) const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(serde::Deserialize)]
+struct TokenBody<'a> {
+    access_token: Option<&'a str>,
+    refresh_token: Option<&'a str>,
+    expires_in: Option<u32>,
+    error: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq)]
+#[allow(
+    clippy::large_enum_variant,
+    reason = "returned once per poll and consumed right away"
+)]
+pub(crate) enum TokenResult {
+    Granted {
+        access_token: String,
+        refresh_token: Option<heapless::String<512>>,
+        expires_in: u32,
+    },
+    /// The user hasn't finished the sign-in yet
+    Pending,
+    /// The client polls too fast, the interval has to be increased
+    SlowDown,
+    Denied,
+    Expired,
+    /// Any other error code, like an invalid client or grant
+    Rejected,
+}
+
+/// Appends `key=value` to an `application/x-www-form-urlencoded` body
+pub(crate) fn push_form_field(form: &mut String, key: &str, value: &str) {
+    if !form.is_empty() {
+        form.push('&');
+    }
+    form.push_str(key);
+    form.push('=');
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                form.push(byte as char)
+            }
+            b' ' => form.push('+'),
+            _ => {
+                let _ = write!(form, "%{:02X}", byte);
+            }
+        }
+    }
+}
+
+/// Parses the answer of the token endpoint, `None` if it isn't a token response at all
+pub(crate) fn parse_token_body(body: &[u8]) -> Option<TokenResult> {
+    let (token, _) = serde_json_core::from_slice::<TokenBody>(body).ok()?;
+
+    if let Some(access_token) = token.access_token {
+        return Some(TokenResult::Granted {
+            access_token: String::from(access_token),
+            refresh_token: token
+                .refresh_token
+                .and_then(|t| heapless::String::try_from(t).ok()),
+            expires_in: token.expires_in.unwrap_or(3600),
+        });
+    }
+
+    Some(match token.error? {
+        "authorization_pending" => TokenResult::Pending,
+        "slow_down" => TokenResult::SlowDown,
+        "access_denied" => TokenResult::Denied,
+        "expired_token" => TokenResult::Expired,
+        _ => TokenResult::Rejected,
+    })
+}
+
+pub(crate) fn basic_authorization(username: &str, password: &str) -> String {
+    let mut credentials = String::with_capacity(username.len() + password.len() + 1);
+    credentials.push_str(username);
+    credentials.push(':');
+    credentials.push_str(password);
+    let mut header = String::from("Basic ");
+    base64::engine::general_purpose::STANDARD.encode_string(credentials, &mut header);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_fields_are_percent_encoded() {
+        let mut form = String::new();
+        push_form_field(&mut form, "client_id", "calendar-display");
+        push_form_field(&mut form, "grant_type", DEVICE_CODE_GRANT);
+        push_form_field(&mut form, "scope", "openid offline_access");
+        push_form_field(&mut form, "client_secret", "a+b/c=~é");
+        assert_eq!(
+            form,
+            "client_id=calendar-display\
+             &grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code\
+             &scope=openid+offline_access\
+             &client_secret=a%2Bb%2Fc%3D~%C3%A9"
+        );
+    }
+
+    #[test]
+    fn empty_form_value() {
+        let mut form = String::new();
+        push_form_field(&mut form, "client_secret", "");
+        assert_eq!(form, "client_secret=");
+    }
+
+    #[test]
+    fn basic_authorization_header() {
+        // The example of RFC 7617
+        assert_eq!(
+            basic_authorization("Aladdin", "open sesame"),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+        assert_eq!(basic_authorization("", ""), "Basic Og==");
+        assert_eq!(
+            basic_authorization("user", "pass:word"),
+            "Basic dXNlcjpwYXNzOndvcmQ="
+        );
+    }
+
+    #[test]
+    fn granted_token() {
+        // RFC 6749, section 5.1
+        let body = br#"{
+            "access_token": "2YotnFZFEjr1zCsicMWpAA",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "refresh_token": "tGzv3JOkF0XG5Qx2TlKWIA",
+            "example_parameter": "example_value"
+        }"#;
+        assert_eq!(
+            parse_token_body(body),
+            Some(TokenResult::Granted {
+                access_token: String::from("2YotnFZFEjr1zCsicMWpAA"),
+                refresh_token: Some(heapless::String::try_from("tGzv3JOkF0XG5Qx2TlKWIA").unwrap()),
+                expires_in: 3600,
+            })
+        );
+    }
+
+    #[test]
+    fn granted_token_without_refresh_token_or_lifetime() {
+        let body = br#"{"access_token":"abc","token_type":"Bearer"}"#;
+        assert_eq!(
+            parse_token_body(body),
+            Some(TokenResult::Granted {
+                access_token: String::from("abc"),
+                refresh_token: None,
+                expires_in: 3600,
+            })
+        );
+    }
+
+    #[test]
+    fn device_flow_errors() {
+        // RFC 8628, section 3.5
+        for (error, result) in [
+            ("authorization_pending", TokenResult::Pending),
+            ("slow_down", TokenResult::SlowDown),
+            ("access_denied", TokenResult::Denied),
+            ("expired_token", TokenResult::Expired),
+            ("invalid_grant", TokenResult::Rejected),
+        ] {
+            let body = format!(r#"{{"error":"{error}","error_description":"see RFC 8628"}}"#);
+            assert_eq!(parse_token_body(body.as_bytes()), Some(result), "{error}");
+        }
+    }
+
+    #[test]
+    fn not_a_token_response() {
+        assert_eq!(parse_token_body(b"<html>Bad Gateway</html>"), None);
+        assert_eq!(parse_token_body(br#"{"token_type":"Bearer"}"#), None);
+    }
+}
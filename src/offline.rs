@@ -0,0 +1,69 @@
+//! Keeps the events of the last successful sync, so an unreachable network or calendar server
+//! still shows the calendar instead of a stale image without any hint.
+//!
+//! The events are only written to flash when they changed, the time of the latest sync is
+//! kept in the RTC memory to spare the flash a write on every wake.
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use esp_storage::FlashStorage;
+use vcal_parser::vevent::VEventData;
+
+use crate::storage::LastEvents;
+
+/// Unix time of the last successful sync in seconds, zero if unknown
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static LAST_SYNC_S: portable_atomic::AtomicU32 = portable_atomic::AtomicU32::new(0);
+
+/// Stores the events of a successful sync
+pub(crate) async fn remember(
+    flash: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    rtc: &esp_hal::rtc_cntl::Rtc<'_>,
+    events: &[VEventData],
+) {
+    let now = crate::hardware::get_time(rtc).timestamp().as_second();
+    LAST_SYNC_S.store(now as u32, core::sync::atomic::Ordering::Relaxed);
+
+    let stored = crate::storage::read_last_events(flash).await;
+    if stored.is_some_and(|stored| stored.events == events) {
+        return;
+    }
+    let last = LastEvents {
+        synced_at: now,
+        events: events.to_vec(),
+    };
+    match crate::storage::write_last_events(flash, &last).await {
+        Ok(()) => crate::defmt::info!("Stored {} events for offline rendering", events.len()),
+        // Too many events for a flash page, the error screen is shown instead
+        Err(e) => crate::defmt::warn!(
+            "Failed to store the events: {}",
+            crate::defmt::Display2Format(&e)
+        ),
+    }
+}
+
+/// The stored events of the current display window and the time they were synced
+pub(crate) async fn recall(
+    flash: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    rtc: &esp_hal::rtc_cntl::Rtc<'_>,
+) -> Option<(alloc::vec::Vec<VEventData>, jiff::Zoned)> {
+    let LastEvents {
+        synced_at,
+        mut events,
+    } = crate::storage::read_last_events(flash).await?;
+
+    let now = crate::hardware::get_time(rtc);
+    let last_sync = LAST_SYNC_S.load(core::sync::atomic::Ordering::Relaxed) as i64;
+    // The RTC memory survives the deep sleep but not a power loss
+    let synced_at = if last_sync > synced_at && last_sync <= now.timestamp().as_second() {
+        last_sync
+    } else {
+        synced_at
+    };
+
+    let (start, end) =
+        crate::networking::display_window(&now.with_time_zone(crate::networking::USER_TIMEZONE));
+    events.retain(|event| event.overlaps(start.timestamp(), end.timestamp()));
+
+    let synced_at = jiff::Timestamp::from_second(synced_at).ok()?;
+    Some((events, synced_at.to_zoned(now.time_zone().clone())))
+}
@@ -1,7 +1,7 @@
-use vcal_parser::{
-    calendars::CalendarData,
-    vevent::{VEventData, parse_datetime},
-};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use vcal_parser::{calendars::CalendarData, collector::EventCollector, vevent::VEventData};
 
 /// The internal nom parser for calendar bodies
 pub(crate) async fn parse_body<B>(
@@ -20,9 +20,7 @@ where
     let mut next_href = false;
     let mut next_name = false;
     loop {
-        let buf = embedded_io_async::BufRead::fill_buf(body_reader)
-            .await
-            .unwrap();
+        let buf = embedded_io_async::BufRead::fill_buf(body_reader).await?;
         let len = buf.len();
         if len == 0 {
             break;
@@ -149,24 +147,101 @@ where
     Ok(calendars)
 }
 
-pub(crate) async fn parse_body_cal<B>(
-    body_reader: &mut reqwless::response::BodyReader<B>,
-) -> Result<alloc::vec::Vec<VEventData>, reqwless::Error>
+/// One `<d:response>` of a WebDAV multistatus
+#[derive(Default, Debug)]
+pub(crate) struct DavResponse {
+    pub href: String,
+    pub etag: Option<String>,
+    /// sync-collection reports deleted resources with a 404 status
+    pub removed: bool,
+    /// The server left out results, reported with a 507 status for the collection
+    pub truncated: bool,
+    pub events: Vec<VEventData>,
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct Multistatus {
+    pub responses: Vec<DavResponse>,
+    pub sync_token: Option<String>,
+    pub ctag: Option<String>,
+    /// The response doesn't contain every match, see [`DavResponse::truncated`]
+    pub truncated: bool,
+}
+
+/// The events a response may still add, a busy shared calendar would otherwise exhaust the
+/// heap in the middle of parsing
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EventBudget {
+    pub events: u16,
+    pub bytes: usize,
+    /// Set once an event didn't fit, the rest of the body was dropped
+    pub exhausted: bool,
+}
+
+impl EventBudget {
+    /// For the responses which shouldn't contain any events
+    pub(crate) const NONE: Self = Self::new(0, 0);
+
+    pub(crate) const fn new(events: u16, bytes: usize) -> Self {
+        Self {
+            events,
+            bytes,
+            exhausted: false,
+        }
+    }
+
+    /// The heap an event takes up, including its slot in the vector
+    pub(crate) fn event_size(event: &VEventData) -> usize {
+        core::mem::size_of::<VEventData>() + event.summary.as_ref().map_or(0, |s| s.capacity())
+    }
+
+    /// Accounts for `event`, returns false if it doesn't fit anymore
+    pub(crate) fn take(&mut self, event: &VEventData) -> bool {
+        let size = Self::event_size(event);
+        if self.exhausted || self.events == 0 || size > self.bytes {
+            self.exhausted = true;
+            return false;
+        }
+        self.events -= 1;
+        self.bytes -= size;
+        true
+    }
+}
+
+/// The element whose text comes next
+#[derive(Clone, Copy, PartialEq)]
+enum TextField {
+    None,
+    Href,
+    Etag,
+    Status,
+    SyncToken,
+    Ctag,
+    CalendarData,
+}
+
+/// Parses the multistatus of a calendar REPORT or PROPFIND, keeping the events of every
+/// resource apart so they can be cached by ETag.
+///
+/// Takes any body reader, so a compressed body can be decoded on the way. Parsing stops
+/// once the events exceed `budget`, which is marked as exhausted then.
+pub(crate) async fn parse_multistatus<R>(
+    body_reader: &mut R,
+    budget: &mut EventBudget,
+) -> Result<Multistatus, reqwless::Error>
 where
-    B: embedded_io_async::Read + embedded_io_async::BufRead,
+    R: embedded_io_async::BufRead<Error = reqwless::Error>,
 {
-    if let reqwless::response::BodyReader::Empty = body_reader {
-        return Ok(alloc::vec::Vec::new());
-    }
-    let mut spill_buffer: alloc::vec::Vec<u8> = alloc::vec::Vec::new();
+    let mut multistatus = Multistatus::default();
+    let mut spill_buffer: Vec<u8> = Vec::new();
     let handled_start = false;
-    let mut cal_data = VEventData::default();
-    let mut events: alloc::vec::Vec<VEventData> = alloc::vec::Vec::new();
-    let mut in_calendar_data = false;
+    let mut response = DavResponse::default();
+    // the server expands the recurring events for the REPORT
+    let mut collector = EventCollector::new(crate::networking::USER_TIMEZONE, None);
+    let mut field = TextField::None;
+    let mut in_propstat = false;
     loop {
-        let buf = embedded_io_async::BufRead::fill_buf(body_reader)
-            .await
-            .unwrap();
+        let buf = embedded_io_async::BufRead::fill_buf(body_reader).await?;
         let len = buf.len();
         if len == 0 {
             break;
@@ -199,107 +274,228 @@ where
                 }
             }
 
-            loop {
-                if current_str.is_empty() {
-                    break;
-                }
-
+            while !current_str.is_empty() {
                 match vcal_parser::calendars::parse_xml_event(current_str) {
                     Ok((remaining, event)) => {
                         use vcal_parser::calendars::XmlEvent;
-                        use vcal_parser::calendars::{CalNamespace, Namespace};
+                        use vcal_parser::calendars::{CalNamespace, DNamespace, Namespace};
 
                         match event {
+                            XmlEvent::Open(Namespace::D(DNamespace::Href)) => {
+                                field = TextField::Href
+                            }
+                            XmlEvent::Open(Namespace::D(DNamespace::GetEtag)) => {
+                                field = TextField::Etag
+                            }
+                            XmlEvent::Open(Namespace::D(DNamespace::Status)) if !in_propstat => {
+                                field = TextField::Status
+                            }
+                            XmlEvent::Open(Namespace::D(DNamespace::SyncToken)) => {
+                                field = TextField::SyncToken
+                            }
+                            XmlEvent::Open(Namespace::Other(_, name)) if name == "getctag" => {
+                                field = TextField::Ctag
+                            }
                             XmlEvent::Open(Namespace::Cal(CalNamespace::CalendarData)) => {
-                                in_calendar_data = true;
+                                field = TextField::CalendarData
                             }
-                            XmlEvent::Close(Namespace::Cal(CalNamespace::CalendarData)) => {
-                                in_calendar_data = false;
+                            XmlEvent::Open(Namespace::D(DNamespace::PropStat)) => {
+                                in_propstat = true
                             }
-                            XmlEvent::Text(text) => {
-                                let mut txt = text.as_str();
-                                if in_calendar_data {
-                                    if text.is_empty() {
-                                        break;
-                                    }
-                                    loop {
-                                        match vcal_parser::vevent::parse_vcal_event(txt) {
-                                            Ok((rem, vevent)) => {
-                                                if let Some(vevent) = vevent {
-                                                    match vevent {
-                                                        vcal_parser::vevent::VcalEvent::Begin(
-                                                            name,
-                                                        ) => {
-                                                            if name == "VEVENT" {
-                                                                cal_data = VEventData::default();
-                                                            }
-                                                        }
-                                                        vcal_parser::vevent::VcalEvent::End(
-                                                            name,
-                                                        ) => {
-                                                            if name == "VEVENT" {
-                                                                events.push(core::mem::take(
-                                                                    &mut cal_data,
-                                                                ));
-                                                            }
-                                                        }
-                                                        vcal_parser::vevent::VcalEvent::Summary(
-                                                            summary,
-                                                        ) => {
-                                                            cal_data.summary = Some(summary);
-                                                        }
-                                                        vcal_parser::vevent::VcalEvent::DtStart(
-                                                            ref dtstart,
-                                                        ) => {
-                                                            cal_data.dtstart =
-                                                                parse_datetime(dtstart.as_str())
-                                                                    .ok();
-                                                        }
-                                                        vcal_parser::vevent::VcalEvent::DtEnd(
-                                                            ref dtend,
-                                                        ) => {
-                                                            cal_data.dtend =
-                                                                parse_datetime(dtend.as_str()).ok();
-                                                        }
-                                                    }
-                                                }
-                                                txt = rem;
-                                                if txt.is_empty() {
-                                                    break;
-                                                }
-                                            }
-                                            Err(e) => {
-                                                crate::defmt::error!(
-                                                    "Failed to parse VEVENT data: {}",
-                                                    crate::defmt::Debug2Format(&e)
-                                                )
-                                            }
-                                        }
-                                    }
+                            XmlEvent::Close(Namespace::D(DNamespace::PropStat)) => {
+                                in_propstat = false
+                            }
+                            XmlEvent::Close(Namespace::D(DNamespace::Response)) => {
+                                let response = core::mem::take(&mut response);
+                                if response.truncated {
+                                    multistatus.truncated = true;
+                                } else {
+                                    multistatus.responses.push(response);
                                 }
                             }
+                            XmlEvent::Close(_) => field = TextField::None,
+                            XmlEvent::Text(text) => match field {
+                                TextField::Href => response.href = text,
+                                TextField::Etag => response.etag = Some(text),
+                                TextField::Status => {
+                                    response.removed = text.contains(" 404 ");
+                                    response.truncated = text.contains(" 507 ");
+                                }
+                                TextField::SyncToken => multistatus.sync_token = Some(text),
+                                TextField::Ctag => multistatus.ctag = Some(text),
+                                TextField::CalendarData => parse_calendar_data(
+                                    &text,
+                                    &mut collector,
+                                    &mut response.events,
+                                    budget,
+                                ),
+                                TextField::None => (),
+                            },
                             _ => (),
                         }
+                        if budget.exhausted {
+                            crate::defmt::warn!(
+                                "Event budget exhausted after {} responses",
+                                multistatus.responses.len()
+                            );
+                            return Ok(multistatus);
+                        }
                         parsed_bytes += current_str.len() - remaining.len();
                         current_str = remaining;
                     }
                     Err(nom::Err::Incomplete(_)) => {
-                        crate::defmt::warn!(
-                            "Incomplete chunked vcal data, waiting for more data to arrive"
+                        crate::defmt::debug!(
+                            "Incomplete multistatus data, waiting for more data to arrive"
                         );
                         break;
                     }
-                    Err(nom::Err::Error(err)) => {
+                    Err(e) => {
                         crate::defmt::error!(
-                            "Failed to parse chunked vcal data: {}",
-                            crate::defmt::Debug2Format(&err)
+                            "Failed to parse multistatus data: {}",
+                            crate::defmt::Debug2Format(&e)
                         );
                         break;
                     }
-                    Err(nom::Err::Failure(fail)) => {
+                }
+            }
+        }
+
+        if spill_buffer.is_empty() {
+            // Copy the remaining unparsed bytes into the spill buffer
+            if parsed_bytes < len {
+                spill_buffer.extend_from_slice(&buf[parsed_bytes..]);
+            }
+        } else {
+            spill_buffer.drain(..parsed_bytes);
+        }
+
+        // Consume all remaining bytes, it only fetches new data if we consumed everything that was previously fetched
+        embedded_io_async::BufRead::consume(body_reader, len);
+    }
+    crate::defmt::info!(
+        "Finished parsing multistatus, responses: {}",
+        multistatus.responses.len()
+    );
+    Ok(multistatus)
+}
+
+/// Collects the VEVENTs of a `calendar-data` element as long as they fit into `budget`
+fn parse_calendar_data(
+    mut text: &str,
+    collector: &mut EventCollector,
+    events: &mut Vec<VEventData>,
+    budget: &mut EventBudget,
+) {
+    let mut emit = |event: VEventData| {
+        if !budget.take(&event) {
+            return false;
+        }
+        events.push(event);
+        true
+    };
+
+    while !text.is_empty() {
+        // the element holds the whole calendar, its end is the end of the last line
+        match vcal_parser::vevent::parse_vcal_event_complete(text) {
+            Ok((rem, line)) => {
+                if let Some(line) = line
+                    && !collector.push(line, &mut emit)
+                {
+                    return;
+                }
+                text = rem;
+            }
+            Err(e) => {
+                crate::defmt::error!(
+                    "Failed to parse VEVENT data: {}",
+                    crate::defmt::Debug2Format(&e)
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Longest token the discovery responses may contain, anything longer is rejected instead
+/// of growing the spill buffer
+const MAX_DISCOVERY_TOKEN_LEN: usize = 2048;
+
+/// Finds the `href` of the first `property` element in a PROPFIND response, like the
+/// `current-user-principal` or the `calendar-home-set`.
+///
+/// Only the local names are compared because the prefixes differ between servers.
+pub(crate) async fn parse_property_href<R>(
+    body_reader: &mut R,
+    property: &str,
+) -> Result<Option<String>, reqwless::Error>
+where
+    R: embedded_io_async::BufRead<Error = reqwless::Error>,
+{
+    let mut spill_buffer: Vec<u8> = Vec::new();
+    let mut handled_start = false;
+    let mut in_property = false;
+    let mut in_href = false;
+    loop {
+        let buf = embedded_io_async::BufRead::fill_buf(body_reader).await?;
+        let len = buf.len();
+        if len == 0 {
+            break;
+        }
+
+        let parse_slice = if spill_buffer.is_empty() {
+            buf
+        } else {
+            spill_buffer.extend_from_slice(buf);
+            &spill_buffer
+        };
+
+        let mut parsed_bytes = 0;
+
+        if let Ok(mut current_str) = core::str::from_utf8(parse_slice) {
+            if !handled_start && current_str.starts_with("<?") {
+                match vcal_parser::calendars::parse_xml_version(current_str) {
+                    Ok((rest, _)) => {
+                        parsed_bytes += current_str.len() - rest.len();
+                        current_str = rest;
+                        handled_start = true;
+                    }
+                    Err(nom::Err::Incomplete(_)) => {}
+                    Err(e) => {
                         crate::defmt::error!(
-                            "Failed to parse chunked vcal data: {}",
-                            crate::defmt::Debug2Format(&fail)
+                            "Failed parsing XML version: {}",
+                            crate::defmt::Debug2Format(&e)
+                        )
+                    }
+                }
+            }
+
+            while !current_str.is_empty() {
+                match vcal_parser::calendars::parse_xml_event(current_str) {
+                    Ok((remaining, event)) => {
+                        use vcal_parser::calendars::XmlEvent;
+
+                        match event {
+                            XmlEvent::Open(ns) if ns.local_name() == property => in_property = true,
+                            XmlEvent::Close(ns) if ns.local_name() == property => {
+                                in_property = false
+                            }
+                            XmlEvent::Open(ns) if in_property && ns.local_name() == "href" => {
+                                in_href = true
+                            }
+                            XmlEvent::Close(_) => in_href = false,
+                            XmlEvent::Text(text) if in_href => {
+                                return Ok(Some(String::from(text.trim())));
+                            }
+                            _ => (),
+                        }
+                        parsed_bytes += current_str.len() - remaining.len();
+                        current_str = remaining;
+                    }
+                    Err(nom::Err::Incomplete(_)) => break,
+                    Err(e) => {
+                        crate::defmt::error!(
+                            "Failed to parse PROPFIND response: {}",
+                            crate::defmt::Debug2Format(&e)
                         );
                         break;
                     }
@@ -307,6 +503,110 @@ where
             }
         }
 
+        if spill_buffer.is_empty() {
+            if parsed_bytes < len {
+                spill_buffer.extend_from_slice(&buf[parsed_bytes..]);
+            }
+        } else {
+            spill_buffer.drain(..parsed_bytes);
+        }
+        if spill_buffer.len() > MAX_DISCOVERY_TOKEN_LEN {
+            return Err(reqwless::Error::BufferTooSmall);
+        }
+
+        embedded_io_async::BufRead::consume(body_reader, len);
+    }
+    Ok(None)
+}
+
+/// Parses a plain iCalendar body, like a `.ics` subscription.
+///
+/// Only the events which overlap the `[start, end)` window are kept, because a feed
+/// usually contains the whole history of the calendar, and the recurring ones are expanded
+/// over it. Parsing stops once they exceed `budget`, which is marked as exhausted then.
+pub(crate) async fn parse_body_ics<R>(
+    body_reader: &mut R,
+    start: jiff::Timestamp,
+    end: jiff::Timestamp,
+    budget: &mut EventBudget,
+) -> Result<alloc::vec::Vec<VEventData>, reqwless::Error>
+where
+    R: embedded_io_async::BufRead<Error = reqwless::Error>,
+{
+    let mut spill_buffer: alloc::vec::Vec<u8> = alloc::vec::Vec::new();
+    let mut collector = EventCollector::new(crate::networking::USER_TIMEZONE, Some((start, end)));
+    let mut events: alloc::vec::Vec<VEventData> = alloc::vec::Vec::new();
+    let mut emit = |event: VEventData| {
+        if !budget.take(&event) {
+            return false;
+        }
+        events.push(event);
+        true
+    };
+    loop {
+        let buf = embedded_io_async::BufRead::fill_buf(body_reader).await?;
+        let len = buf.len();
+        // the last line only ends with the body
+        let at_end = len == 0;
+        if at_end && spill_buffer.is_empty() {
+            break;
+        }
+
+        let parse_slice = if spill_buffer.is_empty() {
+            buf
+        } else {
+            spill_buffer.extend_from_slice(buf);
+            &spill_buffer
+        };
+
+        let mut parsed_bytes = 0;
+
+        // the chunk may end inside a utf-8 character, only parse the valid part
+        let valid = match core::str::from_utf8(parse_slice) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&parse_slice[..e.valid_up_to()]).unwrap_or_default(),
+        };
+        let mut current_str = valid;
+
+        while !current_str.is_empty() {
+            let parsed = if at_end {
+                vcal_parser::vevent::parse_vcal_event_complete(current_str)
+            } else {
+                vcal_parser::vevent::parse_vcal_event(current_str)
+            };
+            match parsed {
+                Ok((rem, line)) => {
+                    if let Some(line) = line
+                        && !collector.push(line, &mut emit)
+                    {
+                        crate::defmt::warn!("Event budget exhausted");
+                        return Ok(without_superseded(events, &mut collector));
+                    }
+                    parsed_bytes += current_str.len() - rem.len();
+                    current_str = rem;
+                }
+                // the rest of the line is in the next chunk
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(e) => {
+                    crate::defmt::error!(
+                        "Failed to parse ics line: {}",
+                        crate::defmt::Debug2Format(&e)
+                    );
+                    // skip the broken line
+                    match current_str.find('\n') {
+                        Some(idx) => {
+                            parsed_bytes += idx + 1;
+                            current_str = &current_str[idx + 1..];
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+        if at_end {
+            break;
+        }
+
         if spill_buffer.is_empty() {
             // Copy the remaining unparsed bytes into the spill buffer
             if parsed_bytes < len {
@@ -319,9 +619,22 @@ where
         // Consume all remaining bytes, it only fetches new data if we consumed everything that was previously fetched
         embedded_io_async::BufRead::consume(body_reader, len);
     }
+    let events = without_superseded(events, &mut collector);
     crate::defmt::info!(
-        "Finished parsing calendar events, total events parsed: {:?}",
-        events.len()
+        "Finished parsing ics feed, events in window: {}, skipped: {}",
+        events.len(),
+        collector.skipped
     );
     Ok(events)
 }
+
+/// Drops the occurrences of recurring events which a RECURRENCE-ID moved or changed
+fn without_superseded(
+    mut events: alloc::vec::Vec<VEventData>,
+    collector: &mut EventCollector,
+) -> alloc::vec::Vec<VEventData> {
+    for index in collector.take_superseded().into_iter().rev() {
+        events.remove(index);
+    }
+    events
+}
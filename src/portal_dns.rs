@@ -0,0 +1,136 @@
+//! The DNS answers of the captive portal, which point every name to the access point.
+use smoltcp::wire::{DnsFlags, DnsOpcode, DnsPacket, DnsQueryType, DnsQuestion};
+
+const DNS_CLASS_IN: u16 = 1;
+/// Short, the names resolve to the real servers again once the device left the portal
+const PORTAL_DNS_TTL_S: u32 = 60;
+
+/// Answers a DNS query with the portal `address`. Other types than A get an empty answer, so the
+/// clients don't wait for an IPv6 address. Returns the length of the response.
+pub(crate) fn response(query: &[u8], buffer: &mut [u8], address: [u8; 4]) -> Option<usize> {
+    let packet = DnsPacket::new_checked(query).ok()?;
+    if packet.flags().contains(DnsFlags::RESPONSE)
+        || packet.opcode() != DnsOpcode::Query
+        || packet.question_count() != 1
+    {
+        return None;
+    }
+    let (rest, question) = DnsQuestion::parse(packet.payload()).ok()?;
+    // The header and the question are sent back, without the additional records of EDNS
+    let question_end = query.len() - rest.len();
+    let answer = question.type_ == DnsQueryType::A;
+    let len = question_end + if answer { 16 } else { 0 };
+    let response = buffer.get_mut(..len)?;
+    response[..question_end].copy_from_slice(&query[..question_end]);
+
+    let mut header = DnsPacket::new_unchecked(&mut response[..]);
+    header.set_flags(
+        DnsFlags::RESPONSE
+            | DnsFlags::AUTHORITATIVE
+            | DnsFlags::RECURSION_AVAILABLE
+            | (packet.flags() & DnsFlags::RECURSION_DESIRED),
+    );
+    header.set_answer_record_count(answer as u16);
+    header.set_authority_record_count(0);
+    header.set_additional_record_count(0);
+
+    if answer {
+        let record = &mut response[question_end..];
+        // The name is a pointer to the one in the question
+        record[..2].copy_from_slice(&[0xc0, 0x0c]);
+        record[2..4].copy_from_slice(&u16::from(DnsQueryType::A).to_be_bytes());
+        record[4..6].copy_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        record[6..10].copy_from_slice(&PORTAL_DNS_TTL_S.to_be_bytes());
+        record[10..12].copy_from_slice(&(address.len() as u16).to_be_bytes());
+        record[12..].copy_from_slice(&address);
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::wire::{DnsRecord, DnsRecordData};
+
+    use super::*;
+
+    const PORTAL: [u8; 4] = [192, 168, 0, 1];
+
+    /// `dig captive.apple.com`, with an EDNS cookie in the additional records
+    const EDNS_QUERY: [u8; 58] = [
+        0x12, 0x34, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x07, b'c', b'a',
+        b'p', b't', b'i', b'v', b'e', 0x05, b'a', b'p', b'p', b'l', b'e', 0x03, b'c', b'o', b'm',
+        0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x0c, 0x00, 0x0a, 0x00, 0x08, 0xc1, 0x1e, 0x2f, 0x5a, 0x8d, 0x3b, 0x94, 0x07,
+    ];
+    const QUESTION_END: usize = 35;
+
+    /// The AAAA query of Android's connectivity check
+    const AAAA_QUERY: [u8; 47] = [
+        0xa7, 0x0e, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, b'c', b'o',
+        b'n', b'n', b'e', b'c', b't', b'i', b'v', b'i', b't', b'y', b'c', b'h', b'e', b'c', b'k',
+        0x07, b'g', b's', b't', b'a', b't', b'i', b'c', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x1c,
+        0x00, 0x01,
+    ];
+
+    #[test]
+    fn a_query_gets_the_portal() {
+        let mut buffer = [0u8; 512];
+        let len = response(&EDNS_QUERY, &mut buffer, PORTAL).unwrap();
+        let answer = &buffer[..len];
+
+        // The OPT record is left out, the answer points back to the question
+        let mut expected = EDNS_QUERY[..QUESTION_END].to_vec();
+        expected[2..4].copy_from_slice(&[0x85, 0x80]);
+        expected[6..8].copy_from_slice(&[0, 1]);
+        expected[10..12].copy_from_slice(&[0, 0]);
+        expected.extend_from_slice(&[
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 192, 168, 0, 1,
+        ]);
+        assert_eq!(answer, expected);
+
+        let packet = DnsPacket::new_checked(answer).unwrap();
+        assert_eq!(packet.transaction_id(), 0x1234);
+        assert!(
+            packet
+                .flags()
+                .contains(DnsFlags::RESPONSE | DnsFlags::RECURSION_DESIRED)
+        );
+        let (rest, question) = DnsQuestion::parse(packet.payload()).unwrap();
+        assert_eq!(question.type_, DnsQueryType::A);
+        let (rest, record) = DnsRecord::parse(rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(record.ttl, PORTAL_DNS_TTL_S);
+        assert_eq!(record.data, DnsRecordData::A(PORTAL.into()));
+        let name: Vec<_> = packet.parse_name(record.name).map(Result::unwrap).collect();
+        assert_eq!(name, [&b"captive"[..], b"apple", b"com"]);
+    }
+
+    #[test]
+    fn other_types_get_no_answer() {
+        let mut buffer = [0u8; 512];
+        let len = response(&AAAA_QUERY, &mut buffer, PORTAL).unwrap();
+        assert_eq!(len, AAAA_QUERY.len());
+
+        let packet = DnsPacket::new_checked(&buffer[..len]).unwrap();
+        assert_eq!(packet.transaction_id(), 0xa70e);
+        assert_eq!(packet.answer_record_count(), 0);
+        assert_eq!(&buffer[12..len], &AAAA_QUERY[12..]);
+    }
+
+    #[test]
+    fn only_single_queries_are_answered() {
+        let mut buffer = [0u8; 512];
+        // a response
+        let mut answer = EDNS_QUERY;
+        answer[2] |= 0x80;
+        assert_eq!(response(&answer, &mut buffer, PORTAL), None);
+        // two questions
+        let mut two = AAAA_QUERY;
+        two[5] = 2;
+        assert_eq!(response(&two, &mut buffer, PORTAL), None);
+        // truncated inside the question
+        assert_eq!(response(&EDNS_QUERY[..20], &mut buffer, PORTAL), None);
+        // no room for the answer
+        assert_eq!(response(&EDNS_QUERY, &mut buffer[..40], PORTAL), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Keeps the fetched events of the day in the RTC memory, so most wakes only redraw the screen
+//! without turning on Wi-Fi.
+//!
+//! The network is used again when the refresh interval passed, the displayed window moved past
+//! the fetched range or the clock needs an NTP sync. The memory isn't initialized after a power
+//! loss, a checksum over the stored state rejects whatever it contains then.
+use core::sync::atomic::Ordering;
+
+use portable_atomic::{AtomicU8, AtomicU16, AtomicU32};
+use vcal_parser::vevent::VEventData;
+
+use crate::fnv::fnv1a;
+
+/// Room for the encoded events, the RTC fast memory only has 8 KiB
+const CAPACITY: usize = 3072;
+
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static EVENTS: [AtomicU8; CAPACITY] = [const { AtomicU8::new(0) }; CAPACITY];
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static EVENTS_LEN: AtomicU16 = AtomicU16::new(0);
+/// Unix time of the fetch in seconds
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static FETCHED_AT_S: AtomicU32 = AtomicU32::new(0);
+/// The fetched time range in unix seconds
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static RANGE_START_S: AtomicU32 = AtomicU32::new(0);
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static RANGE_END_S: AtomicU32 = AtomicU32::new(0);
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static CHECKSUM: AtomicU32 = AtomicU32::new(0);
+
+/// FNV-1a over the header and the encoded events
+fn checksum(len: u16, fetched_at: u32, range: (u32, u32)) -> u32 {
+    let header = [len as u32, fetched_at, range.0, range.1];
+    fnv1a(
+        header.iter().flat_map(|value| value.to_le_bytes()).chain(
+            EVENTS[..len as usize]
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed)),
+        ),
+    )
+}
+
+/// Keeps the events fetched for `range`, a day which doesn't fit is fetched on every wake
+pub(crate) fn store(
+    rtc: &esp_hal::rtc_cntl::Rtc<'_>,
+    events: &[VEventData],
+    range: (jiff::Timestamp, jiff::Timestamp),
+) {
+    let encoded = match postcard::to_allocvec(events) {
+        Ok(encoded) if encoded.len() <= CAPACITY => encoded,
+        _ => {
+            crate::defmt::warn!("Events don't fit into the RTC memory");
+            clear();
+            return;
+        }
+    };
+    for (slot, byte) in EVENTS.iter().zip(&encoded) {
+        slot.store(*byte, Ordering::Relaxed);
+    }
+
+    let len = encoded.len() as u16;
+    let fetched_at = crate::hardware::get_time(rtc).timestamp().as_second() as u32;
+    let range = (range.0.as_second() as u32, range.1.as_second() as u32);
+    EVENTS_LEN.store(len, Ordering::Relaxed);
+    FETCHED_AT_S.store(fetched_at, Ordering::Relaxed);
+    RANGE_START_S.store(range.0, Ordering::Relaxed);
+    RANGE_END_S.store(range.1, Ordering::Relaxed);
+    CHECKSUM.store(checksum(len, fetched_at, range), Ordering::Relaxed);
+    crate::defmt::info!(
+        "Kept {} events ({} bytes) in the RTC memory",
+        events.len(),
+        len
+    );
+}
+
+/// Forgets the events, the configuration may have changed
+pub(crate) fn clear() {
+    EVENTS_LEN.store(0, Ordering::Relaxed);
+    CHECKSUM.store(0, Ordering::Relaxed);
+}
+
+/// Returns the kept events and their fetch time if they are recent enough and cover the
+/// displayed window
+pub(crate) fn load(
+    rtc: &esp_hal::rtc_cntl::Rtc<'_>,
+    refresh_minutes: u16,
+) -> Option<(alloc::vec::Vec<VEventData>, jiff::Zoned)> {
+    let len = EVENTS_LEN.load(Ordering::Relaxed);
+    let fetched_at = FETCHED_AT_S.load(Ordering::Relaxed);
+    let range = (
+        RANGE_START_S.load(Ordering::Relaxed),
+        RANGE_END_S.load(Ordering::Relaxed),
+    );
+    if len == 0
+        || len as usize > CAPACITY
+        || CHECKSUM.load(Ordering::Relaxed) != checksum(len, fetched_at, range)
+    {
+        return None;
+    }
+
+    let now = crate::hardware::get_time(rtc);
+    let age = now.timestamp().as_second() - fetched_at as i64;
+    if !(0..refresh_minutes as i64 * 60).contains(&age) {
+        crate::defmt::info!("Kept events are {} s old, refreshing", age);
+        return None;
+    }
+    let (start, end) = crate::networking::display_window(&now);
+    if start.timestamp().as_second() < range.0 as i64
+        || end.timestamp().as_second() > range.1 as i64
+    {
+        crate::defmt::info!("Displayed window moved past the kept events");
+        return None;
+    }
+
+    let encoded: alloc::vec::Vec<u8> = EVENTS[..len as usize]
+        .iter()
+        .map(|b| b.load(Ordering::Relaxed))
+        .collect();
+    let events = postcard::from_bytes(&encoded).ok()?;
+    let fetched_at = jiff::Timestamp::from_second(fetched_at as i64).ok()?;
+    Some((events, fetched_at.to_zoned(now.time_zone().clone())))
+}
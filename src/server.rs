@@ -4,6 +4,8 @@ use alloc::string::String;
 use alloc::vec::Vec;
 #[cfg(not(target_arch = "xtensa"))]
 use std::string::String;
+#[cfg(not(target_arch = "xtensa"))]
+use std::vec::Vec;
 
 use picoserve::AppBuilder;
 use vcal_parser::calendars::CalendarData;
@@ -14,6 +16,8 @@ const INDEX_HTML_GZ: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/credentia
 const DISPLAY_HTML_GZ: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/calendar-config.html.gz"));
 
 pub const WEB_TASK_POOL_SIZE: usize = 2;
+/// The setup page on the access point at `wifi::AP_IP_ADDR`
+pub(crate) const PORTAL_URL: &str = "http://192.168.0.1/";
 
 pub const MAX_ORIGIN_LEN: usize = 128;
 pub const MAX_PATH_LEN: usize = 255;
@@ -29,6 +33,11 @@ pub enum AppError {
     Network(#[from] crate::networking::NetworkError),
     #[error("Storage error: {0}")]
     Storage(#[from] crate::storage::StorageError),
+    #[error("Wi-Fi scan failed")]
+    WifiScan,
+    #[status_code(BAD_REQUEST)]
+    #[error("Trial connection failed: {0:?}")]
+    WifiTrial(WifiTrial),
 }
 
 #[cfg(target_arch = "xtensa")]
@@ -48,12 +57,10 @@ pub struct AppProps {
     #[cfg(target_arch = "xtensa")]
     pub http_client_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
-        reqwless::client::HttpClient<
-            'static,
-            embassy_net::tcp::client::TcpClient<'static, 1, 4096, 4096>,
-            embassy_net::dns::DnsSocket<'static>,
-        >,
+        crate::networking::HttpClients<'static>,
     >,
+    #[cfg(target_arch = "xtensa")]
+    pub net_stack: embassy_net::Stack<'static>,
 }
 
 impl AppBuilder for AppProps {
@@ -64,6 +71,8 @@ impl AppBuilder for AppProps {
         let flash = self.flash_storage;
         #[cfg(target_arch = "xtensa")]
         let http_client_mutex = self.http_client_mutex;
+        #[cfg(target_arch = "xtensa")]
+        let net_stack = self.net_stack;
 
         // Reuse existing REQ_BUFFER
         #[cfg(target_arch = "xtensa")]
@@ -76,25 +85,134 @@ impl AppBuilder for AppProps {
 
         picoserve::Router::new()
             .route("/", picoserve::routing::get(config_page_handler))
+            // The connectivity checks of Android, Apple, Windows and Firefox
+            .route(
+                "/generate_204",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route("/gen_204", picoserve::routing::get(captive_portal_redirect))
+            .route(
+                "/hotspot-detect.html",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route(
+                "/library/test/success.html",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route(
+                "/connecttest.txt",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route(
+                "/ncsi.txt",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route(
+                "/redirect",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route(
+                "/canonical.html",
+                picoserve::routing::get(captive_portal_redirect),
+            )
+            .route(
+                "/success.txt",
+                picoserve::routing::get(captive_portal_redirect),
+            )
             .route(
                 "/api/config/wifi",
-                picoserve::routing::post(
-                    move |picoserve::extract::Json(resp_wifi): picoserve::extract::Json<
-                        storage::WifiCreds,
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    let nvs = storage::read_config(flash).await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let mut nvs = storage::read_config().await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    nvs.wifi.extend([
+                        storage::WifiCreds {
+                            ssid: heapless::String::try_from("Office").unwrap(),
+                            password: heapless::String::try_from("office-secret").unwrap(),
+                            priority: 1,
+                            static_ip: Some(storage::StaticIpv4 {
+                                address: core::net::Ipv4Addr::new(10, 20, 0, 42),
+                                prefix_len: 24,
+                                gateway: Some(core::net::Ipv4Addr::new(10, 20, 0, 1)),
+                                dns_servers: heapless::Vec::from_slice(&[
+                                    core::net::Ipv4Addr::new(10, 20, 0, 1),
+                                ])
+                                .unwrap(),
+                            }),
+                        },
+                        storage::WifiCreds {
+                            ssid: heapless::String::try_from("Home").unwrap(),
+                            password: heapless::String::try_from("home-secret").unwrap(),
+                            priority: 0,
+                            static_ip: None,
+                        },
+                    ]);
+
+                    // The passwords never leave the device
+                    let networks = nvs
+                        .wifi
+                        .into_iter()
+                        .map(|network| storage::WifiCreds {
+                            password: heapless::String::new(),
+                            ..network
+                        })
+                        .collect::<Vec<_>>();
+                    picoserve::response::json::Json(networks)
+                })
+                .post(
+                    move |picoserve::extract::Json(networks): picoserve::extract::Json<
+                        Vec<storage::WifiCreds>,
                     >| async move {
                         #[cfg(feature = "defmt")]
-                        crate::defmt::info!("Received config change request: {:?}", resp_wifi);
+                        crate::defmt::info!("Received {} Wi-Fi networks", networks.len());
+
+                        let duplicate = |(i, network): (usize, &storage::WifiCreds)| {
+                            networks[..i].iter().any(|other| other.ssid == network.ssid)
+                        };
+                        if networks.len() > storage::MAX_WIFI_NETWORKS
+                            || networks.iter().any(|network| network.ssid.is_empty())
+                            || networks.iter().enumerate().any(duplicate)
+                            || !networks.iter().all(|network| {
+                                network.static_ip.as_ref().is_none_or(valid_static_ip)
+                            })
+                        {
+                            return Ok(picoserve::response::StatusCode::BAD_REQUEST);
+                        }
 
                         #[cfg(target_arch = "xtensa")]
                         let mut nvs = storage::read_config(flash).await.unwrap_or_default();
                         #[cfg(not(target_arch = "xtensa"))]
                         let mut nvs = storage::read_config().await.unwrap_or_default();
 
-                        nvs.wifi = Some(resp_wifi);
+                        let stored = core::mem::take(&mut nvs.wifi);
+                        nvs.wifi = merge_wifi_passwords(networks, &stored);
+
+                        for network in nvs.wifi.iter().filter(|network| untried(network, &stored)) {
+                            #[cfg(target_arch = "xtensa")]
+                            let state = crate::wifi::trial(network.clone()).await;
+                            #[cfg(not(target_arch = "xtensa"))]
+                            let state = {
+                                crate::defmt::info!(
+                                    "Mock trial connection to {}",
+                                    crate::defmt::Display2Format(&network.ssid.as_str())
+                                );
+                                WifiTrial::Connected
+                            };
+                            // Without the access point the list is saved without a check
+                            if !matches!(state, WifiTrial::Connected | WifiTrial::Unavailable) {
+                                return Err(AppError::WifiTrial(state));
+                            }
+                        }
+
                         #[cfg(target_arch = "xtensa")]
-                        storage::write_config(flash, nvs).await;
+                        let written = storage::write_config(flash, nvs).await;
                         #[cfg(not(target_arch = "xtensa"))]
-                        storage::write_config(nvs).await;
+                        let written = storage::write_config(nvs).await;
+                        written
+                            .map(|()| picoserve::response::StatusCode::OK)
+                            .map_err(AppError::Storage)
                     },
                 ),
             )
@@ -128,12 +246,233 @@ impl AppBuilder for AppProps {
                         nvs.display = Some(resp_caldav);
 
                         #[cfg(target_arch = "xtensa")]
-                        storage::write_config(flash, nvs).await;
+                        let written = storage::write_config(flash, nvs).await;
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let written = storage::write_config(nvs).await;
+                        written.map(|()| picoserve::response::StatusCode::OK)
+                    },
+                ),
+            )
+            .route(
+                "/api/config/ics",
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    let nvs = storage::read_config(flash).await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let mut nvs = storage::read_config().await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    nvs.ics_feeds.push(storage::IcsFeed {
+                        name: heapless::String::try_from("Public holidays").unwrap(),
+                        url: heapless::String::try_from("webcal://example.com/holidays.ics")
+                            .unwrap(),
+                        enabled: true,
+                    });
+
+                    picoserve::response::json::Json(nvs.ics_feeds)
+                })
+                .post(
+                    move |picoserve::extract::Json(feeds): picoserve::extract::Json<
+                        Vec<storage::IcsFeed>,
+                    >| async move {
+                        for feed in &feeds {
+                            if !(feed.url.starts_with("https://")
+                                || feed.url.starts_with("webcal://"))
+                                || fluent_uri::Uri::parse(feed.url.as_str()).is_err()
+                            {
+                                return Ok(picoserve::response::StatusCode::BAD_REQUEST);
+                            }
+                        }
+
+                        #[cfg(target_arch = "xtensa")]
+                        let mut nvs = storage::read_config(flash).await.unwrap_or_default();
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let mut nvs = storage::read_config().await.unwrap_or_default();
+
+                        nvs.ics_feeds = feeds;
+
+                        #[cfg(target_arch = "xtensa")]
+                        let written = storage::write_config(flash, nvs).await;
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let written = storage::write_config(nvs).await;
+                        written.map(|()| picoserve::response::StatusCode::OK)
+                    },
+                ),
+            )
+            .route(
+                "/api/config/ntp",
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    let nvs = storage::read_config(flash).await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let nvs = storage::read_config().await.unwrap_or_default();
+
+                    picoserve::response::json::Json(nvs.ntp_servers)
+                })
+                .post(
+                    move |picoserve::extract::Json(servers): picoserve::extract::Json<
+                        Vec<heapless::String<64>>,
+                    >| async move {
+                        let valid_host = |host: &heapless::String<64>| {
+                            !host.is_empty()
+                                && host
+                                    .bytes()
+                                    .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+                        };
+                        if servers.len() > storage::MAX_NTP_SERVERS
+                            || !servers.iter().all(valid_host)
+                        {
+                            return Ok(picoserve::response::StatusCode::BAD_REQUEST);
+                        }
+
+                        #[cfg(target_arch = "xtensa")]
+                        let mut nvs = storage::read_config(flash).await.unwrap_or_default();
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let mut nvs = storage::read_config().await.unwrap_or_default();
+
+                        nvs.ntp_servers = servers;
+
+                        #[cfg(target_arch = "xtensa")]
+                        let written = storage::write_config(flash, nvs).await;
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let written = storage::write_config(nvs).await;
+                        written.map(|()| picoserve::response::StatusCode::OK)
+                    },
+                ),
+            )
+            .route(
+                "/api/config/hostname",
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    let nvs = storage::read_config(flash).await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let nvs = storage::read_config().await.unwrap_or_default();
+
+                    picoserve::response::json::Json(nvs.hostname)
+                })
+                .post(
+                    move |picoserve::extract::Json(hostname): picoserve::extract::Json<
+                        Option<heapless::String<{ storage::MAX_HOSTNAME_LEN }>>,
+                    >| async move {
+                        if !hostname.as_deref().is_none_or(valid_hostname) {
+                            return Ok(picoserve::response::StatusCode::BAD_REQUEST);
+                        }
+
+                        #[cfg(target_arch = "xtensa")]
+                        let mut nvs = storage::read_config(flash).await.unwrap_or_default();
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let mut nvs = storage::read_config().await.unwrap_or_default();
+
+                        nvs.hostname = hostname;
+
+                        #[cfg(target_arch = "xtensa")]
+                        let written = storage::write_config(flash, nvs).await;
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let written = storage::write_config(nvs).await;
+                        written.map(|()| picoserve::response::StatusCode::OK)
+                    },
+                ),
+            )
+            .route(
+                "/api/config/tls",
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    let trust = storage::read_tls_trust(flash).await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let trust = storage::read_tls_trust().await.unwrap_or_default();
+
+                    picoserve::response::json::Json(TlsTrustResponse::new(&trust))
+                })
+                .post(
+                    move |picoserve::extract::Json(body): picoserve::extract::Json<
+                        TlsTrustRequest,
+                    >| async move {
+                        #[cfg(target_arch = "xtensa")]
+                        return save_tls_trust_handler(flash, body).await;
                         #[cfg(not(target_arch = "xtensa"))]
-                        storage::write_config(nvs).await;
+                        return save_tls_trust_handler(body).await;
                     },
                 ),
             )
+            .route(
+                "/api/config/tls/version",
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    let nvs = storage::read_config(flash).await.unwrap_or_default();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let nvs = storage::read_config().await.unwrap_or_default();
+
+                    picoserve::response::json::Json(nvs.tls_min_version)
+                })
+                .post(
+                    move |picoserve::extract::Json(min_version): picoserve::extract::Json<
+                        storage::TlsVersion,
+                    >| async move {
+                        #[cfg(target_arch = "xtensa")]
+                        let mut nvs = storage::read_config(flash).await.unwrap_or_default();
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let mut nvs = storage::read_config().await.unwrap_or_default();
+
+                        nvs.tls_min_version = min_version;
+
+                        #[cfg(target_arch = "xtensa")]
+                        let written = storage::write_config(flash, nvs).await;
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let written = storage::write_config(nvs).await;
+                        written.map(|()| picoserve::response::StatusCode::OK)
+                    },
+                ),
+            )
+            .route(
+                "/api/config/wifi/test",
+                picoserve::routing::get(|| async {
+                    #[cfg(target_arch = "xtensa")]
+                    let state = crate::wifi::trial_state();
+                    #[cfg(not(target_arch = "xtensa"))]
+                    let state = WifiTrial::Connected;
+                    picoserve::response::json::Json(state)
+                })
+                .post(
+                    move |picoserve::extract::Json(network): picoserve::extract::Json<
+                        storage::WifiCreds,
+                    >| async move {
+                        if !network.static_ip.as_ref().is_none_or(valid_static_ip) {
+                            return Err(picoserve::response::StatusCode::BAD_REQUEST);
+                        }
+
+                        #[cfg(target_arch = "xtensa")]
+                        let nvs = storage::read_config(flash).await.unwrap_or_default();
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let nvs = storage::read_config().await.unwrap_or_default();
+
+                        let network = merge_wifi_passwords(Vec::from([network]), &nvs.wifi)
+                            .pop()
+                            .unwrap();
+                        #[cfg(target_arch = "xtensa")]
+                        let state = crate::wifi::start_trial(network);
+                        #[cfg(not(target_arch = "xtensa"))]
+                        let state = {
+                            crate::defmt::info!(
+                                "Mock trial connection to {}",
+                                crate::defmt::Display2Format(&network.ssid.as_str())
+                            );
+                            WifiTrial::Testing
+                        };
+                        Ok(picoserve::response::json::Json(state))
+                    },
+                ),
+            )
+            .route("/api/wifi/scan", picoserve::routing::get(wifi_scan))
+            .route(
+                "/api/diagnostics/tls",
+                picoserve::routing::get(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    return tls_diagnostics(flash, net_stack)
+                        .await
+                        .map_err(AppError::Network);
+                    #[cfg(not(target_arch = "xtensa"))]
+                    return tls_diagnostics().await.map_err(AppError::Network);
+                }),
+            )
             .route(
                 "/display_config",
                 picoserve::routing::get(display_config_page_handler),
@@ -148,11 +487,12 @@ impl AppBuilder for AppProps {
                         return fetch_domain_endpoint(
                             http_client_mutex,
                             &body.url,
+                            body.allow_http,
                             req_buffer_mutex,
                         )
                         .await;
                         #[cfg(not(target_arch = "xtensa"))]
-                        return fetch_domain_endpoint(&body.url).await;
+                        return fetch_domain_endpoint(&body.url, body.allow_http).await;
                     },
                 ),
             )
@@ -167,6 +507,7 @@ impl AppBuilder for AppProps {
                             return check_caldav_credentials(
                                 http_client_mutex,
                                 req_buffer_mutex,
+                                flash,
                                 &credentials,
                             )
                             .await
@@ -195,6 +536,7 @@ impl AppBuilder for AppProps {
                             http_client_mutex,
                             &nvs.url,
                             req_buffer_mutex,
+                            flash,
                             &nvs,
                         )
                         .await
@@ -204,20 +546,48 @@ impl AppBuilder for AppProps {
                     return fetch_calendars().await.map_err(AppError::Network);
                 }),
             )
+            .route(
+                "/api/config/caldav/oauth/start",
+                picoserve::routing::post(
+                    move |picoserve::extract::Json(credentials): picoserve::extract::Json<
+                        storage::CaldavCreds,
+                    >| async move {
+                        #[cfg(target_arch = "xtensa")]
+                        return start_device_authorization(
+                            http_client_mutex,
+                            req_buffer_mutex,
+                            credentials,
+                        )
+                        .await
+                        .map_err(AppError::Network);
+                        #[cfg(not(target_arch = "xtensa"))]
+                        return start_device_authorization(credentials)
+                            .await
+                            .map_err(AppError::Network);
+                    },
+                ),
+            )
+            .route(
+                "/api/config/caldav/oauth/poll",
+                picoserve::routing::post(move || async move {
+                    #[cfg(target_arch = "xtensa")]
+                    return poll_device_authorization(http_client_mutex, req_buffer_mutex, flash)
+                        .await;
+                    #[cfg(not(target_arch = "xtensa"))]
+                    return poll_device_authorization().await;
+                }),
+            )
     }
 }
 
 async fn fetch_domain_endpoint(
     #[cfg(target_arch = "xtensa")] http_client_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
-        reqwless::client::HttpClient<
-            'static,
-            embassy_net::tcp::client::TcpClient<'static, 1, 4096, 4096>,
-            embassy_net::dns::DnsSocket<'static>,
-        >,
+        crate::networking::HttpClients<'static>,
     >,
     #[cfg(target_arch = "xtensa")] body: &str,
     #[cfg(not(target_arch = "xtensa"))] body: &str,
+    allow_http: bool,
     #[cfg(target_arch = "xtensa")] req_buffer_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
         &'static mut [u8; 8192],
@@ -227,10 +597,12 @@ async fn fetch_domain_endpoint(
     {
         let mut buf_guard = req_buffer_mutex.lock().await;
 
-        let mut client = http_client_mutex.lock().await;
+        let mut clients = http_client_mutex.lock().await;
+        let client = clients
+            .for_url(body, allow_http)
+            .map_err(|_| picoserve::response::StatusCode::BAD_REQUEST)?;
 
-        let endpoint =
-            crate::networking::fetch_domain_endpoint(&mut *client, body, *buf_guard).await;
+        let endpoint = crate::networking::fetch_domain_endpoint(client, body, *buf_guard).await;
         match endpoint {
             Some(url) => Ok(picoserve::response::json::Json(EndpointResponse {
                 endpoint: url,
@@ -240,7 +612,7 @@ async fn fetch_domain_endpoint(
     }
     #[cfg(not(target_arch = "xtensa"))]
     {
-        let _ = body;
+        let _ = (body, allow_http);
         let resp: heapless::String<{ MAX_URL_LEN }> =
             heapless::String::try_from("https://example.com/caldav").unwrap();
         Ok(picoserve::response::json::Json(EndpointResponse {
@@ -252,46 +624,53 @@ async fn fetch_domain_endpoint(
 async fn fetch_calendars(
     #[cfg(target_arch = "xtensa")] http_client_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
-        reqwless::client::HttpClient<
-            'static,
-            embassy_net::tcp::client::TcpClient<'static, 1, 4096, 4096>,
-            embassy_net::dns::DnsSocket<'static>,
-        >,
+        crate::networking::HttpClients<'static>,
     >,
     #[cfg(target_arch = "xtensa")] body: &str,
     #[cfg(target_arch = "xtensa")] req_buffer_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
         &'static mut [u8; 8192],
     >,
+    #[cfg(target_arch = "xtensa")] flash: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        storage::FlashStorage<'static>,
+    >,
     #[cfg(target_arch = "xtensa")] credentials: &storage::CaldavCreds,
 ) -> Result<picoserve::response::json::Json<Vec<CalendarData>>, crate::networking::NetworkError> {
     #[cfg(target_arch = "xtensa")]
     {
         let mut buf_guard = req_buffer_mutex.lock().await;
 
-        let mut client = http_client_mutex.lock().await;
+        let mut clients = http_client_mutex.lock().await;
 
-        let principal_url =
-            crate::networking::fetch_principal_url(&mut *client, body, credentials, *buf_guard)
-                .await
-                .ok_or(crate::networking::NetworkError::ParsingError)?;
+        let authorization =
+            crate::oauth::authorization(&mut clients.tls, credentials, *buf_guard, flash).await?;
+        let client = clients.caldav(credentials)?;
+
+        let principal_url = crate::networking::fetch_principal_url(
+            client,
+            body,
+            &credentials.url,
+            &authorization,
+            *buf_guard,
+        )
+        .await?;
         let calendar_home = crate::networking::fetch_calendar_home_set(
-            &mut *client,
+            client,
             body,
             &principal_url,
-            credentials,
+            &authorization,
             *buf_guard,
         )
-        .await
-        .ok_or(crate::networking::NetworkError::ParsingError)?;
+        .await?;
         let calendars = crate::networking::fetch_calendars(
-            &mut *client,
+            client,
             body,
             &calendar_home,
-            credentials,
+            &authorization,
             *buf_guard,
         )
-        .await;
+        .await?;
         Ok(picoserve::response::json::Json(calendars))
     }
     #[cfg(not(target_arch = "xtensa"))]
@@ -307,16 +686,16 @@ async fn fetch_calendars(
 async fn check_caldav_credentials(
     #[cfg(target_arch = "xtensa")] http_client_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
-        reqwless::client::HttpClient<
-            'static,
-            embassy_net::tcp::client::TcpClient<'static, 1, 4096, 4096>,
-            embassy_net::dns::DnsSocket<'static>,
-        >,
+        crate::networking::HttpClients<'static>,
     >,
     #[cfg(target_arch = "xtensa")] req_buffer_mutex: &'static embassy_sync::mutex::Mutex<
         embassy_sync::blocking_mutex::raw::NoopRawMutex,
         &'static mut [u8; 8192],
     >,
+    #[cfg(target_arch = "xtensa")] flash: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        storage::FlashStorage<'static>,
+    >,
     #[cfg(target_arch = "xtensa")] credentials: &storage::CaldavCreds,
     #[cfg(not(target_arch = "xtensa"))] credentials: &storage::CaldavCreds,
 ) -> Result<picoserve::response::StatusCode, crate::networking::NetworkError> {
@@ -324,7 +703,11 @@ async fn check_caldav_credentials(
     {
         let mut buf_guard = req_buffer_mutex.lock().await;
 
-        let mut client = http_client_mutex.lock().await;
+        let mut clients = http_client_mutex.lock().await;
+
+        let authorization =
+            crate::oauth::authorization(&mut clients.tls, credentials, *buf_guard, flash).await?;
+        let client = clients.caldav(credentials)?;
 
         let url_str = credentials.url.as_str();
         let uri = fluent_uri::Uri::parse(url_str)
@@ -336,10 +719,10 @@ async fn check_caldav_credentials(
         let path = uri.path().as_str();
 
         crate::networking::check_credentials(
-            &mut *client,
+            client,
             &origin,
             if path.is_empty() { "/" } else { path },
-            credentials,
+            &authorization,
             *buf_guard,
         )
         .await?;
@@ -353,9 +736,202 @@ async fn check_caldav_credentials(
     }
 }
 
+async fn start_device_authorization(
+    #[cfg(target_arch = "xtensa")] http_client_mutex: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        crate::networking::HttpClients<'static>,
+    >,
+    #[cfg(target_arch = "xtensa")] req_buffer_mutex: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        &'static mut [u8; 8192],
+    >,
+    credentials: storage::CaldavCreds,
+) -> Result<picoserve::response::json::Json<DeviceCodeResponse>, crate::networking::NetworkError> {
+    let storage::CaldavAuth::OAuth2(config) = &credentials.auth else {
+        return Err(crate::networking::NetworkError::AuthorizationFailed);
+    };
+
+    #[cfg(target_arch = "xtensa")]
+    {
+        let mut buf_guard = req_buffer_mutex.lock().await;
+
+        let mut clients = http_client_mutex.lock().await;
+
+        let auth = crate::oauth::request_device_code(&mut clients.tls, config, *buf_guard).await?;
+
+        let now = embassy_time::Instant::now();
+        *crate::oauth::PENDING_AUTHORIZATION.lock().await =
+            Some(crate::oauth::PendingAuthorization {
+                creds: credentials.clone(),
+                device_code: auth.device_code,
+                interval: embassy_time::Duration::from_secs(auth.interval as u64),
+                expires_at: now + embassy_time::Duration::from_secs(auth.expires_in as u64),
+            });
+
+        Ok(picoserve::response::json::Json(DeviceCodeResponse {
+            user_code: auth.user_code,
+            verification_uri: auth.verification_uri,
+            verification_uri_complete: auth.verification_uri_complete,
+            interval: auth.interval,
+            expires_in: auth.expires_in,
+        }))
+    }
+    #[cfg(not(target_arch = "xtensa"))]
+    {
+        // Answered by the mock authorization server of web-test
+        let _ = config;
+        let auth = crate::mock_oauth::ENDPOINT
+            .lock()
+            .unwrap()
+            .device_authorization(std::time::Instant::now());
+        Ok(picoserve::response::json::Json(DeviceCodeResponse {
+            user_code: heapless::String::try_from(auth.user_code).unwrap(),
+            verification_uri: heapless::String::try_from(auth.verification_uri).unwrap(),
+            verification_uri_complete: heapless::String::try_from(auth.verification_uri_complete)
+                .ok(),
+            interval: auth.interval,
+            expires_in: auth.expires_in,
+        }))
+    }
+}
+
+async fn poll_device_authorization(
+    #[cfg(target_arch = "xtensa")] http_client_mutex: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        crate::networking::HttpClients<'static>,
+    >,
+    #[cfg(target_arch = "xtensa")] req_buffer_mutex: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        &'static mut [u8; 8192],
+    >,
+    #[cfg(target_arch = "xtensa")] flash: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        storage::FlashStorage<'static>,
+    >,
+) -> Result<picoserve::response::json::Json<DeviceFlowStatus>, AppError> {
+    #[cfg(target_arch = "xtensa")]
+    {
+        use crate::oauth::TokenResult;
+
+        let mut pending_guard = crate::oauth::PENDING_AUTHORIZATION.lock().await;
+        let Some(pending) = pending_guard.as_mut() else {
+            return Ok(picoserve::response::json::Json(DeviceFlowStatus::Expired));
+        };
+        if pending.expires_at < embassy_time::Instant::now() {
+            *pending_guard = None;
+            return Ok(picoserve::response::json::Json(DeviceFlowStatus::Expired));
+        }
+        let storage::CaldavAuth::OAuth2(config) = &mut pending.creds.auth else {
+            return Err(crate::networking::NetworkError::AuthorizationFailed.into());
+        };
+
+        let mut buf_guard = req_buffer_mutex.lock().await;
+
+        let mut clients = http_client_mutex.lock().await;
+
+        let status = match crate::oauth::poll_device_token(
+            &mut clients.tls,
+            config,
+            &pending.device_code,
+            *buf_guard,
+        )
+        .await?
+        {
+            TokenResult::Pending => DeviceFlowStatus::Pending,
+            TokenResult::SlowDown => {
+                pending.interval += embassy_time::Duration::from_secs(5);
+                DeviceFlowStatus::SlowDown {
+                    interval: pending.interval.as_secs() as u32,
+                }
+            }
+            TokenResult::Denied => {
+                *pending_guard = None;
+                DeviceFlowStatus::Denied
+            }
+            TokenResult::Expired => {
+                *pending_guard = None;
+                DeviceFlowStatus::Expired
+            }
+            // Reported as an error by the poll
+            TokenResult::Rejected => {
+                *pending_guard = None;
+                return Err(crate::networking::NetworkError::AuthorizationFailed.into());
+            }
+            TokenResult::Granted {
+                access_token,
+                refresh_token,
+                expires_in,
+            } => {
+                let Some(refresh_token) = refresh_token else {
+                    crate::defmt::error!("The token endpoint didn't return a refresh token");
+                    *pending_guard = None;
+                    return Err(crate::networking::NetworkError::AuthorizationFailed.into());
+                };
+                config.refresh_token = refresh_token;
+                crate::oauth::cache_access_token(config, &access_token, expires_in);
+
+                let creds = pending_guard.take().unwrap().creds;
+                let mut nvs = storage::read_config(flash).await.unwrap_or_default();
+                nvs.caldav = Some(creds);
+                storage::write_config(flash, nvs).await?;
+                DeviceFlowStatus::Complete
+            }
+        };
+        Ok(picoserve::response::json::Json(status))
+    }
+    #[cfg(not(target_arch = "xtensa"))]
+    {
+        use crate::oauth_messages::TokenResult;
+
+        let mut endpoint = crate::mock_oauth::ENDPOINT.lock().unwrap();
+        let body = endpoint
+            .token(
+                crate::oauth_messages::DEVICE_CODE_GRANT,
+                crate::mock_oauth::DEVICE_CODE,
+                std::time::Instant::now(),
+            )
+            .to_body();
+        let status = match crate::oauth_messages::parse_token_body(&body) {
+            Some(TokenResult::Pending) => DeviceFlowStatus::Pending,
+            Some(TokenResult::SlowDown) => DeviceFlowStatus::SlowDown {
+                interval: endpoint.interval().as_secs() as u32,
+            },
+            Some(TokenResult::Denied) => DeviceFlowStatus::Denied,
+            Some(TokenResult::Expired) => DeviceFlowStatus::Expired,
+            Some(TokenResult::Granted { .. }) => DeviceFlowStatus::Complete,
+            Some(TokenResult::Rejected) | None => {
+                return Err(crate::networking::NetworkError::AuthorizationFailed.into());
+            }
+        };
+        Ok(picoserve::response::json::Json(status))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct DeviceCodeResponse {
+    user_code: heapless::String<16>,
+    verification_uri: heapless::String<128>,
+    verification_uri_complete: Option<heapless::String<192>>,
+    interval: u32,
+    expires_in: u32,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "status")]
+enum DeviceFlowStatus {
+    Pending,
+    SlowDown { interval: u32 },
+    Complete,
+    Denied,
+    Expired,
+}
+
 #[derive(serde::Deserialize)]
 struct EndpointRequest {
     url: String,
+    /// Same as [`storage::CaldavCreds::allow_http`], the account isn't saved yet
+    #[serde(default)]
+    allow_http: bool,
 }
 
 #[derive(serde::Serialize)]
@@ -363,6 +939,392 @@ struct EndpointResponse {
     endpoint: heapless::String<{ MAX_URL_LEN }>,
 }
 
+/// Shows which TLS version and cipher the calendar server picks for the configured
+/// minimum version, from a probe handshake next to the calendar connections
+async fn tls_diagnostics(
+    #[cfg(target_arch = "xtensa")] flash: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        storage::FlashStorage<'static>,
+    >,
+    #[cfg(target_arch = "xtensa")] net_stack: embassy_net::Stack<'static>,
+) -> Result<picoserve::response::json::Json<TlsDiagnostics>, crate::networking::NetworkError> {
+    #[cfg(target_arch = "xtensa")]
+    {
+        use crate::networking::NetworkError;
+
+        let nvs = storage::read_config(flash).await.unwrap_or_default();
+        let url = match (&nvs.caldav, nvs.ics_feeds.first()) {
+            (Some(caldav), _) => String::from(caldav.url.as_str()),
+            (None, Some(feed)) => crate::networking::ics_https_url(&feed.url),
+            (None, None) => return Err(NetworkError::WrongUrl),
+        };
+        let uri = fluent_uri::Uri::parse(url.as_str()).map_err(|_| NetworkError::WrongUrl)?;
+        let authority = uri.authority().ok_or(NetworkError::WrongUrl)?;
+        let default_port = match uri.scheme().as_str() {
+            scheme if scheme.eq_ignore_ascii_case("http") => 80,
+            _ => 443,
+        };
+        let port = authority
+            .port_to_u16()
+            .map_err(|_| NetworkError::WrongUrl)?
+            .unwrap_or(default_port);
+        let host = authority.host();
+
+        let negotiated =
+            crate::tls_probe::probe(net_stack, host, port, nvs.tls_min_version).await?;
+        #[cfg(feature = "defmt")]
+        crate::defmt::info!(
+            "{} negotiates {} with {}",
+            host,
+            negotiated.version_name(),
+            negotiated.cipher_name().as_str()
+        );
+
+        let dns = crate::dns_cache::cache_stats();
+        Ok(picoserve::response::json::Json(TlsDiagnostics {
+            host: heapless::String::try_from(host).map_err(|_| NetworkError::WrongUrl)?,
+            min_version: nvs.tls_min_version,
+            probe_version: negotiated.version_name(),
+            probe_cipher: negotiated.cipher_name(),
+            probe_accepted: negotiated.accepted(nvs.tls_min_version),
+            dns_cache_hits: dns.hits,
+            dns_saved_ms: dns.saved_ms,
+        }))
+    }
+    #[cfg(not(target_arch = "xtensa"))]
+    {
+        let nvs = storage::read_config().await.unwrap_or_default();
+        Ok(picoserve::response::json::Json(TlsDiagnostics {
+            host: heapless::String::try_from("caldav.example.com").unwrap(),
+            min_version: nvs.tls_min_version,
+            probe_version: "TLS 1.2",
+            probe_cipher: heapless::String::try_from("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")
+                .unwrap(),
+            probe_accepted: nvs.tls_min_version == storage::TlsVersion::Tls1_2,
+            dns_cache_hits: 2,
+            dns_saved_ms: 180,
+        }))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TlsDiagnostics {
+    host: heapless::String<{ MAX_ORIGIN_LEN }>,
+    min_version: storage::TlsVersion,
+    /// What the server answered to the ClientHello of [`crate::tls_probe`], which offers
+    /// the same versions and suites as the calendar connections. The calendar connections
+    /// can't report what they negotiated.
+    probe_version: &'static str,
+    probe_cipher: heapless::String<48>,
+    /// False if the server picks a version below the configured minimum
+    probe_accepted: bool,
+    /// Lookups of the last refresh answered from the DNS cache and the time that saved
+    dns_cache_hits: u32,
+    dns_saved_ms: u32,
+}
+
+/// Progress of the trial connection to a network before it is saved
+#[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiTrial {
+    Idle,
+    Testing,
+    Connected,
+    /// The 4-way handshake or the WPA3 authentication failed
+    WrongPassword,
+    /// The Wi-Fi driver refused the station settings, like a password of the wrong length
+    InvalidConfig,
+    /// The network refused the device for another reason, like a full access point or an
+    /// unsupported security mode
+    Rejected,
+    /// No answer from the network before the connect timeout
+    Timeout,
+    NotFound,
+    /// Joined, but no address from DHCP
+    NoDhcp,
+    /// Only the access point of the portal has a free station interface for the trial
+    Unavailable,
+}
+
+/// Security of a scanned network, the portal asks for a password unless it is open
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(
+    not(target_arch = "xtensa"),
+    allow(dead_code, reason = "the mock scan doesn't list every kind")
+)]
+enum WifiAuth {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Wpa3,
+    Enterprise,
+    Other,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct ScannedNetwork {
+    ssid: heapless::String<32>,
+    rssi: i8,
+    channel: u8,
+    auth: WifiAuth,
+}
+
+#[derive(serde::Deserialize)]
+struct TlsTrustRequest {
+    ca_pem: String,
+    exclusive: bool,
+    /// Fingerprint of the CalDAV server's leaf certificate, hex with optional colons
+    leaf_sha256: Option<heapless::String<95>>,
+}
+
+#[derive(serde::Serialize)]
+struct TlsTrustResponse {
+    /// SHA-256 of the uploaded certificates, so they can be told apart on the page
+    fingerprints: Vec<heapless::String<64>>,
+    exclusive: bool,
+    leaf_sha256: Option<heapless::String<64>>,
+}
+
+impl TlsTrustResponse {
+    fn new(trust: &storage::TlsTrust) -> Self {
+        let fingerprints = pem_certificates(&trust.ca_pem)
+            .unwrap_or_default()
+            .iter()
+            .map(|der| sha256_hex(der))
+            .collect();
+        Self {
+            fingerprints,
+            exclusive: trust.exclusive,
+            leaf_sha256: trust.leaf_sha256.as_ref().map(hex),
+        }
+    }
+}
+
+/// Decodes the DER bodies of the `CERTIFICATE` blocks in a PEM bundle
+fn pem_certificates(pem: &str) -> Option<Vec<Vec<u8>>> {
+    use base64::Engine;
+
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(BEGIN) {
+        let body = &rest[start + BEGIN.len()..];
+        let end = body.find(END)?;
+        let encoded: String = body[..end]
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .collect();
+        certificates.push(
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .ok()?,
+        );
+        rest = &body[end + END.len()..];
+    }
+    Some(certificates)
+}
+
+fn sha256_hex(data: &[u8]) -> heapless::String<64> {
+    use sha2::Digest;
+
+    hex(&sha2::Sha256::digest(data).into())
+}
+
+fn hex(digest: &[u8; 32]) -> heapless::String<64> {
+    use core::fmt::Write;
+
+    let mut hex = heapless::String::new();
+    for byte in digest {
+        // 32 bytes always fit
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Parses a SHA-256 fingerprint as shown by browsers and `openssl x509 -fingerprint`, with or
+/// without the colons
+fn parse_fingerprint(text: &str) -> Option<[u8; 32]> {
+    let mut digits = text.bytes().filter(|&b| b != b':');
+    let mut fingerprint = [0; 32];
+    for byte in &mut fingerprint {
+        let mut digit = || (digits.next()? as char).to_digit(16);
+        *byte = (digit()? << 4 | digit()?) as u8;
+    }
+    digits.next().is_none().then_some(fingerprint)
+}
+
+/// Keeps the strongest access point of each network, the strongest network first
+fn strongest_per_ssid(found: impl IntoIterator<Item = ScannedNetwork>) -> Vec<ScannedNetwork> {
+    let mut networks: Vec<ScannedNetwork> = Vec::new();
+    // Hidden networks don't announce their SSID
+    for network in found.into_iter().filter(|network| !network.ssid.is_empty()) {
+        match networks.iter_mut().find(|other| other.ssid == network.ssid) {
+            Some(other) if other.rssi < network.rssi => *other = network,
+            Some(_) => {}
+            None => networks.push(network),
+        }
+    }
+    networks.sort_unstable_by_key(|network| core::cmp::Reverse(network.rssi));
+    networks
+}
+
+async fn wifi_scan() -> Result<picoserve::response::json::Json<Vec<ScannedNetwork>>, AppError> {
+    #[cfg(target_arch = "xtensa")]
+    {
+        use esp_radio::wifi::AuthMethod;
+
+        // A scan visits every channel, which takes a few seconds
+        let found =
+            embassy_time::with_timeout(embassy_time::Duration::from_secs(10), crate::wifi::scan())
+                .await
+                .map_err(|_| AppError::WifiScan)?
+                .map_err(|_| AppError::WifiScan)?;
+        let found = found.into_iter().filter_map(|info| {
+            Some(ScannedNetwork {
+                ssid: heapless::String::try_from(info.ssid.as_str()).ok()?,
+                rssi: info.signal_strength,
+                channel: info.channel,
+                auth: match info.auth_method {
+                    None | Some(AuthMethod::None) => WifiAuth::Open,
+                    Some(AuthMethod::Wep) => WifiAuth::Wep,
+                    Some(AuthMethod::Wpa) => WifiAuth::Wpa,
+                    Some(AuthMethod::Wpa2Personal | AuthMethod::WpaWpa2Personal) => WifiAuth::Wpa2,
+                    Some(AuthMethod::Wpa3Personal | AuthMethod::Wpa2Wpa3Personal) => WifiAuth::Wpa3,
+                    Some(AuthMethod::Wpa2Enterprise) => WifiAuth::Enterprise,
+                    #[allow(unreachable_patterns)]
+                    Some(_) => WifiAuth::Other,
+                },
+            })
+        });
+        Ok(picoserve::response::json::Json(strongest_per_ssid(found)))
+    }
+    #[cfg(not(target_arch = "xtensa"))]
+    {
+        let network = |ssid: &str, rssi, channel, auth| ScannedNetwork {
+            ssid: heapless::String::try_from(ssid).unwrap(),
+            rssi,
+            channel,
+            auth,
+        };
+        Ok(picoserve::response::json::Json(strongest_per_ssid([
+            network("Office", -48, 6, WifiAuth::Wpa2),
+            network("Office", -71, 11, WifiAuth::Wpa2),
+            network("Home", -63, 1, WifiAuth::Wpa3),
+            network("Office-Guest", -55, 6, WifiAuth::Open),
+            network("eduroam", -80, 36, WifiAuth::Enterprise),
+            network("", -66, 11, WifiAuth::Wpa2),
+        ])))
+    }
+}
+
+/// A single DNS label, the name is answered as `<hostname>.local`
+fn valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// A host address of its subnet, with the gateway inside the subnet
+fn valid_static_ip(ip: &storage::StaticIpv4) -> bool {
+    use core::net::Ipv4Addr;
+
+    if !(1..=32).contains(&ip.prefix_len) {
+        return false;
+    }
+    let mask = u32::MAX << (32 - ip.prefix_len);
+    let usable = |address: &Ipv4Addr| {
+        !(address.is_unspecified()
+            || address.is_broadcast()
+            || address.is_multicast()
+            || address.is_loopback())
+    };
+    let host = ip.address.to_bits() & !mask;
+    // /31 and /32 have no network and broadcast address
+    let host_valid = ip.prefix_len >= 31 || (host != 0 && host != !mask);
+    let same_subnet = |address: &Ipv4Addr| (address.to_bits() ^ ip.address.to_bits()) & mask == 0;
+
+    usable(&ip.address)
+        && host_valid
+        && ip
+            .gateway
+            .as_ref()
+            .is_none_or(|gateway| usable(gateway) && *gateway != ip.address && same_subnet(gateway))
+        && ip.dns_servers.iter().all(usable)
+}
+
+/// The list page doesn't know the saved passwords, an empty one keeps the stored password of
+/// the network
+fn merge_wifi_passwords(
+    networks: Vec<storage::WifiCreds>,
+    stored: &[storage::WifiCreds],
+) -> Vec<storage::WifiCreds> {
+    networks
+        .into_iter()
+        .map(|mut network| {
+            if network.password.is_empty()
+                && let Some(saved) = stored.iter().find(|saved| saved.ssid == network.ssid)
+            {
+                network.password = saved.password.clone();
+            }
+            network
+        })
+        .collect()
+}
+
+/// New networks and changed passwords or addresses are tried before the list is saved
+fn untried(network: &storage::WifiCreds, stored: &[storage::WifiCreds]) -> bool {
+    !stored.iter().any(|saved| {
+        saved.ssid == network.ssid
+            && saved.password == network.password
+            && saved.static_ip == network.static_ip
+    })
+}
+
+async fn save_tls_trust_handler(
+    #[cfg(target_arch = "xtensa")] flash: &'static embassy_sync::mutex::Mutex<
+        embassy_sync::blocking_mutex::raw::NoopRawMutex,
+        storage::FlashStorage<'static>,
+    >,
+    body: TlsTrustRequest,
+) -> Result<picoserve::response::json::Json<TlsTrustResponse>, picoserve::response::StatusCode> {
+    if body.ca_pem.len() > storage::MAX_CA_PEM_LEN {
+        return Err(picoserve::response::StatusCode::BAD_REQUEST);
+    }
+    let certificates =
+        pem_certificates(&body.ca_pem).ok_or(picoserve::response::StatusCode::BAD_REQUEST)?;
+    // Without any certificate an exclusive store would reject every server
+    if body.exclusive && certificates.is_empty() {
+        return Err(picoserve::response::StatusCode::BAD_REQUEST);
+    }
+
+    let leaf_sha256 = match body.leaf_sha256.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(text) => {
+            Some(parse_fingerprint(text).ok_or(picoserve::response::StatusCode::BAD_REQUEST)?)
+        }
+    };
+
+    let trust = storage::TlsTrust {
+        ca_pem: body.ca_pem,
+        exclusive: body.exclusive,
+        leaf_sha256,
+    };
+    let response = TlsTrustResponse::new(&trust);
+
+    #[cfg(target_arch = "xtensa")]
+    let written = storage::write_tls_trust(flash, trust).await;
+    #[cfg(not(target_arch = "xtensa"))]
+    let written = storage::write_tls_trust(trust).await;
+    written.map_err(|_| picoserve::response::StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(picoserve::response::json::Json(response))
+}
+
 async fn config_page_handler() -> impl picoserve::response::IntoResponse {
     (
         [
@@ -374,6 +1336,11 @@ async fn config_page_handler() -> impl picoserve::response::IntoResponse {
     )
 }
 
+/// Anything but the expected answer makes the OS open the portal in its sign-in window
+async fn captive_portal_redirect() -> impl picoserve::response::IntoResponse {
+    picoserve::response::Redirect::to(PORTAL_URL)
+}
+
 async fn display_config_page_handler() -> impl picoserve::response::IntoResponse {
     (
         [
@@ -404,7 +1371,7 @@ async fn save_caldav_handler(
         Err(err) => {
             #[cfg(feature = "defmt")]
             crate::defmt::error!("Failed to parse URL: {}", crate::defmt::Debug2Format(&err));
-            return picoserve::response::StatusCode::BAD_REQUEST;
+            return Ok(picoserve::response::StatusCode::BAD_REQUEST);
         }
     }
 
@@ -416,10 +1383,10 @@ async fn save_caldav_handler(
     nvs.caldav = Some(resp_caldav);
 
     #[cfg(target_arch = "xtensa")]
-    storage::write_config(flash, nvs).await;
+    let written = storage::write_config(flash, nvs).await;
     #[cfg(not(target_arch = "xtensa"))]
-    storage::write_config(nvs).await;
-    picoserve::response::StatusCode::OK
+    let written = storage::write_config(nvs).await;
+    written.map(|()| picoserve::response::StatusCode::OK)
 }
 
 #[derive(serde::Serialize, PartialEq, Clone, Copy)]
@@ -448,7 +1415,8 @@ mod xtensa {
         [const { StaticCell::new() }; WEB_TASK_POOL_SIZE];
     static TCP_TX_BUFFERS: [StaticCell<[u8; 1024]>; WEB_TASK_POOL_SIZE] =
         [const { StaticCell::new() }; WEB_TASK_POOL_SIZE];
-    static HTTP_BUFFERS: [StaticCell<[u8; 2048]>; WEB_TASK_POOL_SIZE] =
+    // Fits the headers and a JSON encoded PEM bundle of `storage::MAX_CA_PEM_LEN`
+    static HTTP_BUFFERS: [StaticCell<[u8; 6144]>; WEB_TASK_POOL_SIZE] =
         [const { StaticCell::new() }; WEB_TASK_POOL_SIZE];
 
     #[cfg_attr(target_arch = "xtensa", embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE))]
@@ -463,7 +1431,7 @@ mod xtensa {
         #[allow(clippy::large_stack_frames, reason = "false positive")]
         let tcp_tx_buffer = TCP_TX_BUFFERS[task_id].init_with(|| [0; 1024]);
         #[allow(clippy::large_stack_frames, reason = "false positive")]
-        let http_buffer = HTTP_BUFFERS[task_id].init_with(|| [0; 2048]);
+        let http_buffer = HTTP_BUFFERS[task_id].init_with(|| [0; 6144]);
 
         picoserve::Server::new(app, &CONFIG, http_buffer)
             .listen_and_serve(task_id, stack, port, tcp_rx_buffer, tcp_tx_buffer)
@@ -471,3 +1439,230 @@ mod xtensa {
             .into_never()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
+
+    use super::*;
+
+    fn static_ip(
+        address: [u8; 4],
+        prefix_len: u8,
+        gateway: Option<[u8; 4]>,
+    ) -> storage::StaticIpv4 {
+        storage::StaticIpv4 {
+            address: Ipv4Addr::from(address),
+            prefix_len,
+            gateway: gateway.map(Ipv4Addr::from),
+            dns_servers: heapless::Vec::new(),
+        }
+    }
+
+    #[test]
+    fn static_ip_is_a_host_of_its_subnet() {
+        assert!(valid_static_ip(&static_ip(
+            [192, 168, 1, 10],
+            24,
+            Some([192, 168, 1, 1])
+        )));
+        assert!(valid_static_ip(&static_ip([10, 1, 2, 3], 8, None)));
+        // network and broadcast address
+        assert!(!valid_static_ip(&static_ip([192, 168, 1, 0], 24, None)));
+        assert!(!valid_static_ip(&static_ip([192, 168, 1, 255], 24, None)));
+        assert!(!valid_static_ip(&static_ip([10, 0, 0, 4], 30, None)));
+        assert!(!valid_static_ip(&static_ip([10, 0, 0, 7], 30, None)));
+        assert!(valid_static_ip(&static_ip(
+            [10, 0, 0, 6],
+            30,
+            Some([10, 0, 0, 5])
+        )));
+        assert!(!valid_static_ip(&static_ip([192, 168, 1, 10], 0, None)));
+        assert!(!valid_static_ip(&static_ip([192, 168, 1, 10], 33, None)));
+        assert!(!valid_static_ip(&static_ip([0, 0, 0, 0], 24, None)));
+        assert!(!valid_static_ip(&static_ip([127, 0, 0, 1], 8, None)));
+    }
+
+    #[test]
+    fn point_to_point_subnets_use_every_address() {
+        // RFC 3021, both addresses of a /31 are hosts
+        assert!(valid_static_ip(&static_ip(
+            [10, 0, 0, 0],
+            31,
+            Some([10, 0, 0, 1])
+        )));
+        assert!(valid_static_ip(&static_ip(
+            [10, 0, 0, 1],
+            31,
+            Some([10, 0, 0, 0])
+        )));
+        assert!(valid_static_ip(&static_ip([10, 0, 0, 5], 32, None)));
+        // a /32 has no room for a gateway
+        assert!(!valid_static_ip(&static_ip(
+            [10, 0, 0, 5],
+            32,
+            Some([10, 0, 0, 1])
+        )));
+    }
+
+    #[test]
+    fn gateway_is_inside_the_subnet() {
+        assert!(!valid_static_ip(&static_ip(
+            [192, 168, 1, 10],
+            24,
+            Some([192, 168, 2, 1])
+        )));
+        assert!(!valid_static_ip(&static_ip(
+            [10, 0, 0, 6],
+            30,
+            Some([10, 0, 0, 9])
+        )));
+        assert!(!valid_static_ip(&static_ip(
+            [192, 168, 1, 10],
+            24,
+            Some([192, 168, 1, 10])
+        )));
+        assert!(!valid_static_ip(&static_ip(
+            [192, 168, 1, 10],
+            24,
+            Some([255, 255, 255, 255])
+        )));
+        assert!(valid_static_ip(&static_ip(
+            [192, 168, 1, 10],
+            23,
+            Some([192, 168, 0, 1])
+        )));
+    }
+
+    #[test]
+    fn dns_servers_are_usable() {
+        let mut ip = static_ip([192, 168, 1, 10], 24, Some([192, 168, 1, 1]));
+        ip.dns_servers.push(Ipv4Addr::new(9, 9, 9, 9)).unwrap();
+        assert!(valid_static_ip(&ip));
+        ip.dns_servers.push(Ipv4Addr::new(224, 0, 0, 251)).unwrap();
+        assert!(!valid_static_ip(&ip));
+    }
+
+    #[test]
+    fn hostname_is_a_single_label() {
+        assert!(valid_hostname("kitchen"));
+        assert!(valid_hostname("cal-display-2"));
+        assert!(valid_hostname("a"));
+        assert!(!valid_hostname(""));
+        assert!(!valid_hostname("-kitchen"));
+        assert!(!valid_hostname("kitchen-"));
+        assert!(!valid_hostname("-"));
+        assert!(!valid_hostname("kitchen.local"));
+        assert!(!valid_hostname("kitchen_display"));
+        assert!(!valid_hostname("küche"));
+    }
+
+    fn scanned(ssid: &str, rssi: i8, channel: u8) -> ScannedNetwork {
+        ScannedNetwork {
+            ssid: heapless::String::try_from(ssid).unwrap(),
+            rssi,
+            channel,
+            auth: WifiAuth::Wpa2,
+        }
+    }
+
+    #[test]
+    fn scan_keeps_the_strongest_access_point() {
+        let networks = strongest_per_ssid([
+            scanned("Office", -71, 11),
+            scanned("Home", -63, 1),
+            scanned("Office", -48, 6),
+            scanned("Office", -80, 1),
+            scanned("Home", -63, 13),
+        ]);
+        let found: Vec<_> = networks
+            .iter()
+            .map(|network| (network.ssid.as_str(), network.rssi, network.channel))
+            .collect();
+        // Of equally strong ones the first is kept
+        assert_eq!(found, [("Office", -48, 6), ("Home", -63, 1)]);
+    }
+
+    #[test]
+    fn scan_drops_hidden_networks() {
+        let networks = strongest_per_ssid([
+            scanned("", -40, 6),
+            scanned("Home", -63, 1),
+            scanned("", -50, 11),
+        ]);
+        assert_eq!(networks.len(), 1);
+        assert_eq!(networks[0].ssid, "Home");
+        assert!(strongest_per_ssid([scanned("", -40, 6)]).is_empty());
+    }
+
+    fn creds(ssid: &str, password: &str) -> storage::WifiCreds {
+        storage::WifiCreds {
+            ssid: heapless::String::try_from(ssid).unwrap(),
+            password: heapless::String::try_from(password).unwrap(),
+            priority: 0,
+            static_ip: None,
+        }
+    }
+
+    #[test]
+    fn fingerprints_with_and_without_colons() {
+        let plain = "d0e885ad9491b75d0ad9a52a5093ae5c37ca95168594743a337a2c2f352b485d";
+        let fingerprint = parse_fingerprint(plain).unwrap();
+        assert_eq!(fingerprint[..4], [0xd0, 0xe8, 0x85, 0xad]);
+        assert_eq!(hex(&fingerprint), plain);
+
+        let colons = "D0:E8:85:AD:94:91:B7:5D:0A:D9:A5:2A:50:93:AE:5C:37:CA:95:16:85:94:74:3A:\
+            33:7A:2C:2F:35:2B:48:5D";
+        assert_eq!(
+            parse_fingerprint(&colons.replace(' ', "")),
+            Some(fingerprint)
+        );
+
+        // too short, too long, not hex
+        assert_eq!(parse_fingerprint(&plain[..62]), None);
+        assert_eq!(parse_fingerprint(&[plain, "00"].concat()), None);
+        assert_eq!(parse_fingerprint(&plain.replace('d', "g")), None);
+        assert_eq!(parse_fingerprint(""), None);
+    }
+
+    #[test]
+    fn empty_password_keeps_the_stored_one() {
+        let stored = [creds("Home", "hunter22"), creds("Office", "letmein1")];
+        let merged = merge_wifi_passwords(
+            vec![
+                creds("Home", ""),
+                creds("Office", "changed1"),
+                creds("Cafe", ""),
+            ],
+            &stored,
+        );
+        let passwords: Vec<_> = merged
+            .iter()
+            .map(|network| (network.ssid.as_str(), network.password.as_str()))
+            .collect();
+        // An open network stays without a password
+        assert_eq!(
+            passwords,
+            [("Home", "hunter22"), ("Office", "changed1"), ("Cafe", "")]
+        );
+    }
+
+    #[test]
+    fn only_changed_networks_are_tried() {
+        let stored = [creds("Home", "hunter22"), creds("Office", "letmein1")];
+        let mut moved = creds("Home", "hunter22");
+        moved.static_ip = Some(storage::StaticIpv4 {
+            address: core::net::Ipv4Addr::new(192, 168, 1, 20),
+            prefix_len: 24,
+            gateway: None,
+            dns_servers: heapless::Vec::new(),
+        });
+        let mut preferred = creds("Office", "letmein1");
+        preferred.priority = 3;
+
+        assert!(!untried(&preferred, &stored));
+        assert!(untried(&creds("Office", "changed1"), &stored));
+        assert!(untried(&creds("Cafe", ""), &stored));
+        assert!(untried(&moved, &stored));
+    }
+}
@@ -8,13 +8,34 @@ use std::{string::String, vec::Vec};
 pub enum StorageError {
     #[error("Failed to read NVS")]
     ReadError,
+    #[error("Failed to write NVS")]
+    WriteError,
 }
 
+/// Upper limit for the configured NTP servers and the ones announced by DHCP
+pub const MAX_NTP_SERVERS: usize = 4;
+
+/// Upper limit for the saved Wi-Fi networks
+pub const MAX_WIFI_NETWORKS: usize = 8;
+
+/// A single DNS label of the `.local` name
+pub const MAX_HOSTNAME_LEN: usize = 63;
+
+/// Postcard ignores `#[serde(default)]`, a field added here needs a new `CONFIG_KEY` and a
+/// migration from the previous layout
 #[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
 pub struct NvsConfig {
-    pub wifi: Option<WifiCreds>,
+    /// Known networks, the one in range with the highest priority is joined
+    pub wifi: Vec<WifiCreds>,
     pub caldav: Option<CaldavCreds>,
     pub display: Option<DisplayConfig>,
+    pub ics_feeds: Vec<IcsFeed>,
+    /// Tried before the servers from DHCP and the public pool
+    pub ntp_servers: Vec<heapless::String<64>>,
+    /// Lowest TLS version accepted from the calendar servers
+    pub tls_min_version: TlsVersion,
+    /// Answered over mDNS as `<hostname>.local`, derived from the MAC if unset
+    pub hostname: Option<heapless::String<MAX_HOSTNAME_LEN>>,
 }
 
 #[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
@@ -22,6 +43,19 @@ pub struct NvsConfig {
 pub struct WifiCreds {
     pub ssid: heapless::String<32>,
     pub password: heapless::String<32>,
+    /// Higher is preferred, networks of the same priority are ordered by signal strength
+    pub priority: u8,
+    /// For networks without DHCP
+    pub static_ip: Option<StaticIpv4>,
+}
+
+#[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StaticIpv4 {
+    pub address: core::net::Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<core::net::Ipv4Addr>,
+    pub dns_servers: heapless::Vec<core::net::Ipv4Addr, 3>,
 }
 
 #[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
@@ -29,14 +63,110 @@ pub struct WifiCreds {
 pub struct CaldavCreds {
     pub url: heapless::String<128>,
     pub username: heapless::String<32>,
+    /// Only used with [`CaldavAuth::Basic`]
     pub password: heapless::String<32>,
+    pub auth: CaldavAuth,
+    /// Opt-in for `http://` URLs on a trusted LAN, the credentials are sent unencrypted
+    pub allow_http: bool,
 }
 
+/// How the device authenticates against the CalDAV server
+#[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+#[allow(
+    clippy::large_enum_variant,
+    reason = "part of the config, which is read and written as a whole"
+)]
+pub enum CaldavAuth {
+    /// HTTP basic authentication with the username and password
+    #[default]
+    Basic,
+    /// Bearer tokens obtained with the OAuth2 refresh token flow
+    OAuth2(OAuth2Config),
+}
+
+#[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct OAuth2Config {
+    pub token_endpoint: heapless::String<128>,
+    pub device_authorization_endpoint: heapless::String<128>,
+    pub client_id: heapless::String<128>,
+    pub client_secret: heapless::String<64>,
+    pub scope: heapless::String<128>,
+    /// Filled in by the device after the device authorization grant completed
+    pub refresh_token: heapless::String<512>,
+}
+
+/// A read-only `.ics` subscription, fetched with a plain GET
+#[cfg_attr(feature = "defmt", derive(crate::defmt::Format))]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct IcsFeed {
+    pub name: heapless::String<32>,
+    /// `https://` or `webcal://` URL of the feed
+    pub url: heapless::String<255>,
+    pub enabled: bool,
+}
+
+/// TLS 1.3 is always offered, 1.2 only as long as it is the minimum. mbedtls-rs can't be
+/// given a maximum.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls1_2,
+    #[serde(rename = "1.3")]
+    Tls1_3,
+}
+
+/// Longest PEM bundle that fits into a flash page next to the item header
+pub const MAX_CA_PEM_LEN: usize = 3072;
+
+/// Extra trust anchors for CalDAV servers behind an internal CA, stored apart from
+/// [`NvsConfig`] because of its size
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct TlsTrust {
+    /// PEM encoded certificates, appended to the built-in roots
+    pub ca_pem: String,
+    /// Don't trust the built-in roots, which pins the connections to `ca_pem`
+    pub exclusive: bool,
+    /// SHA-256 of the DER leaf certificate the CalDAV server has to present
+    pub leaf_sha256: Option<[u8; 32]>,
+}
+
+/// Minutes between the calendar fetches, the wakes in between redraw the kept events
+pub const DEFAULT_REFRESH_MINUTES: u16 = 30;
+
+/// Events of the day a single calendar may have, more are shown as an error
+pub const DEFAULT_MAX_EVENTS: u16 = 100;
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct DisplayConfig {
     pub displayed_hours: u8,
     pub calendars: Vec<String>,
     pub show_current_day_only: bool,
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_minutes: u16,
+    #[serde(default = "default_max_events")]
+    pub max_events: u16,
+}
+
+fn default_refresh_minutes() -> u16 {
+    DEFAULT_REFRESH_MINUTES
+}
+
+fn default_max_events() -> u16 {
+    DEFAULT_MAX_EVENTS
 }
 
 impl Default for DisplayConfig {
@@ -45,6 +175,74 @@ impl Default for DisplayConfig {
             displayed_hours: 18,
             calendars: Vec::new(),
             show_current_day_only: false,
+            refresh_minutes: DEFAULT_REFRESH_MINUTES,
+            max_events: DEFAULT_MAX_EVENTS,
+        }
+    }
+}
+
+/// The layout of [`NvsConfig`] before it grew multiple networks, feeds and the network
+/// settings. Postcard has no field names, so old configs only decode with these structs.
+#[cfg(any(target_arch = "xtensa", test))]
+mod legacy {
+    #[cfg(target_arch = "xtensa")]
+    use alloc::{string::String, vec::Vec};
+    #[cfg(not(target_arch = "xtensa"))]
+    use std::{string::String, vec::Vec};
+
+    use super::{CaldavCreds, DisplayConfig, NvsConfig, WifiCreds};
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    pub struct NvsConfigV1 {
+        pub wifi: Option<WifiCredsV1>,
+        pub caldav: Option<CaldavCredsV1>,
+        pub display: Option<DisplayConfigV1>,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    pub struct WifiCredsV1 {
+        pub ssid: heapless::String<32>,
+        pub password: heapless::String<32>,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    pub struct CaldavCredsV1 {
+        pub url: heapless::String<128>,
+        pub username: heapless::String<32>,
+        pub password: heapless::String<32>,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    pub struct DisplayConfigV1 {
+        pub displayed_hours: u8,
+        pub calendars: Vec<String>,
+        pub show_current_day_only: bool,
+    }
+
+    impl From<NvsConfigV1> for NvsConfig {
+        fn from(old: NvsConfigV1) -> Self {
+            let wifi = old.wifi.map(|wifi| WifiCreds {
+                ssid: wifi.ssid,
+                password: wifi.password,
+                priority: 0,
+                static_ip: None,
+            });
+            Self {
+                wifi: wifi.into_iter().collect(),
+                caldav: old.caldav.map(|caldav| CaldavCreds {
+                    url: caldav.url,
+                    username: caldav.username,
+                    password: caldav.password,
+                    ..Default::default()
+                }),
+                display: old.display.map(|display| DisplayConfig {
+                    displayed_hours: display.displayed_hours,
+                    calendars: display.calendars,
+                    show_current_day_only: display.show_current_day_only,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
         }
     }
 }
@@ -62,14 +260,26 @@ mod xtensa {
     pub use esp_storage::FlashStorage;
     use static_cell::StaticCell;
 
-    use super::NvsConfig;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use super::legacy::NvsConfigV1;
+    use super::{NvsConfig, StorageError, TlsTrust};
 
     const NVS_STORAGE_START: u32 = 0x9000;
     const NVS_STORAGE_SIZE: u32 = 0x6000;
     const NVS_RANGE: core::ops::Range<u32> =
         NVS_STORAGE_START..NVS_STORAGE_START + NVS_STORAGE_SIZE;
 
-    const CONFIG_KEY: u8 = 1;
+    /// The config as written by the first firmware, see [`NvsConfigV1`]
+    const CONFIG_V1_KEY: u8 = 1;
+    const TLS_TRUST_KEY: u8 = 2;
+    const SYNC_CACHE_KEY: u8 = 3;
+    const LAST_EVENTS_KEY: u8 = 4;
+    const AP_CREDENTIALS_KEY: u8 = 5;
+    const CONFIG_KEY: u8 = 6;
+    // The OAuth2 tokens don't fit into a stack buffer
+    const CONFIG_BUFFER_SIZE: usize = 4096;
 
     static FLASH: StaticCell<Mutex<NoopRawMutex, FlashStorage<'static>>> = StaticCell::new();
 
@@ -81,12 +291,64 @@ mod xtensa {
     }
 
     impl sequential_storage::map::PostcardValue<'_> for NvsConfig {}
+    impl sequential_storage::map::PostcardValue<'_> for NvsConfigV1 {}
+    impl sequential_storage::map::PostcardValue<'_> for TlsTrust {}
+    impl sequential_storage::map::PostcardValue<'_> for SyncCache {}
+    impl sequential_storage::map::PostcardValue<'_> for LastEvents {}
+    impl sequential_storage::map::PostcardValue<'_> for ApCredentials {}
 
-    pub(crate) async fn read_config(
+    /// The access point of the portal, generated on the first boot. Stored apart from
+    /// [`NvsConfig`] so it exists before anything is configured.
+    #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+    pub struct ApCredentials {
+        pub ssid: heapless::String<32>,
+        pub password: heapless::String<64>,
+    }
+
+    /// The CalDAV events of one day, kept between wakes so an unchanged calendar costs a
+    /// single request
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq)]
+    pub struct SyncCache {
+        /// Start of the cached day, the expanded recurrences are only valid for it
+        pub day_start: i64,
+        pub calendars: Vec<CachedCalendar>,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq)]
+    pub struct CachedCalendar {
+        /// Request path of the calendar collection
+        pub path: String,
+        /// RFC 6578 sync token, missing if the server doesn't support sync-collection
+        pub sync_token: Option<String>,
+        /// Fallback change marker of the collection for servers without sync tokens
+        pub ctag: Option<String>,
+        pub resources: Vec<CachedResource>,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+    pub struct CachedResource {
+        pub href: String,
+        pub etag: String,
+        pub events: Vec<vcal_parser::vevent::VEventData>,
+    }
+
+    /// The events of the last successful sync, rendered while the calendars are unreachable
+    #[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq)]
+    pub struct LastEvents {
+        /// Unix time of the sync that fetched the events
+        pub synced_at: i64,
+        pub events: Vec<vcal_parser::vevent::VEventData>,
+    }
+
+    async fn fetch_item<T>(
         flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
-    ) -> Option<NvsConfig> {
+        key: u8,
+    ) -> Option<T>
+    where
+        T: for<'a> sequential_storage::map::PostcardValue<'a>,
+    {
         let mut borrow = flash_cell.lock().await;
-        let mut data_buffer = [0u8; 2048];
+        let mut data_buffer = alloc::vec![0u8; CONFIG_BUFFER_SIZE];
 
         let async_flash = BlockingAsync::new(&mut *borrow);
 
@@ -96,21 +358,25 @@ mod xtensa {
             sequential_storage::cache::NoCache::new(),
         );
 
-        ms.fetch_item::<NvsConfig>(&mut data_buffer, &CONFIG_KEY)
+        ms.fetch_item::<T>(&mut data_buffer, &key)
             .await
             .ok()
             .flatten()
     }
 
-    pub(crate) async fn write_config(
+    async fn store_item<T>(
         flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
-        config: NvsConfig,
-    ) {
+        key: u8,
+        item: &T,
+    ) -> Result<(), StorageError>
+    where
+        T: for<'a> sequential_storage::map::PostcardValue<'a>,
+    {
         let mut borrow = flash_cell.lock().await;
 
         let async_flash = BlockingAsync::new(&mut *borrow);
 
-        let mut data_buffer = [0u8; 2048];
+        let mut data_buffer = alloc::vec![0u8; CONFIG_BUFFER_SIZE];
 
         let mut l = sequential_storage::map::MapStorage::<u8, _, _>::new(
             async_flash,
@@ -118,11 +384,90 @@ mod xtensa {
             sequential_storage::cache::NoCache::new(),
         );
 
-        l.store_item(&mut data_buffer, &CONFIG_KEY, &config)
+        l.store_item(&mut data_buffer, &key, item)
             .await
-            .unwrap();
+            .map_err(|_| StorageError::WriteError)
+    }
+
+    pub(crate) async fn read_config(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    ) -> Option<NvsConfig> {
+        if let Some(config) = fetch_item(flash_cell, CONFIG_KEY).await {
+            return Some(config);
+        }
 
+        let config = NvsConfig::from(fetch_item::<NvsConfigV1>(flash_cell, CONFIG_V1_KEY).await?);
+        crate::defmt::info!("Migrating the config of the first firmware");
+        if store_item(flash_cell, CONFIG_KEY, &config).await.is_err() {
+            crate::defmt::warn!("Failed to write the migrated config, migrating again next boot");
+        }
+        Some(config)
+    }
+
+    pub(crate) async fn write_config(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+        config: NvsConfig,
+    ) -> Result<(), StorageError> {
+        store_item(flash_cell, CONFIG_KEY, &config).await?;
         crate::defmt::info!("Config written to flash");
+        Ok(())
+    }
+
+    pub(crate) async fn read_tls_trust(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    ) -> Option<TlsTrust> {
+        fetch_item(flash_cell, TLS_TRUST_KEY).await
+    }
+
+    pub(crate) async fn write_tls_trust(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+        trust: TlsTrust,
+    ) -> Result<(), StorageError> {
+        store_item(flash_cell, TLS_TRUST_KEY, &trust).await?;
+        crate::defmt::info!("Custom certificates written to flash");
+        Ok(())
+    }
+
+    pub(crate) async fn read_sync_cache(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    ) -> Option<SyncCache> {
+        fetch_item(flash_cell, SYNC_CACHE_KEY).await
+    }
+
+    /// A busy day may not fit into a flash page, then the next wake fetches everything
+    pub(crate) async fn write_sync_cache(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+        cache: &SyncCache,
+    ) -> Result<(), StorageError> {
+        store_item(flash_cell, SYNC_CACHE_KEY, cache).await
+    }
+
+    pub(crate) async fn read_last_events(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    ) -> Option<LastEvents> {
+        fetch_item(flash_cell, LAST_EVENTS_KEY).await
+    }
+
+    pub(crate) async fn write_last_events(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+        last: &LastEvents,
+    ) -> Result<(), StorageError> {
+        store_item(flash_cell, LAST_EVENTS_KEY, last).await
+    }
+
+    pub(crate) async fn read_ap_credentials(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+    ) -> Option<ApCredentials> {
+        fetch_item(flash_cell, AP_CREDENTIALS_KEY).await
+    }
+
+    pub(crate) async fn write_ap_credentials(
+        flash_cell: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+        credentials: &ApCredentials,
+    ) -> Result<(), StorageError> {
+        store_item(flash_cell, AP_CREDENTIALS_KEY, credentials).await?;
+        crate::defmt::info!("Access point credentials written to flash");
+        Ok(())
     }
 }
 
@@ -134,10 +479,106 @@ mod not_xtensa {
         Some(NvsConfig::default())
     }
 
-    pub async fn write_config(config: NvsConfig) {
+    pub async fn write_config(config: NvsConfig) -> Result<(), StorageError> {
         crate::defmt::info!(
             "Mock writing config: {:?}",
             crate::defmt::Debug2Format(&config)
         );
+        Ok(())
+    }
+
+    pub async fn read_tls_trust() -> Option<TlsTrust> {
+        None
+    }
+
+    pub async fn write_tls_trust(trust: TlsTrust) -> Result<(), StorageError> {
+        crate::defmt::info!(
+            "Mock writing custom certificates: {:?}",
+            crate::defmt::Debug2Format(&trust)
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::legacy::NvsConfigV1;
+    use super::*;
+
+    /// A config as the first firmware wrote it, with a network, a CalDAV account and two
+    /// calendars
+    fn v1_blob() -> Vec<u8> {
+        [
+            &[1, 4][..],
+            b"home",
+            &[8],
+            b"secret12",
+            &[1, 28],
+            b"https://cal.example.com/dav/",
+            &[4],
+            b"anna",
+            &[2],
+            b"pw",
+            &[1, 12, 2, 4],
+            b"work",
+            &[4],
+            b"home",
+            &[1],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn v1_config_migrates() {
+        let blob = v1_blob();
+        // The new layout can't read it, which is why it has its own key
+        assert!(postcard::from_bytes::<NvsConfig>(&blob).is_err());
+
+        let config = NvsConfig::from(postcard::from_bytes::<NvsConfigV1>(&blob).unwrap());
+        assert_eq!(config.wifi.len(), 1);
+        assert_eq!(config.wifi[0].ssid, "home");
+        assert_eq!(config.wifi[0].password, "secret12");
+        assert_eq!(config.wifi[0].priority, 0);
+        assert_eq!(config.wifi[0].static_ip, None);
+
+        let caldav = config.caldav.unwrap();
+        assert_eq!(caldav.url, "https://cal.example.com/dav/");
+        assert_eq!(caldav.username, "anna");
+        assert_eq!(caldav.password, "pw");
+        assert!(matches!(caldav.auth, CaldavAuth::Basic));
+        assert!(!caldav.allow_http);
+
+        let display = config.display.unwrap();
+        assert_eq!(display.displayed_hours, 12);
+        assert_eq!(display.calendars, ["work", "home"]);
+        assert!(display.show_current_day_only);
+        assert_eq!(display.refresh_minutes, DEFAULT_REFRESH_MINUTES);
+        assert_eq!(display.max_events, DEFAULT_MAX_EVENTS);
+
+        assert!(config.ics_feeds.is_empty());
+        assert!(config.ntp_servers.is_empty());
+        assert_eq!(config.tls_min_version, TlsVersion::Tls1_2);
+        assert_eq!(config.hostname, None);
+    }
+
+    #[test]
+    fn empty_v1_config_migrates() {
+        let config = NvsConfig::from(postcard::from_bytes::<NvsConfigV1>(&[0, 0, 0]).unwrap());
+        assert!(config.wifi.is_empty());
+        assert!(config.caldav.is_none());
+        assert!(config.display.is_none());
+    }
+
+    #[test]
+    fn config_round_trips() {
+        let config = NvsConfig {
+            hostname: Some(heapless::String::try_from("kitchen").unwrap()),
+            ..NvsConfig::from(postcard::from_bytes::<NvsConfigV1>(&v1_blob()).unwrap())
+        };
+        let blob = postcard::to_allocvec(&config).unwrap();
+        let decoded = postcard::from_bytes::<NvsConfig>(&blob).unwrap();
+        assert_eq!(decoded.wifi[0].ssid, "home");
+        assert_eq!(decoded.hostname.as_deref(), Some("kitchen"));
+        assert_eq!(decoded.display.unwrap().calendars, ["work", "home"]);
     }
 }
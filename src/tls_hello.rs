@@ -0,0 +1,397 @@
+//! The ClientHello and ServerHello of [`crate::tls_probe`], apart from the socket code so
+//! they can be tested on the host.
+use crate::storage::TlsVersion;
+
+pub(crate) const CONTENT_HANDSHAKE: u8 = 0x16;
+pub(crate) const CONTENT_ALERT: u8 = 0x15;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+pub(crate) const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+pub(crate) const ALERT_PROTOCOL_VERSION: u8 = 70;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+const EXT_KEY_SHARE: u16 = 0x0033;
+
+pub(crate) const VERSION_TLS1_2: u16 = 0x0303;
+const VERSION_TLS1_3: u16 = 0x0304;
+
+const GROUP_X25519: u16 = 0x001d;
+
+const TLS1_3_SUITES: [u16; 3] = [0x1301, 0x1302, 0x1303];
+// ECDHE suites first, like the mbedtls default preference list
+const TLS1_2_SUITES: [u16; 10] = [
+    0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc023, 0xc027, 0x009c, 0x009d,
+];
+const GROUPS: [u16; 3] = [GROUP_X25519, 0x0017, 0x0018];
+const SIGNATURE_ALGORITHMS: [u16; 6] = [0x0403, 0x0503, 0x0804, 0x0805, 0x0401, 0x0501];
+
+/// The parameters chosen by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Negotiated {
+    pub version: u16,
+    pub cipher: u16,
+}
+
+impl Negotiated {
+    pub(crate) fn version_name(&self) -> &'static str {
+        match self.version {
+            VERSION_TLS1_3 => "TLS 1.3",
+            VERSION_TLS1_2 => "TLS 1.2",
+            0x0302 => "TLS 1.1",
+            0x0301 => "TLS 1.0",
+            _ => "unknown",
+        }
+    }
+
+    pub(crate) fn cipher_name(&self) -> heapless::String<48> {
+        let name = match self.cipher {
+            0x1301 => "TLS_AES_128_GCM_SHA256",
+            0x1302 => "TLS_AES_256_GCM_SHA384",
+            0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+            0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+            0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+            0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+            0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305",
+            0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305",
+            0xc023 => "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA256",
+            0xc027 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA256",
+            0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+            0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+            other => return heapless::format!("0x{:04x}", other).unwrap_or_default(),
+        };
+        heapless::String::try_from(name).unwrap_or_default()
+    }
+
+    /// Whether the version is at least the configured minimum
+    pub(crate) fn accepted(&self, min: TlsVersion) -> bool {
+        let min = match min {
+            TlsVersion::Tls1_2 => VERSION_TLS1_2,
+            TlsVersion::Tls1_3 => VERSION_TLS1_3,
+        };
+        self.version >= min
+    }
+}
+
+/// Appends a big endian length placeholder and returns its position
+fn begin_length(out: &mut heapless::Vec<u8, 512>, width: usize) -> Option<usize> {
+    let position = out.len();
+    out.extend_from_slice(&[0; 3][..width]).ok()?;
+    Some(position)
+}
+
+/// Fills in a placeholder from [`begin_length`] with the bytes written since
+fn end_length(out: &mut heapless::Vec<u8, 512>, position: usize, width: usize) {
+    let len = (out.len() - position - width) as u32;
+    out[position..position + width].copy_from_slice(&len.to_be_bytes()[4 - width..]);
+}
+
+fn put_u16s(out: &mut heapless::Vec<u8, 512>, values: &[u16]) -> Option<()> {
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes()).ok()?;
+    }
+    Some(())
+}
+
+/// A ClientHello record offering TLS 1.3, and 1.2 if it is the minimum, with the suites
+/// mbedtls would offer for them. `None` only if an absurdly long host name overflows it.
+///
+/// `fill_random` provides the random, the legacy session id and the key share.
+pub(crate) fn client_hello(
+    host: &str,
+    min: TlsVersion,
+    mut fill_random: impl FnMut(&mut [u8]),
+) -> Option<heapless::Vec<u8, 512>> {
+    let tls1_2 = min == TlsVersion::Tls1_2;
+
+    let mut out = heapless::Vec::<u8, 512>::new();
+    out.extend_from_slice(&[CONTENT_HANDSHAKE, 0x03, 0x01])
+        .ok()?;
+    let record = begin_length(&mut out, 2)?;
+    out.push(HANDSHAKE_CLIENT_HELLO).ok()?;
+    let handshake = begin_length(&mut out, 3)?;
+
+    put_u16s(&mut out, &[VERSION_TLS1_2])?;
+    let mut random = [0u8; 32];
+    fill_random(&mut random);
+    out.extend_from_slice(&random).ok()?;
+    // A legacy session id keeps middleboxes happy with TLS 1.3
+    out.push(32).ok()?;
+    fill_random(&mut random);
+    out.extend_from_slice(&random).ok()?;
+
+    let suites = begin_length(&mut out, 2)?;
+    put_u16s(&mut out, &TLS1_3_SUITES)?;
+    if tls1_2 {
+        put_u16s(&mut out, &TLS1_2_SUITES)?;
+    }
+    end_length(&mut out, suites, 2);
+    // Only the null compression
+    out.extend_from_slice(&[1, 0]).ok()?;
+
+    let extensions = begin_length(&mut out, 2)?;
+
+    put_u16s(&mut out, &[EXT_SERVER_NAME])?;
+    let ext = begin_length(&mut out, 2)?;
+    let list = begin_length(&mut out, 2)?;
+    out.push(0).ok()?;
+    let name = begin_length(&mut out, 2)?;
+    out.extend_from_slice(host.as_bytes()).ok()?;
+    end_length(&mut out, name, 2);
+    end_length(&mut out, list, 2);
+    end_length(&mut out, ext, 2);
+
+    put_u16s(&mut out, &[EXT_SUPPORTED_GROUPS])?;
+    let ext = begin_length(&mut out, 2)?;
+    let list = begin_length(&mut out, 2)?;
+    put_u16s(&mut out, &GROUPS)?;
+    end_length(&mut out, list, 2);
+    end_length(&mut out, ext, 2);
+
+    put_u16s(&mut out, &[EXT_EC_POINT_FORMATS])?;
+    // One format, uncompressed
+    out.extend_from_slice(&[0, 2, 1, 0]).ok()?;
+
+    put_u16s(&mut out, &[EXT_SIGNATURE_ALGORITHMS])?;
+    let ext = begin_length(&mut out, 2)?;
+    let list = begin_length(&mut out, 2)?;
+    put_u16s(&mut out, &SIGNATURE_ALGORITHMS)?;
+    end_length(&mut out, list, 2);
+    end_length(&mut out, ext, 2);
+
+    put_u16s(&mut out, &[EXT_SUPPORTED_VERSIONS])?;
+    let ext = begin_length(&mut out, 2)?;
+    let list = begin_length(&mut out, 1)?;
+    put_u16s(&mut out, &[VERSION_TLS1_3])?;
+    if tls1_2 {
+        put_u16s(&mut out, &[VERSION_TLS1_2])?;
+    }
+    end_length(&mut out, list, 1);
+    end_length(&mut out, ext, 2);
+
+    // Any 32 bytes are a valid X25519 public key, the handshake never gets far
+    // enough to need the private half
+    put_u16s(&mut out, &[EXT_KEY_SHARE])?;
+    let ext = begin_length(&mut out, 2)?;
+    let list = begin_length(&mut out, 2)?;
+    put_u16s(&mut out, &[GROUP_X25519, 32])?;
+    fill_random(&mut random);
+    out.extend_from_slice(&random).ok()?;
+    end_length(&mut out, list, 2);
+    end_length(&mut out, ext, 2);
+
+    end_length(&mut out, extensions, 2);
+    end_length(&mut out, handshake, 3);
+    end_length(&mut out, record, 2);
+    Some(out)
+}
+
+/// Reads the version and cipher from a ServerHello handshake message, without the record
+/// header
+pub(crate) fn parse_server_hello(message: &[u8]) -> Option<Negotiated> {
+    let u16_at = |data: &[u8], at: usize| {
+        data.get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+
+    if *message.first()? != HANDSHAKE_SERVER_HELLO {
+        return None;
+    }
+    let body = message.get(4..)?;
+    let legacy_version = u16_at(body, 0)?;
+    // version and random
+    let session_id_len = *body.get(34)? as usize;
+    let mut at = 35 + session_id_len;
+    let cipher = u16_at(body, at)?;
+    // cipher suite and compression method
+    at += 3;
+
+    let mut version = legacy_version;
+    if let Some(extensions_len) = u16_at(body, at) {
+        at += 2;
+        let end = (at + extensions_len as usize).min(body.len());
+        while at + 4 <= end {
+            let kind = u16_at(body, at)?;
+            let len = u16_at(body, at + 2)? as usize;
+            if kind == EXT_SUPPORTED_VERSIONS {
+                version = u16_at(body, at + 4)?;
+            }
+            at += 4 + len;
+        }
+    }
+
+    Some(Negotiated { version, cipher })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        text.split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect()
+    }
+
+    /// The ServerHello of the simple 1-RTT handshake in RFC 8448, section 3
+    const RFC8448_SERVER_HELLO: &str = "02 00 00 56 03 03 a6 af 06 a4 12 18 60 dc 5e 6e 60 24 9c
+        d3 4c 95 93 0c 8a c5 cb 14 34 da c1 55 77 2e d3 e2 69 28 00 13 01 00 00 2e 00 33 00 24 00
+        1d 00 20 c9 82 88 76 11 20 95 fe 66 76 2b db f7 c6 72 e1 56 d6 cc 25 3b 83 3d f1 dd 69 b1
+        b0 4e 75 1f 0f 00 2b 00 02 03 04";
+
+    /// A TLS 1.3 ServerHello echoing a legacy session id, from "The Illustrated TLS 1.3
+    /// Connection"
+    const ECHOED_SESSION_SERVER_HELLO: &str = "02 00 00 76 03 03 70 71 72 73 74 75 76 77 78 79
+        7a 7b 7c 7d 7e 7f 80 81 82 83 84 85 86 87 88 89 8a 8b 8c 8d 8e 8f 20 e0 e1 e2 e3 e4 e5 e6
+        e7 e8 e9 ea eb ec ed ee ef f0 f1 f2 f3 f4 f5 f6 f7 f8 f9 fa fb fc fd fe ff 13 02 00 00 2e
+        00 2b 00 02 03 04 00 33 00 24 00 1d 00 20 9f d7 ad 6d cf f4 29 8d d3 f9 6d 5b 1b 2a f9 10
+        a0 53 5b 14 88 d7 f8 fa bb 34 9a 98 28 80 b6 15";
+
+    /// A TLS 1.2 ServerHello with only a renegotiation_info extension, from "The
+    /// Illustrated TLS 1.2 Connection"
+    const TLS1_2_SERVER_HELLO: &str = "02 00 00 2d 03 03 70 71 72 73 74 75 76 77 78 79 7a 7b 7c
+        7d 7e 7f 80 81 82 83 84 85 86 87 88 89 8a 8b 8c 8d 8e 8f 00 c0 13 00 00 05 ff 01 00 01 00";
+
+    #[test]
+    fn tls1_3_server_hello() {
+        let negotiated = parse_server_hello(&hex(RFC8448_SERVER_HELLO)).unwrap();
+        assert_eq!(
+            negotiated,
+            Negotiated {
+                version: VERSION_TLS1_3,
+                cipher: 0x1301,
+            }
+        );
+        assert_eq!(negotiated.version_name(), "TLS 1.3");
+        assert_eq!(negotiated.cipher_name(), "TLS_AES_128_GCM_SHA256");
+        assert!(negotiated.accepted(TlsVersion::Tls1_2));
+        assert!(negotiated.accepted(TlsVersion::Tls1_3));
+
+        let negotiated = parse_server_hello(&hex(ECHOED_SESSION_SERVER_HELLO)).unwrap();
+        assert_eq!(
+            negotiated,
+            Negotiated {
+                version: VERSION_TLS1_3,
+                cipher: 0x1302,
+            }
+        );
+    }
+
+    #[test]
+    fn tls1_2_server_hello() {
+        let negotiated = parse_server_hello(&hex(TLS1_2_SERVER_HELLO)).unwrap();
+        assert_eq!(
+            negotiated,
+            Negotiated {
+                version: VERSION_TLS1_2,
+                cipher: 0xc013,
+            }
+        );
+        assert_eq!(negotiated.version_name(), "TLS 1.2");
+        // not among the suites we offer
+        assert_eq!(negotiated.cipher_name(), "0xc013");
+        assert!(negotiated.accepted(TlsVersion::Tls1_2));
+        assert!(!negotiated.accepted(TlsVersion::Tls1_3));
+    }
+
+    #[test]
+    fn not_a_server_hello() {
+        // cut off inside the random
+        let hello = hex(RFC8448_SERVER_HELLO);
+        assert_eq!(parse_server_hello(&hello[..20]), None);
+        // a ClientHello
+        let mut client = hello.clone();
+        client[0] = HANDSHAKE_CLIENT_HELLO;
+        assert_eq!(parse_server_hello(&client), None);
+        assert_eq!(parse_server_hello(&[]), None);
+    }
+
+    /// The fields of a ClientHello record, checked against the lengths it declares
+    struct ParsedClientHello {
+        suites: Vec<u16>,
+        extensions: Vec<(u16, Vec<u8>)>,
+    }
+
+    fn parse_client_hello(record: &[u8]) -> ParsedClientHello {
+        let u16_at = |at: usize| u16::from_be_bytes([record[at], record[at + 1]]);
+        assert_eq!(record[0], CONTENT_HANDSHAKE);
+        assert_eq!(u16_at(3) as usize, record.len() - 5);
+        assert_eq!(record[5], HANDSHAKE_CLIENT_HELLO);
+        let handshake_len = u32::from_be_bytes([0, record[6], record[7], record[8]]);
+        assert_eq!(handshake_len as usize, record.len() - 9);
+        assert_eq!(u16_at(9), VERSION_TLS1_2);
+        // the random and the legacy session id
+        assert_eq!(record[43], 32);
+        let mut at = 44 + 32;
+
+        let suites_len = u16_at(at) as usize;
+        let suites = (0..suites_len / 2)
+            .map(|i| u16_at(at + 2 + 2 * i))
+            .collect();
+        at += 2 + suites_len;
+        assert_eq!(&record[at..at + 2], &[1, 0]);
+        at += 2;
+
+        let extensions_end = at + 2 + u16_at(at) as usize;
+        assert_eq!(extensions_end, record.len());
+        at += 2;
+        let mut extensions = Vec::new();
+        while at < extensions_end {
+            let len = u16_at(at + 2) as usize;
+            extensions.push((u16_at(at), record[at + 4..at + 4 + len].to_vec()));
+            at += 4 + len;
+        }
+        assert_eq!(at, extensions_end);
+        ParsedClientHello { suites, extensions }
+    }
+
+    fn extension(hello: &ParsedClientHello, kind: u16) -> Option<&[u8]> {
+        hello
+            .extensions
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    #[test]
+    fn client_hello_offers_both_versions() {
+        let record = client_hello("caldav.example.com", TlsVersion::Tls1_2, |buf| {
+            buf.fill(0xab)
+        })
+        .unwrap();
+        let hello = parse_client_hello(&record);
+        assert_eq!(&hello.suites[..3], &TLS1_3_SUITES);
+        assert_eq!(&hello.suites[3..], &TLS1_2_SUITES);
+
+        let name = extension(&hello, EXT_SERVER_NAME).unwrap();
+        assert_eq!(&name[..5], &[0, 21, 0, 0, 18]);
+        assert_eq!(&name[5..], b"caldav.example.com");
+        assert_eq!(
+            extension(&hello, EXT_SUPPORTED_VERSIONS),
+            Some(&[4, 0x03, 0x04, 0x03, 0x03][..])
+        );
+        let key_share = extension(&hello, EXT_KEY_SHARE).unwrap();
+        assert_eq!(&key_share[..6], &[0, 36, 0, 0x1d, 0, 32]);
+        assert_eq!(&key_share[6..], &[0xab; 32]);
+    }
+
+    #[test]
+    fn client_hello_respects_the_minimum() {
+        let record = client_hello("example.com", TlsVersion::Tls1_3, |buf| buf.fill(1)).unwrap();
+        let hello = parse_client_hello(&record);
+        assert_eq!(hello.suites, TLS1_3_SUITES);
+        assert_eq!(
+            extension(&hello, EXT_SUPPORTED_VERSIONS),
+            Some(&[2, 0x03, 0x04][..])
+        );
+    }
+
+    #[test]
+    fn client_hello_rejects_huge_host_names() {
+        let host = "a".repeat(400);
+        assert!(client_hello(&host, TlsVersion::Tls1_2, |buf| buf.fill(0)).is_none());
+    }
+}
@@ -0,0 +1,357 @@
+//! Checks the SHA-256 of the server's leaf certificate against a pin while the handshake
+//! runs.
+//!
+//! mbedtls-rs has no verify callback and no accessor for the peer certificate, so the
+//! check reads the records the server sends before they reach the TLS session. Only TLS 1.2
+//! sends the Certificate message in the clear, a server negotiating TLS 1.3 or resuming a
+//! session can't be checked and is treated as not matching.
+use sha2::{Digest, Sha256};
+
+use crate::tls_hello::{
+    CONTENT_ALERT, CONTENT_HANDSHAKE, HANDSHAKE_SERVER_HELLO, VERSION_TLS1_2, parse_server_hello,
+};
+
+const HANDSHAKE_CERTIFICATE: u8 = 11;
+const HANDSHAKE_SERVER_HELLO_DONE: u8 = 14;
+
+/// Longest ServerHello kept for [`parse_server_hello`], a P-384 key share still fits
+const SERVER_HELLO_MAX: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PinCheck {
+    /// The leaf certificate hasn't arrived yet
+    Pending,
+    Matched,
+    /// The leaf has a different fingerprint, or the server sent no certificate
+    Mismatched,
+    /// The server negotiated TLS 1.3 or skipped the certificate, it can't be checked
+    Unverifiable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// Collecting the 4 byte handshake header
+    Header,
+    /// Buffering the ServerHello for its version
+    ServerHello,
+    /// Collecting the lengths of the certificate list and of its first entry
+    CertificateLengths,
+    /// Hashing the leaf certificate
+    Leaf,
+    /// Passing over a message that isn't checked
+    Skip,
+}
+
+/// Follows the server's side of a handshake, fed with the bytes as they are read
+pub(crate) struct LeafPin {
+    pin: [u8; 32],
+    result: PinCheck,
+    /// Record header, collected across reads
+    record_header: [u8; 5],
+    record_header_len: usize,
+    record_type: u8,
+    /// Bytes left in the current record
+    record_left: usize,
+    step: Step,
+    /// Handshake header, ServerHello or certificate lengths, depending on the step
+    collected: heapless::Vec<u8, SERVER_HELLO_MAX>,
+    /// Bytes left in the current handshake message
+    message_left: usize,
+    /// Bytes left in the leaf certificate
+    leaf_left: usize,
+    hasher: Sha256,
+    server_hello_seen: bool,
+}
+
+impl LeafPin {
+    pub(crate) fn new(pin: [u8; 32]) -> Self {
+        Self {
+            pin,
+            result: PinCheck::Pending,
+            record_header: [0; 5],
+            record_header_len: 0,
+            record_type: 0,
+            record_left: 0,
+            step: Step::Header,
+            collected: heapless::Vec::new(),
+            message_left: 0,
+            leaf_left: 0,
+            hasher: Sha256::new(),
+            server_hello_seen: false,
+        }
+    }
+
+    /// Takes the next bytes read from the server, once the result isn't
+    /// [`PinCheck::Pending`] it stays the same
+    pub(crate) fn feed(&mut self, mut data: &[u8]) -> PinCheck {
+        while !data.is_empty() && self.result == PinCheck::Pending {
+            if self.record_left == 0 {
+                let take = (5 - self.record_header_len).min(data.len());
+                self.record_header[self.record_header_len..][..take].copy_from_slice(&data[..take]);
+                self.record_header_len += take;
+                data = &data[take..];
+                if self.record_header_len == 5 {
+                    self.record_header_len = 0;
+                    self.record_type = self.record_header[0];
+                    self.record_left =
+                        u16::from_be_bytes([self.record_header[3], self.record_header[4]]) as usize;
+                    // ChangeCipherSpec or application data before the certificate: a
+                    // resumed session, or TLS 1.3 where the rest is encrypted
+                    if !matches!(self.record_type, CONTENT_HANDSHAKE | CONTENT_ALERT) {
+                        self.result = PinCheck::Unverifiable;
+                    }
+                }
+                continue;
+            }
+
+            let (body, rest) = data.split_at(self.record_left.min(data.len()));
+            self.record_left -= body.len();
+            data = rest;
+            if self.record_type == CONTENT_HANDSHAKE {
+                self.handshake(body);
+            }
+        }
+        self.result
+    }
+
+    /// Handshake messages may span several records, and a record may hold several of them
+    fn handshake(&mut self, mut data: &[u8]) {
+        while !data.is_empty() && self.result == PinCheck::Pending {
+            match self.step {
+                Step::Header => {
+                    let take = (4 - self.collected.len()).min(data.len());
+                    // can't overflow, the buffer is empty at the start of a message
+                    let _ = self.collected.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    if self.collected.len() == 4 {
+                        self.begin_message();
+                    }
+                }
+                Step::ServerHello | Step::CertificateLengths => {
+                    let wanted = match self.step {
+                        Step::ServerHello => self.message_left,
+                        _ => (6 - self.collected.len()).min(self.message_left),
+                    };
+                    let take = wanted.min(data.len());
+                    if self.collected.extend_from_slice(&data[..take]).is_err() {
+                        self.result = PinCheck::Unverifiable;
+                        return;
+                    }
+                    self.message_left -= take;
+                    data = &data[take..];
+                    self.collected_more();
+                }
+                Step::Leaf => {
+                    let take = self.leaf_left.min(data.len());
+                    self.hasher.update(&data[..take]);
+                    self.leaf_left -= take;
+                    data = &data[take..];
+                    if self.leaf_left == 0 {
+                        let digest: [u8; 32] = self.hasher.finalize_reset().into();
+                        self.result = if digest == self.pin {
+                            PinCheck::Matched
+                        } else {
+                            PinCheck::Mismatched
+                        };
+                    }
+                }
+                Step::Skip => {
+                    let take = self.message_left.min(data.len());
+                    self.message_left -= take;
+                    data = &data[take..];
+                    if self.message_left == 0 {
+                        self.next_message();
+                    }
+                }
+            }
+        }
+    }
+
+    fn begin_message(&mut self) {
+        let kind = self.collected[0];
+        self.message_left =
+            u32::from_be_bytes([0, self.collected[1], self.collected[2], self.collected[3]])
+                as usize;
+        match kind {
+            // the parser wants the header too
+            HANDSHAKE_SERVER_HELLO => self.step = Step::ServerHello,
+            HANDSHAKE_CERTIFICATE if self.server_hello_seen => {
+                self.collected.clear();
+                self.step = Step::CertificateLengths;
+            }
+            HANDSHAKE_SERVER_HELLO_DONE => {
+                self.result = PinCheck::Mismatched;
+                return;
+            }
+            _ => {
+                self.collected.clear();
+                self.step = Step::Skip;
+            }
+        }
+        self.collected_more();
+    }
+
+    /// Acts on the ServerHello or the certificate lengths once they are complete
+    fn collected_more(&mut self) {
+        match self.step {
+            Step::ServerHello if self.message_left == 0 => {
+                match parse_server_hello(&self.collected) {
+                    Some(negotiated) if negotiated.version == VERSION_TLS1_2 => {
+                        self.server_hello_seen = true;
+                        self.next_message();
+                    }
+                    _ => self.result = PinCheck::Unverifiable,
+                }
+            }
+            Step::CertificateLengths if self.collected.len() == 6 => {
+                let leaf_len = &self.collected[3..6];
+                self.leaf_left =
+                    u32::from_be_bytes([0, leaf_len[0], leaf_len[1], leaf_len[2]]) as usize;
+                self.message_left -= self.leaf_left.min(self.message_left);
+                self.step = Step::Leaf;
+                if self.leaf_left == 0 {
+                    self.result = PinCheck::Mismatched;
+                }
+            }
+            // an empty certificate list
+            Step::CertificateLengths if self.message_left == 0 => {
+                self.result = PinCheck::Mismatched;
+            }
+            Step::Skip if self.message_left == 0 => self.next_message(),
+            _ => {}
+        }
+    }
+
+    fn next_message(&mut self) {
+        self.collected.clear();
+        self.step = Step::Header;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `openssl s_server -tls1_2` sent for a self-signed P-256 certificate, from the
+    /// ServerHello to the ServerHelloDone
+    const TLS1_2_FLIGHT: &[u8] = include_bytes!("../web-test/fixtures/tls12-server-flight.bin");
+    /// The same server with `-tls1_3`, everything after the ServerHello is encrypted
+    const TLS1_3_FLIGHT: &[u8] = include_bytes!("../web-test/fixtures/tls13-server-flight.bin");
+
+    /// SHA-256 of the certificate in the flights
+    const LEAF_SHA256: [u8; 32] = [
+        0xd0, 0xe8, 0x85, 0xad, 0x94, 0x91, 0xb7, 0x5d, 0x0a, 0xd9, 0xa5, 0x2a, 0x50, 0x93, 0xae,
+        0x5c, 0x37, 0xca, 0x95, 0x16, 0x85, 0x94, 0x74, 0x3a, 0x33, 0x7a, 0x2c, 0x2f, 0x35, 0x2b,
+        0x48, 0x5d,
+    ];
+
+    /// A TLS 1.2 ServerHello record followed by a Certificate record with a single
+    /// certificate
+    fn flight(leaf: &[u8]) -> Vec<u8> {
+        let hello = [
+            &[2, 0, 0, 38, 3, 3][..],
+            &[0x70; 32],
+            &[0, 0xc0, 0x2f, 0],
+            // no extensions
+        ]
+        .concat();
+        let mut out = vec![CONTENT_HANDSHAKE, 3, 3, 0, hello.len() as u8];
+        out.extend_from_slice(&hello);
+
+        let u24 = |n: usize| (n as u32).to_be_bytes()[1..].to_vec();
+        let list = [u24(leaf.len()), leaf.to_vec()].concat();
+        let body = [u24(list.len()), list].concat();
+        let message = [vec![HANDSHAKE_CERTIFICATE], u24(body.len()), body].concat();
+        out.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 3]);
+        out.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        out.extend_from_slice(&message);
+        out
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn openssl_tls1_2_flight() {
+        assert_eq!(
+            LeafPin::new(LEAF_SHA256).feed(TLS1_2_FLIGHT),
+            PinCheck::Matched
+        );
+        assert_eq!(
+            LeafPin::new([0; 32]).feed(TLS1_2_FLIGHT),
+            PinCheck::Mismatched
+        );
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let mut pin = LeafPin::new(LEAF_SHA256);
+        let mut result = PinCheck::Pending;
+        for byte in TLS1_2_FLIGHT {
+            result = pin.feed(core::slice::from_ref(byte));
+        }
+        assert_eq!(result, PinCheck::Matched);
+
+        for size in [3, 7, 100, 512] {
+            let mut pin = LeafPin::new(LEAF_SHA256);
+            let results: Vec<_> = TLS1_2_FLIGHT.chunks(size).map(|c| pin.feed(c)).collect();
+            assert_eq!(results.last(), Some(&PinCheck::Matched), "chunks of {size}");
+        }
+    }
+
+    #[test]
+    fn certificate_split_across_records() {
+        let leaf = vec![0x30; 300];
+        let whole = flight(&leaf);
+        // the ServerHello record is 47 bytes, cut the Certificate record in two
+        let (hello, certificate) = whole.split_at(47);
+        let (first, second) = certificate[5..].split_at(100);
+        let mut split = hello.to_vec();
+        for part in [first, second] {
+            split.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 3]);
+            split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            split.extend_from_slice(part);
+        }
+        assert_eq!(LeafPin::new(sha256(&leaf)).feed(&whole), PinCheck::Matched);
+        assert_eq!(LeafPin::new(sha256(&leaf)).feed(&split), PinCheck::Matched);
+    }
+
+    #[test]
+    fn pending_until_the_leaf_is_complete() {
+        let leaf = [0x30; 64];
+        let flight = flight(&leaf);
+        let mut pin = LeafPin::new(sha256(&leaf));
+        assert_eq!(pin.feed(&flight[..flight.len() - 1]), PinCheck::Pending);
+        assert_eq!(pin.feed(&flight[flight.len() - 1..]), PinCheck::Matched);
+        // stays matched whatever follows
+        assert_eq!(pin.feed(&[20, 3, 3, 0, 1, 1]), PinCheck::Matched);
+    }
+
+    #[test]
+    fn tls1_3_is_unverifiable() {
+        assert_eq!(
+            LeafPin::new(LEAF_SHA256).feed(TLS1_3_FLIGHT),
+            PinCheck::Unverifiable
+        );
+    }
+
+    #[test]
+    fn no_certificate() {
+        let mut flight = flight(&[]);
+        // a ChangeCipherSpec right after the ServerHello, as in a resumed session
+        flight.truncate(47);
+        flight.extend_from_slice(&[20, 3, 3, 0, 1, 1]);
+        assert_eq!(LeafPin::new([0; 32]).feed(&flight), PinCheck::Unverifiable);
+
+        // a ServerHelloDone without a certificate
+        flight.truncate(47);
+        flight.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 3, 0, 4, 14, 0, 0, 0]);
+        assert_eq!(LeafPin::new([0; 32]).feed(&flight), PinCheck::Mismatched);
+
+        // an empty certificate list
+        flight.truncate(47);
+        flight.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 3, 0, 7, 11, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(LeafPin::new([0; 32]).feed(&flight), PinCheck::Mismatched);
+    }
+}
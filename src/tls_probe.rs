@@ -0,0 +1,105 @@
+//! Reads the TLS version and cipher suite a server picks for our client.
+//!
+//! The calendar connections can't report this themselves: reqwless keeps the mbedtls-rs
+//! session private and mbedtls-rs has no accessor for the negotiated version or cipher
+//! suite, so the live session can't be asked. Instead the diagnostics send a ClientHello
+//! offering the same versions and suites and parse the ServerHello. The connection is
+//! dropped right after it, no keys are derived.
+
+use embassy_net::Stack;
+use embassy_net::tcp::TcpSocket;
+
+use crate::networking::{NetworkError, RequestStage};
+use crate::storage::TlsVersion;
+use crate::tls_hello::{
+    ALERT_PROTOCOL_VERSION, CONTENT_ALERT, CONTENT_HANDSHAKE, Negotiated, client_hello,
+    parse_server_hello,
+};
+
+const PROBE_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(5);
+
+pub(crate) async fn probe(
+    stack: Stack<'_>,
+    host: &str,
+    port: u16,
+    min_version: TlsVersion,
+) -> Result<Negotiated, NetworkError> {
+    let mut address = None;
+    for type_ in crate::dns_cache::query_types(stack) {
+        if let Ok(addresses) = stack.dns_query(host, type_).await
+            && let Some(first) = addresses.first()
+        {
+            address = Some(*first);
+            break;
+        }
+    }
+    let address = address.ok_or(NetworkError::DnsFailed)?;
+
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 512];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(PROBE_TIMEOUT));
+
+    let result = embassy_time::with_timeout(PROBE_TIMEOUT, async {
+        socket
+            .connect((address, port))
+            .await
+            .map_err(|_| NetworkError::TlsProbeFailed)?;
+
+        let rng = esp_hal::rng::Rng::new();
+        // Only an absurdly long host name overflows the ClientHello buffer
+        let hello =
+            client_hello(host, min_version, |buf| rng.read(buf)).ok_or(NetworkError::WrongUrl)?;
+        write_all(&mut socket, &hello).await?;
+
+        let mut header = [0u8; 5];
+        read_exact(&mut socket, &mut header).await?;
+        let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+
+        match header[0] {
+            CONTENT_HANDSHAKE => {}
+            CONTENT_ALERT => {
+                let mut alert = [0u8; 2];
+                read_exact(&mut socket, &mut alert).await?;
+                return Err(match alert[1] {
+                    ALERT_PROTOCOL_VERSION => NetworkError::TlsVersionUnsupported,
+                    description => NetworkError::TlsAlert(description),
+                });
+            }
+            _ => return Err(NetworkError::ParsingError),
+        }
+
+        // Only the ServerHello is needed, the rest of the record can stay unread
+        let mut message = [0u8; 512];
+        let len = record_len.min(message.len());
+        read_exact(&mut socket, &mut message[..len]).await?;
+        parse_server_hello(&message[..len]).ok_or(NetworkError::ParsingError)
+    })
+    .await
+    .unwrap_or(Err(NetworkError::Timeout(RequestStage::TlsHandshake)));
+
+    socket.abort();
+    let _ = socket.flush().await;
+    result
+}
+
+async fn write_all(socket: &mut TcpSocket<'_>, mut data: &[u8]) -> Result<(), NetworkError> {
+    while !data.is_empty() {
+        let written = socket
+            .write(data)
+            .await
+            .map_err(|_| NetworkError::TlsProbeFailed)?;
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+async fn read_exact(socket: &mut TcpSocket<'_>, mut buf: &mut [u8]) -> Result<(), NetworkError> {
+    while !buf.is_empty() {
+        match socket.read(buf).await {
+            Ok(0) | Err(_) => return Err(NetworkError::TlsProbeFailed),
+            Ok(read) => buf = &mut buf[read..],
+        }
+    }
+    Ok(())
+}
@@ -1,47 +1,337 @@
 use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
 
 use edge_nal::UdpBind;
 use edge_nal_embassy::{Udp, UdpBuffers};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
 use embassy_net::{DhcpConfig, Runner};
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Timer};
 use esp_alloc as _;
 use esp_backtrace as _;
 use esp_hal::peripherals::{ADC1, RNG, WIFI};
+use esp_radio::wifi::event::{EventExt, StaDisconnected};
 use esp_radio::wifi::{
-    AccessPointConfig, ClientConfig, ModeConfig, WifiController, WifiDevice, WifiEvent,
-    WifiStaState,
+    AccessPointConfig, AccessPointInfo, ClientConfig, ModeConfig, ScanConfig, WifiController,
+    WifiDevice, WifiError, WifiEvent, WifiStaState,
 };
 use static_cell::StaticCell;
 
-use crate::storage::WifiCreds;
+use crate::server::WifiTrial;
+use crate::storage::{ApCredentials, MAX_WIFI_NETWORKS, StaticIpv4, WifiCreds};
+use crate::wifi_cache::AccessPoint;
 
 pub static STOP_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 pub static STOPPED_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 pub static WIFI_STARTED: portable_atomic::AtomicBool = portable_atomic::AtomicBool::new(false);
+/// Scans are requested by the web server, the task owning the controller runs them
+static SCAN_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SCAN_RESULT: Signal<CriticalSectionRawMutex, Result<Vec<AccessPointInfo>, WifiError>> =
+    Signal::new();
+static SCAN_LOCK: embassy_sync::mutex::Mutex<CriticalSectionRawMutex, ()> =
+    embassy_sync::mutex::Mutex::new(());
+/// Trial connections are requested by the web server and run by the access point task
+static TRIAL_REQUEST: Signal<CriticalSectionRawMutex, WifiCreds> = Signal::new();
+static TRIAL_STATE: Mutex<CriticalSectionRawMutex, Cell<WifiTrial>> =
+    Mutex::new(Cell::new(WifiTrial::Idle));
+/// 802.11 reason code of the last station disconnect, `connect_async` only reports that
+/// it happened
+static DISCONNECT_REASON: portable_atomic::AtomicU16 = portable_atomic::AtomicU16::new(0);
+static AP_RUNNING: portable_atomic::AtomicBool = portable_atomic::AtomicBool::new(false);
+static CONNECTED_SSID: Mutex<CriticalSectionRawMutex, RefCell<heapless::String<32>>> =
+    Mutex::new(RefCell::new(heapless::String::new()));
 
 pub const AP_IP_ADDR: [u8; 4] = [192, 168, 0, 1];
 const WIFI_RETRY_DELAY_MS: u64 = 100;
-
-const NETWORK_STACK_NUM: usize = 5;
+const TRIAL_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const TRIAL_DHCP_TIMEOUT: Duration = Duration::from_secs(15);
+/// A failed 4-way handshake (MIC failure, 4-way handshake timeout and the ESP-IDF handshake
+/// timeout) is how a wrong WPA2 password shows, a failed authentication how a wrong WPA3
+/// (SAE) one does
+const WRONG_PASSWORD_REASONS: [u16; 4] = [14, 15, 204, 202];
+const DNS_PORT: u16 = 53;
+/// Without the look-alike characters, 32 of them so every one is 5 random bits
+const AP_ALPHABET: &[u8; 32] = b"23456789abcdefghijkmnpqrstuvwxyz";
+const AP_SSID_SUFFIX_LEN: usize = 4;
+/// 60 bits, the portal is only up while the device is being set up
+const AP_PASSWORD_LEN: usize = 12;
+
+const NETWORK_STACK_NUM: usize = 8;
 
 static NETWORK_STACK: StaticCell<embassy_net::StackResources<NETWORK_STACK_NUM>> =
     StaticCell::new();
+/// The station interface of the portal, only used by the trial connections
+static TRIAL_STACK: StaticCell<embassy_net::StackResources<1>> = StaticCell::new();
 static DHCP_UDP_BUFFERS: StaticCell<UdpBuffers<1>> = StaticCell::new();
 static RADIO_CONTROLLER: StaticCell<esp_radio::Controller> = StaticCell::new();
 static TRNG: StaticCell<esp_hal::rng::Trng> = StaticCell::new();
 static TRNG_SOURCE: StaticCell<esp_hal::rng::TrngSource> = StaticCell::new();
 
+/// The station config, directed at `ap` if it is known
+fn client_config(ssid: &str, pass: &str, ap: Option<AccessPoint>) -> ModeConfig {
+    let config = ClientConfig::default()
+        .with_ssid(ssid.to_string())
+        .with_password(pass.to_string());
+    ModeConfig::Client(match ap {
+        Some(ap) => config.with_bssid(ap.bssid).with_channel(ap.channel),
+        None => config,
+    })
+}
+
+/// A network in range with the strongest access point of it
+#[derive(Clone, Copy)]
+struct Candidate {
+    /// Index into the saved networks
+    network: usize,
+    ap: AccessPoint,
+}
+
+/// The embassy-net form of a static address
+fn static_config(ip: &StaticIpv4) -> embassy_net::StaticConfigV4 {
+    #[allow(clippy::default_trait_access)]
+    let mut config = embassy_net::StaticConfigV4 {
+        address: embassy_net::Ipv4Cidr::new(ip.address, ip.prefix_len),
+        gateway: ip.gateway,
+        dns_servers: Default::default(),
+    };
+    for dns in &ip.dns_servers {
+        let _ = config.dns_servers.push(*dns);
+    }
+    config
+}
+
+/// The address config of a network, a static one or DHCP
+fn ip_config(network: &WifiCreds) -> embassy_net::ConfigV4 {
+    match &network.static_ip {
+        Some(ip) => embassy_net::ConfigV4::Static(static_config(ip)),
+        None => embassy_net::ConfigV4::Dhcp(DhcpConfig::default()),
+    }
+}
+
+/// The saved network the last wake connected to, with its access point
+fn last_network(networks: &[WifiCreds]) -> Option<Candidate> {
+    networks.iter().enumerate().find_map(|(network, creds)| {
+        crate::wifi_cache::access_point(&creds.ssid).map(|ap| Candidate { network, ap })
+    })
+}
+
+/// Finds the saved networks in range, ordered by priority and then by signal strength
+async fn scan_for(
+    controller: &mut WifiController<'static>,
+    networks: &[WifiCreds],
+) -> heapless::Vec<Candidate, MAX_WIFI_NETWORKS> {
+    let found = match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            crate::defmt::warn!("Wi-Fi scan failed: {:?}", e);
+            return heapless::Vec::new();
+        }
+    };
+
+    let mut candidates = heapless::Vec::<(Candidate, i8), MAX_WIFI_NETWORKS>::new();
+    for (network, creds) in networks.iter().enumerate() {
+        // Several access points may serve the network, the strongest one is joined
+        if let Some(info) = found
+            .iter()
+            .filter(|info| info.ssid == creds.ssid.as_str())
+            .max_by_key(|info| info.signal_strength)
+        {
+            let ap = AccessPoint {
+                bssid: info.bssid,
+                channel: info.channel,
+            };
+            let _ = candidates.push((Candidate { network, ap }, info.signal_strength));
+        }
+    }
+    candidates.sort_unstable_by_key(|(candidate, signal)| {
+        (
+            core::cmp::Reverse(networks[candidate.network].priority),
+            core::cmp::Reverse(*signal),
+        )
+    });
+    candidates
+        .into_iter()
+        .map(|(candidate, _)| candidate)
+        .collect()
+}
+
+/// Scans the channels in the station and in the access point mode
+pub async fn scan() -> Result<Vec<AccessPointInfo>, WifiError> {
+    let _guard = SCAN_LOCK.lock().await;
+    SCAN_RESULT.reset();
+    SCAN_REQUEST.signal(());
+    SCAN_RESULT.wait().await
+}
+
+async fn answer_scan(controller: &mut WifiController<'static>) {
+    crate::defmt::info!("Scanning for networks");
+    SCAN_RESULT.signal(
+        controller
+            .scan_with_config_async(ScanConfig::default())
+            .await,
+    );
+}
+
+/// Starts a trial connection to `network` next to the access point of the portal
+pub fn start_trial(network: WifiCreds) -> WifiTrial {
+    if !AP_RUNNING.load(core::sync::atomic::Ordering::Relaxed) {
+        // Joining another network would drop the connection the portal is served on
+        return WifiTrial::Unavailable;
+    }
+    TRIAL_STATE.lock(|state| state.set(WifiTrial::Testing));
+    TRIAL_REQUEST.signal(network);
+    WifiTrial::Testing
+}
+
+pub fn trial_state() -> WifiTrial {
+    TRIAL_STATE.lock(Cell::get)
+}
+
+/// Runs a trial connection to `network` and waits for its result
+pub async fn trial(network: WifiCreds) -> WifiTrial {
+    let mut state = start_trial(network);
+    while state == WifiTrial::Testing {
+        Timer::after(Duration::from_millis(500)).await;
+        state = trial_state();
+    }
+    state
+}
+
+fn portal_config(station: ClientConfig, credentials: &ApCredentials) -> ModeConfig {
+    ModeConfig::ApSta(
+        station,
+        AccessPointConfig::default()
+            .with_max_connections(1)
+            .with_auth_method(esp_radio::wifi::AuthMethod::Wpa2Wpa3Personal)
+            .with_ssid(credentials.ssid.to_string())
+            .with_password(credentials.password.to_string()),
+    )
+}
+
+fn random_char(trng: &mut esp_hal::rng::Trng) -> char {
+    AP_ALPHABET[trng.random() as usize % AP_ALPHABET.len()] as char
+}
+
+/// `AP_SSID` from the build with a random suffix, so the devices next to each other can be
+/// told apart, and a random password
+fn generate_ap_credentials(trng: &mut esp_hal::rng::Trng) -> ApCredentials {
+    let mut ssid = heapless::String::<32>::new();
+    // Shortened to leave room for the suffix
+    for c in env!("AP_SSID")
+        .chars()
+        .take(ssid.capacity() - AP_SSID_SUFFIX_LEN - 1)
+    {
+        let _ = ssid.push(c);
+    }
+    let _ = ssid.push('-');
+    for _ in 0..AP_SSID_SUFFIX_LEN {
+        let _ = ssid.push(random_char(trng));
+    }
+    let password = (0..AP_PASSWORD_LEN).map(|_| random_char(trng)).collect();
+    ApCredentials { ssid, password }
+}
+
+/// The `WIFI:` text of a QR code, which phones offer to join after scanning it
+pub(crate) fn ap_login_qr_text(credentials: &ApCredentials) -> alloc::string::String {
+    fn escape(value: &str) -> alloc::string::String {
+        let mut escaped = alloc::string::String::new();
+        for c in value.chars() {
+            if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+    alloc::format!(
+        "WIFI:S:{};T:WPA;P:{};;",
+        escape(&credentials.ssid),
+        escape(&credentials.password)
+    )
+}
+
+/// Joins the network on the station interface and waits for its address, then leaves it.
+///
+/// The access point follows the station to the channel of the network, so the phone showing
+/// the portal may reconnect in between.
+async fn run_trial(
+    controller: &mut WifiController<'static>,
+    stack: embassy_net::Stack<'static>,
+    network: &WifiCreds,
+    credentials: &ApCredentials,
+) -> WifiTrial {
+    crate::defmt::info!("Trial connection to {}", network.ssid);
+    let in_range = controller
+        .scan_with_config_async(ScanConfig::default().with_ssid(network.ssid.as_str()))
+        .await
+        .is_ok_and(|found| found.iter().any(|info| info.ssid == network.ssid.as_str()));
+    if !in_range {
+        return WifiTrial::NotFound;
+    }
+
+    stack.set_config_v4(ip_config(network));
+    let station = ClientConfig::default()
+        .with_ssid(network.ssid.to_string())
+        .with_password(network.password.to_string());
+    // Rejected for a password of the wrong length
+    if controller
+        .set_config(&portal_config(station, credentials))
+        .is_err()
+    {
+        return WifiTrial::InvalidConfig;
+    }
+    DISCONNECT_REASON.store(0, core::sync::atomic::Ordering::Relaxed);
+    let result =
+        match embassy_time::with_timeout(TRIAL_CONNECT_TIMEOUT, controller.connect_async()).await {
+            Ok(Ok(())) => {
+                match embassy_time::with_timeout(TRIAL_DHCP_TIMEOUT, stack.wait_config_up()).await {
+                    Ok(()) => WifiTrial::Connected,
+                    Err(_) => WifiTrial::NoDhcp,
+                }
+            }
+            Ok(Err(e)) => {
+                let reason = DISCONNECT_REASON.load(core::sync::atomic::Ordering::Relaxed);
+                crate::defmt::warn!("Trial connection failed: {:?}, reason {}", e, reason);
+                if WRONG_PASSWORD_REASONS.contains(&reason) {
+                    WifiTrial::WrongPassword
+                } else {
+                    WifiTrial::Rejected
+                }
+            }
+            Err(_) => WifiTrial::Timeout,
+        };
+
+    let _ = controller.disconnect_async().await;
+    let _ = controller.set_config(&portal_config(ClientConfig::default(), credentials));
+    crate::defmt::info!("Trial connection finished: {:?}", result);
+    result
+}
+
+/// The SSID of the network the station is connected to
+pub fn connected_ssid() -> heapless::String<32> {
+    CONNECTED_SSID.lock(|ssid| ssid.borrow().clone())
+}
+
 #[embassy_executor::task]
 pub async fn connection(
     mut controller: WifiController<'static>,
     mut runner: Runner<'static, WifiDevice<'static>>,
-    ssid: heapless::String<32>,
-    pass: heapless::String<32>,
+    stack: embassy_net::Stack<'static>,
+    networks: Vec<WifiCreds>,
+    mut configured_for: Option<usize>,
 ) {
     crate::defmt::info!("Device capabilities: {:?}", controller.capabilities());
 
+    // The access point of the last wake is tried first, without scanning
+    let mut candidates = heapless::Vec::<Candidate, MAX_WIFI_NETWORKS>::new();
+    candidates.extend(last_network(&networks));
+    let mut cached = !candidates.is_empty();
     let connection_fut = async {
         loop {
             if STOP_SIGNAL.signaled() {
@@ -50,30 +340,54 @@ pub async fn connection(
 
             if esp_radio::wifi::sta_state() == WifiStaState::Connected {
                 // wait until we're no longer connected
-                match embassy_futures::select::select(
+                match embassy_futures::select::select3(
                     controller.wait_for_event(WifiEvent::StaDisconnected),
                     STOP_SIGNAL.wait(),
+                    SCAN_REQUEST.wait(),
                 )
                 .await
                 {
-                    embassy_futures::select::Either::First(_) => {
+                    embassy_futures::select::Either3::First(_) => {
                         crate::defmt::warn!("Disconnected, retrying...");
                         Timer::after(Duration::from_millis(WIFI_RETRY_DELAY_MS)).await;
                     }
-                    embassy_futures::select::Either::Second(_) => return,
+                    embassy_futures::select::Either3::Second(_) => return,
+                    embassy_futures::select::Either3::Third(_) => {
+                        answer_scan(&mut controller).await;
+                    }
                 }
             } else {
                 if !matches!(controller.is_started(), Ok(true)) {
-                    let station_config = ModeConfig::Client(
-                        ClientConfig::default()
-                            .with_ssid(ssid.to_string())
-                            .with_password(pass.to_string()),
-                    );
-                    controller.set_config(&station_config).unwrap();
+                    let first = &networks[candidates.first().map_or(0, |c| c.network)];
+                    controller
+                        .set_config(&client_config(&first.ssid, &first.password, None))
+                        .unwrap();
                     crate::defmt::info!("Starting wifi");
                     controller.start_async().await.unwrap();
                     crate::defmt::info!("Wifi started!");
                 }
+                if SCAN_REQUEST.try_take().is_some() {
+                    answer_scan(&mut controller).await;
+                }
+                // Roams to the best network in range after a disconnect or when every
+                // candidate failed
+                if candidates.is_empty() {
+                    candidates = scan_for(&mut controller, &networks).await;
+                    if candidates.is_empty() {
+                        crate::defmt::warn!("None of the saved networks is in range");
+                        Timer::after(Duration::from_millis(WIFI_RETRY_DELAY_MS)).await;
+                        continue;
+                    }
+                }
+                let candidate = candidates.remove(0);
+                let creds = &networks[candidate.network];
+                controller
+                    .set_config(&client_config(
+                        &creds.ssid,
+                        &creds.password,
+                        Some(candidate.ap),
+                    ))
+                    .unwrap();
 
                 match embassy_futures::select::select(
                     controller.connect_async(),
@@ -82,10 +396,28 @@ pub async fn connection(
                 .await
                 {
                     embassy_futures::select::Either::First(Ok(())) => {
-                        crate::defmt::info!("Wifi connected!")
+                        crate::defmt::info!("Wifi connected to {}!", creds.ssid);
+                        // The stack starts with the address of the last network
+                        if configured_for != Some(candidate.network)
+                            && (configured_for.is_some() || creds.static_ip.is_some())
+                        {
+                            if crate::wifi_cache::lease_reused() {
+                                crate::wifi_cache::forget_lease();
+                            }
+                            stack.set_config_v4(ip_config(creds));
+                            configured_for = creds.static_ip.is_some().then_some(candidate.network);
+                        }
+                        crate::wifi_cache::remember_access_point(&creds.ssid, candidate.ap);
+                        CONNECTED_SSID.lock(|ssid| *ssid.borrow_mut() = creds.ssid.clone());
+                        candidates.clear();
                     }
                     embassy_futures::select::Either::First(Err(e)) => {
-                        crate::defmt::error!("Failed to connect to wifi: {:?}", e);
+                        crate::defmt::error!("Failed to connect to {}: {:?}", creds.ssid, e);
+                        if cached {
+                            // The access point moved, look for it again
+                            crate::wifi_cache::forget_access_point();
+                            cached = false;
+                        }
                         Timer::after(Duration::from_millis(WIFI_RETRY_DELAY_MS)).await;
                     }
                     embassy_futures::select::Either::Second(_) => return,
@@ -107,8 +439,17 @@ pub async fn ap_task(
     mut controller: WifiController<'static>,
     mut runner: Runner<'static, WifiDevice<'static>>,
     stack: embassy_net::Stack<'static>,
+    mut trial_runner: Runner<'static, WifiDevice<'static>>,
+    trial_stack: embassy_net::Stack<'static>,
+    credentials: ApCredentials,
 ) {
     crate::defmt::info!("Device capabilities: {:?}", controller.capabilities());
+    StaDisconnected::update_handler(|event| {
+        DISCONNECT_REASON.store(
+            u16::from(event.reason()),
+            core::sync::atomic::Ordering::Relaxed,
+        );
+    });
 
     let ap_fut = async {
         loop {
@@ -116,29 +457,33 @@ pub async fn ap_task(
                 return;
             }
             if !matches!(controller.is_started(), Ok(true)) {
-                let ap_config = ModeConfig::AccessPoint(
-                    AccessPointConfig::default()
-                        .with_max_connections(1)
-                        .with_auth_method(esp_radio::wifi::AuthMethod::Wpa2Wpa3Personal)
-                        .with_ssid(alloc::string::String::from(env!("AP_SSID")))
-                        .with_password(alloc::string::String::from(env!("AP_PASS"))),
-                );
-                controller.set_config(&ap_config).unwrap();
+                // The idle station interface lets the portal scan and try networks
+                controller
+                    .set_config(&portal_config(ClientConfig::default(), &credentials))
+                    .unwrap();
                 crate::defmt::info!("Starting AP");
                 controller.start_async().await.unwrap();
                 crate::defmt::info!("AP started!");
             }
-            match embassy_futures::select::select(
+            match embassy_futures::select::select4(
                 controller.wait_for_event(WifiEvent::ApStop),
                 STOP_SIGNAL.wait(),
+                SCAN_REQUEST.wait(),
+                TRIAL_REQUEST.wait(),
             )
             .await
             {
-                embassy_futures::select::Either::First(_) => {
+                embassy_futures::select::Either4::First(_) => {
                     crate::defmt::warn!("AP stopped, restarting...");
                     Timer::after(Duration::from_millis(1000)).await;
                 }
-                embassy_futures::select::Either::Second(_) => return,
+                embassy_futures::select::Either4::Second(_) => return,
+                embassy_futures::select::Either4::Third(_) => answer_scan(&mut controller).await,
+                embassy_futures::select::Either4::Fourth(network) => {
+                    let result =
+                        run_trial(&mut controller, trial_stack, &network, &credentials).await;
+                    TRIAL_STATE.lock(|state| state.set(result));
+                }
             }
         }
     };
@@ -146,7 +491,11 @@ pub async fn ap_task(
     let dhcp_fut = async {
         let server_ip = core::net::Ipv4Addr::from_octets(AP_IP_ADDR);
         let mut gw_buf = [server_ip];
-        let server_options = edge_dhcp::server::ServerOptions::new(server_ip, Some(&mut gw_buf));
+        let dns = [server_ip];
+        let mut server_options =
+            edge_dhcp::server::ServerOptions::new(server_ip, Some(&mut gw_buf));
+        // The DNS responder below points every name to the portal
+        server_options.dns = &dns;
         let mut server = edge_dhcp::server::Server::<_, 4>::new_with_et(server_ip);
 
         #[allow(clippy::large_stack_frames, reason = "false positive")]
@@ -171,9 +520,45 @@ pub async fn ap_task(
         }
     };
 
-    embassy_futures::select::select(
-        runner.run(),
-        embassy_futures::select::select(dhcp_fut, ap_fut),
+    // Every name resolves to the portal, so the phones detect it and open the setup page
+    let dns_fut = async {
+        let mut rx_meta = [PacketMetadata::EMPTY; 4];
+        let mut rx_buffer = [0u8; 1024];
+        let mut tx_meta = [PacketMetadata::EMPTY; 4];
+        let mut tx_buffer = [0u8; 1024];
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        socket
+            .bind(DNS_PORT)
+            .expect("DNS: failed to bind to port 53");
+
+        let mut query = [0u8; 512];
+        let mut response = [0u8; 512];
+        loop {
+            let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+                continue;
+            };
+            if let Some(len) = crate::portal_dns::response(&query[..len], &mut response, AP_IP_ADDR)
+                && socket
+                    .send_to(&response[..len], meta.endpoint)
+                    .await
+                    .is_err()
+            {
+                crate::defmt::warn!("DNS: failed to answer {}", meta.endpoint);
+            }
+        }
+    };
+
+    embassy_futures::select::select4(
+        embassy_futures::join::join(runner.run(), trial_runner.run()),
+        dhcp_fut,
+        dns_fut,
+        ap_fut,
     )
     .await;
 
@@ -181,12 +566,18 @@ pub async fn ap_task(
     STOPPED_SIGNAL.signal(());
 }
 
+/// Raises the portal with the stored credentials, new ones are generated if there are none
 pub fn start_ap(
     spawner: embassy_executor::Spawner,
     wifi: WIFI<'static>,
     rng_per: RNG<'static>,
     adc1: ADC1<'static>,
-) -> (embassy_net::Stack<'static>, &'static mut esp_hal::rng::Trng) {
+    stored: Option<ApCredentials>,
+) -> (
+    embassy_net::Stack<'static>,
+    &'static mut esp_hal::rng::Trng,
+    ApCredentials,
+) {
     let wifi_config = esp_radio::wifi::Config::default()
         .with_power_save_mode(esp_radio::wifi::PowerSaveMode::Minimum);
 
@@ -213,6 +604,7 @@ pub fn start_ap(
     #[allow(clippy::large_stack_frames, reason = "false positive")]
     let trng = TRNG.init_with(|| esp_hal::rng::Trng::try_new().unwrap());
     let seed = (trng.random() as u64) << 32 | trng.random() as u64;
+    let credentials = stored.unwrap_or_else(|| generate_ap_credentials(trng));
 
     // Init network stack
     let (net_stack, runner) = embassy_net::new(
@@ -226,20 +618,39 @@ pub fn start_ap(
     STOP_SIGNAL.reset();
     STOPPED_SIGNAL.reset();
 
+    let (trial_stack, trial_runner) = embassy_net::new(
+        interfaces.sta,
+        embassy_net::Config::dhcpv4(DhcpConfig::default()),
+        TRIAL_STACK.init_with(embassy_net::StackResources::<1>::new),
+        (trng.random() as u64) << 32 | trng.random() as u64,
+    );
+
     spawner
-        .spawn(ap_task(wifi_controller, runner, net_stack))
+        .spawn(ap_task(
+            wifi_controller,
+            runner,
+            net_stack,
+            trial_runner,
+            trial_stack,
+            credentials.clone(),
+        ))
         .ok();
 
+    AP_RUNNING.store(true, core::sync::atomic::Ordering::Relaxed);
+
     WIFI_STARTED.store(true, core::sync::atomic::Ordering::Relaxed);
-    (net_stack, trng)
+    (net_stack, trng, credentials)
 }
 
+/// Connects to one of the saved networks, reusing the DHCP lease of the last wake while it is
+/// valid at `now`. Networks with a static address skip DHCP.
 pub fn start_con(
     spawner: embassy_executor::Spawner,
     wifi: WIFI<'static>,
-    wifi_creds: WifiCreds,
+    networks: Vec<WifiCreds>,
     rng_per: RNG<'static>,
     adc1: ADC1<'static>,
+    now: jiff::Timestamp,
 ) -> (embassy_net::Stack<'static>, &'static mut esp_hal::rng::Trng) {
     let wifi_config = esp_radio::wifi::Config::default()
         .with_power_save_mode(esp_radio::wifi::PowerSaveMode::Minimum);
@@ -255,7 +666,25 @@ pub fn start_con(
 
     let wifi_interface = interfaces.sta;
 
-    let config = embassy_net::Config::dhcpv4(DhcpConfig::default());
+    // The connection task switches the config if another network is joined
+    let last = last_network(&networks).map(|last| (last.network, &networks[last.network]));
+    let (config, configured_for) = match last {
+        Some((last, network)) => match (
+            &network.static_ip,
+            crate::wifi_cache::lease(&network.ssid, now),
+        ) {
+            (Some(ip), _) => (
+                embassy_net::Config::ipv4_static(static_config(ip)),
+                Some(last),
+            ),
+            (None, Some(lease)) => {
+                crate::defmt::info!("Reusing the DHCP lease {}", lease.address);
+                (embassy_net::Config::ipv4_static(lease), Some(last))
+            }
+            (None, None) => (embassy_net::Config::dhcpv4(DhcpConfig::default()), None),
+        },
+        None => (embassy_net::Config::dhcpv4(DhcpConfig::default()), None),
+    };
 
     let _trng_source = TRNG_SOURCE.init(esp_hal::rng::TrngSource::new(rng_per, adc1));
 
@@ -279,10 +708,12 @@ pub fn start_con(
         .spawn(connection(
             wifi_controller,
             runner,
-            wifi_creds.ssid,
-            wifi_creds.password,
+            net_stack,
+            networks,
+            configured_for,
         ))
         .ok();
+    spawner.spawn(crate::ipv6::slaac_task(net_stack)).ok();
 
     WIFI_STARTED.store(true, core::sync::atomic::Ordering::Relaxed);
     (net_stack, trng)
@@ -308,6 +739,10 @@ pub async fn stop_wifi() {
 }
 
 pub async fn wait_until_wifi_stop() {
+    // Wakes which redraw from the RTC memory never start it
+    if !WIFI_STARTED.load(core::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
     STOPPED_SIGNAL.wait().await;
     WIFI_STARTED.store(false, core::sync::atomic::Ordering::Relaxed);
     crate::defmt::info!("Wifi stopped!");
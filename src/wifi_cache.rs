@@ -0,0 +1,207 @@
+//! Keeps the access point and the DHCP lease of the last connection in the RTC memory, so the
+//! next wake connects without a scan and reuses the address without a DHCP exchange.
+//!
+//! The lease is only reused until its renewal time, later wakes ask the DHCP server again.
+//! embassy-net doesn't expose the lease duration, so it is read from a copy of the DHCP ACK.
+use core::net::Ipv4Addr;
+use core::sync::atomic::Ordering;
+
+use embassy_net::Stack;
+use portable_atomic::AtomicU32;
+use smoltcp::wire::{
+    DHCP_CLIENT_PORT, DHCP_SERVER_PORT, DhcpMessageType, DhcpPacket, DhcpRepr, IpProtocol,
+    IpVersion, Ipv4Packet, UdpPacket,
+};
+
+use crate::fnv::fnv1a;
+
+/// SSID hash, BSSID and channel, followed by the checksum
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static ACCESS_POINT: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+/// SSID hash, address, prefix length, gateway, DNS server and renewal time in unix seconds,
+/// followed by the checksum
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static LEASE: [AtomicU32; 7] = [const { AtomicU32::new(0) }; 7];
+
+/// Whether this wake uses the lease from the RTC memory instead of DHCP
+static LEASE_REUSED: portable_atomic::AtomicBool = portable_atomic::AtomicBool::new(false);
+
+/// The access point of a network, for a connect without scanning the channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AccessPoint {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+fn ssid_hash(ssid: &str) -> u32 {
+    fnv1a(ssid.bytes())
+}
+
+/// The words of a record if its checksum matches, the memory isn't initialized after a
+/// power loss
+fn load<const N: usize>(record: &[AtomicU32]) -> Option<[u32; N]> {
+    let mut words = [0; N];
+    for (word, slot) in words.iter_mut().zip(record) {
+        *word = slot.load(Ordering::Relaxed);
+    }
+    let checksum = fnv1a(words.iter().flat_map(|word| word.to_le_bytes()));
+    (record[N].load(Ordering::Relaxed) == checksum).then_some(words)
+}
+
+fn store(record: &[AtomicU32], words: &[u32]) {
+    for (slot, word) in record.iter().zip(words) {
+        slot.store(*word, Ordering::Relaxed);
+    }
+    let checksum = fnv1a(words.iter().flat_map(|word| word.to_le_bytes()));
+    record[words.len()].store(checksum, Ordering::Relaxed);
+}
+
+fn clear(record: &[AtomicU32]) {
+    if let Some(checksum) = record.last() {
+        checksum.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn access_point(ssid: &str) -> Option<AccessPoint> {
+    let [hash, low, high] = load::<3>(&ACCESS_POINT)?;
+    if hash != ssid_hash(ssid) {
+        return None;
+    }
+    let [b0, b1, b2, b3] = low.to_le_bytes();
+    let [b4, b5, channel, _] = high.to_le_bytes();
+    Some(AccessPoint {
+        bssid: [b0, b1, b2, b3, b4, b5],
+        channel,
+    })
+}
+
+pub(crate) fn remember_access_point(ssid: &str, ap: AccessPoint) {
+    let [b0, b1, b2, b3, b4, b5] = ap.bssid;
+    store(
+        &ACCESS_POINT,
+        &[
+            ssid_hash(ssid),
+            u32::from_le_bytes([b0, b1, b2, b3]),
+            u32::from_le_bytes([b4, b5, ap.channel, 0]),
+        ],
+    );
+}
+
+/// The access point moved or is gone, the next connect scans again
+pub(crate) fn forget_access_point() {
+    clear(&ACCESS_POINT);
+}
+
+/// The cached lease of the network if it doesn't need a renewal yet
+pub(crate) fn lease(ssid: &str, now: jiff::Timestamp) -> Option<embassy_net::StaticConfigV4> {
+    let [hash, address, prefix, gateway, dns, renew_at] = load::<6>(&LEASE)?;
+    if hash != ssid_hash(ssid) || now.as_second() >= renew_at as i64 || prefix > 32 {
+        return None;
+    }
+
+    #[allow(clippy::default_trait_access)]
+    let mut config = embassy_net::StaticConfigV4 {
+        address: embassy_net::Ipv4Cidr::new(Ipv4Addr::from_bits(address), prefix as u8),
+        gateway: (gateway != 0).then(|| Ipv4Addr::from_bits(gateway)),
+        dns_servers: Default::default(),
+    };
+    if dns != 0 {
+        let _ = config.dns_servers.push(Ipv4Addr::from_bits(dns));
+    }
+    LEASE_REUSED.store(true, Ordering::Relaxed);
+    Some(config)
+}
+
+/// Keeps the lease DHCP assigned until its renewal time
+pub(crate) fn remember_lease(
+    ssid: &str,
+    config: &embassy_net::StaticConfigV4,
+    renew_at: jiff::Timestamp,
+) {
+    store(
+        &LEASE,
+        &[
+            ssid_hash(ssid),
+            config.address.address().to_bits(),
+            config.address.prefix_len() as u32,
+            config.gateway.map_or(0, Ipv4Addr::to_bits),
+            config.dns_servers.first().map_or(0, |dns| dns.to_bits()),
+            renew_at.as_second() as u32,
+        ],
+    );
+}
+
+/// The network may have changed, the next wake asks the DHCP server again
+pub(crate) fn forget_lease() {
+    clear(&LEASE);
+}
+
+pub(crate) fn lease_reused() -> bool {
+    LEASE_REUSED.load(Ordering::Relaxed)
+}
+
+/// Waits for the DHCP ACK and returns the seconds until the lease has to be renewed, gives up
+/// shortly after the stack got configured.
+///
+/// embassy-net's DHCP socket consumes the reply, a raw socket gets a copy of it.
+pub(crate) async fn capture_renewal_time(stack: Stack<'_>) -> Option<u32> {
+    if lease_reused() {
+        return None;
+    }
+    let mac = match stack.hardware_address() {
+        embassy_net::HardwareAddress::Ethernet(mac) => mac,
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    };
+
+    let mut rx_meta = [embassy_net::raw::PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 2048];
+    let mut tx_meta = [embassy_net::raw::PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 1];
+    let raw = embassy_net::raw::RawSocket::new::<esp_radio::wifi::WifiDevice<'static>>(
+        stack,
+        IpVersion::Ipv4,
+        IpProtocol::Udp,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut packet = [0u8; 1500];
+    let ack = async {
+        loop {
+            let len = raw.recv(&mut packet).await.ok()?;
+            let Some(ip) = Ipv4Packet::new_checked(&packet[..len]).ok() else {
+                continue;
+            };
+            let Some(udp) = UdpPacket::new_checked(ip.payload()).ok() else {
+                continue;
+            };
+            if udp.src_port() != DHCP_SERVER_PORT || udp.dst_port() != DHCP_CLIENT_PORT {
+                continue;
+            }
+            let Some(dhcp) = DhcpPacket::new_checked(udp.payload()).ok() else {
+                continue;
+            };
+            if let Ok(repr) = DhcpRepr::parse(&dhcp)
+                && repr.message_type == DhcpMessageType::Ack
+                && repr.client_hardware_address == mac
+            {
+                // T1 defaults to half of the lease
+                return repr
+                    .renew_duration
+                    .or(repr.lease_duration.map(|lease| lease / 2));
+            }
+        }
+    };
+    // The ACK arrives right before the stack is configured
+    let configured = async {
+        stack.wait_config_up().await;
+        embassy_time::Timer::after(embassy_time::Duration::from_secs(1)).await;
+    };
+    match embassy_futures::select::select(ack, configured).await {
+        embassy_futures::select::Either::First(renewal) => renewal,
+        embassy_futures::select::Either::Second(()) => None,
+    }
+}
@@ -53,6 +53,7 @@ pub enum DNamespace {
     ResourceType,
     Collection,
     GetEtag,
+    SyncToken,
     Other(String),
 }
 
@@ -65,6 +66,48 @@ pub enum CalNamespace {
     Other(String),
 }
 
+impl Namespace {
+    /// The element name without its prefix, servers don't agree on the prefixes
+    pub fn local_name(&self) -> &str {
+        match self {
+            Namespace::D(d) => d.local_name(),
+            Namespace::Cal(cal) => cal.local_name(),
+            Namespace::Other(_, name) => name,
+        }
+    }
+}
+
+impl DNamespace {
+    pub fn local_name(&self) -> &str {
+        match self {
+            DNamespace::Multistatus => "multistatus",
+            DNamespace::Response => "response",
+            DNamespace::Href => "href",
+            DNamespace::PropStat => "propstat",
+            DNamespace::Prop => "prop",
+            DNamespace::DisplayName => "displayname",
+            DNamespace::Status => "status",
+            DNamespace::ResourceType => "resourcetype",
+            DNamespace::Collection => "collection",
+            DNamespace::GetEtag => "getetag",
+            DNamespace::SyncToken => "sync-token",
+            DNamespace::Other(name) => name,
+        }
+    }
+}
+
+impl CalNamespace {
+    pub fn local_name(&self) -> &str {
+        match self {
+            CalNamespace::SupportedCalendarComponentSet => "supported-calendar-component-set",
+            CalNamespace::Comp => "comp",
+            CalNamespace::Calendar => "calendar",
+            CalNamespace::CalendarData => "calendar-data",
+            CalNamespace::Other(name) => name,
+        }
+    }
+}
+
 fn tag_name_char(c: char) -> bool {
     c.is_alphanumeric() || c == '-' || c == '_' || c == '.'
 }
@@ -82,6 +125,7 @@ fn classify_namespace(ns: &str, name: &str) -> Namespace {
             "resourcetype" => DNamespace::ResourceType,
             "collection" => DNamespace::Collection,
             "getetag" => DNamespace::GetEtag,
+            "sync-token" => DNamespace::SyncToken,
             other => DNamespace::Other(other.to_string()),
         }),
         "cal" => Namespace::Cal(match name {
@@ -178,17 +222,72 @@ mod tests {
         assert_eq!(event, XmlEvent::Close(Namespace::D(DNamespace::Href)));
         let (input, event) = parse_xml_event(input).unwrap();
         assert_eq!(event, XmlEvent::Close(Namespace::D(DNamespace::Response)));
-        let (input, event) = parse_xml_event(input).unwrap();
+        let (_, event) = parse_xml_event(input).unwrap();
         assert_eq!(
             event,
             XmlEvent::Close(Namespace::D(DNamespace::Multistatus))
         );
     }
 
+    #[test]
+    fn sync_collection_test() {
+        let input = "<d:multistatus xmlns:d=\"DAV:\"><d:response><d:href>/calendars/u/work/a.ics</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response><d:sync-token>http://sabre.io/ns/sync/42</d:sync-token></d:multistatus>";
+
+        let mut events = alloc::vec::Vec::new();
+        let mut remaining = input;
+        while !remaining.is_empty() {
+            let (rest, event) = parse_xml_event(remaining).unwrap();
+            events.push(event);
+            remaining = rest;
+        }
+        assert_eq!(
+            events[events.len() - 4..],
+            [
+                XmlEvent::Open(Namespace::D(DNamespace::SyncToken)),
+                XmlEvent::Text("http://sabre.io/ns/sync/42".to_string()),
+                XmlEvent::Close(Namespace::D(DNamespace::SyncToken)),
+                XmlEvent::Close(Namespace::D(DNamespace::Multistatus)),
+            ]
+        );
+    }
+
+    #[test]
+    fn local_name_test() {
+        let input = "<D:current-user-principal><d:href>/principals/u/</d:href></D:current-user-principal><cal:calendar-home-set/>";
+
+        let (input, event) = parse_xml_event(input).unwrap();
+        assert_eq!(
+            event,
+            XmlEvent::Open(Namespace::Other(
+                "D".to_string(),
+                "current-user-principal".to_string()
+            ))
+        );
+        let (input, event) = parse_xml_event(input).unwrap();
+        let XmlEvent::Open(href) = event else {
+            panic!("Expected an opening tag, got {:?}", event);
+        };
+        assert_eq!(href.local_name(), "href");
+        let (input, _) = parse_xml_event(input).unwrap();
+        let (input, _) = parse_xml_event(input).unwrap();
+        let (input, event) = parse_xml_event(input).unwrap();
+        let XmlEvent::Close(principal) = event else {
+            panic!("Expected a closing tag, got {:?}", event);
+        };
+        assert_eq!(principal.local_name(), "current-user-principal");
+        let (_, event) = parse_xml_event(input).unwrap();
+        assert_eq!(
+            event,
+            XmlEvent::SelfClosing(Namespace::Cal(CalNamespace::Other(
+                "calendar-home-set".to_string()
+            )))
+        );
+    }
+
     #[test]
     fn href_test() {
         let input = "/remote.php/dav/calendars/tesztelek/</d:href>";
-        let (input, event) = parse_xml_event(input).unwrap();
+        let (_, event) = parse_xml_event(input).unwrap();
         assert_eq!(
             event,
             XmlEvent::Text("/remote.php/dav/calendars/tesztelek/".to_string())
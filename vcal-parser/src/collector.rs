@@ -0,0 +1,855 @@
+//! Turns the parsed lines of a calendar into events: applies the time zones of their dates
+//! and expands the recurring ones over the displayed window.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use jiff::civil::{DateTime, Time};
+use jiff::tz::{Offset, TimeZone};
+use jiff::{Span, Timestamp};
+
+use crate::recurrence::{Frequency, RRule, parse_rrule};
+use crate::vevent::{DateProperty, DateValue, VEventData, VcalEvent, parse_date_value};
+
+/// A STANDARD or DAYLIGHT component of a VTIMEZONE
+#[derive(Default)]
+struct Observance {
+    daylight: bool,
+    offset: Option<Offset>,
+    dtstart: Option<DateTime>,
+    rrule: Option<String>,
+}
+
+#[derive(Default)]
+struct PendingEvent {
+    summary: Option<String>,
+    uid: Option<String>,
+    dtstart: Option<DateProperty>,
+    dtend: Option<DateProperty>,
+    recurrence_id: Option<DateProperty>,
+    exdates: Vec<DateProperty>,
+    rrule: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Component {
+    #[default]
+    None,
+    Event,
+    TimeZone,
+    Observance,
+}
+
+/// A DTSTART or DTEND in the civil time of its zone
+struct CivilDate {
+    datetime: DateTime,
+    zone: TimeZone,
+    date_only: bool,
+}
+
+/// How long every occurrence of an event lasts
+#[derive(Clone, Copy)]
+enum Length {
+    /// All-day events keep their days across a DST change
+    Days(i32),
+    Seconds(i64),
+}
+
+/// Collects the VEVENTs of an iCalendar stream, fed line by line from
+/// [`crate::vevent::parse_vcal_event`].
+///
+/// Whole days, `VALUE=DATE`, and floating times are placed in `local`, the zone of the
+/// display. A `TZID` is looked up in the VTIMEZONE components which came before the event,
+/// the device has no time zone database, and falls back to `local` if it is missing.
+pub struct EventCollector {
+    local: TimeZone,
+    /// Recurring events are expanded over it and the events outside of it are dropped.
+    /// Without it every event is kept with its first occurrence only, for the CalDAV
+    /// responses the server already expanded.
+    window: Option<(Timestamp, Timestamp)>,
+    component: Component,
+    /// Depth of the components inside the current one which are ignored, like VALARM
+    nested: u8,
+    event: PendingEvent,
+    tzid: Option<String>,
+    observances: Vec<Observance>,
+    timezones: Vec<(String, TimeZone)>,
+    emitted: usize,
+    /// The emitted occurrences of recurring events, a RECURRENCE-ID may still replace them
+    occurrences: Vec<(String, Timestamp, usize)>,
+    /// The RECURRENCE-IDs seen so far
+    overrides: Vec<(String, Timestamp)>,
+    superseded: Vec<usize>,
+    /// The events without any occurrence in the window
+    pub skipped: usize,
+}
+
+impl EventCollector {
+    pub fn new(local: TimeZone, window: Option<(Timestamp, Timestamp)>) -> Self {
+        Self {
+            local,
+            window,
+            component: Component::None,
+            nested: 0,
+            event: PendingEvent::default(),
+            tzid: None,
+            observances: Vec::new(),
+            timezones: Vec::new(),
+            emitted: 0,
+            occurrences: Vec::new(),
+            overrides: Vec::new(),
+            superseded: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Takes the next parsed line and passes the finished events to `emit`.
+    ///
+    /// `emit` returns false once it doesn't take any more events, the collector stops then
+    /// and returns false too.
+    pub fn push(&mut self, line: VcalEvent, emit: &mut impl FnMut(VEventData) -> bool) -> bool {
+        use Component as C;
+        use VcalEvent as L;
+
+        if self.nested > 0 {
+            match line {
+                L::Begin(_) => self.nested = self.nested.saturating_add(1),
+                L::End(_) => self.nested -= 1,
+                _ => (),
+            }
+            return true;
+        }
+
+        match (self.component, line) {
+            (C::None, L::Begin(name)) if name == "VEVENT" => {
+                self.component = C::Event;
+                self.event = PendingEvent::default();
+            }
+            (C::None, L::Begin(name)) if name == "VTIMEZONE" => {
+                self.component = C::TimeZone;
+                self.tzid = None;
+                self.observances.clear();
+            }
+            (C::TimeZone, L::Begin(name)) if name == "STANDARD" || name == "DAYLIGHT" => {
+                self.component = C::Observance;
+                self.observances.push(Observance {
+                    daylight: name == "DAYLIGHT",
+                    ..Default::default()
+                });
+            }
+            (C::Event | C::TimeZone | C::Observance, L::Begin(_)) => self.nested = 1,
+            (C::Event, L::End(_)) => {
+                self.component = C::None;
+                return self.finish_event(emit);
+            }
+            (C::TimeZone, L::End(_)) => {
+                self.component = C::None;
+                self.finish_timezone();
+            }
+            (C::Observance, L::End(_)) => self.component = C::TimeZone,
+
+            (C::Event, L::Summary(summary)) => self.event.summary = Some(summary),
+            (C::Event, L::Uid(uid)) => self.event.uid = Some(uid),
+            (C::Event, L::DtStart(dtstart)) => self.event.dtstart = Some(dtstart),
+            (C::Event, L::DtEnd(dtend)) => self.event.dtend = Some(dtend),
+            (C::Event, L::RecurrenceId(id)) => self.event.recurrence_id = Some(id),
+            (C::Event, L::ExDate(exdate)) => self.event.exdates.push(exdate),
+            (C::Event, L::RRule(rrule)) => self.event.rrule = Some(rrule),
+
+            (C::TimeZone, L::TzId(tzid)) => self.tzid = Some(tzid),
+            (C::Observance, line) => {
+                let Some(observance) = self.observances.last_mut() else {
+                    return true;
+                };
+                match line {
+                    L::DtStart(dtstart) => {
+                        observance.dtstart = match parse_date_value(&dtstart.value, false) {
+                            Ok(DateValue::Local(datetime)) => Some(datetime),
+                            _ => None,
+                        }
+                    }
+                    L::TzOffsetTo(offset) => observance.offset = parse_offset(&offset),
+                    L::RRule(rrule) => observance.rrule = Some(rrule),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        true
+    }
+
+    /// The indices of the emitted events which a later RECURRENCE-ID replaced, in order.
+    ///
+    /// The overrides may come after the event they change, so these can only be removed
+    /// once the whole calendar is parsed.
+    pub fn take_superseded(&mut self) -> Vec<usize> {
+        let mut superseded = core::mem::take(&mut self.superseded);
+        superseded.sort_unstable();
+        superseded.dedup();
+        superseded
+    }
+
+    fn finish_timezone(&mut self) {
+        let Some(tzid) = self.tzid.take() else {
+            return;
+        };
+        match observance_zone(&self.observances) {
+            Some(zone) => self.timezones.push((tzid, zone)),
+            None => defmt::warn!("Unsupported VTIMEZONE: {}", tzid.as_str()),
+        }
+        self.observances.clear();
+    }
+
+    /// The zone of a date which isn't in UTC
+    fn zone(&self, property: &DateProperty) -> &TimeZone {
+        let Some(tzid) = property.tzid.as_deref() else {
+            return &self.local;
+        };
+        match self.timezones.iter().find(|(id, _)| id == tzid) {
+            Some((_, zone)) => zone,
+            None => {
+                defmt::warn!("No VTIMEZONE for {}, using the local time", tzid);
+                &self.local
+            }
+        }
+    }
+
+    fn civil(&self, property: &DateProperty, value: &str) -> Option<CivilDate> {
+        match parse_date_value(value, property.date_only) {
+            Ok(DateValue::Date(date)) => Some(CivilDate {
+                datetime: date.to_datetime(Time::midnight()),
+                zone: self.local.clone(),
+                date_only: true,
+            }),
+            Ok(DateValue::Utc(timestamp)) => Some(CivilDate {
+                datetime: TimeZone::UTC.to_datetime(timestamp),
+                zone: TimeZone::UTC,
+                date_only: false,
+            }),
+            Ok(DateValue::Local(datetime)) => Some(CivilDate {
+                datetime,
+                zone: self.zone(property).clone(),
+                date_only: false,
+            }),
+            Err(_) => {
+                defmt::warn!("Invalid date: {}", value);
+                None
+            }
+        }
+    }
+
+    fn timestamp(&self, property: &DateProperty) -> Option<Timestamp> {
+        let civil = self.civil(property, &property.value)?;
+        civil.zone.to_timestamp(civil.datetime).ok()
+    }
+
+    fn emit(&mut self, event: VEventData, emit: &mut impl FnMut(VEventData) -> bool) -> bool {
+        if !emit(event) {
+            return false;
+        }
+        self.emitted += 1;
+        true
+    }
+
+    fn finish_event(&mut self, emit: &mut impl FnMut(VEventData) -> bool) -> bool {
+        let event = core::mem::take(&mut self.event);
+        let Some(start) = event.dtstart.as_ref().and_then(|d| self.civil(d, &d.value)) else {
+            self.skipped += 1;
+            return true;
+        };
+        let Ok(start_time) = start.zone.to_timestamp(start.datetime) else {
+            self.skipped += 1;
+            return true;
+        };
+        let end = event.dtend.as_ref().and_then(|d| self.civil(d, &d.value));
+        let length = match end {
+            Some(end) if start.date_only && end.date_only => Length::Days(
+                start
+                    .datetime
+                    .date()
+                    .until(end.datetime.date())
+                    .map_or(1, |span| span.get_days()),
+            ),
+            Some(end) => Length::Seconds(
+                end.zone
+                    .to_timestamp(end.datetime)
+                    .map_or(0, |end| end.as_second() - start_time.as_second()),
+            ),
+            // RFC 5545 section 3.6.1
+            None if start.date_only => Length::Days(1),
+            None => Length::Seconds(0),
+        };
+
+        let Some((window_start, window_end)) = self.window else {
+            let end = occurrence_end(&start.zone, start.datetime, start_time, length);
+            let summary = event.summary;
+            return self.emit(
+                VEventData {
+                    summary,
+                    dtstart: Some(start_time),
+                    dtend: end,
+                },
+                emit,
+            );
+        };
+
+        if let (Some(uid), Some(id)) = (&event.uid, &event.recurrence_id)
+            && let Some(id) = self.timestamp(id)
+        {
+            self.superseded.extend(
+                self.occurrences
+                    .iter()
+                    .filter(|(u, start, _)| u == uid && *start == id)
+                    .map(|(_, _, index)| *index),
+            );
+            self.overrides.push((uid.clone(), id));
+        }
+
+        let rule = match event.rrule.as_deref().map(parse_rrule) {
+            Some(Ok(rule)) if event.recurrence_id.is_none() => Some(rule),
+            Some(Err(e)) => {
+                defmt::warn!(
+                    "Only the first occurrence is shown: {}",
+                    defmt::Debug2Format(&e)
+                );
+                None
+            }
+            _ => None,
+        };
+        let Some(rule) = rule else {
+            let end = occurrence_end(&start.zone, start.datetime, start_time, length);
+            let single = VEventData {
+                summary: event.summary,
+                dtstart: Some(start_time),
+                dtend: end,
+            };
+            if !single.overlaps(window_start, window_end) {
+                self.skipped += 1;
+                return true;
+            }
+            return self.emit(single, emit);
+        };
+
+        let exdates: Vec<Timestamp> = event
+            .exdates
+            .iter()
+            .flat_map(|exdate| {
+                exdate
+                    .value
+                    .split(',')
+                    .filter_map(|value| self.civil(exdate, value))
+            })
+            .filter_map(|civil| civil.zone.to_timestamp(civil.datetime).ok())
+            .collect();
+        let mut shown = 0;
+        for (occurrence_start, occurrence_end) in
+            self.occurrences_in_window(&rule, &start, length, window_start, window_end)
+        {
+            let replaced = |uid: &String| {
+                self.overrides
+                    .iter()
+                    .any(|(u, id)| u == uid && *id == occurrence_start)
+            };
+            if exdates.contains(&occurrence_start) || event.uid.as_ref().is_some_and(replaced) {
+                continue;
+            }
+            let occurrence = VEventData {
+                summary: event.summary.clone(),
+                dtstart: Some(occurrence_start),
+                dtend: Some(occurrence_end),
+            };
+            if !self.emit(occurrence, emit) {
+                return false;
+            }
+            shown += 1;
+            if let Some(uid) = &event.uid {
+                self.occurrences
+                    .push((uid.clone(), occurrence_start, self.emitted - 1));
+            }
+        }
+        if shown == 0 {
+            self.skipped += 1;
+        }
+        true
+    }
+
+    /// The starts and ends of the occurrences which overlap the window
+    fn occurrences_in_window(
+        &self,
+        rule: &RRule,
+        start: &CivilDate,
+        length: Length,
+        window_start: Timestamp,
+        window_end: Timestamp,
+    ) -> Vec<(Timestamp, Timestamp)> {
+        let zone = &start.zone;
+        let until = rule.until.map(|until| match until {
+            // inclusive, RFC 5545 section 3.3.10
+            DateValue::Date(date) => date.to_datetime(Time::MAX),
+            DateValue::Utc(timestamp) => zone.to_datetime(timestamp),
+            DateValue::Local(datetime) => datetime,
+        });
+        let mut occurrences = rule.occurrences(start.datetime, until);
+        // an occurrence which started earlier may still last into the window
+        let lookback = match length {
+            Length::Days(days) => i64::from(days) + 1,
+            Length::Seconds(seconds) => seconds / 86_400 + 1,
+        };
+        if let Ok(from) = window_start.checked_sub(Span::new().days(lookback)) {
+            occurrences.skip_to(zone.to_datetime(from).date());
+        }
+
+        let mut in_window = Vec::new();
+        for occurrence in occurrences {
+            let Ok(occurrence_start) = zone.to_timestamp(occurrence) else {
+                continue;
+            };
+            if occurrence_start >= window_end {
+                break;
+            }
+            let Some(occurrence_end) = occurrence_end(zone, occurrence, occurrence_start, length)
+            else {
+                continue;
+            };
+            if occurrence_end > window_start {
+                in_window.push((occurrence_start, occurrence_end));
+            }
+        }
+        in_window
+    }
+}
+
+fn occurrence_end(
+    zone: &TimeZone,
+    start: DateTime,
+    start_time: Timestamp,
+    length: Length,
+) -> Option<Timestamp> {
+    match length {
+        Length::Days(days) => {
+            let end = start.checked_add(Span::new().days(days)).ok()?;
+            zone.to_timestamp(end).ok()
+        }
+        Length::Seconds(seconds) => start_time.checked_add(Span::new().seconds(seconds)).ok(),
+    }
+}
+
+/// Parses a UTC offset like `+0200` or `-053000`
+fn parse_offset(value: &str) -> Option<Offset> {
+    let (sign, digits) = match value.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    let field = |range: core::ops::Range<usize>| -> Option<i32> {
+        match digits.get(range) {
+            Some("") | None => Some(0),
+            Some(field) => field.parse().ok(),
+        }
+    };
+    if !matches!(digits.len(), 4 | 6) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seconds = field(0..2)? * 3600 + field(2..4)? * 60 + field(4..6)?;
+    Offset::from_seconds(sign * seconds).ok()
+}
+
+/// `+02:00` as the `-2:00:00` of a POSIX TZ string, which counts the other way
+fn posix_offset(offset: Offset) -> String {
+    let seconds = -offset.seconds();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.unsigned_abs();
+    format!(
+        "{}{}:{:02}:{:02}",
+        sign,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The yearly change of an observance as a POSIX `Mm.w.d/time` rule
+fn posix_rule(observance: &Observance) -> Option<String> {
+    let rule = parse_rrule(observance.rrule.as_deref()?).ok()?;
+    let ([month], [(nth, weekday)]) = (&rule.by_month[..], &rule.by_day[..]) else {
+        return None;
+    };
+    let week = match nth {
+        1..=4 => *nth,
+        -1 => 5,
+        _ => return None,
+    };
+    if rule.frequency != Frequency::Yearly || rule.interval != 1 || !rule.by_month_day.is_empty() {
+        return None;
+    }
+    // the time of the change in the offset before it, like in a POSIX rule
+    let time = observance.dtstart?.time();
+    Some(format!(
+        "M{}.{}.{}/{}:{:02}:{:02}",
+        month,
+        week,
+        weekday.to_sunday_zero_offset(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    ))
+}
+
+/// Builds the zone of a VTIMEZONE from the observances which are still in effect
+fn observance_zone(observances: &[Observance]) -> Option<TimeZone> {
+    let current = |daylight: bool| {
+        observances
+            .iter()
+            .filter(|o| o.daylight == daylight && o.offset.is_some())
+            .filter(|o| o.rrule.as_deref().is_none_or(|r| !r.contains("UNTIL=")))
+            .max_by_key(|o| o.dtstart)
+    };
+    let (standard, daylight) = match (current(false), current(true)) {
+        (Some(standard), Some(daylight)) => (standard, daylight),
+        (Some(only), None) | (None, Some(only)) => return Some(TimeZone::fixed(only.offset?)),
+        (None, None) => return None,
+    };
+    if let (Some(to_standard), Some(to_daylight)) = (posix_rule(standard), posix_rule(daylight)) {
+        let posix = format!(
+            "STD{}DST{},{},{}",
+            posix_offset(standard.offset?),
+            posix_offset(daylight.offset?),
+            to_daylight,
+            to_standard
+        );
+        if let Ok(zone) = TimeZone::posix(&posix) {
+            return Some(zone);
+        }
+    }
+    // a one-off change, the latest one is in effect
+    let latest = if daylight.dtstart > standard.dtstart {
+        daylight
+    } else {
+        standard
+    };
+    Some(TimeZone::fixed(latest.offset?))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::vevent::{parse_vcal_event, parse_vcal_event_complete};
+
+    /// The display is in Central European Summer Time during the tests
+    const LOCAL: TimeZone = TimeZone::fixed(jiff::tz::offset(2));
+
+    /// Runs `body` through the parser like `parse_body_ics` does, in chunks of
+    /// `chunk` bytes
+    fn parse_ics(body: &str, window: (&str, &str), chunk: usize) -> Vec<VEventData> {
+        let window = (window.0.parse().unwrap(), window.1.parse().unwrap());
+        let mut collector = EventCollector::new(LOCAL, Some(window));
+        let mut events = Vec::new();
+        let mut emit = |event| {
+            events.push(event);
+            true
+        };
+
+        let mut spill = String::new();
+        let mut chunks = body.as_bytes().chunks(chunk).peekable();
+        while let Some(bytes) = chunks.next() {
+            spill.push_str(core::str::from_utf8(bytes).unwrap());
+            let at_end = chunks.peek().is_none();
+            let mut current = spill.as_str();
+            while !current.is_empty() {
+                let parsed = if at_end {
+                    parse_vcal_event_complete(current)
+                } else {
+                    parse_vcal_event(current)
+                };
+                match parsed {
+                    Ok((rem, line)) => {
+                        if let Some(line) = line {
+                            collector.push(line, &mut emit);
+                        }
+                        current = rem;
+                    }
+                    Err(nom::Err::Incomplete(_)) => break,
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+            spill = current.to_string();
+        }
+
+        for index in collector.take_superseded().into_iter().rev() {
+            events.remove(index);
+        }
+        events
+    }
+
+    fn event(summary: &str, start: &str, end: &str) -> VEventData {
+        VEventData::new(summary, start.parse().unwrap(), end.parse().unwrap())
+    }
+
+    const DAY: (&str, &str) = ("2026-04-15T00:00:00+02:00", "2026-04-16T00:00:00+02:00");
+
+    fn calendar(events: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Test//EN\r\n{events}END:VCALENDAR\r\n")
+    }
+
+    #[test]
+    fn utc_events_in_window() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nSUMMARY:Lunch\r\nDTSTART:20260415T100000Z\r\nDTEND:20260415T110000Z\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nSUMMARY:Yesterday\r\nDTSTART:20260414T100000Z\r\nDTEND:20260414T110000Z\r\nEND:VEVENT\r\n",
+        );
+        for chunk in [7, 64, body.len()] {
+            assert_eq!(
+                parse_ics(&body, DAY, chunk),
+                [event(
+                    "Lunch",
+                    "2026-04-15T10:00:00Z",
+                    "2026-04-15T11:00:00Z"
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn all_day_events() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nSUMMARY:Holiday\r\nDTSTART;VALUE=DATE:20260415\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nSUMMARY:Trip\r\nDTSTART;VALUE=DATE:20260413\r\nDTEND;VALUE=DATE:20260416\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nSUMMARY:Over\r\nDTSTART;VALUE=DATE:20260414\r\nDTEND;VALUE=DATE:20260415\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(
+            parse_ics(&body, DAY, 32),
+            [
+                event(
+                    "Holiday",
+                    "2026-04-15T00:00:00+02:00",
+                    "2026-04-16T00:00:00+02:00"
+                ),
+                event(
+                    "Trip",
+                    "2026-04-13T00:00:00+02:00",
+                    "2026-04-16T00:00:00+02:00"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn floating_times_are_local() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nSUMMARY:Standup\r\nDTSTART:20260415T093000\r\nDTEND:20260415T094500\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(
+            parse_ics(&body, DAY, 16),
+            [event(
+                "Standup",
+                "2026-04-15T09:30:00+02:00",
+                "2026-04-15T09:45:00+02:00"
+            )]
+        );
+    }
+
+    const NEW_YORK: &str = "BEGIN:VTIMEZONE\r\nTZID:America/New_York\r\n\
+        BEGIN:DAYLIGHT\r\nTZOFFSETFROM:-0500\r\nTZOFFSETTO:-0400\r\nTZNAME:EDT\r\n\
+        DTSTART:19700308T020000\r\nRRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=2SU\r\nEND:DAYLIGHT\r\n\
+        BEGIN:STANDARD\r\nTZOFFSETFROM:-0400\r\nTZOFFSETTO:-0500\r\nTZNAME:EST\r\n\
+        DTSTART:19701101T020000\r\nRRULE:FREQ=YEARLY;BYMONTH=11;BYDAY=1SU\r\nEND:STANDARD\r\n\
+        END:VTIMEZONE\r\n";
+
+    #[test]
+    fn tzid_from_vtimezone() {
+        let body = calendar(&format!(
+            "{NEW_YORK}BEGIN:VEVENT\r\nSUMMARY:Call\r\n\
+             DTSTART;TZID=America/New_York:20260414T200000\r\n\
+             DTEND;TZID=America/New_York:20260414T210000\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nSUMMARY:Winter call\r\n\
+             DTSTART;TZID=America/New_York:20260105T200000\r\n\
+             DTEND;TZID=America/New_York:20260105T210000\r\nEND:VEVENT\r\n"
+        ));
+        // daylight saving time in April
+        assert_eq!(
+            parse_ics(&body, DAY, 50),
+            [event(
+                "Call",
+                "2026-04-14T20:00:00-04:00",
+                "2026-04-14T21:00:00-04:00"
+            )]
+        );
+        let january = ("2026-01-06T00:00:00Z", "2026-01-07T00:00:00Z");
+        assert_eq!(
+            parse_ics(&body, january, 50),
+            [event(
+                "Winter call",
+                "2026-01-05T20:00:00-05:00",
+                "2026-01-05T21:00:00-05:00"
+            )]
+        );
+    }
+
+    #[test]
+    fn unknown_tzid_is_local() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nSUMMARY:Call\r\nDTSTART;TZID=Europe/Budapest:20260415T100000\r\n\
+             DTEND;TZID=Europe/Budapest:20260415T110000\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(
+            parse_ics(&body, DAY, 64),
+            [event(
+                "Call",
+                "2026-04-15T10:00:00+02:00",
+                "2026-04-15T11:00:00+02:00"
+            )]
+        );
+    }
+
+    #[test]
+    fn folded_lines() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nSUMMARY:Quarterly planning with the whole \r\n team and the \r\n\tstakeholders\r\n\
+             DTSTART;TZID=America/New_Yo\r\n rk:20260415T\r\n 100000Z\r\nDTEND:20260415T110000Z\r\nEND:VEVENT\r\n",
+        );
+        // the folds fall on the chunk boundaries as well
+        for chunk in 1..=12 {
+            assert_eq!(
+                parse_ics(&body, DAY, chunk),
+                [event(
+                    "Quarterly planning with the whole team and the stakeholders",
+                    "2026-04-15T10:00:00Z",
+                    "2026-04-15T11:00:00Z"
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn weekly_recurrence_in_window() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nUID:standup\r\nSUMMARY:Standup\r\nDTSTART:20260105T093000\r\n\
+             DTEND:20260105T094500\r\nRRULE:FREQ=WEEKLY;BYDAY=MO,WE,FR\r\nEND:VEVENT\r\n",
+        );
+        // a Wednesday
+        assert_eq!(
+            parse_ics(&body, DAY, 64),
+            [event(
+                "Standup",
+                "2026-04-15T09:30:00+02:00",
+                "2026-04-15T09:45:00+02:00"
+            )]
+        );
+        // nothing on Thursday
+        let thursday = ("2026-04-16T00:00:00+02:00", "2026-04-17T00:00:00+02:00");
+        assert!(parse_ics(&body, thursday, 64).is_empty());
+    }
+
+    #[test]
+    fn recurrence_keeps_the_local_time_across_dst() {
+        let body = calendar(&format!(
+            "{NEW_YORK}BEGIN:VEVENT\r\nUID:call\r\nSUMMARY:Call\r\n\
+             DTSTART;TZID=America/New_York:20260105T200000\r\n\
+             DTEND;TZID=America/New_York:20260105T210000\r\nRRULE:FREQ=DAILY\r\nEND:VEVENT\r\n"
+        ));
+        assert_eq!(
+            parse_ics(&body, DAY, 64),
+            [event(
+                "Call",
+                "2026-04-14T20:00:00-04:00",
+                "2026-04-14T21:00:00-04:00"
+            )]
+        );
+    }
+
+    #[test]
+    fn recurrence_exceptions() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nUID:gym\r\nSUMMARY:Gym\r\nDTSTART:20260401T160000Z\r\n\
+             DTEND:20260401T170000Z\r\nRRULE:FREQ=DAILY;COUNT=30\r\nEXDATE:20260414T160000Z,20260416T160000Z\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:lunch\r\nSUMMARY:Lunch\r\nDTSTART:20260401T100000Z\r\n\
+             DTEND:20260401T110000Z\r\nRRULE:FREQ=DAILY\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:lunch\r\nRECURRENCE-ID:20260415T100000Z\r\nSUMMARY:Late lunch\r\n\
+             DTSTART:20260415T120000Z\r\nDTEND:20260415T130000Z\r\nEND:VEVENT\r\n",
+        );
+        let window = ("2026-04-14T00:00:00Z", "2026-04-17T00:00:00Z");
+        assert_eq!(
+            parse_ics(&body, window, 64),
+            [
+                event("Gym", "2026-04-15T16:00:00Z", "2026-04-15T17:00:00Z"),
+                event("Lunch", "2026-04-14T10:00:00Z", "2026-04-14T11:00:00Z"),
+                event("Lunch", "2026-04-16T10:00:00Z", "2026-04-16T11:00:00Z"),
+                event("Late lunch", "2026-04-15T12:00:00Z", "2026-04-15T13:00:00Z"),
+            ]
+        );
+
+        // the override may also come first
+        let body = calendar(
+            "BEGIN:VEVENT\r\nUID:lunch\r\nRECURRENCE-ID:20260415T100000Z\r\nSUMMARY:Late lunch\r\n\
+             DTSTART:20260415T120000Z\r\nDTEND:20260415T130000Z\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:lunch\r\nSUMMARY:Lunch\r\nDTSTART:20260401T100000Z\r\n\
+             DTEND:20260401T110000Z\r\nRRULE:FREQ=DAILY\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(
+            parse_ics(&body, DAY, 64),
+            [event(
+                "Late lunch",
+                "2026-04-15T12:00:00Z",
+                "2026-04-15T13:00:00Z"
+            )]
+        );
+    }
+
+    #[test]
+    fn yearly_all_day_recurrence() {
+        let body = calendar(
+            "BEGIN:VEVENT\r\nUID:birthday\r\nSUMMARY:Birthday\r\nDTSTART;VALUE=DATE:19900415\r\n\
+             DTEND;VALUE=DATE:19900416\r\nRRULE:FREQ=YEARLY\r\n\
+             BEGIN:VALARM\r\nACTION:EMAIL\r\nSUMMARY:Reminder\r\nTRIGGER:-P1D\r\nEND:VALARM\r\nEND:VEVENT\r\n",
+        );
+        assert_eq!(
+            parse_ics(&body, DAY, 64),
+            [event(
+                "Birthday",
+                "2026-04-15T00:00:00+02:00",
+                "2026-04-16T00:00:00+02:00"
+            )]
+        );
+    }
+
+    #[test]
+    fn vtimezone_offsets() {
+        assert_eq!(parse_offset("+0200"), Some(jiff::tz::offset(2)));
+        assert_eq!(
+            parse_offset("-0530").map(Offset::seconds),
+            Some(-(5 * 3600 + 30 * 60))
+        );
+        assert_eq!(parse_offset("0200"), None);
+        assert_eq!(posix_offset(jiff::tz::offset(2)), "-2:00:00");
+        assert_eq!(posix_offset(jiff::tz::offset(-5)), "+5:00:00");
+    }
+
+    #[test]
+    fn collector_without_window_keeps_every_event() {
+        let mut collector = EventCollector::new(LOCAL, None);
+        let mut events = Vec::new();
+        let mut emit = |event| {
+            events.push(event);
+            true
+        };
+        for line in [
+            VcalEvent::Begin("VEVENT".to_string()),
+            VcalEvent::Summary("Old".to_string()),
+            VcalEvent::DtStart(DateProperty::new("20200101T100000Z")),
+            VcalEvent::RRule("FREQ=DAILY".to_string()),
+            VcalEvent::End("VEVENT".to_string()),
+        ] {
+            collector.push(line, &mut emit);
+        }
+        assert_eq!(
+            events,
+            [VEventData {
+                summary: Some("Old".to_string()),
+                dtstart: Some("2020-01-01T10:00:00Z".parse().unwrap()),
+                dtend: Some("2020-01-01T10:00:00Z".parse().unwrap()),
+            }]
+        );
+    }
+}
@@ -2,4 +2,6 @@
 extern crate alloc;
 
 pub mod calendars;
+pub mod collector;
+pub mod recurrence;
 pub mod vevent;
@@ -0,0 +1,484 @@
+//! Expands the `RRULE` of recurring events, RFC 5545 section 3.3.10.
+//!
+//! Only the parts calendar apps write for their repeat options are supported: the daily,
+//! weekly, monthly and yearly frequencies with `INTERVAL`, `COUNT`, `UNTIL`, `BYDAY`,
+//! `BYMONTHDAY`, `BYMONTH` and `WKST`.
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use jiff::civil::{Date, DateTime, Weekday};
+
+use crate::vevent::{DateValue, ParserErrors, parse_date_value};
+
+/// Stops the expansion of rules which never match, like every 30th of February
+const MAX_PERIODS: u32 = 10_000;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct RRule {
+    pub frequency: Frequency,
+    pub interval: u16,
+    pub count: Option<u32>,
+    pub until: Option<DateValue>,
+    /// The weekdays with their ordinal in the month or year, 0 stands for every one of them
+    pub by_day: Vec<(i8, Weekday)>,
+    /// Negative days count from the end of the month
+    pub by_month_day: Vec<i8>,
+    pub by_month: Vec<i8>,
+    pub week_start: Weekday,
+}
+
+fn weekday(name: &str) -> Option<Weekday> {
+    Some(match name {
+        "MO" => Weekday::Monday,
+        "TU" => Weekday::Tuesday,
+        "WE" => Weekday::Wednesday,
+        "TH" => Weekday::Thursday,
+        "FR" => Weekday::Friday,
+        "SA" => Weekday::Saturday,
+        "SU" => Weekday::Sunday,
+        _ => return None,
+    })
+}
+
+/// Parses a `BYDAY` entry like `TU` or `-1SU`
+fn ordinal_weekday(value: &str) -> Option<(i8, Weekday)> {
+    let split = value.len().checked_sub(2)?;
+    let day = weekday(value.get(split..)?)?;
+    let ordinal = match &value[..split] {
+        "" => 0,
+        ordinal => ordinal.trim_start_matches('+').parse().ok()?,
+    };
+    Some((ordinal, day))
+}
+
+fn list<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    value.split(',').map(parse).collect()
+}
+
+/// Parses the value of an `RRULE`, like `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH`
+pub fn parse_rrule(value: &str) -> Result<RRule, ParserErrors> {
+    let unsupported = || ParserErrors::UnsupportedRule(value.to_string());
+    let mut rule = RRule {
+        frequency: Frequency::Daily,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: Vec::new(),
+        by_month_day: Vec::new(),
+        by_month: Vec::new(),
+        week_start: Weekday::Monday,
+    };
+    let mut frequency = None;
+
+    for part in value.split(';').filter(|p| !p.is_empty()) {
+        let (name, value) = part.split_once('=').ok_or_else(unsupported)?;
+        match name {
+            "FREQ" => {
+                frequency = Some(match value {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    "YEARLY" => Frequency::Yearly,
+                    _ => return Err(unsupported()),
+                })
+            }
+            "INTERVAL" => rule.interval = value.parse().map_err(|_| unsupported())?,
+            "COUNT" => rule.count = Some(value.parse().map_err(|_| unsupported())?),
+            "UNTIL" => rule.until = Some(parse_date_value(value, false)?),
+            "BYDAY" => rule.by_day = list(value, ordinal_weekday).ok_or_else(unsupported)?,
+            "BYMONTHDAY" => {
+                rule.by_month_day = list(value, |d| d.parse().ok()).ok_or_else(unsupported)?
+            }
+            "BYMONTH" => rule.by_month = list(value, |m| m.parse().ok()).ok_or_else(unsupported)?,
+            "WKST" => rule.week_start = weekday(value).ok_or_else(unsupported)?,
+            _ => return Err(unsupported()),
+        }
+    }
+
+    rule.frequency = frequency.ok_or_else(unsupported)?;
+    if rule.interval == 0 {
+        return Err(unsupported());
+    }
+    Ok(rule)
+}
+
+/// The first `weekday` on or after `date`
+fn next_weekday(date: Date, weekday: Weekday) -> Option<Date> {
+    date.checked_add(jiff::Span::new().days(weekday.since(date.weekday())))
+        .ok()
+}
+
+/// The `nth` `weekday` between `first` and `last`, negative ordinals count from the end
+/// and 0 returns all of them
+fn weekdays_between(first: Date, last: Date, nth: i8, weekday: Weekday, dates: &mut Vec<Date>) {
+    let Some(mut day) = next_weekday(first, weekday) else {
+        return;
+    };
+    let start = dates.len();
+    while day <= last {
+        dates.push(day);
+        let Ok(next) = day.checked_add(jiff::Span::new().weeks(1)) else {
+            break;
+        };
+        day = next;
+    }
+    if nth == 0 {
+        return;
+    }
+    let found = dates.len() - start;
+    let index = if nth > 0 {
+        nth as usize - 1
+    } else {
+        found.wrapping_sub(nth.unsigned_abs() as usize)
+    };
+    let pick = (index < found).then(|| dates[start + index]);
+    dates.truncate(start);
+    dates.extend(pick);
+}
+
+fn days_between(earlier: Date, later: Date) -> i64 {
+    earlier
+        .until(later)
+        .map_or(0, |span| i64::from(span.get_days()))
+}
+
+impl RRule {
+    /// The starts of the occurrences in the civil time of `dtstart`, which is the first one
+    pub fn occurrences(&self, dtstart: DateTime, until: Option<DateTime>) -> Occurrences<'_> {
+        Occurrences {
+            rule: self,
+            dtstart,
+            until,
+            period: 0,
+            candidates: Vec::new(),
+            next_candidate: 0,
+            yielded: 0,
+            started: false,
+        }
+    }
+
+    /// The first day of the weekly period `dtstart` falls in
+    fn week_of(&self, date: Date) -> Date {
+        let back = date.weekday().since(self.week_start);
+        date.checked_sub(jiff::Span::new().days(back))
+            .unwrap_or(date)
+    }
+
+    /// The days of a month the rule picks, before `BYMONTH` filters them
+    fn days_of_month(&self, month: Date, dtstart: Date, dates: &mut Vec<Date>) {
+        let first = month.first_of_month();
+        let last = month.last_of_month();
+        let start = dates.len();
+        if !self.by_day.is_empty() {
+            for &(nth, weekday) in &self.by_day {
+                weekdays_between(first, last, nth, weekday, dates);
+            }
+            if !self.by_month_day.is_empty() {
+                let mut i = start;
+                while i < dates.len() {
+                    if self.month_day_matches(dates[i]) {
+                        i += 1;
+                    } else {
+                        dates.remove(i);
+                    }
+                }
+            }
+        } else if !self.by_month_day.is_empty() {
+            for &day in &self.by_month_day {
+                let day = if day < 0 {
+                    month.days_in_month() + day + 1
+                } else {
+                    day
+                };
+                dates.extend(Date::new(month.year(), month.month(), day).ok());
+            }
+        } else {
+            // months without the day of DTSTART are skipped, RFC 5545 section 3.3.10
+            dates.extend(Date::new(month.year(), month.month(), dtstart.day()).ok());
+        }
+    }
+
+    fn month_day_matches(&self, date: Date) -> bool {
+        let from_end = date.day() - date.days_in_month() - 1;
+        self.by_month_day
+            .iter()
+            .any(|&d| d == date.day() || d == from_end)
+    }
+
+    /// The candidates of the `period`th period after the one of `dtstart`, in order
+    fn period_dates(&self, dtstart: Date, period: i64, dates: &mut Vec<Date>) {
+        let step = period * i64::from(self.interval);
+        match self.frequency {
+            Frequency::Daily => {
+                let Ok(day) = dtstart.checked_add(jiff::Span::new().days(step)) else {
+                    return;
+                };
+                let weekday_matches =
+                    self.by_day.is_empty() || self.by_day.iter().any(|&(_, w)| w == day.weekday());
+                let month_day_matches = self.by_month_day.is_empty() || self.month_day_matches(day);
+                if weekday_matches && month_day_matches {
+                    dates.push(day);
+                }
+            }
+            Frequency::Weekly => {
+                let Ok(week) = self
+                    .week_of(dtstart)
+                    .checked_add(jiff::Span::new().weeks(step))
+                else {
+                    return;
+                };
+                if self.by_day.is_empty() {
+                    dates.extend(next_weekday(week, dtstart.weekday()));
+                }
+                for &(_, weekday) in &self.by_day {
+                    dates.extend(next_weekday(week, weekday));
+                }
+            }
+            Frequency::Monthly => {
+                let Ok(month) = dtstart
+                    .first_of_month()
+                    .checked_add(jiff::Span::new().months(step))
+                else {
+                    return;
+                };
+                self.days_of_month(month, dtstart, dates);
+            }
+            Frequency::Yearly => {
+                let Ok(year) = Date::new(dtstart.year(), 1, 1)
+                    .and_then(|y| y.checked_add(jiff::Span::new().years(step)))
+                else {
+                    return;
+                };
+                if self.by_month.is_empty() && !self.by_day.is_empty() {
+                    // the ordinals count in the whole year
+                    let last = year.last_of_year();
+                    for &(nth, weekday) in &self.by_day {
+                        weekdays_between(year, last, nth, weekday, dates);
+                    }
+                } else if self.by_month.is_empty() {
+                    let month = Date::new(year.year(), dtstart.month(), 1);
+                    if let Ok(month) = month {
+                        self.days_of_month(month, dtstart, dates);
+                    }
+                } else {
+                    for &month in &self.by_month {
+                        if let Ok(month) = Date::new(year.year(), month, 1) {
+                            self.days_of_month(month, dtstart, dates);
+                        }
+                    }
+                }
+            }
+        }
+        if !self.by_month.is_empty() {
+            dates.retain(|d| self.by_month.contains(&d.month()));
+        }
+        dates.sort_unstable();
+        dates.dedup();
+    }
+
+    /// The last period which starts before `date`, the ones before it can be skipped
+    fn period_before(&self, dtstart: Date, date: Date) -> i64 {
+        let periods = match self.frequency {
+            Frequency::Daily => days_between(dtstart, date),
+            Frequency::Weekly => days_between(self.week_of(dtstart), date) / 7,
+            Frequency::Monthly => {
+                i64::from(date.year() - dtstart.year()) * 12
+                    + i64::from(date.month() - dtstart.month())
+            }
+            Frequency::Yearly => i64::from(date.year() - dtstart.year()),
+        };
+        (periods / i64::from(self.interval) - 1).max(0)
+    }
+}
+
+/// Iterator of the occurrences of an [`RRule`]
+pub struct Occurrences<'r> {
+    rule: &'r RRule,
+    dtstart: DateTime,
+    until: Option<DateTime>,
+    period: i64,
+    candidates: Vec<Date>,
+    next_candidate: usize,
+    yielded: u32,
+    started: bool,
+}
+
+impl Occurrences<'_> {
+    /// Skips the periods which end before `date`.
+    ///
+    /// Only possible without `COUNT`, which has to count every occurrence from the start.
+    pub fn skip_to(&mut self, date: Date) {
+        if self.rule.count.is_some() || self.period != 0 {
+            return;
+        }
+        let period = self.rule.period_before(self.dtstart.date(), date);
+        if period > 0 {
+            self.period = period;
+            self.started = true;
+        }
+    }
+}
+
+impl Iterator for Occurrences<'_> {
+    type Item = DateTime;
+
+    fn next(&mut self) -> Option<DateTime> {
+        if self.rule.count.is_some_and(|count| self.yielded >= count) {
+            return None;
+        }
+        let next = if !self.started {
+            // DTSTART is always the first occurrence
+            self.started = true;
+            self.dtstart
+        } else {
+            loop {
+                if let Some(&date) = self.candidates.get(self.next_candidate) {
+                    self.next_candidate += 1;
+                    let start = date.to_datetime(self.dtstart.time());
+                    if start > self.dtstart {
+                        break start;
+                    }
+                    continue;
+                }
+                if self.period >= i64::from(MAX_PERIODS) {
+                    return None;
+                }
+                self.candidates.clear();
+                self.next_candidate = 0;
+                self.rule
+                    .period_dates(self.dtstart.date(), self.period, &mut self.candidates);
+                self.period += 1;
+            }
+        };
+        if self.until.is_some_and(|until| next > until) {
+            return None;
+        }
+        self.yielded += 1;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::civil::date;
+
+    use super::*;
+
+    fn expand(rule: &str, dtstart: DateTime, take: usize) -> Vec<DateTime> {
+        let rule = parse_rrule(rule).unwrap();
+        rule.occurrences(dtstart, None).take(take).collect()
+    }
+
+    fn days(dates: &[(i16, i8, i8)], hour: i8) -> Vec<DateTime> {
+        dates
+            .iter()
+            .map(|&(y, m, d)| date(y, m, d).at(hour, 0, 0, 0))
+            .collect()
+    }
+
+    #[test]
+    fn daily_with_interval_and_count() {
+        let start = date(2026, 4, 29).at(9, 0, 0, 0);
+        assert_eq!(
+            expand("FREQ=DAILY;INTERVAL=2;COUNT=3", start, 10),
+            days(&[(2026, 4, 29), (2026, 5, 1), (2026, 5, 3)], 9)
+        );
+    }
+
+    #[test]
+    fn weekly_on_several_days() {
+        // a Monday
+        let start = date(2026, 4, 13).at(10, 0, 0, 0);
+        assert_eq!(
+            expand("FREQ=WEEKLY;BYDAY=MO,TH;INTERVAL=2", start, 5),
+            days(
+                &[
+                    (2026, 4, 13),
+                    (2026, 4, 16),
+                    (2026, 4, 27),
+                    (2026, 4, 30),
+                    (2026, 5, 11)
+                ],
+                10
+            )
+        );
+        assert_eq!(
+            expand("FREQ=WEEKLY", start, 3),
+            days(&[(2026, 4, 13), (2026, 4, 20), (2026, 4, 27)], 10)
+        );
+    }
+
+    #[test]
+    fn weekly_until_is_inclusive() {
+        let start = date(2026, 4, 13).at(10, 0, 0, 0);
+        let rule = parse_rrule("FREQ=WEEKLY;UNTIL=20260427T100000Z").unwrap();
+        let until = date(2026, 4, 27).at(10, 0, 0, 0);
+        assert_eq!(
+            rule.occurrences(start, Some(until)).collect::<Vec<_>>(),
+            days(&[(2026, 4, 13), (2026, 4, 20), (2026, 4, 27)], 10)
+        );
+    }
+
+    #[test]
+    fn monthly_by_weekday_and_day() {
+        let start = date(2026, 1, 30).at(8, 0, 0, 0);
+        // the last Friday of the month
+        assert_eq!(
+            expand("FREQ=MONTHLY;BYDAY=-1FR", start, 3),
+            days(&[(2026, 1, 30), (2026, 2, 27), (2026, 3, 27)], 8)
+        );
+        // months without a 30th are skipped
+        assert_eq!(
+            expand("FREQ=MONTHLY", start, 3),
+            days(&[(2026, 1, 30), (2026, 3, 30), (2026, 4, 30)], 8)
+        );
+        assert_eq!(
+            expand("FREQ=MONTHLY;BYMONTHDAY=1,-1", start, 4),
+            days(
+                &[(2026, 1, 30), (2026, 1, 31), (2026, 2, 1), (2026, 2, 28)],
+                8
+            )
+        );
+    }
+
+    #[test]
+    fn yearly_birthday_and_leap_day() {
+        let start = date(2024, 2, 29).at(0, 0, 0, 0);
+        assert_eq!(
+            expand("FREQ=YEARLY", start, 2),
+            days(&[(2024, 2, 29), (2028, 2, 29)], 0)
+        );
+        // the fourth Thursday of November
+        let start = date(2025, 11, 27).at(0, 0, 0, 0);
+        assert_eq!(
+            expand("FREQ=YEARLY;BYMONTH=11;BYDAY=4TH", start, 2),
+            days(&[(2025, 11, 27), (2026, 11, 26)], 0)
+        );
+    }
+
+    #[test]
+    fn skipping_to_the_window() {
+        let rule = parse_rrule("FREQ=DAILY").unwrap();
+        let start = date(2010, 1, 1).at(7, 30, 0, 0);
+        let mut occurrences = rule.occurrences(start, None);
+        occurrences.skip_to(date(2026, 4, 15));
+        let first = occurrences.find(|o| o.date() >= date(2026, 4, 15)).unwrap();
+        assert_eq!(first, date(2026, 4, 15).at(7, 30, 0, 0));
+    }
+
+    #[test]
+    fn unsupported_rules() {
+        assert!(parse_rrule("FREQ=HOURLY").is_err());
+        assert!(parse_rrule("FREQ=MONTHLY;BYSETPOS=-1;BYDAY=MO,TU,WE,TH,FR").is_err());
+        assert!(parse_rrule("INTERVAL=2").is_err());
+        assert!(parse_rrule("FREQ=DAILY;INTERVAL=0").is_err());
+    }
+}
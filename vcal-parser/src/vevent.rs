@@ -1,24 +1,26 @@
 #[cfg(test)]
 extern crate std;
+use alloc::borrow::Cow;
 use alloc::string::{String, ToString};
-#[cfg(test)]
-use std::println;
 
 use jiff::Timestamp;
+use jiff::civil;
 use nom::{
     IResult, Parser,
     branch::alt,
-    bytes::streaming::{tag, take_till, take_while, take_while1},
-    character::streaming::char,
-    combinator::opt,
+    bytes::complete::{tag, take_till, take_till1, take_while1},
+    character::complete::char,
+    combinator::{opt, rest},
+    multi::many0,
+    sequence::{delimited, preceded, separated_pair},
 };
 
 #[derive(thiserror::Error, Debug)]
 pub enum ParserErrors {
-    #[error("Only datetime format is supported")]
-    DateOnly,
     #[error("Invalid date format: {0}")]
     InvalidDateFormat(String),
+    #[error("Unsupported recurrence rule: {0}")]
+    UnsupportedRule(String),
     #[error("Jiff parsing error: {0}")]
     JiffParsingError(alloc::string::String),
 }
@@ -34,17 +36,71 @@ pub enum VcalEvent {
     Begin(String),
     End(String),
     Summary(String),
-    DtStart(String),
-    DtEnd(String),
+    Uid(String),
+    DtStart(DateProperty),
+    DtEnd(DateProperty),
+    RecurrenceId(DateProperty),
+    /// May hold several dates separated by commas
+    ExDate(DateProperty),
+    RRule(String),
+    /// The name of a VTIMEZONE, which the `TZID` parameters refer to
+    TzId(String),
+    TzOffsetTo(String),
+}
+
+/// A date or date-time property with the parameters which decide how it is read
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct DateProperty {
+    pub value: String,
+    /// `VALUE=DATE`, the property is a whole day
+    pub date_only: bool,
+    pub tzid: Option<String>,
+}
+
+impl DateProperty {
+    pub fn new(value: &str) -> Self {
+        Self {
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A date or date-time value whose time zone isn't applied yet
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DateValue {
+    /// A whole day, in the time zone of the display
+    Date(civil::Date),
+    Utc(Timestamp),
+    /// A floating time or one in the `TZID` of the property
+    Local(civil::DateTime),
 }
 
-#[derive(Eq, PartialEq, Default, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Eq, PartialEq, Default, Clone, Debug)]
 pub struct VEventData {
     pub summary: Option<String>,
+    #[serde(with = "unix_seconds")]
     pub dtstart: Option<Timestamp>,
+    #[serde(with = "unix_seconds")]
     pub dtend: Option<Timestamp>,
 }
 
+/// Keeps the cached events small, jiff itself would serialize the timestamps as strings
+mod unix_seconds {
+    use jiff::Timestamp;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(ts: &Option<Timestamp>, s: S) -> Result<S::Ok, S::Error> {
+        ts.map(|t| t.as_second()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Timestamp>, D::Error> {
+        Option::<i64>::deserialize(d)?
+            .map(|secs| Timestamp::from_second(secs).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
 impl VEventData {
     pub fn new(summary: &str, dtstart: Timestamp, dtend: Timestamp) -> Self {
         Self {
@@ -61,6 +117,15 @@ impl VEventData {
             _ => None,
         }
     }
+
+    /// Returns true if the event intersects the `[start, end)` time window.
+    /// Events without both timestamps never overlap.
+    pub fn overlaps(&self, start: Timestamp, end: Timestamp) -> bool {
+        match (self.dtstart, self.dtend) {
+            (Some(event_start), Some(event_end)) => event_start < end && event_end > start,
+            _ => false,
+        }
+    }
 }
 
 impl PartialOrd for VEventData {
@@ -82,19 +147,56 @@ impl Ord for VEventData {
     }
 }
 
-pub fn parse_datetime(dt: &str) -> Result<Timestamp, ParserErrors> {
-    defmt::info!("Parsing date: {}", dt);
+/// Parses a DATE like `20260505` or a DATE-TIME like `20260505T100000Z`, the trailing `Z`
+/// marks UTC
+pub fn parse_date_value(value: &str, date_only: bool) -> Result<DateValue, ParserErrors> {
     use jiff::fmt::strtime;
 
-    // when the event is whole day, like 20260505
-    if dt.len() == 8 {
-        Err(ParserErrors::DateOnly)
-    } else if dt.len() == 16 {
-        let s = dt.strip_suffix('Z').unwrap();
-        let civil_dt = strtime::parse("%Y%m%dT%H%M%S", s)?.to_datetime()?;
-        Ok(civil_dt.to_zoned(jiff::tz::TimeZone::UTC)?.timestamp())
+    if date_only || value.len() == 8 {
+        Ok(DateValue::Date(strtime::parse("%Y%m%d", value)?.to_date()?))
+    } else if let Some(utc) = value.strip_suffix('Z') {
+        let civil_dt = strtime::parse("%Y%m%dT%H%M%S", utc)?.to_datetime()?;
+        Ok(DateValue::Utc(
+            jiff::tz::TimeZone::UTC.to_timestamp(civil_dt)?,
+        ))
+    } else if value.len() == 15 {
+        let civil_dt = strtime::parse("%Y%m%dT%H%M%S", value)?.to_datetime()?;
+        Ok(DateValue::Local(civil_dt))
     } else {
-        Err(ParserErrors::InvalidDateFormat(dt.to_string()))
+        Err(ParserErrors::InvalidDateFormat(value.to_string()))
+    }
+}
+
+/// Reads one content line and joins the folded lines which continue it, RFC 5545
+/// section 3.1.
+///
+/// Whether a line is folded only turns out from the first character of the next one, so
+/// the end of `input` is only the end of the line if `at_end` is set.
+fn unfold_line(input: &str, at_end: bool) -> IResult<&str, Cow<'_, str>> {
+    let incomplete = || nom::Err::Incomplete(nom::Needed::Unknown);
+    let mut line: Option<Cow<str>> = None;
+    let mut input = input;
+    loop {
+        let (physical, next) = match input.find('\n') {
+            Some(idx) => (&input[..idx], &input[idx + 1..]),
+            None if at_end => (input, ""),
+            None => return Err(incomplete()),
+        };
+        let physical = physical.strip_suffix('\r').unwrap_or(physical);
+        // CalDAV servers escape the CR inside the XML
+        let physical = physical.strip_suffix("&#13;").unwrap_or(physical);
+        match &mut line {
+            None => line = Some(Cow::Borrowed(physical)),
+            // the first whitespace only marks the fold
+            Some(line) => line.to_mut().push_str(&physical[1..]),
+        }
+        input = next;
+
+        match input.chars().next() {
+            Some(' ' | '\t') => (),
+            None if !at_end => return Err(incomplete()),
+            _ => return Ok((input, line.unwrap_or_default())),
+        }
     }
 }
 
@@ -102,32 +204,90 @@ fn property_name(input: &str) -> IResult<&str, &str> {
     take_while1(|c: char| c.is_alphanumeric() || c == '-').parse(input)
 }
 
-pub fn parse_vcal_event(input: &str) -> IResult<&str, Option<VcalEvent>> {
-    let (input, _) = take_while(|c: char| c == ' ' || c == '\t').parse(input)?;
-    let (input, name) = property_name(input)?;
-    let (input, _) = take_till(|c| c == ':' || c == '\r' || c == '\n').parse(input)?;
-    let (input, has_colon) = opt(char(':')).parse(input)?;
+/// A `;NAME=value` parameter, quoted values may contain `:` and `;`
+fn parameter(input: &str) -> IResult<&str, (&str, &str)> {
+    preceded(
+        char(';'),
+        separated_pair(
+            property_name,
+            char('='),
+            alt((
+                delimited(char('"'), take_till(|c| c == '"'), char('"')),
+                take_till1(|c| c == ';' || c == ':'),
+            )),
+        ),
+    )
+    .parse(input)
+}
 
-    let (input, value) = if has_colon.is_some() {
-        take_till(|c| c == '\r' || c == '\n').parse(input)?
-    } else {
-        (input, "")
-    };
+/// The name, parameters and value of a content line
+type ContentLine<'a> = (&'a str, alloc::vec::Vec<(&'a str, &'a str)>, &'a str);
 
-    let value = value.strip_suffix("&#13;").unwrap_or(value);
+/// Splits an unfolded content line into its name, parameters and value
+fn content_line(line: &str) -> IResult<&str, ContentLine<'_>> {
+    let (line, name) = property_name(line)?;
+    let (line, parameters) = many0(parameter).parse(line)?;
+    // a few servers leave out the colon of empty values
+    let (line, _) = take_till(|c| c == ':').parse(line)?;
+    let (line, value) = preceded(opt(tag(":")), rest).parse(line)?;
+    Ok((line, (name, parameters, value)))
+}
 
-    let (input, _) = alt((tag("\r\n"), tag("\n"))).parse(input)?;
+fn date_property(parameters: &[(&str, &str)], value: &str) -> DateProperty {
+    let parameter = |name: &str| {
+        parameters
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    };
+    DateProperty {
+        value: value.to_string(),
+        date_only: parameter("VALUE").is_some_and(|v| v.eq_ignore_ascii_case("DATE")),
+        tzid: parameter("TZID").map(ToString::to_string),
+    }
+}
+
+fn vcal_event(input: &str, at_end: bool) -> IResult<&str, Option<VcalEvent>> {
+    let (rem, line) = unfold_line(input, at_end)?;
+    if line.is_empty() {
+        return Ok((rem, None));
+    }
+    let Ok((_, (name, parameters, value))) = content_line(&line) else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    };
 
     let event = match name {
         "BEGIN" => Some(VcalEvent::Begin(value.to_string())),
         "END" => Some(VcalEvent::End(value.to_string())),
         "SUMMARY" => Some(VcalEvent::Summary(value.to_string())),
-        "DTSTART" => Some(VcalEvent::DtStart(value.to_string())),
-        "DTEND" => Some(VcalEvent::DtEnd(value.to_string())),
+        "UID" => Some(VcalEvent::Uid(value.to_string())),
+        "DTSTART" => Some(VcalEvent::DtStart(date_property(&parameters, value))),
+        "DTEND" => Some(VcalEvent::DtEnd(date_property(&parameters, value))),
+        "RECURRENCE-ID" => Some(VcalEvent::RecurrenceId(date_property(&parameters, value))),
+        "EXDATE" => Some(VcalEvent::ExDate(date_property(&parameters, value))),
+        "RRULE" => Some(VcalEvent::RRule(value.to_string())),
+        "TZID" => Some(VcalEvent::TzId(value.to_string())),
+        "TZOFFSETTO" => Some(VcalEvent::TzOffsetTo(value.to_string())),
         _ => None,
     };
 
-    Ok((input, event))
+    Ok((rem, event))
+}
+
+/// Parses the next content line of a streamed calendar.
+///
+/// Returns [`nom::Err::Incomplete`] until the first character after the line is known,
+/// see [`parse_vcal_event_complete`] for the end of the stream.
+pub fn parse_vcal_event(input: &str) -> IResult<&str, Option<VcalEvent>> {
+    vcal_event(input, false)
+}
+
+/// Like [`parse_vcal_event`], but the end of `input` also ends the last line
+pub fn parse_vcal_event_complete(input: &str) -> IResult<&str, Option<VcalEvent>> {
+    vcal_event(input, true)
 }
 
 #[cfg(test)]
@@ -137,38 +297,191 @@ mod tests {
 
     #[test]
     fn test_parse_vcal_event() {
-        let input = r#"VERSION:2.0&#13;
-        PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
-        CALSCALE:GREGORIAN&#13;
-        BEGIN:VEVENT&#13;
-        DTSTAMP:20260312T063325Z&#13;
-        UID:1d1a6701-97b5-40c0-933a-2f158030dbe4&#13;
-        SUMMARY:Este&#13;
-        DTSTART:20260416T210000Z&#13;
-        DTEND:20260416T215900Z&#13;
-        STATUS:CONFIRMED&#13;
-        SEQUENCE:4&#13;
-        CREATED:20260219T105853Z&#13;
-        RECURRENCE-ID:20260416T210000Z&#13;
-        END:VEVENT&#13;
-        END:VCALENDAR&#13;"#;
+        let mut input = "VERSION:2.0&#13;
+PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
+CALSCALE:GREGORIAN&#13;
+BEGIN:VEVENT&#13;
+DTSTAMP:20260312T063325Z&#13;
+UID:1d1a6701-97b5-40c0-933a-2f158030dbe4&#13;
+SUMMARY:Este&#13;
+DTSTART:20260416T210000Z&#13;
+DTEND:20260416T215900Z&#13;
+STATUS:CONFIRMED&#13;
+SEQUENCE:4&#13;
+CREATED:20260219T105853Z&#13;
+RECURRENCE-ID:20260416T210000Z&#13;
+END:VEVENT&#13;
+END:VCALENDAR&#13;
+";
 
-        let (input, event) = parse_vcal_event(input).unwrap();
-        assert_eq!(event, Some(VcalEvent::Summary("Este".to_string())));
+        let mut events = alloc::vec::Vec::new();
+        while !input.is_empty() {
+            let (rem, event) = parse_vcal_event_complete(input).unwrap();
+            events.extend(event);
+            input = rem;
+        }
+        assert_eq!(
+            events,
+            [
+                VcalEvent::Begin("VEVENT".to_string()),
+                VcalEvent::Uid("1d1a6701-97b5-40c0-933a-2f158030dbe4".to_string()),
+                VcalEvent::Summary("Este".to_string()),
+                VcalEvent::DtStart(DateProperty::new("20260416T210000Z")),
+                VcalEvent::DtEnd(DateProperty::new("20260416T215900Z")),
+                VcalEvent::RecurrenceId(DateProperty::new("20260416T210000Z")),
+                VcalEvent::End("VEVENT".to_string()),
+                VcalEvent::End("VCALENDAR".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_line_waits_for_the_next_one() {
+        let input = "SUMMARY:Este\r\n";
+        assert!(matches!(
+            parse_vcal_event(input),
+            Err(nom::Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            parse_vcal_event("SUMMARY:Es"),
+            Err(nom::Err::Incomplete(_))
+        ));
+        assert_eq!(
+            parse_vcal_event_complete(input).unwrap(),
+            ("", Some(VcalEvent::Summary("Este".to_string())))
+        );
+        assert_eq!(
+            parse_vcal_event_complete("SUMMARY:Este").unwrap(),
+            ("", Some(VcalEvent::Summary("Este".to_string())))
+        );
+    }
 
+    #[test]
+    fn test_folded_lines() {
+        // RFC 5545 section 3.1, any whitespace after the line break is a fold
+        let input = "SUMMARY:This is a lo\r\n ng description\r\n\t that exists on a long line.\r\nDTSTART\r\n ;TZID=Europe/Budapest:20260415T\r\n 103000\r\nEND:VEVENT\r\n";
         let (input, event) = parse_vcal_event(input).unwrap();
         assert_eq!(
             event,
-            Some(VcalEvent::DtStart("20251222T170000".to_string()))
+            Some(VcalEvent::Summary(
+                "This is a long description that exists on a long line.".to_string()
+            ))
         );
-
         let (input, event) = parse_vcal_event(input).unwrap();
-        assert_eq!(event, Some(VcalEvent::DtEnd("20251222T180000".to_string())));
+        assert_eq!(
+            event,
+            Some(VcalEvent::DtStart(DateProperty {
+                value: "20260415T103000".to_string(),
+                date_only: false,
+                tzid: Some("Europe/Budapest".to_string()),
+            }))
+        );
+        assert_eq!(input, "END:VEVENT\r\n");
 
-        let (input, event) = parse_vcal_event(input).unwrap();
-        assert_eq!(event, Some(VcalEvent::End("VEVENT".to_string())));
+        // the fold may be cut off by the end of the chunk
+        assert!(matches!(
+            parse_vcal_event("SUMMARY:This is a lo\r\n ng"),
+            Err(nom::Err::Incomplete(_))
+        ));
+
+        // inside a CalDAV response
+        let (_, event) = parse_vcal_event("SUMMARY:Szie&#13;\n szta&#13;\nEND:VEVENT").unwrap();
+        assert_eq!(event, Some(VcalEvent::Summary("Szieszta".to_string())));
+    }
+
+    #[test]
+    fn test_date_parameters() {
+        let event = |line: &str| parse_vcal_event_complete(line).unwrap().1;
+        assert_eq!(
+            event("DTSTART;VALUE=DATE:20260505"),
+            Some(VcalEvent::DtStart(DateProperty {
+                value: "20260505".to_string(),
+                date_only: true,
+                tzid: None,
+            }))
+        );
+        assert_eq!(
+            event("DTEND;TZID=\"America/New_York\";X-NOTE=\"a;b:c\":20260505T090000"),
+            Some(VcalEvent::DtEnd(DateProperty {
+                value: "20260505T090000".to_string(),
+                date_only: false,
+                tzid: Some("America/New_York".to_string()),
+            }))
+        );
+        assert_eq!(
+            event("EXDATE;VALUE=DATE:20260505,20260512"),
+            Some(VcalEvent::ExDate(DateProperty {
+                value: "20260505,20260512".to_string(),
+                date_only: true,
+                tzid: None,
+            }))
+        );
+        assert_eq!(
+            event("RRULE:FREQ=WEEKLY;BYDAY=MO,WE"),
+            Some(VcalEvent::RRule("FREQ=WEEKLY;BYDAY=MO,WE".to_string()))
+        );
+        assert_eq!(event("X-WR-CALNAME:Holidays"), None);
+        assert_eq!(event(""), None);
+    }
+
+    #[test]
+    fn test_parse_date_value() {
+        assert_eq!(
+            parse_date_value("20260505", true).unwrap(),
+            DateValue::Date(civil::date(2026, 5, 5))
+        );
+        // VALUE=DATE is optional for the short form
+        assert_eq!(
+            parse_date_value("20260505", false).unwrap(),
+            DateValue::Date(civil::date(2026, 5, 5))
+        );
+        assert_eq!(
+            parse_date_value("20260505T100000Z", false).unwrap(),
+            DateValue::Utc("2026-05-05T10:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            parse_date_value("20260505T100000", false).unwrap(),
+            DateValue::Local(civil::date(2026, 5, 5).at(10, 0, 0, 0))
+        );
+        assert!(parse_date_value("2026-05-05", false).is_err());
+        assert!(parse_date_value("20261305", true).is_err());
+    }
+
+    #[test]
+    fn test_postcard_round_trip() {
+        let event = VEventData::new(
+            "Standup",
+            Timestamp::from_second(1_776_290_400).unwrap(),
+            Timestamp::from_second(1_776_292_200).unwrap(),
+        );
+        let bytes = postcard::to_allocvec(&event).unwrap();
+        assert_eq!(postcard::from_bytes::<VEventData>(&bytes).unwrap(), event);
+
+        let empty = VEventData::default();
+        let bytes = postcard::to_allocvec(&empty).unwrap();
+        assert_eq!(postcard::from_bytes::<VEventData>(&bytes).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let event = VEventData::new(
+            "Lunch",
+            "2026-04-15T11:00:00Z".parse().unwrap(),
+            "2026-04-15T12:00:00Z".parse().unwrap(),
+        );
+        let window = |start: &str, end: &str| (start.parse().unwrap(), end.parse().unwrap());
+
+        let (start, end) = window("2026-04-15T08:00:00Z", "2026-04-15T16:00:00Z");
+        assert!(event.overlaps(start, end));
+        let (start, end) = window("2026-04-15T11:30:00Z", "2026-04-15T11:45:00Z");
+        assert!(event.overlaps(start, end));
+        let (start, end) = window("2026-04-15T12:00:00Z", "2026-04-15T20:00:00Z");
+        assert!(!event.overlaps(start, end));
+        let (start, end) = window("2026-04-15T06:00:00Z", "2026-04-15T11:00:00Z");
+        assert!(!event.overlaps(start, end));
 
-        assert_eq!(input, "");
+        let (start, end) = window("2026-04-15T08:00:00Z", "2026-04-15T16:00:00Z");
+        assert!(!VEventData::default().overlaps(start, end));
     }
 
     #[test]
@@ -184,55 +497,55 @@ mod tests {
             CalNamespace, Namespace, XmlEvent, parse_xml_event, parse_xml_version,
         };
         let input = r#"<?xml version="1.0"?>
-        <d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:cal="urn:ietf:params:xml:ns:caldav" xmlns:cs="http://calendarserver.org/ns/" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns"><d:response><d:href>/remote.php/dav/calendars/mmartin/szakdoga-teszt/65E6F4B7-4CEF-4CD0-BEDC-77734C0D5A61.ics</d:href><d:propstat><d:prop><d:getetag>&quot;19cd4124f96694c0be3b8c5ed8798a25&quot;</d:getetag><cal:calendar-data>BEGIN:VCALENDAR&#13;
-        VERSION:2.0&#13;
-        PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
-        CALSCALE:GREGORIAN&#13;
-        BEGIN:VEVENT&#13;
-        DTSTAMP:20260312T063325Z&#13;
-        UID:7e784d46-957c-4edd-9a4f-7179ebd5809c&#13;
-        SUMMARY:Szieszta&#13;
-        DTSTART:20260415T103000Z&#13;
-        DTEND:20260415T113000Z&#13;
-        STATUS:CONFIRMED&#13;
-        SEQUENCE:4&#13;
-        CREATED:20260219T111359Z&#13;
-        RECURRENCE-ID:20260415T103000Z&#13;
-        END:VEVENT&#13;
-        END:VCALENDAR&#13;
-        </cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response><d:response><d:href>/remote.php/dav/calendars/mmartin/szakdoga-teszt/8329A57E-DAFE-45CA-8DCC-E52998614CD1.ics</d:href><d:propstat><d:prop><d:getetag>&quot;6c1b7c1cc4f3dc040bc4e4897d0b9cbc&quot;</d:getetag><cal:calendar-data>BEGIN:VCALENDAR&#13;
-        VERSION:2.0&#13;
-        PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
-        CALSCALE:GREGORIAN&#13;
-        BEGIN:VEVENT&#13;
-        DTSTAMP:20260312T063325Z&#13;
-        UID:0401437d-1f41-4bbe-8d73-c4c60b191f20&#13;
-        SUMMARY:Éjfél&#13;
-        DTSTART:20260415T220000Z&#13;
-        DTEND:20260415T230000Z&#13;
-        STATUS:CONFIRMED&#13;
-        SEQUENCE:7&#13;
-        CREATED:20260218T125559Z&#13;
-        RECURRENCE-ID:20260415T220000Z&#13;
-        END:VEVENT&#13;
-        END:VCALENDAR&#13;
-        </cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response><d:response><d:href>/remote.php/dav/calendars/mmartin/szakdoga-teszt/AC4DC0A8-CE09-4AA0-AE0A-DDD88FAF24F5.ics</d:href><d:propstat><d:prop><d:getetag>&quot;8eee8c86bb5fbda441052450b01e9fe2&quot;</d:getetag><cal:calendar-data>BEGIN:VCALENDAR&#13;
-        VERSION:2.0&#13;
-        PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
-        CALSCALE:GREGORIAN&#13;
-        BEGIN:VEVENT&#13;
-        DTSTAMP:20260312T063325Z&#13;
-        UID:1d1a6701-97b5-40c0-933a-2f158030dbe4&#13;
-        SUMMARY:Este&#13;
-        DTSTART:20260415T210000Z&#13;
-        DTEND:20260415T215900Z&#13;
-        STATUS:CONFIRMED&#13;
-        SEQUENCE:4&#13;
-        CREATED:20260219T105853Z&#13;
-        RECURRENCE-ID:20260415T210000Z&#13;
-        END:VEVENT&#13;
-        END:VCALENDAR&#13;
-        </cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response></d:multistatus>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:cal="urn:ietf:params:xml:ns:caldav" xmlns:cs="http://calendarserver.org/ns/" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns"><d:response><d:href>/remote.php/dav/calendars/mmartin/szakdoga-teszt/65E6F4B7-4CEF-4CD0-BEDC-77734C0D5A61.ics</d:href><d:propstat><d:prop><d:getetag>&quot;19cd4124f96694c0be3b8c5ed8798a25&quot;</d:getetag><cal:calendar-data>BEGIN:VCALENDAR&#13;
+VERSION:2.0&#13;
+PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
+CALSCALE:GREGORIAN&#13;
+BEGIN:VEVENT&#13;
+DTSTAMP:20260312T063325Z&#13;
+UID:7e784d46-957c-4edd-9a4f-7179ebd5809c&#13;
+SUMMARY:Szieszta&#13;
+DTSTART:20260415T103000Z&#13;
+DTEND:20260415T113000Z&#13;
+STATUS:CONFIRMED&#13;
+SEQUENCE:4&#13;
+CREATED:20260219T111359Z&#13;
+RECURRENCE-ID:20260415T103000Z&#13;
+END:VEVENT&#13;
+END:VCALENDAR&#13;
+</cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response><d:response><d:href>/remote.php/dav/calendars/mmartin/szakdoga-teszt/8329A57E-DAFE-45CA-8DCC-E52998614CD1.ics</d:href><d:propstat><d:prop><d:getetag>&quot;6c1b7c1cc4f3dc040bc4e4897d0b9cbc&quot;</d:getetag><cal:calendar-data>BEGIN:VCALENDAR&#13;
+VERSION:2.0&#13;
+PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
+CALSCALE:GREGORIAN&#13;
+BEGIN:VEVENT&#13;
+DTSTAMP:20260312T063325Z&#13;
+UID:0401437d-1f41-4bbe-8d73-c4c60b191f20&#13;
+SUMMARY:Éjfél&#13;
+DTSTART:20260415T220000Z&#13;
+DTEND:20260415T230000Z&#13;
+STATUS:CONFIRMED&#13;
+SEQUENCE:7&#13;
+CREATED:20260218T125559Z&#13;
+RECURRENCE-ID:20260415T220000Z&#13;
+END:VEVENT&#13;
+END:VCALENDAR&#13;
+</cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response><d:response><d:href>/remote.php/dav/calendars/mmartin/szakdoga-teszt/AC4DC0A8-CE09-4AA0-AE0A-DDD88FAF24F5.ics</d:href><d:propstat><d:prop><d:getetag>&quot;8eee8c86bb5fbda441052450b01e9fe2&quot;</d:getetag><cal:calendar-data>BEGIN:VCALENDAR&#13;
+VERSION:2.0&#13;
+PRODID:-//Sabre//Sabre VObject 4.5.6//EN&#13;
+CALSCALE:GREGORIAN&#13;
+BEGIN:VEVENT&#13;
+DTSTAMP:20260312T063325Z&#13;
+UID:1d1a6701-97b5-40c0-933a-2f158030dbe4&#13;
+SUMMARY:Este&#13;
+DTSTART:20260415T210000Z&#13;
+DTEND:20260415T215900Z&#13;
+STATUS:CONFIRMED&#13;
+SEQUENCE:4&#13;
+CREATED:20260219T105853Z&#13;
+RECURRENCE-ID:20260415T210000Z&#13;
+END:VEVENT&#13;
+END:VCALENDAR&#13;
+</cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response></d:multistatus>
 "#;
 
         let (mut remaining, _) = parse_xml_version(input).unwrap();
@@ -274,8 +587,8 @@ mod tests {
 
         assert!(parsed_events.contains(&VcalEvent::Begin("VEVENT".to_string())));
         assert!(parsed_events.contains(&VcalEvent::Summary("Szieszta".to_string())));
-        assert!(parsed_events.contains(&VcalEvent::DtStart("20260415T103000Z".to_string())));
-        assert!(parsed_events.contains(&VcalEvent::DtEnd("20260415T113000Z".to_string())));
+        assert!(parsed_events.contains(&VcalEvent::DtStart(DateProperty::new("20260415T103000Z"))));
+        assert!(parsed_events.contains(&VcalEvent::DtEnd(DateProperty::new("20260415T113000Z"))));
         assert!(parsed_events.contains(&VcalEvent::End("VEVENT".to_string())));
     }
 }
@@ -1,13 +1,51 @@
 #![cfg(not(target_arch = "xtensa"))]
 #![feature(impl_trait_in_assoc_type)]
+#![recursion_limit = "256"]
 use std::net::SocketAddr;
 
+pub use ::defmt;
+
 #[path = "../../src/storage.rs"]
 pub mod storage;
 
 #[path = "../../src/server.rs"]
 pub mod server;
 
+#[path = "../../src/oauth_messages.rs"]
+#[allow(dead_code, reason = "the request bodies are built by the firmware")]
+pub mod oauth_messages;
+
+mod mock_oauth;
+mod networking;
+
+#[cfg(test)]
+#[path = "../../src/inflate.rs"]
+#[allow(dead_code, reason = "the header is only sent by the firmware")]
+mod inflate;
+
+#[cfg(test)]
+#[path = "../../src/ipv6_packets.rs"]
+#[allow(dead_code, reason = "the DHCPv6 request is built by the ipv6 task")]
+mod ipv6_packets;
+
+#[cfg(test)]
+#[path = "../../src/mdns_records.rs"]
+#[allow(dead_code, reason = "the announcement is sent by the mdns task")]
+mod mdns_records;
+
+#[cfg(test)]
+#[path = "../../src/portal_dns.rs"]
+mod portal_dns;
+
+#[cfg(test)]
+#[path = "../../src/tls_hello.rs"]
+#[allow(dead_code, reason = "the alert constants are only read by tls_probe")]
+mod tls_hello;
+
+#[cfg(test)]
+#[path = "../../src/tls_pin.rs"]
+mod tls_pin;
+
 use picoserve::AppBuilder;
 use server::AppProps;
 
@@ -31,13 +69,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let listener = tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
 
+    log::info!(
+        "Mock OAuth2 authorization server on http://localhost:{}",
+        mock_oauth::PORT
+    );
+    let mock_app = picoserve::make_static!(
+        picoserve::Router<<mock_oauth::MockOAuth as AppBuilder>::PathRouter>,
+        mock_oauth::MockOAuth.build_app()
+    );
+    let mock_app: &'static _ = &*mock_app;
+    let mock_listener =
+        tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], mock_oauth::PORT))).await?;
+
     tokio::task::LocalSet::new()
         .run_until(async {
+            tokio::task::spawn_local(async move {
+                loop {
+                    let (stream, _) = mock_listener.accept().await.unwrap();
+
+                    tokio::task::spawn_local(async move {
+                        let mut buffer = [0; 2048];
+                        if let Err(err) =
+                            picoserve::Server::new_tokio(mock_app, config, &mut buffer)
+                                .serve(stream)
+                                .await
+                        {
+                            println!("Error serving mock OAuth2 connection: {:?}", err);
+                        }
+                    });
+                }
+            });
+
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
 
                 tokio::task::spawn_local(async move {
-                    let mut buffer = [0; 2048];
+                    let mut buffer = [0; 6144];
 
                     match picoserve::Server::new_tokio(app, config, &mut buffer)
                         .serve(stream)
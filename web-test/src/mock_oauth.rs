@@ -0,0 +1,292 @@
+//! A local authorization server for the device authorization grant (RFC 8628).
+//!
+//! Setting the device authorization endpoint of an account to
+//! `http://<host>:8001/device_authorization` and the token endpoint to
+//! `http://<host>:8001/token` runs the sign-in of the firmware against it. The portal served
+//! by web-test answers its own sign-in from the same endpoint.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use picoserve::AppBuilder;
+use picoserve::response::StatusCode;
+
+use crate::oauth_messages::DEVICE_CODE_GRANT;
+
+pub const PORT: u16 = 8001;
+
+pub const DEVICE_CODE: &str = "GmRhmhcxhwAzkoEqiMEg_DnyEysNkuNhszIySk9eS";
+const USER_CODE: &str = "WDJB-MJHT";
+const VERIFICATION_URI: &str = "https://example.com/device";
+const ACCESS_TOKEN: &str = "2YotnFZFEjr1zCsicMWpAA";
+const REFRESH_TOKEN: &str = "tGzv3JOkF0XG5Qx2TlKWIA";
+const EXPIRES_IN: u32 = 900;
+/// Polling interval the client is told at the start
+const INTERVAL: Duration = Duration::from_secs(2);
+/// Added to the interval with every `slow_down`, RFC 8628 section 3.5
+const SLOW_DOWN_STEP: Duration = Duration::from_secs(5);
+/// The user finishes the sign-in before this poll
+const GRANTED_AFTER_POLLS: u32 = 3;
+
+#[derive(serde::Serialize)]
+pub struct DeviceAuthorizationResponse {
+    pub device_code: &'static str,
+    pub user_code: &'static str,
+    pub verification_uri: &'static str,
+    pub verification_uri_complete: &'static str,
+    pub expires_in: u32,
+    pub interval: u32,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum TokenResponse {
+    Granted {
+        access_token: &'static str,
+        token_type: &'static str,
+        expires_in: u32,
+        refresh_token: &'static str,
+    },
+    Error {
+        error: &'static str,
+    },
+}
+
+impl TokenResponse {
+    fn granted() -> Self {
+        TokenResponse::Granted {
+            access_token: ACCESS_TOKEN,
+            token_type: "Bearer",
+            expires_in: 3600,
+            refresh_token: REFRESH_TOKEN,
+        }
+    }
+
+    /// The body as the firmware receives it
+    pub fn to_body(&self) -> Vec<u8> {
+        let mut body = [0u8; 256];
+        let len = serde_json_core::to_slice(self, &mut body).unwrap();
+        body[..len].to_vec()
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            TokenResponse::Granted { .. } => StatusCode::OK,
+            TokenResponse::Error { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct TokenRequest {
+    grant_type: heapless::String<64>,
+    #[serde(default)]
+    device_code: heapless::String<64>,
+}
+
+/// State of the one sign-in the endpoint knows about
+pub struct MockTokenEndpoint {
+    started: Option<Instant>,
+    interval: Duration,
+    polls: u32,
+    last_poll: Option<Instant>,
+}
+
+impl MockTokenEndpoint {
+    pub const fn new() -> Self {
+        Self {
+            started: None,
+            interval: INTERVAL,
+            polls: 0,
+            last_poll: None,
+        }
+    }
+
+    /// Starts a new sign-in, an earlier one is forgotten
+    pub fn device_authorization(&mut self, now: Instant) -> DeviceAuthorizationResponse {
+        *self = Self::new();
+        self.started = Some(now);
+        DeviceAuthorizationResponse {
+            device_code: DEVICE_CODE,
+            user_code: USER_CODE,
+            verification_uri: VERIFICATION_URI,
+            verification_uri_complete: "https://example.com/device?user_code=WDJB-MJHT",
+            expires_in: EXPIRES_IN,
+            interval: INTERVAL.as_secs() as u32,
+        }
+    }
+
+    /// Grows with every `slow_down`
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Answers a request of the token endpoint at `now`
+    pub fn token(&mut self, grant_type: &str, device_code: &str, now: Instant) -> TokenResponse {
+        let error = |error| TokenResponse::Error { error };
+        match grant_type {
+            "refresh_token" => TokenResponse::granted(),
+            DEVICE_CODE_GRANT => {
+                let Some(started) = self.started else {
+                    return error("invalid_grant");
+                };
+                if device_code != DEVICE_CODE {
+                    return error("invalid_grant");
+                }
+                if now.duration_since(started) > Duration::from_secs(EXPIRES_IN as u64) {
+                    return error("expired_token");
+                }
+                let too_fast = self
+                    .last_poll
+                    .is_some_and(|last| now.duration_since(last) < self.interval);
+                self.last_poll = Some(now);
+                if too_fast {
+                    self.interval += SLOW_DOWN_STEP;
+                    return error("slow_down");
+                }
+                self.polls += 1;
+                if self.polls < GRANTED_AFTER_POLLS {
+                    return error("authorization_pending");
+                }
+                // The device code is only good for one token
+                self.started = None;
+                TokenResponse::granted()
+            }
+            _ => error("unsupported_grant_type"),
+        }
+    }
+}
+
+impl Default for MockTokenEndpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub static ENDPOINT: Mutex<MockTokenEndpoint> = Mutex::new(MockTokenEndpoint::new());
+
+pub struct MockOAuth;
+
+impl AppBuilder for MockOAuth {
+    type PathRouter = impl picoserve::routing::PathRouter;
+
+    fn build_app(self) -> picoserve::Router<Self::PathRouter> {
+        picoserve::Router::new()
+            .route(
+                "/device_authorization",
+                picoserve::routing::post(|| async {
+                    let response = ENDPOINT.lock().unwrap().device_authorization(Instant::now());
+                    picoserve::response::json::Json(response)
+                }),
+            )
+            .route(
+                "/token",
+                picoserve::routing::post(
+                    |picoserve::extract::Form(request): picoserve::extract::Form<TokenRequest>| async move {
+                        let response = ENDPOINT.lock().unwrap().token(
+                            &request.grant_type,
+                            &request.device_code,
+                            Instant::now(),
+                        );
+                        log::info!("Mock token endpoint: {:?}", response);
+                        let status = response.status();
+                        picoserve::response::json::Json(response)
+                            .into_response()
+                            .with_status_code(status)
+                    },
+                ),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth_messages::{TokenResult, parse_token_body};
+
+    fn parse(response: &TokenResponse) -> Option<TokenResult> {
+        parse_token_body(&response.to_body())
+    }
+
+    #[test]
+    fn sign_in_is_granted_after_pending_polls() {
+        let mut endpoint = MockTokenEndpoint::new();
+        let start = Instant::now();
+        let auth = endpoint.device_authorization(start);
+        let interval = Duration::from_secs(auth.interval as u64);
+
+        let mut now = start;
+        for _ in 1..GRANTED_AFTER_POLLS {
+            now += interval;
+            let response = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, now);
+            assert_eq!(parse(&response), Some(TokenResult::Pending));
+        }
+        now += interval;
+        let response = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, now);
+        assert_eq!(
+            parse(&response),
+            Some(TokenResult::Granted {
+                access_token: ACCESS_TOKEN.into(),
+                refresh_token: Some(heapless::String::try_from(REFRESH_TOKEN).unwrap()),
+                expires_in: 3600,
+            })
+        );
+
+        // The device code can't be used twice
+        now += interval;
+        let response = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, now);
+        assert_eq!(parse(&response), Some(TokenResult::Rejected));
+    }
+
+    #[test]
+    fn polling_too_fast_slows_down() {
+        let mut endpoint = MockTokenEndpoint::new();
+        let start = Instant::now();
+        let auth = endpoint.device_authorization(start);
+
+        let first = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, start);
+        assert_eq!(parse(&first), Some(TokenResult::Pending));
+        let early = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, start);
+        assert_eq!(parse(&early), Some(TokenResult::SlowDown));
+
+        // The old interval isn't enough anymore
+        let now = start + INTERVAL;
+        let response = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, now);
+        assert_eq!(parse(&response), Some(TokenResult::SlowDown));
+        let now = now + INTERVAL + 2 * SLOW_DOWN_STEP;
+        let response = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, now);
+        assert_eq!(parse(&response), Some(TokenResult::Pending));
+    }
+
+    #[test]
+    fn device_code_expires() {
+        let mut endpoint = MockTokenEndpoint::new();
+        let start = Instant::now();
+        let auth = endpoint.device_authorization(start);
+        let now = start + Duration::from_secs(EXPIRES_IN as u64 + 1);
+        let response = endpoint.token(DEVICE_CODE_GRANT, auth.device_code, now);
+        assert_eq!(parse(&response), Some(TokenResult::Expired));
+    }
+
+    #[test]
+    fn unknown_grants_are_rejected() {
+        let mut endpoint = MockTokenEndpoint::new();
+        let now = Instant::now();
+        let response = endpoint.token(DEVICE_CODE_GRANT, DEVICE_CODE, now);
+        assert_eq!(parse(&response), Some(TokenResult::Rejected));
+        endpoint.device_authorization(now);
+        let response = endpoint.token(DEVICE_CODE_GRANT, "guessed", now);
+        assert_eq!(parse(&response), Some(TokenResult::Rejected));
+        let response = endpoint.token("password", "", now);
+        assert_eq!(parse(&response), Some(TokenResult::Rejected));
+    }
+
+    #[test]
+    fn refresh_token_grant() {
+        let mut endpoint = MockTokenEndpoint::new();
+        let response = endpoint.token("refresh_token", "", Instant::now());
+        assert!(matches!(
+            parse(&response),
+            Some(TokenResult::Granted { .. })
+        ));
+    }
+}
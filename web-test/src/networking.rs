@@ -0,0 +1,12 @@
+//! Stands in for the `networking` module of the firmware, which needs the TLS client. Only
+//! the errors the routes return without a network are here.
+#[derive(thiserror::Error, picoserve::response::ErrorWithStatusCode, Debug)]
+#[status_code(INTERNAL_SERVER_ERROR)]
+pub enum NetworkError {
+    #[status_code(BAD_REQUEST)]
+    #[error("Failed to parse URL")]
+    WrongUrl,
+    #[status_code(UNAUTHORIZED)]
+    #[error("OAuth2 authorization failed")]
+    AuthorizationFailed,
+}